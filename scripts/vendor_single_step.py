@@ -0,0 +1,28 @@
+"""Copies the first tests of every opcode of the SingleStepTests suite (https://github.com/SingleStepTests/65x02)
+into asm_tests/single_step/6502, replacing the files there.
+
+Usage: python3 scripts/vendor_single_step.py <checkout of 65x02> [tests per opcode, default 10]
+"""
+import json
+import os
+import sys
+
+OUT_DIR = "asm_tests/single_step/6502"
+
+if len(sys.argv) < 2:
+    sys.exit(__doc__)
+
+upstream = os.path.join(sys.argv[1], "6502", "v1")
+count = int(sys.argv[2]) if len(sys.argv) > 2 else 10
+
+os.makedirs(OUT_DIR, exist_ok=True)
+for opcode in range(256):
+    name = "%02x.json" % opcode
+    with open(os.path.join(upstream, name)) as inFile:
+        tests = json.load(inFile)[:count]
+
+    # One test per line, so changes show up line by line in diffs
+    with open(os.path.join(OUT_DIR, name), "w") as outFile:
+        outFile.write("[\n")
+        outFile.write(",\n".join(json.dumps(test, separators=(",", ":")) for test in tests))
+        outFile.write("\n]\n")
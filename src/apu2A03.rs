@@ -0,0 +1,97 @@
+//! The audio processing unit of the 2A03, the cpu chip of the NES.
+//!
+//! Only the registers exist so far. Writes to them are kept, and the status register reports which channels are enabled.
+//! There is no sound yet.
+
+use crate::region::Region;
+
+/// The registers of the APU at $4000-$4013, $4015 and $4017
+#[derive(Debug, Clone)]
+pub struct Apu2A03 {
+    /// Decides the rate tables of the noise channel, the DMC and the frame counter
+    region: Region,
+    /// $4000-$4013: the registers of the two pulse channels, the triangle, the noise and the DMC channel
+    channel_registers: [u8; 0x14],
+    /// $4015: which channels are enabled, one bit each
+    channel_enable: u8,
+    /// $4017: the sequencer mode in bit 7 and the IRQ inhibit flag in bit 6
+    frame_counter: u8,
+}
+
+#[allow(unused)]
+impl Apu2A03 {
+    pub fn new() -> Self {
+        Apu2A03 {
+            region: Region::default(),
+            channel_registers: [0; 0x14],
+            channel_enable: 0,
+            frame_counter: 0,
+        }
+    }
+
+    /// Clears all registers, like on power on. The region is kept
+    pub fn power_on(&mut self) {
+        *self = Apu2A03 { region: self.region, ..Apu2A03::new() };
+    }
+
+    /// The reset button silences all channels, like writing 0 to $4015. The other registers keep their values
+    pub fn reset(&mut self) {
+        self.channel_enable = 0;
+    }
+
+    /// Switches the rate tables to those of the region
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+    }
+
+    /// Read from the main bus. Once the frame counter runs, reading the status acknowledges its interrupt
+    pub fn cpu_read(&mut self, addr: u16) -> u8 {
+        self.cpu_peek(addr)
+    }
+
+    /// What reading from the main bus would return, without acknowledging any interrupt.
+    /// Only the status register at $4015 can be read, the other registers are write only
+    pub fn cpu_peek(&self, addr: u16) -> u8 {
+        match addr {
+            // As there are no length counters yet, the channels count as playing while they are enabled
+            0x4015 => self.channel_enable & 0x1F,
+            _ => 0
+        }
+    }
+
+    /// Write to the main bus
+    pub fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4000..=0x4013 => self.channel_registers[(addr - 0x4000) as usize] = data,
+            0x4015 => self.channel_enable = data & 0x1F,
+            0x4017 => self.frame_counter = data & 0xC0,
+            // $4018-$401F are the test registers of the 2A03. They are disabled on retail consoles
+            _ => {}
+        }
+    }
+
+    /// The value last written to a channel register at $4000-$4013
+    pub fn channel_register(&self, addr: u16) -> u8 {
+        self.channel_registers[(addr - 0x4000) as usize]
+    }
+
+    /// The value last written to the frame counter at $4017
+    pub fn frame_counter(&self) -> u8 {
+        self.frame_counter
+    }
+
+    /// The period of the noise channel in cpu cycles, as selected by $400E
+    pub fn noise_period(&self) -> u16 {
+        self.region.noise_periods()[(self.channel_register(0x400E) & 0x0F) as usize]
+    }
+
+    /// The period of the DMC in cpu cycles, as selected by $4010
+    pub fn dmc_rate(&self) -> u16 {
+        self.region.dmc_rates()[(self.channel_register(0x4010) & 0x0F) as usize]
+    }
+
+    /// The cpu cycles the frame counter steps on, for the sequence selected by $4017
+    pub fn frame_counter_steps(&self) -> &'static [u32] {
+        self.region.frame_counter_steps(self.frame_counter & 0x80 != 0)
+    }
+}
@@ -1,102 +1,321 @@
-use crate::cpu6502::Cpu6502;
-use std::cell::{RefCell, Ref};
+use crate::cpu6502::memory::CpuBus;
+use image::RgbaImage;
 use std::fmt::Debug;
-use std::rc::Rc;
 use crate::ppu2C02::Ppu2C02;
 use crate::cartridge::Cartridge;
-use bitflags::_core::cell::RefMut;
+use crate::apu2A03::Apu2A03;
+use crate::controller::{Buttons, Controller};
+use crate::dma::{Dma, DmaCycle};
+use crate::region::Region;
+use crate::power_on::{Memory, PowerOnProfile};
+use crate::device::{AddressMap, BusDevice, Mapping, CARTRIDGE_PRIORITY, DEVICE_PRIORITY, REGISTER_PRIORITY};
 
 const RAM_SIZE: usize = 2048;
 
+/// The devices addresses are decoded to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Device {
+    Cartridge,
+    Ram,
+    Ppu,
+    Apu,
+    Dma,
+    Controllers,
+    /// A device connected with `Bus::register_device`, by its index
+    External(usize),
+}
+
+/// Everything the cpu is connected to. The bus owns all of the devices, and is owned by the cpu in turn (see `Nes`)
+///
+/// The cpu runs ahead of the ppu. The ppu is only caught up when the cpu accesses one of its registers,
+/// so it is in the same state it would be in, if both were clocked in lockstep
 pub struct Bus {
-    cpu: RefCell<Cpu6502>,
-    ppu: RefCell<Ppu2C02>,
-    cartridge: Option<Rc<RefCell<Cartridge>>>,
-    cpu_ram: RefCell<[u8; RAM_SIZE]>,
-    system_clock_counter: RefCell<u64>
+    region: Region,
+    cpu_cycle: u64, // The cpu cycle the cpu is working on
+    ppu_dots: u64, // The amount of dots the ppu has drawn, counted from the same point in time as the cpu cycles
+    ppu: Ppu2C02,
+    apu: Apu2A03,
+    controllers: [Controller; 2],
+    dma: Dma,
+    cartridge: Option<Cartridge>,
+    cpu_ram: [u8; RAM_SIZE],
+    open_bus: u8, // The last value on the cpu data bus. Reads nothing answers to return it
+    map: AddressMap<Device>, // Which devices answer to which addresses, see `device`
+    devices: Vec<Box<dyn BusDevice>>, // The devices connected with `register_device`
 }
 
 impl Bus {
-    pub fn new(cpu: Cpu6502, ppu: Ppu2C02) -> Rc<RefCell<Self>> {
-        let bus = Rc::new(RefCell::new(Bus {
-            cpu: RefCell::new(cpu),
-            ppu: RefCell::new(ppu),
+    pub fn new() -> Self {
+        Bus {
+            region: Region::default(),
+            cpu_cycle: 0,
+            ppu_dots: 0,
+            ppu: Ppu2C02::new(),
+            apu: Apu2A03::new(),
+            controllers: [Controller::new(), Controller::new()],
+            dma: Dma::new(),
             cartridge: None,
-            cpu_ram: RefCell::new([0; RAM_SIZE]),
-            system_clock_counter: RefCell::new(0),
-        }));
-        bus.borrow_mut().cpu.borrow_mut().connect_bus(bus.clone());
+            cpu_ram: [0; RAM_SIZE],
+            open_bus: 0,
+            map: Self::console_map(),
+            devices: Vec::new(),
+        }
+    }
+
+    /// The address space of the console
+    fn console_map() -> AddressMap<Device> {
+        let mut map = AddressMap::new();
+        // The cartridge gets "Priority access" to memory. The expansion area at $4020-$5FFF, program RAM at $6000-$7FFF
+        // and the program ROM belong to it alone, the rest of the address space goes to the console, if the cartridge doesn't answer
+        map.register(Mapping::new(0x0000..=0xFFFF, 0xFFFF, CARTRIDGE_PRIORITY), Device::Cartridge);
+        // The actual 2kb of RAM are mirrored across an 8kb address range
+        map.register(Mapping::new(0x0000..=0x1FFF, 0x07FF, DEVICE_PRIORITY), Device::Ram);
+        // Mirroring again. And yes, the ppu only has 8 bytes of memory
+        map.register(Mapping::new(0x2000..=0x3FFF, 0x0007, DEVICE_PRIORITY), Device::Ppu);
+        map.register(Mapping::new(0x4014..=0x4014, 0xFFFF, REGISTER_PRIORITY), Device::Dma);
+        map.register(Mapping::new(0x4016..=0x4017, 0xFFFF, REGISTER_PRIORITY), Device::Controllers);
+        // The APU, including the frame counter at $4017 and the test registers at $4018-$401F
+        map.register(Mapping::new(0x4000..=0x401F, 0xFFFF, DEVICE_PRIORITY), Device::Apu);
+        map
+    }
+
+    pub fn ppu(&self) -> &Ppu2C02 {
+        &self.ppu
+    }
+
+    pub fn ppu_mut(&mut self) -> &mut Ppu2C02 {
+        &mut self.ppu
+    }
+
+    /// Draws pattern table 0 or 1 in the colors of the palette for the debugger. See `Ppu2C02::draw_pattern_table`
+    pub fn draw_pattern_table(&mut self, i: usize, palette: u8) -> &RgbaImage {
+        self.ppu.draw_pattern_table(self.cartridge.as_ref(), i, palette);
+        self.ppu.get_pattern_table(i)
+    }
+
+    /// Draws name table 0 or 1 for the debugger. See `Ppu2C02::draw_name_table`
+    pub fn draw_name_table(&mut self, i: usize) -> &RgbaImage {
+        self.ppu.draw_name_table(self.cartridge.as_ref(), i);
+        self.ppu.get_name_table(i)
+    }
+
+    /// Puts every device into the state it is in when the console is switched on.
+    /// The RAM of the cpu, the ppu and the cartridge is filled the way the profile says
+    pub fn power_on(&mut self, profile: PowerOnProfile) {
+        profile.fill(Memory::CpuRam, &mut self.cpu_ram);
+        self.ppu.power_on(profile);
+        self.apu.power_on();
+        self.controllers = [Controller::new(), Controller::new()];
+        self.dma = Dma::new();
+        self.open_bus = 0;
+        if let Some(cartridge) = self.cartridge.as_mut() {
+            cartridge.power_on(profile);
+        }
+    }
+
+    /// Passes the reset button on to the devices wired to it. Unlike a power cycle, all RAM keeps its contents
+    pub fn reset(&mut self) {
+        self.ppu.reset();
+        self.apu.reset();
+        self.dma.reset();
+        if let Some(cartridge) = self.cartridge.as_mut() {
+            cartridge.reset();
+        }
+    }
 
-        bus
+    /// Presses the given buttons on the controller in port 0 or 1, and releases all others
+    pub fn set_buttons(&mut self, port: usize, buttons: Buttons) {
+        assert!(port < self.controllers.len(), "There are only {} controller ports", self.controllers.len());
+        self.controllers[port].set_buttons(buttons);
     }
 
-    pub fn cpu(&self) -> Ref<Cpu6502> {
-        self.cpu.borrow()
+    /// Switches the ppu and the APU to the timing of the region
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+        self.ppu.set_region(region);
+        self.apu.set_region(region);
     }
 
-    pub fn cpu_mut(&self) -> RefMut<Cpu6502> {
-        self.cpu.borrow_mut()
+    /// Tells the bus which cycle the cpu is working on. The cycles are counted from the same point in time as the ppu dots
+    pub fn start_cpu_cycle(&mut self, cycle: u64) {
+        self.cpu_cycle = cycle;
     }
 
-    pub fn ppu(&self) -> Ref<Ppu2C02> {
-        self.ppu.borrow()
+    /// Restarts counting cpu cycles and ppu dots from the given values, without clocking anything
+    pub fn set_clock(&mut self, cpu_cycle: u64, ppu_dots: u64) {
+        self.cpu_cycle = cpu_cycle;
+        self.ppu_dots = ppu_dots;
     }
 
-    pub fn ppu_mut(&mut self) -> RefMut<Ppu2C02> {
-        self.ppu.borrow_mut()
+    /// Clocks the ppu until it has drawn the given amount of dots. If it is there already, nothing happens
+    pub fn run_ppu(&mut self, dots: u64) {
+        if self.ppu_dots < dots {
+            self.ppu.run(dots - self.ppu_dots);
+            self.ppu_dots = dots;
+        }
     }
 
-    pub fn cpu_write(&self, addr: u16, data: u8) {
+    /// Catches the ppu up to the start of the current cpu cycle, which is where it would be in lockstep
+    pub fn sync_ppu(&mut self) {
+        self.run_ppu(self.region.dots_before_cycle(self.cpu_cycle));
+    }
 
-        if let Some(cartridge) = self.cartridge.as_ref() { // Cartridge gets "Priority access" to memory
-            if cartridge.borrow_mut().cpu_write(addr, data) {
-                return;
+    /// The region the cartridge was made for, if its header says so
+    pub fn cartridge_region(&self) -> Option<Region> {
+        self.cartridge.as_ref().and_then(Cartridge::region)
+    }
+
+    /// Connects another device to the bus, like expansion hardware, a test device or a debugger overlay. See `device`
+    #[allow(unused)]
+    pub fn register_device(&mut self, mapping: Mapping, device: Box<dyn BusDevice>) {
+        self.map.register(mapping, Device::External(self.devices.len()));
+        self.devices.push(device);
+    }
+
+    pub fn cpu_write(&mut self, addr: u16, data: u8) {
+        self.open_bus = data;
+
+        for n in 0..self.map.mapping_count() {
+            if let Some((device, device_addr)) = self.map.decode(n, addr) {
+                if self.write_device(device, device_addr, data) {
+                    return;
+                }
             }
         }
+    }
+
+    pub fn cpu_read(&mut self, addr: u16) -> u8 {
+        let data = self.read_mapped(addr).unwrap_or(self.open_bus);
 
-        if addr <= 0x1FFF { // Address range of the RAM
-            self.cpu_ram.borrow_mut()[addr as usize & 0x07FF] = data; // As the actual 2kb of RAM are mirrored across an 8kb address range, the logic AND maps the given address to the address within the 2kb range
-        } else if addr <= 0x3FFF { // Address range of the PPU
-            self.ppu.borrow_mut().cpu_write(addr & 0x0007, data); // Mirroring again. And yes, the ppu only has 8 bytes of memory
+        // $4015 is read inside the 2A03, so its value never shows up on the external data bus
+        if addr != 0x4015 {
+            self.open_bus = data;
         }
+        data
+    }
+
+    /// What reading the address would return, without changing the state of any device or the data bus
+    pub fn cpu_peek(&self, addr: u16) -> u8 {
+        (0..self.map.mapping_count())
+            .find_map(|n| {
+                let (device, addr) = self.map.decode(n, addr)?;
+                self.peek_device(device, addr)
+            })
+            .unwrap_or(self.open_bus)
     }
 
-    pub fn cpu_read(&self, addr: u16, read_only: bool) -> u8 {
-        let mut data = 0x00;
-        if let Some(cartridge) = self.cartridge.as_ref() { // Cartridge gets "Priority access" to memory
-            if cartridge.borrow_mut().cpu_read(addr, &mut data) {
-                return data;
+    /// Reads from the first device that answers. If none does, the value left on the data bus is read
+    fn read_mapped(&mut self, addr: u16) -> Option<u8> {
+        for n in 0..self.map.mapping_count() {
+            if let Some((device, device_addr)) = self.map.decode(n, addr) {
+                if let Some(data) = self.read_device(device, device_addr) {
+                    return Some(data);
+                }
             }
         }
+        None
+    }
 
-        if addr <= 0x1FFF {
-            self.cpu_ram.borrow()[addr as usize & 0x07FF] // As the actual 2kb of RAM are mirrored across an 8kb address range, the logic AND maps the given address to the address within the 2kb range
-        } else if addr <= 0x3FFF { // Address range of the PPU
-            self.ppu.borrow().cpu_read(addr & 0x0007, read_only) // Mirroring again. And yes, the ppu only has 8 bytes of memory
-        } else {
-            0x00
+    /// Reads from a device. The address has already been mirrored
+    fn read_device(&mut self, device: Device, addr: u16) -> Option<u8> {
+        let open_bus = self.open_bus;
+        match device {
+            // The ppu has its own open bus behind its registers
+            Device::Ppu => {
+                self.sync_ppu();
+                Some(self.ppu.cpu_read(self.cartridge.as_mut(), addr))
+            }
+            // The controller ports only drive the lower 5 bits. Bits 1-4 belong to the expansion port, which is empty
+            Device::Controllers => Some((open_bus & 0xE0) | self.controllers[addr as usize & 0x01].read()),
+            Device::Apu if addr == 0x4015 => Some((self.apu.cpu_read(addr) & !0x20) | (open_bus & 0x20)),
+            Device::Cartridge => {
+                let mut data = open_bus;
+                if self.cartridge.as_mut()?.cpu_read(addr, &mut data) { Some(data) } else { None }
+            }
+            Device::External(i) => self.devices[i].read(addr, open_bus),
+            // Nothing else changes when it is read
+            Device::Ram | Device::Apu | Device::Dma => self.peek_device(device, addr),
         }
     }
 
-    pub fn insert_cartridge(&mut self, cartridge: Rc<RefCell<Cartridge>>) {
-        self.cartridge = Some(cartridge.clone());
-        self.ppu.borrow_mut().connect_cartridge(cartridge);
+    /// Peeks at a device. The address has already been mirrored. Bits the device doesn't drive keep the value last on the data bus.
+    /// Peeking never changes a device: vblank isn't acknowledged, controllers don't shift and mappers don't see the access
+    fn peek_device(&self, device: Device, addr: u16) -> Option<u8> {
+        let open_bus = self.open_bus;
+        match device {
+            Device::Cartridge => {
+                let mut data = open_bus;
+                if self.cartridge.as_ref()?.cpu_peek(addr, &mut data) { Some(data) } else { None }
+            }
+            Device::Ram => Some(self.cpu_ram[addr as usize]),
+            Device::Ppu => Some(self.ppu.cpu_peek(addr)),
+            Device::Controllers => Some((open_bus & 0xE0) | self.controllers[addr as usize & 0x01].peek()),
+            // Bit 5 of the APU status is not connected. The other APU registers are write only, and the test registers are disabled
+            Device::Apu if addr == 0x4015 => Some((self.apu.cpu_peek(addr) & !0x20) | (open_bus & 0x20)),
+            Device::Apu => Some(open_bus),
+            // $4014 is write only, reading it is left to the APU
+            Device::Dma => None,
+            Device::External(i) => self.devices[i].peek(addr, open_bus),
+        }
     }
 
-    pub fn reset(&self) {
-        self.cpu.borrow_mut().reset();
-        *self.system_clock_counter.borrow_mut() = 0;
+    /// Writes to a device. The address has already been mirrored. Returns false if the device ignores the write
+    fn write_device(&mut self, device: Device, addr: u16, data: u8) -> bool {
+        match device {
+            Device::Cartridge => return self.cartridge.as_mut().is_some_and(|cartridge| cartridge.cpu_write(addr, data)),
+            Device::Ram => self.cpu_ram[addr as usize] = data,
+            Device::Ppu => {
+                self.sync_ppu();
+                self.ppu.cpu_write(self.cartridge.as_mut(), addr, data)
+            }
+            Device::Dma => self.dma.start_oam(data),
+            // Bit 0 of $4016 is the strobe of both controllers. $4017 is the frame counter of the APU when written
+            Device::Controllers if addr == 0x4016 => self.controllers.iter_mut().for_each(|controller| controller.write_strobe(data & 0x01 != 0)),
+            Device::Controllers => return false,
+            Device::Apu => self.apu.cpu_write(addr, data),
+            Device::External(i) => return self.devices[i].write(addr, data),
+        }
+        true
     }
 
-    pub fn clock(&self) {
+    /// Whether the DMA unit needs the bus, so the cpu has to be halted
+    pub fn is_dma_active(&self) -> bool {
+        self.dma.is_active()
+    }
 
-        self.ppu.borrow_mut().clock();
-        // The cpu clocks 3 times slower than the ppu
-        if *self.system_clock_counter.borrow() % 3 == 0 {
-            self.cpu.borrow_mut().clock();
+    /// Lets the DMA unit use the bus for a cpu cycle. Reads and writes are even and odd cpu cycles
+    pub fn clock_dma(&mut self, get_cycle: bool) {
+        match self.dma.cycle(get_cycle) {
+            Some(DmaCycle::Read(addr)) => {
+                let data = self.cpu_read(addr);
+                self.dma.read_complete(data);
+            }
+            Some(DmaCycle::Write(data)) => self.cpu_write(0x2004, data),
+            Some(DmaCycle::Halt) | Some(DmaCycle::Align) | None => {}
         }
-        *self.system_clock_counter.borrow_mut() += 1;
+    }
+
+    pub fn insert_cartridge(&mut self, cartridge: Cartridge) {
+        self.cartridge = Some(cartridge);
+    }
+}
+
+/// The bus clocks nothing itself, the machine clocks the ppu next to the cpu (see `Nes::clock`)
+impl CpuBus for Bus {
+    fn read(&mut self, addr: u16) -> u8 {
+        self.cpu_read(addr)
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        self.cpu_write(addr, data)
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        self.cpu_peek(addr)
+    }
 
+    fn ppu_position(&self) -> Option<(i16, i16)> {
+        Some((self.ppu.scanline(), self.ppu.dot()))
     }
 }
 
@@ -105,3 +324,250 @@ impl Debug for Bus {
         write!(fmt, "bus")
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::cartridge::Cartridge;
+    use crate::controller::Buttons;
+    use crate::device::{BusDevice, Mapping, CARTRIDGE_PRIORITY};
+    use crate::nes::Nes;
+    use crate::power_on::PowerOnProfile;
+    use crate::region::Region;
+
+    /// Builds a machine with a mapper 0 cartridge with one bank of program memory
+    fn setup() -> Nes {
+        setup_with(PowerOnProfile::Zeros)
+    }
+
+    /// Like `setup`, but with the memory filled the way the profile says
+    fn setup_with(power_on: PowerOnProfile) -> Nes {
+        let mut nes = Nes::with_power_on(power_on);
+        nes.insert_cartridge(Cartridge::test_rom(&[], &[]));
+        nes
+    }
+
+    #[test]
+    fn ram_mirroring_test() {
+        let mut nes = setup();
+        let bus = nes.bus_mut();
+        bus.cpu_write(0x0012, 0x34);
+
+        assert_eq!(bus.cpu_read(0x0812), 0x34, "RAM not mirrored");
+        assert_eq!(bus.cpu_read(0x1812), 0x34, "RAM not mirrored");
+    }
+
+    #[test]
+    fn power_on_test() {
+        let mut nes = setup_with(PowerOnProfile::Pattern);
+        let bus = nes.bus_mut();
+        assert_eq!(bus.cpu_read(0x0003), 0x00, "RAM should start with the pattern");
+        assert_eq!(bus.cpu_read(0x0804), 0xFF, "RAM should start with the pattern");
+        assert_eq!(bus.cpu_read(0x6004), 0xFF, "Program RAM should start with the pattern");
+        bus.cpu_write(0x2003, 0x04);
+        assert_eq!(bus.cpu_read(0x2004), 0xFF, "OAM should start with the pattern");
+
+        let ram = |nes: &Nes| (0..0x0800).map(|addr| nes.bus().cpu_peek(addr)).collect::<Vec<_>>();
+        let random = setup_with(PowerOnProfile::Random(42));
+        assert_eq!(ram(&random), ram(&setup_with(PowerOnProfile::Random(42))), "The same seed should give the same RAM");
+        assert_ne!(ram(&random), ram(&setup()), "Random RAM should not be cleared");
+    }
+
+    /// 16 bytes of registers
+    struct Registers([u8; 16]);
+
+    impl BusDevice for Registers {
+        fn peek(&self, addr: u16, _open_bus: u8) -> Option<u8> {
+            Some(self.0[addr as usize])
+        }
+
+        fn write(&mut self, addr: u16, data: u8) -> bool {
+            self.0[addr as usize] = data;
+            true
+        }
+    }
+
+    /// Answers reads of the reset vector, and leaves everything else to the devices below it
+    struct ResetVector(u16);
+
+    impl BusDevice for ResetVector {
+        fn peek(&self, addr: u16, _open_bus: u8) -> Option<u8> {
+            match addr {
+                0xFFFC => Some(self.0 as u8),
+                0xFFFD => Some((self.0 >> 8) as u8),
+                _ => None,
+            }
+        }
+
+        fn write(&mut self, _addr: u16, _data: u8) -> bool {
+            false
+        }
+    }
+
+    #[test]
+    fn register_device_test() {
+        let mut nes = setup();
+        let bus = nes.bus_mut();
+        bus.register_device(Mapping::new(0x5000..=0x5FFF, 0x000F, 0), Box::new(Registers([0; 16])));
+        bus.register_device(Mapping::new(0xFFFC..=0xFFFF, 0xFFFF, CARTRIDGE_PRIORITY + 1), Box::new(ResetVector(0x1234)));
+
+        bus.cpu_write(0x5003, 0x42);
+        assert_eq!(bus.cpu_read(0x5FF3), 0x42, "The registers should be mirrored with the mask");
+        assert_eq!(bus.cpu_peek(0x5013), 0x42, "The registers should be mirrored with the mask");
+
+        assert_eq!((bus.cpu_read(0xFFFC), bus.cpu_read(0xFFFD)), (0x34, 0x12), "The overlay should come before the cartridge");
+        assert_eq!(bus.cpu_read(0xFFFE), 0xEA, "The cartridge should answer what the overlay doesn't");
+    }
+
+    #[test]
+    fn program_ram_test() {
+        let mut nes = setup();
+        let bus = nes.bus_mut();
+        bus.cpu_write(0x6000, 0x12);
+        bus.cpu_write(0x7FFF, 0x34);
+
+        assert_eq!(bus.cpu_read(0x6000), 0x12, "Program RAM not written");
+        assert_eq!(bus.cpu_read(0x7FFF), 0x34, "Program RAM not written");
+        assert_eq!(bus.cpu_read(0xC000), 0xEA, "Program ROM not mapped");
+    }
+
+    #[test]
+    fn apu_test() {
+        let mut nes = setup();
+        let bus = nes.bus_mut();
+        bus.cpu_write(0x4000, 0xBF);
+        bus.cpu_write(0x4015, 0xFF);
+        bus.cpu_write(0x4017, 0x40);
+
+        assert_eq!(bus.apu.channel_register(0x4000), 0xBF, "APU register not written");
+        assert_eq!(bus.apu.frame_counter(), 0x40, "$4017 should go to the frame counter");
+        assert_eq!(bus.cpu_read(0x4015), 0x1F, "Only the 5 channels should be enabled");
+
+        bus.cpu_write(0x400E, 0x0F);
+        bus.cpu_write(0x4010, 0x0F);
+        bus.set_region(Region::Pal);
+        assert_eq!(bus.apu.noise_period(), 3778, "The noise channel should use the PAL periods");
+        assert_eq!(bus.apu.dmc_rate(), 50, "The DMC should use the PAL rates");
+        assert_eq!(bus.apu.frame_counter_steps().last(), Some(&33253), "The frame counter should use the PAL timing");
+    }
+
+    #[test]
+    fn controller_test() {
+        let mut nes = setup();
+        let bus = nes.bus_mut();
+        bus.cpu_write(0x4016, 0x01);
+        bus.cpu_write(0x4016, 0x00);
+
+        // Nothing is pressed
+        for _ in 0..8 {
+            assert_eq!(bus.cpu_read(0x4016) & 0x01, 0);
+            assert_eq!(bus.cpu_read(0x4017) & 0x01, 0);
+        }
+        assert_eq!(bus.cpu_read(0x4016) & 0x01, 1, "Reads after the 8 buttons should return 1");
+
+        bus.set_buttons(1, Buttons::B | Buttons::RIGHT);
+        bus.cpu_write(0x4016, 0x01);
+        bus.cpu_write(0x4016, 0x00);
+        let bits = (0..8).map(|_| bus.cpu_read(0x4017) & 0x01).collect::<Vec<_>>();
+        assert_eq!(bits, vec![0, 1, 0, 0, 0, 0, 0, 1], "The buttons of the second controller should be read in order");
+        assert_eq!(bus.cpu_read(0x4016) & 0x01, 0, "The strobe should have reloaded the first controller too");
+    }
+
+    #[test]
+    fn open_bus_test() {
+        let mut nes = setup();
+        let bus = nes.bus_mut();
+        bus.cpu_write(0x0012, 0x34);
+        assert_eq!(bus.cpu_read(0x5000), 0x34, "Unmapped reads should return the last written value");
+
+        bus.cpu_write(0x4015, 0x01);
+        bus.cpu_read(0x0012);
+        assert_eq!(bus.cpu_read(0x4015), 0x21, "Bit 5 of the APU status should come from the bus");
+        assert_eq!(bus.cpu_read(0x4000), 0x34, "Reading $4015 should not change the bus");
+
+        bus.cpu_read(0x0013);
+        assert_eq!(bus.cpu_read(0x4018), 0x00, "Unmapped reads should return the last read value");
+        assert_eq!(bus.cpu_peek(0x5000), 0x00);
+    }
+
+    #[test]
+    fn peek_test() {
+        let mut nes = setup();
+        while nes.bus().cpu_peek(0x2002) & 0x80 == 0 {
+            nes.ppu_mut().run(1);
+        }
+        let bus = nes.bus_mut();
+        bus.cpu_write(0x4016, 0x00);
+        bus.cpu_read(0x0000);
+
+        let peeks = |bus: &crate::bus::Bus| [0x2002, 0x4015, 0x4016, 0x4017, 0x8000].map(|addr| bus.cpu_peek(addr));
+        let before = peeks(bus);
+        assert_eq!(peeks(bus), before, "Peeking should not change anything");
+        assert_eq!(before[0] & 0x80, 0x80, "Peeking should not acknowledge vblank");
+        assert_eq!(bus.cpu_read(0x2002) & 0x80, 0x80, "Peeking should not acknowledge vblank");
+        assert_eq!(bus.cpu_peek(0x2002) & 0x80, 0x00, "Reading should acknowledge vblank");
+    }
+
+    #[test]
+    fn controller_open_bus_test() {
+        let mut nes = setup();
+        // LDA $4016. The last value on the bus before reading the controller is the high byte of the address
+        for (i, &byte) in [0xAD, 0x16, 0x40].iter().enumerate() {
+            nes.bus_mut().cpu_write(0x0300 + i as u16, byte);
+        }
+        nes.cpu_mut().set_program_counter(0x0300);
+        nes.cpu_mut().step();
+
+        assert_eq!(nes.cpu().get_acc(), 0x40, "The upper bits should come from the bus");
+    }
+
+    /// Runs `program` from $0300, followed by `sta $4014`, and returns the amount of cycles the cpu was halted
+    fn oam_dma_stall(program: &[u8]) -> usize {
+        let mut nes = setup();
+        for (i, &byte) in program.iter().chain(&[0x8D, 0x14, 0x40, 0xEA]).enumerate() {
+            nes.bus_mut().cpu_write(0x0300 + i as u16, byte);
+        }
+        nes.cpu_mut().set_program_counter(0x0300);
+
+        // Run until the cpu has started the sta, then until it has started the nop after it
+        while nes.cpu().get_program_counter() != 0x0303 + program.len() as u16 {
+            nes.clock_instruction();
+        }
+        let before = nes.cpu().trace_entry().cycle;
+        nes.clock_instruction();
+
+        // sta takes 4 cycles
+        let after = nes.cpu().trace_entry().cycle;
+        after - before - 4
+    }
+
+    #[test]
+    fn oam_dma_stall_test() {
+        // The cpu starts on an even cycle, so the sta ends on cycles of different parity
+        let stalls = [oam_dma_stall(&[0xA9, 0x02]), oam_dma_stall(&[0xA5, 0x02])];
+        assert!(stalls.contains(&513) && stalls.contains(&514), "The cpu should be halted for 513 or 514 cycles, not {:?}", stalls);
+    }
+
+    #[test]
+    fn oam_dma_test() {
+        let mut nes = setup();
+        let bus = nes.bus_mut();
+        for i in 0..=0xFF {
+            bus.cpu_write(0x0200 + i, i as u8 ^ 0xFF);
+        }
+
+        // DMA starts at the current OAM address and wraps around
+        bus.cpu_write(0x2003, 0x10);
+        bus.cpu_write(0x4014, 0x02);
+        for _ in 0..514 * 3 {
+            nes.clock();
+        }
+
+        let bus = nes.bus_mut();
+
+        for i in 0..=0xFFu8 {
+            bus.cpu_write(0x2003, i.wrapping_add(0x10));
+            assert_eq!(bus.cpu_read(0x2004), i ^ 0xFF, "OAM byte {} not copied", i);
+        }
+    }
+}
+
@@ -1,29 +1,54 @@
-use std::fs::{File};
-use std::io::{Seek, SeekFrom, Read, BufReader};
+use std::io::{Seek, SeekFrom, Read, Cursor};
+use std::path::Path;
 use crate::mappers::Mapper;
-use std::rc::Rc;
 use crate::mappers::mapper_000::Mapper000;
-use bitflags::_core::cell::{RefCell};
+use crate::region::Region;
+use crate::power_on::{Memory, PowerOnProfile};
+
+/// How the 4 name tables the ppu can address end up in the 2kb of VRAM in the console. The cartridge decides this
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mirroring {
+    /// $2000 and $2400 are the first name table, $2800 and $2C00 the second. For games that scroll vertically
+    Horizontal,
+    /// $2000 and $2800 are the first name table, $2400 and $2C00 the second. For games that scroll horizontally
+    Vertical,
+}
 
 pub struct Cartridge {
     program_memory: Vec<u8>,
+    program_ram: Vec<u8>, // RAM at $6000-$7FFF, battery backed on some cartridges
     char_memory: Vec<u8>, // Pattern/Texture memory
     mapper_id: u8, // ID of the mapper currently in use
     program_banks: u8, // Amount of program memory banks
     char_banks: u8, // Amount of char memory banks
-    mapper: Rc<RefCell<dyn Mapper>>
+    region: Option<Region>, // The TV system the game was made for, if the header says so
+    mirroring: Mirroring, // How the name tables are wired, if the mapper doesn't switch them itself
+    mapper: Box<dyn Mapper>
 }
 
 impl Cartridge {
 
-    pub fn new(file_name: &str) -> Rc<RefCell<Self>> {
+    /// Loads a ROM in the iNES format from the `roms` directory. ROMs that aren't in there can be given as a path
+    pub fn new(file_name: &str) -> Self {
+        let in_roms = Path::new("roms").join(file_name);
+        let path = if in_roms.exists() { in_roms.as_path() } else { Path::new(file_name) };
+        let rom = std::fs::read(path).expect("ROM does not exist");
+        Self::from_bytes(&rom)
+    }
+
+    /// Loads a ROM in the iNES format, that is already in memory
+    pub fn from_bytes(rom: &[u8]) -> Self {
+        let mut reader = Cursor::new(rom);
         let mut cartridge = Cartridge {
             program_memory: vec![],
+            program_ram: vec![],
             char_memory: vec![],
             mapper_id: 0,
             program_banks: 0,
             char_banks: 0,
-            mapper: Rc::new(RefCell::new(Mapper000::new(0, 0))), // This is just a placeholder
+            region: None,
+            mirroring: Mirroring::Horizontal,
+            mapper: Box::new(Mapper000::new(0, 0)), // This is just a placeholder
         };
 
         // TODO All of this is pretty weird. If things don't work, I'll come back to this
@@ -37,18 +62,15 @@ impl Cartridge {
             program_ram_size: u8,
             tv_system1: u8,
             tv_system2: u8,
-            unused: String,
+            unused: [u8; 5], // NES 2.0 headers use these as well
         }
 
-        let mut header = NesHeader::default();
-        let file = File::open("roms/".to_owned() + file_name).expect("ROM does not exist");
-        let mut reader = BufReader::new(file);
-
-        header.name = {
+        let name = {
             let mut buf = [0u8; 4];
             reader.read_exact(&mut buf).expect("Error reading name to buffer");
             buf.iter().map(|&n| n as char).collect()
         };
+        let mut header = NesHeader { name, ..NesHeader::default() };
 
         macro_rules! read_u8 {
             ($x:ident) => {
@@ -66,21 +88,31 @@ impl Cartridge {
         read_u8!(tv_system1);
         read_u8!(tv_system2);
 
-        header.unused = {
-            let mut buf = [0u8; 5];
-            reader.read_exact(&mut buf).expect("Error reading name to buffer");
-            buf.iter().map(|&n| n as char).collect()
-        };
+        reader.read_exact(&mut header.unused).expect("Error reading header");
 
         if header.mapper1 & 0x04 > 0 {
             reader.seek(SeekFrom::Current(512)).expect("Error seeking"); // Skip training information
         }
 
-        println!("{:?}", header);
+        cartridge.mirroring = if header.mapper1 & 0x01 != 0 { Mirroring::Vertical } else { Mirroring::Horizontal };
 
         // Determine Mapper ID of the mapper used by the cartridge
         cartridge.mapper_id = ((header.mapper2 >> 4) << 4) | (header.mapper1 >> 4);
 
+        // NES 2.0 headers have the TV system in the lower bits of byte 12. Older headers only have a PAL bit, which is rarely set
+        cartridge.region = if header.mapper2 & 0x0C == 0x08 {
+            match header.unused[1] & 0x03 {
+                0 => Some(Region::Ntsc),
+                1 => Some(Region::Pal),
+                3 => Some(Region::Dendy),
+                _ => None, // Runs on any of them
+            }
+        } else if header.tv_system1 & 0x01 != 0 {
+            Some(Region::Pal)
+        } else {
+            None
+        };
+
         // "Discover" File Format
         let file_type = 1;
 
@@ -95,6 +127,9 @@ impl Cartridge {
             cartridge.program_memory.resize(cartridge.program_banks as usize * 16384, 0);
             reader.read_exact(&mut cartridge.program_memory[..]).expect("Error reading program memory");
 
+            // The size is given in 8kb units. 0 means 8kb as well, as older ROMs leave this empty
+            cartridge.program_ram.resize(header.program_ram_size.max(1) as usize * 8192, 0);
+
             cartridge.char_banks = header.char_rom_chunks;
             cartridge.char_memory.resize(cartridge.char_banks as usize * 8192, 0);
             reader.read_exact(&mut cartridge.char_memory[..]).expect("Error reading char memory");
@@ -105,11 +140,57 @@ impl Cartridge {
         }
 
         match cartridge.mapper_id {
-            0 => cartridge.mapper = Rc::new(RefCell::new(Mapper000::new(cartridge.program_banks, cartridge.char_banks))),
+            0 => cartridge.mapper = Box::new(Mapper000::new(cartridge.program_banks, cartridge.char_banks)),
             _ => unimplemented!("Mapper {} not implemented", cartridge.mapper_id)
         }
 
-        Rc::new(RefCell::new(cartridge))
+        cartridge
+    }
+
+    /// A mapper 0 cartridge with one bank of program memory and one of char memory for tests.
+    /// The program is at $8000, where the reset vector points, and the rest of the bank is filled with NOPs.
+    /// The char memory counts up from 0. `flags` are copied into the header, starting at byte 6
+    #[cfg(test)]
+    pub fn test_rom(program: &[u8], flags: &[u8]) -> Self {
+        let mut rom = vec![b'N', b'E', b'S', 0x1A, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        rom[6..6 + flags.len()].copy_from_slice(flags);
+        rom.extend_from_slice(program);
+        rom.resize(16 + 16384, 0xEA);
+        rom[16 + 0x3FFC..16 + 0x3FFE].copy_from_slice(&[0x00, 0x80]);
+        rom.extend((0..8192).map(|i| i as u8));
+        Self::from_bytes(&rom)
+    }
+
+    /// Fills the program RAM the way the profile says, and puts the mapper into its initial state
+    pub fn power_on(&mut self, profile: PowerOnProfile) {
+        profile.fill(Memory::CartridgeRam, &mut self.program_ram);
+        self.mapper.reset();
+    }
+
+    /// Passes the reset button on to the mapper. The program RAM keeps its contents
+    pub fn reset(&mut self) {
+        self.mapper.reset();
+    }
+
+    /// The region the game was made for, if the header says so
+    pub fn region(&self) -> Option<Region> {
+        self.region
+    }
+
+    /// How the name tables are mirrored
+    pub fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    /// The amount of 16kb program memory banks on the cartridge
+    pub fn program_bank_count(&self) -> usize {
+        self.program_banks as usize
+    }
+
+    /// The raw contents of a 16kb program memory bank, regardless of whether it is currently mapped into the CPU's address space
+    pub fn program_bank(&self, bank: usize) -> &[u8] {
+        assert!(bank < self.program_bank_count(), "Cartridge only has {} program banks", self.program_banks);
+        &self.program_memory[bank * 16384..(bank + 1) * 16384]
     }
 
     // These return true, if the cartridge is handling the read/write
@@ -119,7 +200,24 @@ impl Cartridge {
     pub fn cpu_read(&mut self, addr: u16, data: &mut u8) -> bool {
         let mut mapped_addr = 0;
         // If the mapper says, that the cartridge should handle this read, read the data, otherwise do nothing
-        if self.mapper.borrow_mut().cpu_map_read(addr, &mut mapped_addr) {
+        if self.mapper.cpu_map_ram(addr, &mut mapped_addr) {
+            *data = self.program_ram[mapped_addr as usize % self.program_ram.len()];
+            true
+        } else if self.mapper.cpu_map_read(addr, &mut mapped_addr) {
+            *data = self.program_memory[mapped_addr as usize];
+            true
+        } else {
+            false
+        }
+    }
+
+    /// What reading from the main bus would return, without changing the state of the mapper
+    pub fn cpu_peek(&self, addr: u16, data: &mut u8) -> bool {
+        let mut mapped_addr = 0;
+        if self.mapper.cpu_map_ram(addr, &mut mapped_addr) {
+            *data = self.program_ram[mapped_addr as usize % self.program_ram.len()];
+            true
+        } else if self.mapper.cpu_map_peek(addr, &mut mapped_addr) {
             *data = self.program_memory[mapped_addr as usize];
             true
         } else {
@@ -131,7 +229,11 @@ impl Cartridge {
     pub fn cpu_write(&mut self, addr: u16, data: u8) -> bool {
         let mut mapped_addr = 0;
         // If the mapper says, that the cartridge should handle this read, write the data, otherwise do nothing
-        if self.mapper.borrow_mut().cpu_map_write(addr, &mut mapped_addr) {
+        if self.mapper.cpu_map_ram(addr, &mut mapped_addr) {
+            let len = self.program_ram.len();
+            self.program_ram[mapped_addr as usize % len] = data;
+            true
+        } else if self.mapper.cpu_map_write(addr, &mut mapped_addr) {
             self.program_memory[mapped_addr as usize] = data;
             true
         } else {
@@ -140,9 +242,20 @@ impl Cartridge {
     }
 
     /// Read from the PPU bus
-    pub fn ppu_read(&self, addr: u16, data: &mut u8) -> bool {
+    pub fn ppu_read(&mut self, addr: u16, data: &mut u8) -> bool {
+        let mut mapped_addr = 0;
+        if self.mapper.ppu_map_read(addr, &mut mapped_addr) {
+            *data = self.char_memory[mapped_addr as usize];
+            true
+        } else {
+            false
+        }
+    }
+
+    /// What reading from the PPU bus would return, without changing the state of the mapper
+    pub fn ppu_peek(&self, addr: u16, data: &mut u8) -> bool {
         let mut mapped_addr = 0;
-        if self.mapper.borrow_mut().ppu_map_read(addr, &mut mapped_addr) {
+        if self.mapper.ppu_map_peek(addr, &mut mapped_addr) {
             *data = self.char_memory[mapped_addr as usize];
             true
         } else {
@@ -153,7 +266,7 @@ impl Cartridge {
     /// Write to the PPU bus
     pub fn ppu_write(&mut self, addr: u16, data: u8) -> bool {
         let mut mapped_addr = 0;
-        if self.mapper.borrow_mut().ppu_map_write(addr, &mut mapped_addr) {
+        if self.mapper.ppu_map_write(addr, &mut mapped_addr) {
             self.char_memory[mapped_addr as usize] = data;
             true
         } else {
@@ -0,0 +1,104 @@
+//! The standard NES controller.
+//!
+//! The cpu reads the buttons one at a time. While the strobe line is high, the controller keeps reloading its shift register
+//! with the state of the buttons. Once it is low, every read of $4016 or $4017 shifts out the next button, in the order
+//! A, B, Select, Start, Up, Down, Left, Right.
+//! The shift register fills up with 1s from the other end, so after all 8 buttons have been read, every further read returns 1.
+
+bitflags! {
+    /// The buttons of a standard controller, in the order they are read
+    #[derive(Default)]
+    pub struct Buttons: u8 {
+        const A = 0x01;
+        const B = 0x02;
+        const SELECT = 0x04;
+        const START = 0x08;
+        const UP = 0x10;
+        const DOWN = 0x20;
+        const LEFT = 0x40;
+        const RIGHT = 0x80;
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Controller {
+    /// The buttons that are held down
+    buttons: Buttons,
+    shift_register: u8,
+    strobe: bool,
+}
+
+impl Controller {
+    pub fn new() -> Self {
+        Controller::default()
+    }
+
+    /// Presses the given buttons and releases all others. The cpu sees them the next time it strobes the controller
+    pub fn set_buttons(&mut self, buttons: Buttons) {
+        self.buttons = buttons;
+    }
+
+    /// Sets the strobe line, which is bit 0 of every write to $4016
+    pub fn write_strobe(&mut self, strobe: bool) {
+        // While the strobe is high, the shift register is reloaded all the time, so it keeps the buttons held when it goes low
+        if self.strobe || strobe {
+            self.shift_register = self.buttons.bits();
+        }
+        self.strobe = strobe;
+    }
+
+    /// Reads the next button, which is in bit 0
+    pub fn read(&mut self) -> u8 {
+        let bit = self.peek();
+        // While the strobe is high, the shift register is reloaded all the time, so A is read over and over again
+        if !self.strobe {
+            self.shift_register = (self.shift_register >> 1) | 0x80;
+        }
+        bit
+    }
+
+    /// The button the next read returns, without shifting it out
+    pub fn peek(&self) -> u8 {
+        if self.strobe {
+            self.buttons.bits() & 0x01
+        } else {
+            self.shift_register & 0x01
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::controller::{Buttons, Controller};
+
+    /// Strobes the controller and reads the given amount of bits
+    fn read(controller: &mut Controller, count: usize) -> Vec<u8> {
+        controller.write_strobe(true);
+        controller.write_strobe(false);
+        (0..count).map(|_| controller.read()).collect()
+    }
+
+    #[test]
+    fn read_test() {
+        let mut controller = Controller::new();
+        controller.set_buttons(Buttons::A | Buttons::START | Buttons::LEFT);
+
+        assert_eq!(read(&mut controller, 8), vec![1, 0, 0, 1, 0, 0, 1, 0], "The buttons should be read in order");
+        assert_eq!(read(&mut controller, 12)[8..], [1, 1, 1, 1], "Reads after the 8 buttons should return 1");
+    }
+
+    #[test]
+    fn strobe_test() {
+        let mut controller = Controller::new();
+        controller.set_buttons(Buttons::A);
+        controller.write_strobe(true);
+
+        assert_eq!([controller.read(), controller.read()], [1, 1], "A should be read over and over while strobing");
+        controller.set_buttons(Buttons::B);
+        assert_eq!(controller.read(), 0, "The buttons should be reloaded while strobing");
+
+        controller.write_strobe(false);
+        controller.set_buttons(Buttons::A);
+        assert_eq!([controller.read(), controller.read()], [0, 1], "The buttons should be latched when the strobe goes low");
+    }
+}
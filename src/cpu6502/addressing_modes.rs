@@ -1,12 +1,16 @@
 use crate::cpu6502::Cpu6502;
+use crate::cpu6502::memory::CpuBus;
+use crate::cpu6502::instruction_set::decode;
 
 // Addressing Modes. These return true if they need another clock cycle. false otherwise
 #[allow(non_snake_case, unused)]
-impl Cpu6502 {
+impl<B: CpuBus> Cpu6502<B> {
     /// Implied Addressing Mode.
     /// This means either that there is no additional data is part of the instruction,
     /// or the instruction operates on the accumulator, in which case the data in the accumulator is the fetched data.
     pub fn IMP(&mut self) -> bool {
+        // The cpu always reads the byte after the opcode, even if the instruction doesn't need it
+        self.read(self.pc);
         self.fetched = self.a;
         false
     }
@@ -32,8 +36,10 @@ impl Cpu6502 {
     /// Zero Page Addressing Mode with X-register offset.
     /// Same as `ZP0`, but the address supplied with the instruction has the content of the X-register added to it.
     pub fn ZPX(&mut self) -> bool {
-        self.addr_abs = self.read(self.pc) as u16 + self.x as u16;
-        self.addr_abs &= 0x00FF;
+        let base = self.read(self.pc) as u16;
+        // The cpu reads from the base address, while it is adding X
+        self.read(base);
+        self.addr_abs = (base + self.x as u16) & 0x00FF;
         self.pc += 1;
         false
     }
@@ -41,8 +47,10 @@ impl Cpu6502 {
     /// Zero Page Addressing Mode with Y-register offset.
     /// Same as `ZP0`, but the address supplied with the instruction has the content of the Y-register added to it.
     pub fn ZPY(&mut self) -> bool {
-        self.addr_abs = self.read(self.pc) as u16 + self.y as u16;
-        self.addr_abs &= 0x00FF;
+        let base = self.read(self.pc) as u16;
+        // The cpu reads from the base address, while it is adding Y
+        self.read(base);
+        self.addr_abs = (base + self.y as u16) & 0x00FF;
         self.pc += 1;
         false
     }
@@ -63,32 +71,30 @@ impl Cpu6502 {
     /// Same as ABS, but the supplied address has the content of the X-register added to it.
     /// This instruction needs an additional clock cycle, if after adding the X value to the address, the address changes another page
     /// This is checked by comparing the high byte before and after adding X. If it changed, then the page addressed changed.
+    /// See `indexed_dummy_read`
     pub fn ABX(&mut self) -> bool {
         let lo = self.read(self.pc) as u16;
         self.pc += 1;
         let hi = self.read(self.pc) as u16;
         self.pc += 1;
 
-        self.addr_abs = (hi << 8) | lo;
-        self.addr_abs += self.x as u16;
-
-        self.addr_abs & 0xFF00 != hi << 8
+        self.addr_abs = ((hi << 8) | lo).wrapping_add(self.x as u16);
+        self.indexed_dummy_read(hi << 8)
     }
 
     /// Absolute Addressing Mode with Y-register offset.
     /// Same as ABS, but the supplied address has the content of the Y-register added to it.
     /// This instruction needs an additional clock cycle, if after adding the Y value to the address, the address changes another page
     /// This is checked by comparing the high byte before and after adding Y. If it changed, then the page addressed changed.
+    /// See `indexed_dummy_read`
     pub fn ABY(&mut self) -> bool {
         let lo = self.read(self.pc) as u16;
         self.pc += 1;
         let hi = self.read(self.pc) as u16;
         self.pc += 1;
 
-        self.addr_abs = (hi << 8) | lo;
-        self.addr_abs += self.y as u16;
-
-        self.addr_abs & 0xFF00 != hi << 8
+        self.addr_abs = ((hi << 8) | lo).wrapping_add(self.y as u16);
+        self.indexed_dummy_read(hi << 8)
     }
 
     /// Indirect Addressing Mode.
@@ -109,11 +115,13 @@ impl Cpu6502 {
         // This bug is simulated here
         if ptr_lo == 0x00FF {
             // Simulate page boundary hardware bug
-            self.addr_abs = ((self.read(0xFF00 & ptr) as u16) << 8) | self.read(ptr) as u16;
+            let lo = self.read(ptr) as u16;
+            self.addr_abs = ((self.read(0xFF00 & ptr) as u16) << 8) | lo;
         } else {
             // Behave normally
-            // This reads the high byte and low byte of the actual address
-            self.addr_abs = ((self.read(ptr + 1) as u16) << 8) | self.read(ptr) as u16;
+            // This reads the low byte and high byte of the actual address
+            let lo = self.read(ptr) as u16;
+            self.addr_abs = ((self.read(ptr + 1) as u16) << 8) | lo;
         }
 
         false
@@ -126,6 +134,8 @@ impl Cpu6502 {
         let offset = self.read(self.pc) as u16;
         self.pc += 1;
 
+        // The cpu reads from the supplied address, while it is adding X
+        self.read(offset);
         let lo = self.read((offset + self.x as u16) & 0x00FF) as u16;
         let hi = self.read((offset + self.x as u16 + 1) & 0x00FF) as u16;
 
@@ -145,13 +155,12 @@ impl Cpu6502 {
         let lo = self.read(offset & 0x00FF) as u16;
         let hi = self.read((offset + 1) & 0x00FF) as u16;
 
-        self.addr_abs = (hi << 8) | lo;
-        self.addr_abs += self.y as u16;
+        self.addr_abs = ((hi << 8) | lo).wrapping_add(self.y as u16);
 
         // As we could cross a page boundary by offsetting the absolute address,
         // the instruction could take another clock cycle to complete
         // This is the same check as in ABX and ABY
-        (self.addr_abs & 0xFF00) != hi << 8
+        self.indexed_dummy_read(hi << 8)
     }
 
     /// Relative Addressing Mode.
@@ -170,20 +179,28 @@ impl Cpu6502 {
 
         false
     }
+
+    /// Indexed addressing modes first add the index to the low byte of the address only, and read from the resulting address.
+    /// If that crossed a page, this address is wrong, so the high byte is fixed and the read is repeated in another cycle.
+    /// Instructions writing to memory always take that extra cycle, so the dummy read is always done for them.
+    /// Returns true if a page was crossed
+    fn indexed_dummy_read(&mut self, page: u16) -> bool {
+        let page_crossed = self.addr_abs & 0xFF00 != page;
+        if page_crossed || decode(self.opcode).mnemonic.writes_memory() {
+            self.read(page | (self.addr_abs & 0x00FF));
+        }
+        page_crossed
+    }
 }
 
 #[allow(non_snake_case)]
 #[cfg(test)]
 mod test {
-    use std::rc::Rc;
-    use std::cell::RefCell;
-    use crate::bus::Bus;
     use crate::cpu6502::Cpu6502;
-    use crate::ppu2C02::Ppu2C02;
+    use crate::cpu6502::memory::FlatMemory;
 
-    fn setup() -> Rc<RefCell<Bus>> {
-        let mut cpu = Cpu6502::new();
-        let mut ppu = Ppu2C02::new();
+    fn setup() -> Cpu6502<FlatMemory> {
+        let mut cpu = Cpu6502::with_bus(FlatMemory::new());
 
         // This is so that fetch actually fetches a value from abs_addr
         // If this was 0x00 it wouldn't fetch a values as the operation denoted by 0x00 is BRK
@@ -194,209 +211,209 @@ mod test {
 
         cpu.pc = 0x1000;
 
-        Bus::new(cpu, ppu)
+        cpu
     }
 
     #[test]
     fn IMP_test() {
-        let mut bus = setup();
+        let mut cpu = setup();
 
-        bus.borrow().cpu_mut().a = 10;
-        bus.borrow().cpu_mut().IMP();
+        cpu.a = 10;
+        cpu.IMP();
 
         // The fetched register should now contain the value of the accumulator
-        assert_eq!(bus.borrow().cpu_mut().fetched, 10, "Fetched value incorrect");
+        assert_eq!(cpu.fetched, 10, "Fetched value incorrect");
     }
 
     #[test]
     fn IMM_test() {
-        let mut bus = setup();
-        bus.borrow_mut().cpu_write(0x1000, 10);
+        let mut cpu = setup();
+        cpu.write(0x1000, 10);
 
-        bus.borrow().cpu_mut().IMM();
-        bus.borrow().cpu_mut().fetch();
+        cpu.IMM();
+        cpu.fetch();
 
         // The cpu should have read the data stored at 0x1000
-        assert_eq!(bus.borrow().cpu().fetched, 10, "Fetched value incorrect");
+        assert_eq!(cpu.fetched, 10, "Fetched value incorrect");
     }
 
     #[test]
     fn ZP0_test() {
-        let mut bus = setup();
+        let mut cpu = setup();
         // Write the address of the data to the program
-        bus.borrow_mut().cpu_write(0x1000, 0x20);
+        cpu.write(0x1000, 0x20);
 
         // Write the actual data
-        bus.borrow_mut().cpu_write(0x0020, 10);
+        cpu.write(0x0020, 10);
 
-        bus.borrow().cpu_mut().ZP0();
-        bus.borrow().cpu_mut().fetch();
+        cpu.ZP0();
+        cpu.fetch();
 
-        assert_eq!(bus.borrow().cpu().fetched, 10, "Fetched value incorrect");
+        assert_eq!(cpu.fetched, 10, "Fetched value incorrect");
     }
 
     #[test]
     fn ZPX_test() {
-        let mut bus = setup();
+        let mut cpu = setup();
         // Write the address of the data to the program
-        bus.borrow_mut().cpu_write(0x1000, 0x20);
+        cpu.write(0x1000, 0x20);
 
-        bus.borrow().cpu_mut().x = 5;
+        cpu.x = 5;
 
         // Write the actual data
-        bus.borrow_mut().cpu_write(0x0025, 10);
+        cpu.write(0x0025, 10);
 
-        bus.borrow().cpu_mut().ZPX();
-        bus.borrow().cpu_mut().fetch();
+        cpu.ZPX();
+        cpu.fetch();
 
-        assert_eq!(bus.borrow().cpu().fetched, 10, "Fetched value incorrect");
+        assert_eq!(cpu.fetched, 10, "Fetched value incorrect");
     }
 
     #[test]
     fn ZPY_test() {
-        let mut bus = setup();
+        let mut cpu = setup();
         // Write the address of the data to the program
-        bus.borrow_mut().cpu_write(0x1000, 0x20);
+        cpu.write(0x1000, 0x20);
 
-        bus.borrow().cpu_mut().y = 5;
+        cpu.y = 5;
 
         // Write the actual data
-        bus.borrow_mut().cpu_write(0x0025, 10);
+        cpu.write(0x0025, 10);
 
-        bus.borrow().cpu_mut().ZPY();
-        bus.borrow().cpu_mut().fetch();
+        cpu.ZPY();
+        cpu.fetch();
 
-        assert_eq!(bus.borrow().cpu().fetched, 10, "Fetched value incorrect");
+        assert_eq!(cpu.fetched, 10, "Fetched value incorrect");
     }
 
     #[test]
     fn ABS_test() {
-        let mut bus = setup();
+        let mut cpu = setup();
         // Write the lo and hi byte of the absolute address of the data to the program
-        bus.borrow_mut().cpu_write(0x1000, 0x20);
-        bus.borrow_mut().cpu_write(0x1001, 0x10);
+        cpu.write(0x1000, 0x20);
+        cpu.write(0x1001, 0x10);
 
         // Write data to the target address
-        bus.borrow_mut().cpu_write(0x1020, 10);
+        cpu.write(0x1020, 10);
 
-        bus.borrow().cpu_mut().ABS();
-        bus.borrow().cpu_mut().fetch();
+        cpu.ABS();
+        cpu.fetch();
 
-        assert_eq!(bus.borrow().cpu().fetched, 10, "Fetched value incorrect");
+        assert_eq!(cpu.fetched, 10, "Fetched value incorrect");
     }
 
     #[test]
     fn ABX_test() {
-        let mut bus = setup();
+        let mut cpu = setup();
         // Write the lo and hi byte of the absolute address of the data to the program
-        bus.borrow_mut().cpu_write(0x1000, 0x20);
-        bus.borrow_mut().cpu_write(0x1001, 0x10);
-        bus.borrow_mut().cpu_mut().x = 0x10;
+        cpu.write(0x1000, 0x20);
+        cpu.write(0x1001, 0x10);
+        cpu.x = 0x10;
 
         // Write data to the target address
-        bus.borrow_mut().cpu_write(0x1030, 10);
+        cpu.write(0x1030, 10);
 
-        bus.borrow().cpu_mut().ABX();
-        bus.borrow().cpu_mut().fetch();
+        cpu.ABX();
+        cpu.fetch();
 
-        assert_eq!(bus.borrow().cpu().fetched, 10, "Fetched value incorrect");
+        assert_eq!(cpu.fetched, 10, "Fetched value incorrect");
     }
 
     #[test]
     fn ABY_test() {
-        let mut bus = setup();
+        let mut cpu = setup();
         // Write the lo and hi byte of the absolute address of the data to the program
-        bus.borrow_mut().cpu_write(0x1000, 0x20);
-        bus.borrow_mut().cpu_write(0x1001, 0x10);
-        bus.borrow_mut().cpu_mut().y = 0x10;
+        cpu.write(0x1000, 0x20);
+        cpu.write(0x1001, 0x10);
+        cpu.y = 0x10;
 
         // Write data to the target address
-        bus.borrow_mut().cpu_write(0x1030, 10);
+        cpu.write(0x1030, 10);
 
-        bus.borrow().cpu_mut().ABY();
-        bus.borrow().cpu_mut().fetch();
+        cpu.ABY();
+        cpu.fetch();
 
-        assert_eq!(bus.borrow().cpu().fetched, 10, "Fetched value incorrect");
+        assert_eq!(cpu.fetched, 10, "Fetched value incorrect");
     }
 
     #[test]
     fn IND_test() {
-        let mut bus = setup();
+        let mut cpu = setup();
         // Write the lo and hi byte of the pointer to the location of the target address to the program
-        bus.borrow_mut().cpu_write(0x1000, 0x20);
-        bus.borrow_mut().cpu_write(0x1001, 0x10);
+        cpu.write(0x1000, 0x20);
+        cpu.write(0x1001, 0x10);
 
         // Write target address to the pointer location
-        bus.borrow_mut().cpu_write(0x1020, 0x23);
-        bus.borrow_mut().cpu_write(0x1021, 0x01);
+        cpu.write(0x1020, 0x23);
+        cpu.write(0x1021, 0x01);
 
         // Write data to the location of the target address
-        bus.borrow_mut().cpu_write(0x0123, 10);
+        cpu.write(0x0123, 10);
 
-        bus.borrow().cpu_mut().IND();
-        bus.borrow().cpu_mut().fetch();
+        cpu.IND();
+        cpu.fetch();
 
-        assert_eq!(bus.borrow().cpu().fetched, 10, "Fetched value incorrect");
+        assert_eq!(cpu.fetched, 10, "Fetched value incorrect");
     }
 
     #[test]
     fn IZX_test() {
-        let mut bus = setup();
+        let mut cpu = setup();
         // Write an address to the zero page
-        bus.borrow().cpu_write(0x0020, 0x23);
-        bus.borrow().cpu_write(0x0021, 0x01);
+        cpu.write(0x0020, 0x23);
+        cpu.write(0x0021, 0x01);
 
         // Write an offset to the program counter location
-        bus.borrow().cpu_write(0x1000, 0x10);
-        bus.borrow().cpu_mut().x = 0x10;
+        cpu.write(0x1000, 0x10);
+        cpu.x = 0x10;
 
         // Write data to the target address
 
-        bus.borrow().cpu_write(0x0123, 20);
+        cpu.write(0x0123, 20);
 
-        bus.borrow().cpu_mut().IZX();
-        bus.borrow().cpu_mut().fetch();
+        cpu.IZX();
+        cpu.fetch();
 
-        assert_eq!(bus.borrow().cpu().fetched, 20, "Fetched data incorrect");
+        assert_eq!(cpu.fetched, 20, "Fetched data incorrect");
     }
 
     #[test]
     fn IZY_test() {
-        let mut bus = setup();
+        let mut cpu = setup();
         // Write an address to the zero page
-        bus.borrow().cpu_write(0x0010, 0x20);
-        bus.borrow().cpu_write(0x0011, 0x01);
+        cpu.write(0x0010, 0x20);
+        cpu.write(0x0011, 0x01);
 
         // Write an offset to the program counter location
-        bus.borrow().cpu_write(0x1000, 0x10);
-        bus.borrow().cpu_mut().y = 0x03;
+        cpu.write(0x1000, 0x10);
+        cpu.y = 0x03;
 
         // Write data to the target address
 
-        bus.borrow().cpu_write(0x0123, 20);
+        cpu.write(0x0123, 20);
 
-        bus.borrow().cpu_mut().IZY();
-        bus.borrow().cpu_mut().fetch();
+        cpu.IZY();
+        cpu.fetch();
 
-        assert_eq!(bus.borrow().cpu().fetched, 20, "Fetched data incorrect");
+        assert_eq!(cpu.fetched, 20, "Fetched data incorrect");
     }
 
     #[test]
     fn REL_test() {
-        let mut bus = setup();
+        let mut cpu = setup();
 
         // Write a relative address to the program counter location
-        bus.borrow().cpu_write(0x1000, 0x10);
+        cpu.write(0x1000, 0x10);
 
-        bus.borrow().cpu_mut().REL();
+        cpu.REL();
 
-        assert_eq!(bus.borrow().cpu_mut().addr_rel, 0x0010, "Address not set correctly");
+        assert_eq!(cpu.addr_rel, 0x0010, "Address not set correctly");
 
         // Write a relative address to the program counter location which should count as negative
-        bus.borrow().cpu_write(0x1001, 0x90);
+        cpu.write(0x1001, 0x90);
 
-        bus.borrow().cpu_mut().REL();
+        cpu.REL();
 
-        assert_eq!(bus.borrow().cpu_mut().addr_rel, 0xFF90, "Negative relative address not handled correctly");
+        assert_eq!(cpu.addr_rel, 0xFF90, "Negative relative address not handled correctly");
     }
 }
@@ -0,0 +1,894 @@
+use crate::cpu6502::instruction_set::{encode, AddressingMode, Mnemonic};
+use crate::cpu6502::memory::FlatMemory;
+use crate::cpu6502::Cpu6502;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+
+/// The maximum amount of passes before the assembler gives up on the labels settling on their final addresses
+const MAX_PASSES: usize = 16;
+
+/// How deep `.include`s may be nested. This stops files that include themselves
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// An error in the source, with the place it was found at
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssemblerError {
+    pub file: String,
+    pub line: usize,
+    pub message: String,
+}
+
+impl Display for AssemblerError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}: {}", self.file, self.line, self.message)
+    }
+}
+
+impl std::error::Error for AssemblerError {}
+
+/// The output of the assembler. Every `*=` starts a new chunk of bytes at the given address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssembledProgram {
+    chunks: Vec<(u16, Vec<u8>)>,
+    /// All labels and constants outside of `.block`s
+    symbols: HashMap<String, u16>,
+}
+
+impl AssembledProgram {
+    /// The chunks of assembled bytes and the addresses they start at, in the order they appear in the source
+    pub fn chunks(&self) -> &[(u16, Vec<u8>)] {
+        &self.chunks
+    }
+
+    /// The lowest address any byte was assembled to
+    pub fn origin(&self) -> u16 {
+        self.chunks.iter().map(|(start, _)| *start).min().unwrap_or(0)
+    }
+
+    /// All assembled bytes from the origin to the last byte. Gaps between the chunks are filled with zeroes
+    pub fn bytes(&self) -> Vec<u8> {
+        let origin = self.origin() as usize;
+        let mut bytes = Vec::new();
+        for (start, chunk) in &self.chunks {
+            let offset = *start as usize - origin;
+            if bytes.len() < offset + chunk.len() {
+                bytes.resize(offset + chunk.len(), 0);
+            }
+            bytes[offset..offset + chunk.len()].copy_from_slice(chunk);
+        }
+        bytes
+    }
+
+    /// The value of a label or constant that was defined outside of any `.block`
+    pub fn symbol(&self, name: &str) -> Option<u16> {
+        self.symbols.get(name).copied()
+    }
+
+    /// Copies all chunks to their addresses in the given memory
+    pub fn load_into(&self, memory: &mut FlatMemory) {
+        for (start, chunk) in &self.chunks {
+            memory.load(*start, chunk);
+        }
+    }
+
+    /// A cpu on flat memory with the program loaded, about to execute the instruction at the origin
+    pub fn cpu(&self) -> Cpu6502<FlatMemory> {
+        let mut memory = FlatMemory::new();
+        self.load_into(&mut memory);
+
+        let mut cpu = Cpu6502::with_bus(memory);
+        cpu.set_program_counter(self.origin());
+        cpu
+    }
+}
+
+/// Assembles 6502 source code. `.include`s are looked up relative to the current directory
+pub fn assemble(source: &str) -> Result<AssembledProgram, AssemblerError> {
+    let mut lines = Vec::new();
+    preprocess(source, "<source>", Path::new("."), 0, &mut lines)?;
+    Assembler::new(lines).run()
+}
+
+/// Assembles a source file. `.include`s are looked up relative to the including file
+pub fn assemble_file<P: AsRef<Path>>(path: P) -> Result<AssembledProgram, AssemblerError> {
+    let mut lines = Vec::new();
+    include(path.as_ref(), 0, &mut lines, "<command line>", 0)?;
+    Assembler::new(lines).run()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    /// A directive like `.byte`. The name is stored without the dot and in lower case
+    Directive(String),
+    Number(i64),
+    Str(Vec<u8>),
+    Punct(char),
+    ShiftLeft,
+    ShiftRight,
+}
+
+/// A line of source, split into tokens. The file and line number are kept for error messages
+struct SourceLine {
+    file: String,
+    line: usize,
+    tokens: Vec<Token>,
+}
+
+/// Splits a line into tokens. Comments are dropped
+fn tokenize(line: &str) -> Result<Vec<Token>, String> {
+    let chars = line.chars().collect::<Vec<_>>();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    // Collects characters as long as the predicate holds
+    let take_while = |i: &mut usize, predicate: &dyn Fn(char) -> bool| {
+        let start = *i;
+        while *i < chars.len() && predicate(chars[*i]) {
+            *i += 1;
+        }
+        chars[start..*i].iter().collect::<String>()
+    };
+
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            ';' => break,
+            c if c.is_whitespace() => i += 1,
+            '$' => {
+                i += 1;
+                let digits = take_while(&mut i, &|c| c.is_ascii_hexdigit());
+                let value = i64::from_str_radix(&digits, 16).map_err(|_| "Expected hex digits after '$'".to_string())?;
+                tokens.push(Token::Number(value));
+            }
+            // % is only a binary number, if a binary digit follows
+            '%' if chars.get(i + 1).map(|&c| c == '0' || c == '1').unwrap_or(false) => {
+                i += 1;
+                let digits = take_while(&mut i, &|c| c == '0' || c == '1');
+                tokens.push(Token::Number(i64::from_str_radix(&digits, 2).unwrap()));
+            }
+            c if c.is_ascii_digit() => {
+                let digits = take_while(&mut i, &|c| c.is_ascii_digit());
+                tokens.push(Token::Number(digits.parse().map_err(|_| format!("Number {} is too large", digits))?));
+            }
+            '"' | '\'' => {
+                let quote = c;
+                i += 1;
+                let text = take_while(&mut i, &|c| c != quote);
+                if i >= chars.len() {
+                    return Err("Missing closing quote".to_string());
+                }
+                i += 1;
+                if !text.is_ascii() {
+                    return Err(format!("Only ASCII characters are supported in text, found \"{}\"", text));
+                }
+                tokens.push(Token::Str(text.into_bytes()));
+            }
+            '.' if chars.get(i + 1).map(|c| c.is_ascii_alphabetic()).unwrap_or(false) => {
+                i += 1;
+                let name = take_while(&mut i, &|c| c.is_ascii_alphanumeric() || c == '_');
+                tokens.push(Token::Directive(name.to_ascii_lowercase()));
+            }
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                tokens.push(Token::Ident(take_while(&mut i, &|c| c.is_ascii_alphanumeric() || c == '_')));
+            }
+            '<' if chars.get(i + 1) == Some(&'<') => {
+                i += 2;
+                tokens.push(Token::ShiftLeft);
+            }
+            '>' if chars.get(i + 1) == Some(&'>') => {
+                i += 2;
+                tokens.push(Token::ShiftRight);
+            }
+            '#' | ',' | '(' | ')' | '+' | '-' | '*' | '/' | '<' | '>' | '&' | '|' | '^' | '~' | '!' | '=' | ':' => {
+                i += 1;
+                tokens.push(Token::Punct(c));
+            }
+            _ => return Err(format!("Unexpected character '{}'", c)),
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// Splits the source into lines of tokens and replaces `.include`s with the lines of the included file
+fn preprocess(source: &str, file: &str, dir: &Path, depth: usize, lines: &mut Vec<SourceLine>) -> Result<(), AssemblerError> {
+    for (i, text) in source.lines().enumerate() {
+        let error = |message: String| AssemblerError { file: file.to_string(), line: i + 1, message };
+        let tokens = tokenize(text).map_err(error)?;
+
+        match tokens.as_slice() {
+            [Token::Directive(directive), Token::Str(name)] if directive == "include" => {
+                let name = String::from_utf8(name.clone()).unwrap();
+                include(&dir.join(name), depth + 1, lines, file, i + 1)?;
+            }
+            [Token::Directive(directive), ..] if directive == "include" => {
+                return Err(error("Expected a file name after .include".to_string()));
+            }
+            _ => lines.push(SourceLine { file: file.to_string(), line: i + 1, tokens }),
+        }
+    }
+    Ok(())
+}
+
+/// Reads a file and adds its lines. `from` and `from_line` are where the file was included, for error messages
+fn include(path: &Path, depth: usize, lines: &mut Vec<SourceLine>, from: &str, from_line: usize) -> Result<(), AssemblerError> {
+    let error = |message: String| AssemblerError { file: from.to_string(), line: from_line, message };
+    if depth > MAX_INCLUDE_DEPTH {
+        return Err(error(format!("Includes are nested too deep at {}", path.display())));
+    }
+
+    let source = std::fs::read_to_string(path).map_err(|e| error(format!("Error reading {}: {}", path.display(), e)))?;
+    let dir = path.parent().map(PathBuf::from).unwrap_or_default();
+    preprocess(&source, &path.display().to_string(), &dir, depth, lines)
+}
+
+/// Labels and constants, by the scope they were defined in and their name
+type Symbols = HashMap<(usize, String), i64>;
+
+/// The operand of an instruction, after it has been parsed but before an addressing mode was chosen
+enum Operand {
+    /// No operand, or the accumulator
+    None,
+    Immediate(Option<i64>),
+    /// An address, optionally indexed. `forced_absolute` is set by a leading `!`
+    Direct { value: Option<i64>, index: Option<char>, forced_absolute: bool },
+    Indirect(Option<i64>),
+    IndirectX(Option<i64>),
+    IndirectY(Option<i64>),
+}
+
+/// Evaluates expressions on a line of tokens. Values are `None`, if they depend on a label that is not known yet
+struct ExpressionParser<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+    assembler: &'a Assembler,
+}
+
+impl<'a> ExpressionParser<'a> {
+    fn new(tokens: &'a [Token], assembler: &'a Assembler) -> Self {
+        ExpressionParser { tokens, pos: 0, assembler }
+    }
+
+    fn peek(&self) -> Option<&'a Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn is_at_end(&self) -> bool {
+        self.pos >= self.tokens.len()
+    }
+
+    /// Consumes the next token, if it is the given punctuation
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(&Token::Punct(c)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            Err(format!("Expected '{}'", c))
+        }
+    }
+
+    /// Parses an index register after a comma
+    fn index_register(&mut self) -> Result<char, String> {
+        match self.peek() {
+            Some(Token::Ident(name)) if name.eq_ignore_ascii_case("x") || name.eq_ignore_ascii_case("y") => {
+                self.pos += 1;
+                Ok(name.to_ascii_lowercase().chars().next().unwrap())
+            }
+            _ => Err("Expected X or Y as index register".to_string()),
+        }
+    }
+
+    /// Parses a whole expression. Operators bind from weakest to strongest: `|`, `^`, `&`, `<<` `>>`, `+` `-`, `*` `/`
+    fn expression(&mut self) -> Result<Option<i64>, String> {
+        self.binary(0)
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Option<i64>, String> {
+        const LEVELS: usize = 6;
+        if level == LEVELS {
+            return self.unary();
+        }
+
+        let mut left = self.binary(level + 1)?;
+        loop {
+            let operator = match (level, self.peek()) {
+                (0, Some(Token::Punct('|'))) => '|',
+                (1, Some(Token::Punct('^'))) => '^',
+                (2, Some(Token::Punct('&'))) => '&',
+                (3, Some(Token::ShiftLeft)) => '<',
+                (3, Some(Token::ShiftRight)) => '>',
+                (4, Some(Token::Punct(c @ '+'))) | (4, Some(Token::Punct(c @ '-'))) => *c,
+                (5, Some(Token::Punct(c @ '*'))) | (5, Some(Token::Punct(c @ '/'))) => *c,
+                _ => return Ok(left),
+            };
+            self.pos += 1;
+            let right = self.binary(level + 1)?;
+
+            left = match (left, right) {
+                (Some(l), Some(r)) => Some(match operator {
+                    '|' => l | r,
+                    '^' => l ^ r,
+                    '&' => l & r,
+                    '<' => l << (r & 63),
+                    '>' => l >> (r & 63),
+                    '+' => l + r,
+                    '-' => l - r,
+                    '*' => l * r,
+                    '/' if r == 0 => return Err("Division by zero".to_string()),
+                    _ => l / r,
+                }),
+                _ => None,
+            };
+        }
+    }
+
+    fn unary(&mut self) -> Result<Option<i64>, String> {
+        let token = self.peek().ok_or_else(|| "Expected an expression".to_string())?;
+        self.pos += 1;
+        match token {
+            Token::Punct('-') => Ok(self.unary()?.map(|v| -v)),
+            Token::Punct('+') => self.unary(),
+            Token::Punct('~') => Ok(self.unary()?.map(|v| !v)),
+            // Low and high byte
+            Token::Punct('<') => Ok(self.unary()?.map(|v| v & 0xFF)),
+            Token::Punct('>') => Ok(self.unary()?.map(|v| (v >> 8) & 0xFF)),
+            // The address of the current instruction
+            Token::Punct('*') => Ok(Some(self.assembler.line_pc as i64)),
+            Token::Punct('(') => {
+                let value = self.expression()?;
+                self.expect(')')?;
+                Ok(value)
+            }
+            Token::Number(n) => Ok(Some(*n)),
+            Token::Str(text) if text.len() == 1 => Ok(Some(text[0] as i64)),
+            Token::Str(_) => Err("Only strings with a single character can be used as numbers".to_string()),
+            Token::Ident(name) => self.assembler.lookup(name),
+            _ => Err("Expected an expression".to_string()),
+        }
+    }
+
+    /// Parses the operand of an instruction, which is everything after the mnemonic
+    fn operand(&mut self, mnemonic: Mnemonic) -> Result<Operand, String> {
+        if self.is_at_end() {
+            return Ok(Operand::None);
+        }
+
+        // The accumulator is written as "A" for shifts and rotations
+        if let [Token::Ident(name)] = self.tokens {
+            if name.eq_ignore_ascii_case("a") && encode(mnemonic, AddressingMode::IMP).is_some() {
+                self.pos += 1;
+                return Ok(Operand::None);
+            }
+        }
+
+        let operand = if self.eat('#') {
+            Operand::Immediate(self.expression()?)
+        } else if self.eat('(') {
+            let value = self.expression()?;
+            if self.eat(',') {
+                if self.index_register()? != 'x' {
+                    return Err("Indexed indirect addressing only works with X".to_string());
+                }
+                self.expect(')')?;
+                Operand::IndirectX(value)
+            } else {
+                self.expect(')')?;
+                if self.eat(',') {
+                    if self.index_register()? != 'y' {
+                        return Err("Indirect indexed addressing only works with Y".to_string());
+                    }
+                    Operand::IndirectY(value)
+                } else {
+                    Operand::Indirect(value)
+                }
+            }
+        } else {
+            let forced_absolute = self.eat('!');
+            let value = self.expression()?;
+            let index = if self.eat(',') { Some(self.index_register()?) } else { None };
+            Operand::Direct { value, index, forced_absolute }
+        };
+
+        if !self.is_at_end() {
+            return Err("Unexpected tokens after the operand".to_string());
+        }
+        Ok(operand)
+    }
+}
+
+/// Runs the passes over the source. The addresses of labels are only fully known after a pass has seen all of them,
+/// so the source is assembled until the labels don't move anymore
+struct Assembler {
+    lines: Vec<SourceLine>,
+    /// Whether unknown labels are errors. This is only the case in the last pass
+    final_pass: bool,
+    /// The labels of the previous pass. These are used for labels that are not defined yet in this pass
+    previous: Symbols,
+    symbols: Symbols,
+    /// Whether an expression could not be evaluated in this pass
+    unresolved: bool,
+    /// The parent of every scope. The global scope 0 has no parent
+    scope_parents: Vec<Option<usize>>,
+    scope_stack: Vec<usize>,
+    pc: u16,
+    /// The value of the program counter at the start of the current line, which is what `*` refers to
+    line_pc: u16,
+    chunks: Vec<(u16, Vec<u8>)>,
+}
+
+impl Assembler {
+    fn new(lines: Vec<SourceLine>) -> Self {
+        Assembler {
+            lines,
+            final_pass: false,
+            previous: Symbols::new(),
+            symbols: Symbols::new(),
+            unresolved: false,
+            scope_parents: vec![None],
+            scope_stack: vec![0],
+            pc: 0,
+            line_pc: 0,
+            chunks: Vec::new(),
+        }
+    }
+
+    fn run(mut self) -> Result<AssembledProgram, AssemblerError> {
+        for _ in 0..MAX_PASSES {
+            self.pass()?;
+            let settled = !self.unresolved && self.symbols == self.previous;
+            self.previous = std::mem::take(&mut self.symbols);
+            if settled {
+                break;
+            }
+        }
+
+        self.final_pass = true;
+        self.pass()?;
+        if self.symbols != self.previous {
+            let last = self.lines.last();
+            return Err(AssemblerError {
+                file: last.map(|l| l.file.clone()).unwrap_or_default(),
+                line: last.map(|l| l.line).unwrap_or(0),
+                message: "The addresses of the labels did not settle".to_string(),
+            });
+        }
+
+        let symbols = self.symbols.iter()
+            .filter(|((scope, _), _)| *scope == 0)
+            .map(|((_, name), &value)| (name.clone(), value as u16))
+            .collect();
+
+        Ok(AssembledProgram { chunks: self.chunks, symbols })
+    }
+
+    fn pass(&mut self) -> Result<(), AssemblerError> {
+        self.symbols.clear();
+        self.unresolved = false;
+        self.scope_parents.truncate(1);
+        self.scope_stack = vec![0];
+        self.pc = 0;
+        self.chunks.clear();
+
+        let lines = std::mem::take(&mut self.lines);
+        let result = lines.iter().try_for_each(|line| {
+            self.line(&line.tokens).map_err(|message| AssemblerError {
+                file: line.file.clone(),
+                line: line.line,
+                message,
+            })
+        });
+        self.lines = lines;
+        result?;
+
+        if self.scope_stack.len() > 1 {
+            return Err(AssemblerError {
+                file: self.lines.last().map(|l| l.file.clone()).unwrap_or_default(),
+                line: self.lines.last().map(|l| l.line).unwrap_or(0),
+                message: ".block without .bend".to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Looks up a label in the current scope and all enclosing scopes
+    fn lookup(&self, name: &str) -> Result<Option<i64>, String> {
+        let mut scope = Some(*self.scope_stack.last().unwrap());
+        while let Some(s) = scope {
+            let key = (s, name.to_string());
+            if let Some(&value) = self.symbols.get(&key).or_else(|| self.previous.get(&key)) {
+                return Ok(Some(value));
+            }
+            scope = self.scope_parents[s];
+        }
+
+        if self.final_pass {
+            Err(format!("Unknown label {}", name))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn define(&mut self, name: &str, value: i64) -> Result<(), String> {
+        let key = (*self.scope_stack.last().unwrap(), name.to_string());
+        if self.symbols.insert(key, value).is_some() {
+            return Err(format!("{} is defined twice", name));
+        }
+        Ok(())
+    }
+
+    /// Unwraps a value that might not be known yet. Unknown values are replaced with 0 until the final pass
+    fn known(&mut self, value: Option<i64>) -> i64 {
+        if value.is_none() {
+            self.unresolved = true;
+        }
+        value.unwrap_or(0)
+    }
+
+    fn emit(&mut self, byte: u8) {
+        match self.chunks.last_mut() {
+            Some((start, bytes)) if start.wrapping_add(bytes.len() as u16) == self.pc => bytes.push(byte),
+            _ => self.chunks.push((self.pc, vec![byte])),
+        }
+        self.pc = self.pc.wrapping_add(1);
+    }
+
+    fn emit_byte(&mut self, value: Option<i64>) -> Result<(), String> {
+        let value = self.known(value);
+        if !(-128..=255).contains(&value) {
+            return Err(format!("Value {} does not fit into a byte", value));
+        }
+        self.emit(value as u8);
+        Ok(())
+    }
+
+    fn emit_word(&mut self, value: Option<i64>) -> Result<(), String> {
+        let value = self.known(value);
+        if !(-32768..=65535).contains(&value) {
+            return Err(format!("Value {} does not fit into a word", value));
+        }
+        self.emit(value as u8);
+        self.emit((value >> 8) as u8);
+        Ok(())
+    }
+
+    /// Assembles a single line
+    fn line(&mut self, tokens: &[Token]) -> Result<(), String> {
+        self.line_pc = self.pc;
+        let mut tokens = tokens;
+
+        // Constants: "name = value"
+        if let [Token::Ident(name), Token::Punct('='), value @ ..] = tokens {
+            let value = self.expression(value)?;
+            let value = self.known(value);
+            return self.define(name, value);
+        }
+
+        // Labels, either followed by a colon or anything that is not a mnemonic
+        match tokens {
+            [Token::Ident(name), Token::Punct(':'), rest @ ..] => {
+                self.define(name, self.pc as i64)?;
+                tokens = rest;
+            }
+            [Token::Ident(name), rest @ ..] if Mnemonic::from_name(name).filter(|&m| m != Mnemonic::XXX).is_none() => {
+                self.define(name, self.pc as i64)?;
+                tokens = rest;
+            }
+            _ => {}
+        }
+
+        match tokens {
+            [] => Ok(()),
+            [Token::Punct('*'), Token::Punct('='), value @ ..] => self.origin(value),
+            [Token::Directive(directive), args @ ..] => self.directive(directive, args),
+            [Token::Ident(name), operand @ ..] => {
+                let mnemonic = Mnemonic::from_name(name).ok_or_else(|| format!("Unknown instruction {}", name))?;
+                self.instruction(mnemonic, operand)
+            }
+            _ => Err("Expected a label, an instruction or a directive".to_string()),
+        }
+    }
+
+    fn expression(&self, tokens: &[Token]) -> Result<Option<i64>, String> {
+        let mut parser = ExpressionParser::new(tokens, self);
+        let value = parser.expression()?;
+        if !parser.is_at_end() {
+            return Err("Unexpected tokens after the expression".to_string());
+        }
+        Ok(value)
+    }
+
+    /// Splits comma separated arguments
+    fn arguments(tokens: &[Token]) -> Vec<&[Token]> {
+        if tokens.is_empty() {
+            Vec::new()
+        } else {
+            tokens.split(|t| *t == Token::Punct(',')).collect()
+        }
+    }
+
+    fn origin(&mut self, tokens: &[Token]) -> Result<(), String> {
+        let value = self.expression(tokens)?;
+        let value = self.known(value);
+        if !(0..=0xFFFF).contains(&value) {
+            return Err(format!("Origin ${:X} is outside of the address space", value));
+        }
+        self.pc = value as u16;
+        Ok(())
+    }
+
+    fn directive(&mut self, directive: &str, args: &[Token]) -> Result<(), String> {
+        match directive {
+            // .org and .base are understood as well, so the output of the source exporter can be assembled
+            "org" | "base" => self.origin(args),
+            // The ca65 output of the source exporter picks the cpu and a segment. The linker would place the segment,
+            // here the `.org` after it decides where the bytes go
+            "setcpu" => match args {
+                [Token::Str(cpu)] if cpu.as_slice() == b"6502" => Ok(()),
+                _ => Err("Only .setcpu \"6502\" is supported".to_string()),
+            },
+            "segment" => match args {
+                [Token::Str(_)] => Ok(()),
+                _ => Err(".segment expects a name in quotes".to_string()),
+            },
+            "byte" | "text" | "null" => {
+                for arg in Self::arguments(args) {
+                    match arg {
+                        [Token::Str(text)] => text.iter().for_each(|&b| self.emit(b)),
+                        _ => {
+                            let value = self.expression(arg)?;
+                            self.emit_byte(value)?;
+                        }
+                    }
+                }
+                // .null terminates the text with a zero byte
+                if directive == "null" {
+                    self.emit(0);
+                }
+                Ok(())
+            }
+            "word" => {
+                for arg in Self::arguments(args) {
+                    let value = self.expression(arg)?;
+                    self.emit_word(value)?;
+                }
+                Ok(())
+            }
+            "block" => {
+                self.scope_parents.push(Some(*self.scope_stack.last().unwrap()));
+                self.scope_stack.push(self.scope_parents.len() - 1);
+                Ok(())
+            }
+            "bend" => {
+                if self.scope_stack.len() == 1 {
+                    return Err(".bend without .block".to_string());
+                }
+                self.scope_stack.pop();
+                Ok(())
+            }
+            _ => Err(format!("Unknown directive .{}", directive)),
+        }
+    }
+
+    fn instruction(&mut self, mnemonic: Mnemonic, tokens: &[Token]) -> Result<(), String> {
+        let operand = ExpressionParser::new(tokens, self).operand(mnemonic)?;
+        let has_mode = |mode: AddressingMode| encode(mnemonic, mode).is_some();
+
+        let (mode, value) = match operand {
+            Operand::None => (AddressingMode::IMP, None),
+            Operand::Immediate(value) => (AddressingMode::IMM, Some(self.known(value))),
+            Operand::Indirect(value) => (AddressingMode::IND, Some(self.known(value))),
+            Operand::IndirectX(value) => (AddressingMode::IZX, Some(self.known(value))),
+            Operand::IndirectY(value) => (AddressingMode::IZY, Some(self.known(value))),
+            Operand::Direct { value, index, forced_absolute } => {
+                let (zero_page, absolute) = match index {
+                    None => (AddressingMode::ZP0, AddressingMode::ABS),
+                    Some('x') => (AddressingMode::ZPX, AddressingMode::ABX),
+                    _ => (AddressingMode::ZPY, AddressingMode::ABY),
+                };
+                // Unknown labels are assumed to be absolute, until their address is known
+                let fits_zero_page = value.map(|v| (0..=0xFF).contains(&v)).unwrap_or(false);
+
+                let mode = if index.is_none() && has_mode(AddressingMode::REL) {
+                    AddressingMode::REL
+                } else if has_mode(zero_page) && !forced_absolute && (fits_zero_page || !has_mode(absolute)) {
+                    zero_page
+                } else {
+                    absolute
+                };
+                (mode, Some(self.known(value)))
+            }
+        };
+
+        let opcode = encode(mnemonic, mode)
+            .ok_or_else(|| format!("{} does not support {:?} addressing", mnemonic.name(), mode))?;
+        self.emit(opcode);
+
+        let value = value.unwrap_or(0);
+        match mode {
+            AddressingMode::IMP => Ok(()),
+            AddressingMode::IMM => self.emit_byte(Some(value)),
+            AddressingMode::ZP0 | AddressingMode::ZPX | AddressingMode::ZPY | AddressingMode::IZX | AddressingMode::IZY => {
+                if !(0..=0xFF).contains(&value) {
+                    return Err(format!("Address ${:X} is not in the zero page", value));
+                }
+                self.emit(value as u8);
+                Ok(())
+            }
+            AddressingMode::ABS | AddressingMode::ABX | AddressingMode::ABY | AddressingMode::IND => {
+                if !(0..=0xFFFF).contains(&value) {
+                    return Err(format!("Address ${:X} is outside of the address space", value));
+                }
+                self.emit_word(Some(value))
+            }
+            AddressingMode::REL => {
+                // Branches are relative to the address of the next instruction
+                let offset = value - (self.line_pc as i64 + 2);
+                if !(-128..=127).contains(&offset) {
+                    if self.final_pass {
+                        return Err(format!("Branch target ${:X} is out of range", value));
+                    }
+                    self.unresolved = true;
+                }
+                self.emit(offset as u8);
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cpu6502::assembler::{assemble, assemble_file};
+
+    fn bytes(source: &str) -> Vec<u8> {
+        assemble(source).expect("Error assembling").bytes()
+    }
+
+    #[test]
+    fn addressing_modes_test() {
+        let program = bytes("
+            lda #$05
+            tax
+            asl a
+            lsr
+            lda $20
+            lda $20,x
+            ldx $20,y
+            lda $1234
+            lda $1234,x
+            lda $1234,y
+            lda $20,y
+            jmp ($1234)
+            lda ($20,x)
+            lda ($20),y
+            lda !$20
+        ");
+
+        assert_eq!(program, vec![
+            0xA9, 0x05, 0xAA, 0x0A, 0x4A, 0xA5, 0x20, 0xB5, 0x20, 0xB6, 0x20, 0xAD, 0x34, 0x12, 0xBD, 0x34, 0x12, 0xB9, 0x34, 0x12,
+            // There is no zero page Y mode for LDA, so absolute is used
+            0xB9, 0x20, 0x00, 0x6C, 0x34, 0x12, 0xA1, 0x20, 0xB1, 0x20,
+            // Forced absolute addressing
+            0xAD, 0x20, 0x00,
+        ]);
+    }
+
+    #[test]
+    fn labels_test() {
+        let program = assemble("
+                 *= $C000
+            start
+            loop:    dex
+                     bne loop
+                     beq forward
+                     jsr sub
+            forward  jmp start
+            sub      rts
+            value    = sub + 1
+                     lda #<value
+                     lda #>value
+        ").unwrap();
+
+        assert_eq!(program.origin(), 0xC000);
+        assert_eq!(program.symbol("sub"), Some(0xC00B));
+        assert_eq!(program.symbol("value"), Some(0xC00C));
+        assert_eq!(program.bytes(), vec![
+            0xCA, 0xD0, 0xFD, 0xF0, 0x03, 0x20, 0x0B, 0xC0, 0x4C, 0x00, 0xC0, 0x60, 0xA9, 0x0C, 0xA9, 0xC0,
+        ]);
+    }
+
+    #[test]
+    fn forward_zero_page_test() {
+        // The label is only known to be in the zero page after the first pass
+        let program = bytes("
+                 lda later
+                 *= $10
+            later .byte 1
+        ");
+        assert_eq!(program[..2], [0xA5, 0x10]);
+    }
+
+    #[test]
+    fn expressions_test() {
+        let program = bytes("
+            .byte 1 + 2 * 3, (1 + 2) * 3, %1010 | 1, $F0 & $3C, 1 << 4, $80 >> 3, -1, \"a\", 'b' ^ $20
+            .word $1234, * + 2
+        ");
+        assert_eq!(program, vec![7, 9, 11, 0x30, 16, 16, 0xFF, b'a', b'B', 0x34, 0x12, 0x0B, 0x00]);
+    }
+
+    #[test]
+    fn text_test() {
+        let program = bytes("
+            .text \"ab\", 13
+            .null \"cd\"
+        ");
+        assert_eq!(program, vec![b'a', b'b', 13, b'c', b'd', 0]);
+    }
+
+    #[test]
+    fn block_test() {
+        let program = assemble("
+            wait  jmp wait
+                  .block
+                  jmp wait
+            wait  nop
+                  .bend
+                  jmp wait
+        ").unwrap();
+
+        assert_eq!(program.bytes(), vec![0x4C, 0x00, 0x00, 0x4C, 0x06, 0x00, 0xEA, 0x4C, 0x00, 0x00]);
+        assert_eq!(program.symbol("wait"), Some(0x0000), "Labels inside a block should not be visible outside of it");
+    }
+
+    #[test]
+    fn errors_test() {
+        let error = assemble("lda #1\n  lda unknown").unwrap_err();
+        assert_eq!(error.line, 2);
+
+        assert!(assemble("lda ($20),x").is_err(), "Invalid addressing mode should be an error");
+        assert!(assemble("x nop\nx nop").is_err(), "Labels defined twice should be an error");
+        assert!(assemble("bne far\n*= $1000\nfar nop").is_err(), "Branches out of range should be an error");
+        assert!(assemble(".block\nnop").is_err(), "Unclosed blocks should be an error");
+        assert!(assemble(".setcpu \"65816\"").is_err(), "Other cpus than the 6502 should be an error");
+    }
+
+    #[test]
+    fn include_test() {
+        let dir = std::env::temp_dir().join(format!("nest_include_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("main.asm"), "*= $1000\n.include \"sub.asm\"\njmp sub").unwrap();
+        std::fs::write(dir.join("sub.asm"), "sub rts").unwrap();
+
+        let program = assemble_file(dir.join("main.asm"));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(program.unwrap().bytes(), vec![0x60, 0x4C, 0x00, 0x10]);
+    }
+
+    #[test]
+    fn testsuite_source_test() {
+        let program = assemble_file("asm_tests/testsuite-2.15/ascii-src/adca.asm").expect("Error assembling adca.asm");
+
+        assert_eq!(program.origin(), 0x0801);
+        // The BASIC stub at the start is just bytes
+        assert_eq!(program.bytes()[..6], [0x4C, 0x16, 0x08, 0x00, 0x97, 0x32]);
+        // The first instruction is "jsr print"
+        let print = program.symbol("print").unwrap();
+        assert_eq!(program.bytes()[0x15..0x18], [0x20, print as u8, (print >> 8) as u8]);
+        // "lda !*,x" in print refers to itself
+        let print0 = print + 10;
+        let offset = (print0 - 0x0801) as usize;
+        assert_eq!(program.bytes()[offset..offset + 3], [0xBD, print0 as u8, (print0 >> 8) as u8]);
+    }
+}
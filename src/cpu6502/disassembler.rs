@@ -0,0 +1,259 @@
+use crate::cpu6502::instruction_set::{decode, AddressingMode, Mnemonic, Opcode};
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+
+/// A disassembly of a memory range, ordered by address
+pub type Disassembly = BTreeMap<u16, DisassembledInstruction>;
+
+/// A single decoded instruction, as found in memory at a certain address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DisassembledInstruction {
+    /// The address of the opcode byte
+    pub address: u16,
+    /// The raw bytes of the instruction, starting with the opcode byte
+    pub bytes: Vec<u8>,
+    /// The decode table entry of the opcode
+    pub opcode: &'static Opcode,
+}
+
+impl DisassembledInstruction {
+    /// Decodes the instruction at the given address.
+    /// `read` is used to get the bytes of the instruction and must not have any side effects
+    pub fn decode<F: Fn(u16) -> u8>(address: u16, read: F) -> Self {
+        let opcode = decode(read(address));
+        let bytes = (0..opcode.length as u16)
+            .map(|offset| read(address.wrapping_add(offset)))
+            .collect();
+
+        DisassembledInstruction {
+            address,
+            bytes,
+            opcode,
+        }
+    }
+
+    pub fn mnemonic(&self) -> Mnemonic {
+        self.opcode.mnemonic
+    }
+
+    pub fn mode(&self) -> AddressingMode {
+        self.opcode.mode
+    }
+
+    /// The length of the instruction in bytes
+    pub fn length(&self) -> u8 {
+        self.opcode.length
+    }
+
+    /// The address of the instruction following this one
+    pub fn next_address(&self) -> u16 {
+        self.address.wrapping_add(self.opcode.length as u16)
+    }
+
+    /// The data supplied with the instruction as a number. This is either 1 or 2 bytes long.
+    /// Instructions in implied mode have no operand.
+    /// If the instruction is cut off (e.g. at the end of a byte slice), the missing bytes are treated as 0
+    pub fn operand(&self) -> Option<u16> {
+        let lo = self.bytes.get(1).copied().unwrap_or(0) as u16;
+        let hi = self.bytes.get(2).copied().unwrap_or(0) as u16;
+
+        match self.opcode.mode.operand_bytes() {
+            0 => None,
+            1 => Some(lo),
+            _ => Some((hi << 8) | lo),
+        }
+    }
+
+    /// The address the instruction works on or jumps to, as far as it can be known without the register contents.
+    /// For branches this is the address the branch would jump to.
+    /// Indexed and indirect addressing modes depend on the registers and memory, so they have no target
+    pub fn target(&self) -> Option<u16> {
+        match self.opcode.mode {
+            AddressingMode::ZP0 | AddressingMode::ABS => self.operand(),
+            // The offset is relative to the address of the next instruction and is a signed 8-bit number
+            AddressingMode::REL => self.operand()
+                .map(|offset| self.next_address().wrapping_add(offset as u8 as i8 as u16)),
+            _ => None,
+        }
+    }
+}
+
+impl DisassembledInstruction {
+    /// Formats the operand in the usual 6502 assembler syntax, e.g. `($20),Y` or `#$05`.
+    /// `label` is asked for a name for the addresses the instruction refers to.
+    /// If it returns a name, that name is used instead of the number.
+    /// Branches always show their target address instead of the raw offset
+    pub fn operand_text<L: Fn(u16) -> Option<String>>(&self, label: L) -> String {
+        let operand = match self.operand() {
+            Some(operand) => operand,
+            None => return String::new(),
+        };
+
+        // Formats an address either as a label or as a hex number of the given width
+        let addr = |addr: u16, digits: usize| label(addr).unwrap_or_else(|| format!("${:0>width$X}", addr, width = digits));
+
+        match self.opcode.mode {
+            AddressingMode::IMP => String::new(),
+            AddressingMode::IMM => format!("#${:0>2X}", operand),
+            AddressingMode::ZP0 => addr(operand, 2),
+            AddressingMode::ZPX => format!("{},X", addr(operand, 2)),
+            AddressingMode::ZPY => format!("{},Y", addr(operand, 2)),
+            AddressingMode::ABS => addr(operand, 4),
+            AddressingMode::ABX => format!("{},X", addr(operand, 4)),
+            AddressingMode::ABY => format!("{},Y", addr(operand, 4)),
+            AddressingMode::IND => format!("({})", addr(operand, 4)),
+            AddressingMode::IZX => format!("({},X)", addr(operand, 2)),
+            AddressingMode::IZY => format!("({}),Y", addr(operand, 2)),
+            AddressingMode::REL => addr(self.target().unwrap_or(0), 4),
+        }
+    }
+
+    /// Formats the whole instruction in the usual 6502 assembler syntax, e.g. `LDA ($20),Y`. See `operand_text`
+    pub fn assembly_text<L: Fn(u16) -> Option<String>>(&self, label: L) -> String {
+        let operand = self.operand_text(label);
+        if operand.is_empty() {
+            self.opcode.name().to_string()
+        } else {
+            format!("{} {}", self.opcode.name(), operand)
+        }
+    }
+}
+
+/// Formats the instruction the same way the disassembler always has. E.g. `LDA #$05 (IMM)`
+impl Display for DisassembledInstruction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.opcode.name())?;
+
+        match (self.opcode.mode, self.operand()) {
+            // BRK's padding byte is not shown
+            (AddressingMode::IMP, _) | (_, None) => {}
+            // For immediate addressing, the additional data is just 1 byte of data
+            (AddressingMode::IMM, Some(value)) => write!(f, " #${:0>2X}", value)?,
+            // For all other address modes, the supplied data is an address of either 1 or 2 bytes
+            (_, Some(addr)) => write!(f, " ${:0>4X}", addr)?,
+        }
+
+        write!(f, " ({})", self.opcode.mode.name())
+    }
+}
+
+/// Decodes instructions linearly, starting at `from` and stopping before `to` or at the end of the address space.
+/// `read` must not have any side effects
+pub fn disassemble_range<F: Fn(u16) -> u8>(from: u16, to: u16, read: F) -> Disassembly {
+    let mut disassembly = Disassembly::new();
+    let mut current_addr = from;
+    while current_addr < to {
+        let instruction = DisassembledInstruction::decode(current_addr, &read);
+        let next_addr = instruction.next_address();
+        disassembly.insert(current_addr, instruction);
+
+        // Stop, if the address wrapped around the end of the address space
+        if next_addr <= current_addr {
+            break;
+        }
+        current_addr = next_addr;
+    }
+    disassembly
+}
+
+/// Decodes a program given as raw bytes, as if it was loaded at the given origin address
+pub fn disassemble_bytes(bytes: &[u8], origin: u16) -> Vec<DisassembledInstruction> {
+    let mut program = Vec::new();
+
+    let mut i = 0;
+    while i < bytes.len() {
+        // Bytes past the end of the program are read as 0
+        let mut instruction = DisassembledInstruction::decode(origin.wrapping_add(i as u16), |addr| {
+            bytes.get(addr.wrapping_sub(origin) as usize).copied().unwrap_or(0)
+        });
+        // Don't claim bytes that aren't part of the program
+        instruction.bytes.truncate(bytes.len() - i);
+
+        i += instruction.length() as usize;
+        program.push(instruction);
+    }
+
+    program
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cpu6502::disassembler::{disassemble_bytes, disassemble_range, DisassembledInstruction};
+    use crate::cpu6502::instruction_set::{AddressingMode, Mnemonic};
+    use crate::cpu6502::assembler::assemble;
+
+    #[test]
+    fn decode_test() {
+        let memory = [0xBD, 0x34, 0x12];
+        let instr = DisassembledInstruction::decode(0x0000, |addr| memory[addr as usize]);
+
+        assert_eq!(instr.address, 0x0000);
+        assert_eq!(instr.bytes, vec![0xBD, 0x34, 0x12]);
+        assert_eq!(instr.mnemonic(), Mnemonic::LDA);
+        assert_eq!(instr.mode(), AddressingMode::ABX);
+        assert_eq!(instr.operand(), Some(0x1234));
+        assert_eq!(instr.target(), None, "Indexed addressing has no static target");
+        assert_eq!(instr.length(), 3);
+        assert_eq!(instr.to_string(), "LDA $1234 (ABX)");
+    }
+
+    #[test]
+    fn assembly_text_test() {
+        let program = assemble("
+                    *= $8000
+                    lda ($20),y
+                    stx $10,y
+                    jmp ($1234)
+            loop    bne loop
+                    lda #$05
+                    asl a
+        ").unwrap();
+        let program = disassemble_bytes(&program.bytes(), program.origin());
+        let texts = program.iter().map(|instr| instr.assembly_text(|_| None)).collect::<Vec<_>>();
+        assert_eq!(texts, vec!["LDA ($20),Y", "STX $10,Y", "JMP ($1234)", "BNE $8007", "LDA #$05", "ASL"]);
+
+        let label = |addr: u16| if addr == 0x8007 { Some("loop".to_string()) } else { None };
+        assert_eq!(program[3].assembly_text(label), "BNE loop");
+    }
+
+    #[test]
+    fn branch_target_test() {
+        // BNE -4 at 0x8010, BEQ +16 at 0x8012
+        let program = disassemble_bytes(&[0xD0, 0xFC, 0xF0, 0x10], 0x8010);
+
+        assert_eq!(program[0].target(), Some(0x800E), "Backwards branch target incorrect");
+        assert_eq!(program[1].target(), Some(0x8024), "Forward branch target incorrect");
+    }
+
+    #[test]
+    fn disassemble_range_test() {
+        let memory = assemble("
+            lda #$05
+            tax
+            jsr $C000
+            nop
+        ").unwrap().bytes();
+        let disassembly = disassemble_range(0x0000, memory.len() as u16, |addr| memory[addr as usize]);
+
+        // The instructions should come in the order of their addresses
+        let addresses = disassembly.keys().copied().collect::<Vec<_>>();
+        assert_eq!(addresses, vec![0x0000, 0x0002, 0x0003, 0x0006]);
+        assert_eq!(disassembly[&0x0003].target(), Some(0xC000));
+    }
+
+    #[test]
+    fn disassemble_range_wrap_test() {
+        // This must terminate, even though the last instruction wraps around the end of the address space
+        let disassembly = disassemble_range(0xFFF0, 0xFFFF, |_| 0x20);
+        assert_eq!(*disassembly.keys().last().unwrap(), 0xFFFC);
+    }
+
+    #[test]
+    fn cut_off_instruction_test() {
+        let program = disassemble_bytes(&[0xAA, 0x8D, 0x11], 0x0000);
+
+        assert_eq!(program.len(), 2);
+        assert_eq!(program[1].bytes, vec![0x8D, 0x11], "Bytes past the end should not be part of the instruction");
+        assert_eq!(program[1].operand(), Some(0x0011));
+    }
+}
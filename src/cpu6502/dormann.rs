@@ -0,0 +1,202 @@
+//! Runs Klaus Dormann's 6502 functional and decimal tests (https://github.com/Klaus2m5/6502_65C02_functional_tests).
+//!
+//! The tests are plain binaries covering the whole 64kb address space. They signal their result by
+//! getting stuck in a loop, like `jmp *` or `bne *`: the functional test loops at a known address when every test passed,
+//! and anywhere else, if a test failed. The decimal test always ends in the same loop and stores its result at $000B instead.
+//!
+//! The binaries belong in `asm_tests/dormann`, see the README there. They are not checked in yet, so the tests are ignored.
+
+use crate::cpu6502::memory::FlatMemory;
+use crate::cpu6502::Cpu6502;
+use std::collections::VecDeque;
+use std::fmt::{Display, Formatter};
+
+/// Where the test binaries are expected
+const BINARY_DIR: &str = "asm_tests/dormann";
+
+/// How many of the last executed instructions are shown, if a test gets stuck at the wrong address
+const HISTORY_LENGTH: usize = 32;
+
+/// The functional test runs about 30 million instructions. Anything running longer than this is stuck somewhere
+const MAX_INSTRUCTIONS: usize = 100_000_000;
+
+/// The state of the cpu before executing an instruction
+#[derive(Debug, Clone, Copy)]
+struct HistoryEntry {
+    pc: u16,
+    a: u8,
+    x: u8,
+    y: u8,
+    status: u8,
+    stkp: u8,
+}
+
+/// Where a test got stuck, and what the cpu did right before that
+#[derive(Debug)]
+struct Trap {
+    /// The address of the instruction that jumps to itself
+    pc: u16,
+    /// The amount of instructions executed before getting stuck
+    instructions: usize,
+    /// The last instructions before getting stuck, already disassembled
+    history: Vec<String>,
+}
+
+impl Display for Trap {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Trapped at ${:0>4X} after {} instructions. The last instructions were:", self.pc, self.instructions)?;
+        for line in &self.history {
+            writeln!(f, "    {}", line)?;
+        }
+        Ok(())
+    }
+}
+
+struct DormannTest {
+    cpu: Cpu6502<FlatMemory>,
+    /// The state before the last few instructions. This is only formatted if the test fails, as that is expensive
+    history: VecDeque<HistoryEntry>,
+}
+
+impl DormannTest {
+    /// Loads the chunks of a test image to their addresses and sets the program counter to the start of the test
+    fn new(chunks: &[(u16, Vec<u8>)], start: u16) -> Self {
+        let mut memory = FlatMemory::new();
+        for (load_address, chunk) in chunks {
+            memory.load(*load_address, chunk);
+        }
+
+        // The tests are written for a regular 6502, which has decimal mode
+        let mut cpu = Cpu6502::with_bus(memory);
+        cpu.set_decimal_mode(true);
+        cpu.set_program_counter(start);
+
+        DormannTest {
+            cpu,
+            history: VecDeque::with_capacity(HISTORY_LENGTH),
+        }
+    }
+
+    /// Loads a test binary from `BINARY_DIR`
+    fn from_file(name: &str, load_address: u16, start: u16) -> Self {
+        let path = format!("{}/{}", BINARY_DIR, name);
+        let image = std::fs::read(&path)
+            .unwrap_or_else(|e| panic!("Error reading {}: {}. See {}/README.md", path, e, BINARY_DIR));
+        Self::new(&[(load_address, image)], start)
+    }
+
+    /// Runs the test until the program counter is stuck in a loop
+    fn run(&mut self) -> Trap {
+        for instructions in 0..MAX_INSTRUCTIONS {
+            let cpu = &self.cpu;
+            let pc = cpu.get_program_counter();
+            if self.history.len() == HISTORY_LENGTH {
+                self.history.pop_front();
+            }
+            self.history.push_back(HistoryEntry {
+                pc,
+                a: cpu.a,
+                x: cpu.x,
+                y: cpu.y,
+                status: cpu.status.bits(),
+                stkp: cpu.stkp,
+            });
+
+            self.cpu.step();
+
+            if self.cpu.get_program_counter() == pc {
+                return self.trap(instructions + 1);
+            }
+        }
+
+        panic!("The test did not get stuck in a loop after {} instructions\n{}", MAX_INSTRUCTIONS, self.trap(MAX_INSTRUCTIONS));
+    }
+
+    fn trap(&self, instructions: usize) -> Trap {
+        let history = self.history.iter()
+            .map(|entry| format!(
+                "${:0>4X}: {:<20} A:{:0>2X} X:{:0>2X} Y:{:0>2X} P:{:0>2X} SP:{:0>2X}",
+                entry.pc, self.cpu.decode_instr_at(entry.pc).to_string(), entry.a, entry.x, entry.y, entry.status, entry.stkp
+            ))
+            .collect();
+
+        Trap {
+            pc: self.cpu.get_program_counter(),
+            instructions,
+            history,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cpu6502::assembler::assemble;
+    use crate::cpu6502::dormann::{DormannTest, HISTORY_LENGTH};
+    use crate::cpu6502::memory::CpuBus;
+
+    /// The address the functional test loops at, if all tests passed. This depends on the configuration
+    /// the test was assembled with. This is the `success` label in the listing of the prebuilt binary
+    const FUNCTIONAL_SUCCESS: u16 = 0x3469;
+
+    /// Where the decimal test stores its result. 0 means success
+    const DECIMAL_ERROR: u16 = 0x000B;
+
+    fn assembled(source: &str) -> DormannTest {
+        let program = assemble(source).expect("Error assembling");
+        // The chunks are loaded where they were assembled to, instead of filling the gaps between them
+        DormannTest::new(program.chunks(), program.origin())
+    }
+
+    #[test]
+    fn success_trap_test() {
+        let mut test = assembled("
+                  *= $0400
+                  ldx #3
+            loop  dex
+                  bne loop
+            done  jmp done
+        ");
+
+        let trap = test.run();
+        assert_eq!(trap.pc, 0x0405);
+        assert_eq!(trap.instructions, 8);
+    }
+
+    #[test]
+    fn failure_trap_test() {
+        let mut test = assembled("
+                  *= $0400
+                  ldy #0
+            loop  iny
+                  bne loop
+                  lda #$40
+                  cmp #$41
+            fail  bne fail
+        ");
+
+        let trap = test.run();
+        assert_eq!(trap.pc, 0x0409, "The failing branch should be reported");
+        assert_eq!(trap.history.len(), HISTORY_LENGTH, "Only the last instructions should be kept");
+
+        let report = trap.to_string();
+        assert!(report.contains("Trapped at $0409"), "{}", report);
+        assert!(report.contains("$0407: CMP #$41 (IMM)"), "{}", report);
+        assert!(trap.history.last().unwrap().starts_with("$0409: BNE"), "{}", report);
+    }
+
+    #[test]
+    #[ignore = "6502_functional_test.bin is not vendored yet, see asm_tests/dormann/README.md"]
+    fn functional_test() {
+        let mut test = DormannTest::from_file("6502_functional_test.bin", 0x0000, 0x0400);
+        let trap = test.run();
+        assert_eq!(trap.pc, FUNCTIONAL_SUCCESS, "Functional test failed\n{}", trap);
+    }
+
+    #[test]
+    #[ignore = "6502_decimal_test.bin is not vendored yet, see asm_tests/dormann/README.md"]
+    fn decimal_test() {
+        let mut test = DormannTest::from_file("6502_decimal_test.bin", 0x0200, 0x0200);
+        let trap = test.run();
+        assert_eq!(test.cpu.bus().peek(DECIMAL_ERROR), 0, "Decimal test failed\n{}", trap);
+    }
+}
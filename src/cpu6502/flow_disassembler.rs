@@ -0,0 +1,558 @@
+use crate::cpu6502::disassembler::{DisassembledInstruction, Disassembly};
+use crate::cpu6502::instruction_set::{AddressingMode, Mnemonic};
+use crate::cpu6502::{IRQ_PROGRAM_COUNTER, NMI_PROGRAM_COUNTER, RESET_PROGRAM_COUNTER};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fmt::Write;
+use std::ops::RangeInclusive;
+
+/// What a byte in memory has been identified as
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteKind {
+    /// The byte is not reached by any code path, so it is assumed to be data
+    Data,
+    /// The first byte of an instruction
+    Opcode,
+    /// A byte following the opcode byte of an instruction
+    Operand,
+    /// Part of a jump table or interrupt vector
+    Pointer,
+}
+
+/// A table of addresses that the code jumps to indirectly. These can't be found by following the code,
+/// so they have to be annotated by hand
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JumpTable {
+    /// `count` little endian 16-bit addresses, one after the other, starting at `address`
+    Words { address: u16, count: u16 },
+    /// `count` addresses, with the low bytes in one table and the high bytes in another
+    Split { lo: u16, hi: u16, count: u16 },
+}
+
+/// Where a label came from. This decides the prefix of generated labels
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum LabelKind {
+    Location,
+    Subroutine,
+    JumpTable,
+}
+
+/// A disassembler that follows the flow of the code, instead of decoding everything linearly.
+/// It starts at the interrupt vectors (and any other entry points it is given) and follows branches,
+/// jumps and subroutine calls. Everything that is never reached this way is considered data,
+/// so data between code does not throw the disassembly out of alignment
+pub struct FlowDisassembler<F: Fn(u16) -> u8> {
+    /// Reads memory. This must not have side effects
+    read: F,
+    /// The addresses that are considered for disassembly. Code outside of it is not followed
+    range: RangeInclusive<u16>,
+    entry_points: Vec<(u16, Option<String>)>,
+    jump_tables: Vec<JumpTable>,
+    /// Bytes-per-address offset added to jump table entries. RTS based dispatch stores the target address minus 1
+    jump_table_offset: u16,
+}
+
+impl<F: Fn(u16) -> u8> FlowDisassembler<F> {
+    /// Creates a disassembler that looks at the given address range of the memory behind `read`.
+    /// No entry points are added, see `with_vectors` and `add_entry_point`
+    pub fn new(range: RangeInclusive<u16>, read: F) -> Self {
+        FlowDisassembler {
+            read,
+            range,
+            entry_points: Vec::new(),
+            jump_tables: Vec::new(),
+            jump_table_offset: 0,
+        }
+    }
+
+    /// Adds the addresses in the reset, NMI and IRQ vectors as entry points and marks the vectors as pointers
+    pub fn with_vectors(mut self) -> Self {
+        for &(vector, name) in &[(RESET_PROGRAM_COUNTER, "reset"), (NMI_PROGRAM_COUNTER, "nmi"), (IRQ_PROGRAM_COUNTER, "irq")] {
+            let addr = self.read_word(vector);
+            self.entry_points.push((addr, Some(name.to_string())));
+        }
+        self
+    }
+
+    /// Adds an address at which code starts, optionally with a name for its label
+    pub fn add_entry_point(&mut self, addr: u16, name: Option<&str>) {
+        self.entry_points.push((addr, name.map(String::from)));
+    }
+
+    /// Adds a jump table. All of its entries are followed as entry points
+    pub fn add_jump_table(&mut self, table: JumpTable) {
+        self.jump_tables.push(table);
+    }
+
+    /// Sets an offset that is added to every jump table entry.
+    /// Set this to 1, if the jump tables are used by pushing the entry on the stack and calling RTS
+    pub fn set_jump_table_offset(&mut self, offset: u16) {
+        self.jump_table_offset = offset;
+    }
+
+    fn read_word(&self, addr: u16) -> u16 {
+        (self.read)(addr) as u16 | (((self.read)(addr.wrapping_add(1)) as u16) << 8)
+    }
+
+    /// Follows all code paths from the entry points and sorts every byte in the range into code or data
+    pub fn run(&self) -> FlowAnalysis {
+        let mut analysis = FlowAnalysis {
+            range: self.range.clone(),
+            kinds: vec![ByteKind::Data; 0x10000],
+            words: BTreeSet::new(),
+            instructions: Disassembly::new(),
+            labels: BTreeMap::new(),
+            conflicts: Vec::new(),
+        };
+
+        // The addresses that still have to be followed
+        let mut queue = VecDeque::new();
+
+        for (addr, name) in &self.entry_points {
+            analysis.add_label(*addr, LabelKind::Location, name.clone());
+            queue.push_back(*addr);
+        }
+
+        // Mark the vectors as pointers, so they show up as such in the listing
+        for vector in (NMI_PROGRAM_COUNTER..=0xFFFF).filter(|addr| self.range.contains(addr)) {
+            analysis.kinds[vector as usize] = ByteKind::Pointer;
+            if vector % 2 == 0 {
+                analysis.words.insert(vector);
+            }
+        }
+
+        for table in &self.jump_tables {
+            let (count, pointer_bytes): (u16, Vec<(u16, u16)>) = match *table {
+                JumpTable::Words { address, count } => (count, (0..count).map(|i| {
+                    let lo = address.wrapping_add(2 * i);
+                    analysis.words.insert(lo);
+                    (lo, lo.wrapping_add(1))
+                }).collect()),
+                JumpTable::Split { lo, hi, count } => (count, (0..count).map(|i| (lo.wrapping_add(i), hi.wrapping_add(i))).collect()),
+            };
+            debug_assert_eq!(count as usize, pointer_bytes.len());
+
+            if let Some(&(first, _)) = pointer_bytes.first() {
+                analysis.add_label(first, LabelKind::JumpTable, None);
+            }
+
+            for (lo, hi) in pointer_bytes {
+                analysis.kinds[lo as usize] = ByteKind::Pointer;
+                analysis.kinds[hi as usize] = ByteKind::Pointer;
+                let target = ((self.read)(lo) as u16 | (((self.read)(hi) as u16) << 8)).wrapping_add(self.jump_table_offset);
+                analysis.add_label(target, LabelKind::Location, None);
+                queue.push_back(target);
+            }
+        }
+
+        while let Some(addr) = queue.pop_front() {
+            self.follow(addr, &mut analysis, &mut queue);
+        }
+
+        analysis
+    }
+
+    /// Decodes instructions starting at the given address until the flow of the code ends.
+    /// Targets of branches, jumps and subroutine calls are added to the queue
+    fn follow(&self, start: u16, analysis: &mut FlowAnalysis, queue: &mut VecDeque<u16>) {
+        let mut addr = start;
+        loop {
+            // Already visited or outside of the memory we are looking at
+            if !self.range.contains(&addr) || analysis.kinds[addr as usize] == ByteKind::Opcode {
+                return;
+            }
+
+            let instruction = DisassembledInstruction::decode(addr, &self.read);
+
+            // Illegal opcodes are most likely data that we ran into. Stop following here
+            if !instruction.opcode.official {
+                analysis.conflicts.push(addr);
+                return;
+            }
+
+            // The instruction must not overlap with an instruction or pointer that has already been found
+            let overlaps = (0..instruction.length() as u16)
+                .map(|offset| addr.wrapping_add(offset))
+                .any(|a| !self.range.contains(&a) || analysis.kinds[a as usize] != ByteKind::Data);
+            if overlaps {
+                analysis.conflicts.push(addr);
+                return;
+            }
+
+            analysis.kinds[addr as usize] = ByteKind::Opcode;
+            for offset in 1..instruction.length() as u16 {
+                analysis.kinds[addr.wrapping_add(offset) as usize] = ByteKind::Operand;
+            }
+
+            let next = instruction.next_address();
+            let target = instruction.target();
+            let continues = match (instruction.mnemonic(), instruction.mode()) {
+                (Mnemonic::JSR, _) => {
+                    if let Some(target) = target {
+                        analysis.add_label(target, LabelKind::Subroutine, None);
+                        queue.push_back(target);
+                    }
+                    true
+                }
+                (Mnemonic::JMP, AddressingMode::ABS) => {
+                    if let Some(target) = target {
+                        analysis.add_label(target, LabelKind::Location, None);
+                        queue.push_back(target);
+                    }
+                    false
+                }
+                // Indirect jumps can only be followed through annotated jump tables
+                (Mnemonic::JMP, _) | (Mnemonic::RTS, _) | (Mnemonic::RTI, _) | (Mnemonic::BRK, _) => false,
+                (_, AddressingMode::REL) => {
+                    if let Some(target) = target {
+                        analysis.add_label(target, LabelKind::Location, None);
+                        queue.push_back(target);
+                    }
+                    true
+                }
+                _ => true,
+            };
+
+            analysis.instructions.insert(addr, instruction);
+
+            // Stop at the end of the address space instead of wrapping around
+            if !continues || next <= addr {
+                return;
+            }
+            addr = next;
+        }
+    }
+}
+
+/// A piece of data that is not code
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Data {
+    Bytes(Vec<u8>),
+    /// Little endian 16-bit pointers
+    Words(Vec<u16>),
+}
+
+impl Data {
+    /// The length of the data in bytes
+    pub fn len(&self) -> usize {
+        match self {
+            Data::Bytes(bytes) => bytes.len(),
+            Data::Words(words) => words.len() * 2,
+        }
+    }
+}
+
+/// The result of running a `FlowDisassembler`
+pub struct FlowAnalysis {
+    range: RangeInclusive<u16>,
+    kinds: Vec<ByteKind>,
+    /// The addresses at which a 16-bit pointer starts
+    words: BTreeSet<u16>,
+    instructions: Disassembly,
+    labels: BTreeMap<u16, (LabelKind, String)>,
+    conflicts: Vec<u16>,
+}
+
+impl FlowAnalysis {
+    /// Adds a label for the given address. Names given by hand take precedence over generated ones,
+    /// and subroutine labels take precedence over plain locations.
+    /// If an address is given several names by hand, the first one is kept
+    fn add_label(&mut self, addr: u16, kind: LabelKind, name: Option<String>) {
+        let generated = |kind: LabelKind| match kind {
+            LabelKind::Location => format!("L{:0>4X}", addr),
+            LabelKind::Subroutine => format!("sub_{:0>4X}", addr),
+            LabelKind::JumpTable => format!("tbl_{:0>4X}", addr),
+        };
+
+        match (self.labels.get(&addr), name) {
+            (None, name) => {
+                let name = name.unwrap_or_else(|| generated(kind));
+                self.labels.insert(addr, (kind, name));
+            }
+            // Labels that were given by hand are never replaced
+            (Some((old_kind, old_name)), _) if *old_name != generated(*old_kind) => {}
+            (Some(_), Some(name)) => {
+                self.labels.insert(addr, (kind, name));
+            }
+            // Upgrade generated labels, e.g. from a location to a subroutine
+            (Some((old_kind, _)), None) => {
+                if kind > *old_kind {
+                    self.labels.insert(addr, (kind, generated(kind)));
+                }
+            }
+        }
+    }
+
+    pub fn kind(&self, addr: u16) -> ByteKind {
+        self.kinds[addr as usize]
+    }
+
+    /// Returns true if the byte at the address is part of an instruction
+    pub fn is_code(&self, addr: u16) -> bool {
+        matches!(self.kinds[addr as usize], ByteKind::Opcode | ByteKind::Operand)
+    }
+
+    /// All instructions that were found, ordered by address
+    pub fn instructions(&self) -> &Disassembly {
+        &self.instructions
+    }
+
+    /// The label of the given address, if there is one
+    pub fn label(&self, addr: u16) -> Option<&str> {
+        self.labels.get(&addr).map(|(_, name)| name.as_str())
+    }
+
+    /// All labels, ordered by address
+    pub fn labels(&self) -> impl Iterator<Item=(u16, &str)> {
+        self.labels.iter().map(|(&addr, (_, name))| (addr, name.as_str()))
+    }
+
+    /// Addresses at which code was supposed to start, but which could not be decoded
+    /// because they contain an illegal opcode or overlap with other code
+    pub fn conflicts(&self) -> &[u16] {
+        &self.conflicts
+    }
+
+    /// The range of addresses that has been analysed
+    pub fn range(&self) -> RangeInclusive<u16> {
+        self.range.clone()
+    }
+
+    /// Collects the data starting at the given address, up to the next label, instruction or change in the kind of data.
+    /// At most `max_len` bytes are collected. Pointers that are stored as whole words are returned as words
+    pub fn data_at<F: Fn(u16) -> u8>(&self, addr: u16, max_len: usize, read: F) -> Data {
+        let end = *self.range.end() as u32;
+        // Whether the data may be continued at the given address
+        let continues = |next: u32, kind: ByteKind| next <= end
+            && self.kinds[next as usize] == kind
+            && !self.labels.contains_key(&(next as u16));
+
+        let is_word = |at: u32| self.words.contains(&(at as u16)) && continues(at + 1, ByteKind::Pointer);
+
+        let kind = self.kinds[addr as usize];
+        let word_at = |at: u32| read(at as u16) as u16 | ((read(at as u16 + 1) as u16) << 8);
+        if is_word(addr as u32) {
+            let mut words = vec![word_at(addr as u32)];
+            let mut next = addr as u32 + 2;
+            while (words.len() + 1) * 2 <= max_len && continues(next, ByteKind::Pointer) && is_word(next) {
+                words.push(word_at(next));
+                next += 2;
+            }
+            return Data::Words(words);
+        }
+
+        let mut bytes = vec![read(addr)];
+        while bytes.len() < max_len {
+            let next = addr as u32 + bytes.len() as u32;
+            // Words are never split up
+            if !continues(next, kind) || self.words.contains(&(next as u16)) {
+                break;
+            }
+            bytes.push(read(next as u16));
+        }
+        Data::Bytes(bytes)
+    }
+
+    /// Creates a listing of the analysed range with labels, instructions and data.
+    /// `read` should read the same memory the analysis was run on
+    pub fn listing<F: Fn(u16) -> u8>(&self, read: F) -> String {
+        let mut listing = String::new();
+        let label = |addr: u16| self.label(addr).map(String::from);
+
+        let mut addr = *self.range.start() as u32;
+        let end = *self.range.end() as u32;
+        while addr <= end {
+            let a = addr as u16;
+            if let Some(name) = self.label(a) {
+                writeln!(listing, "{}:", name).unwrap();
+            }
+
+            if let Some(instruction) = self.instructions.get(&a) {
+                let bytes = instruction.bytes.iter().map(|b| format!("{:0>2X}", b)).collect::<Vec<_>>().join(" ");
+                writeln!(listing, "    ${:0>4X}  {:<8}  {}", a, bytes, instruction.assembly_text(label)).unwrap();
+                addr += instruction.length() as u32;
+                continue;
+            }
+
+            let data = self.data_at(a, 8, &read);
+            let directive = match &data {
+                // Pointers are shown as words, with labels where possible
+                Data::Words(words) => format!(".word {}", words.iter()
+                    .map(|&word| label(word).unwrap_or_else(|| format!("${:0>4X}", word)))
+                    .collect::<Vec<_>>()
+                    .join(", ")),
+                Data::Bytes(bytes) => format!(".byte {}", bytes.iter().map(|b| format!("${:0>2X}", b)).collect::<Vec<_>>().join(", ")),
+            };
+            writeln!(listing, "    ${:0>4X}            {}", a, directive).unwrap();
+            addr += data.len() as u32;
+        }
+
+        listing
+    }
+
+    /// Summarises the analysis as assembler comments: how much of the range is code, the labels that were found
+    /// and the conflicts, which usually point at a jump table that still has to be annotated
+    pub fn summary(&self) -> String {
+        let mut summary = String::new();
+        let code = self.range.clone().filter(|&addr| self.is_code(addr)).count();
+        let size = *self.range.end() as usize - *self.range.start() as usize + 1;
+        writeln!(summary, "; {} of {} bytes are code", code, size).unwrap();
+
+        writeln!(summary, "; Labels:").unwrap();
+        for (addr, name) in self.labels() {
+            writeln!(summary, ";   {:<16} ${:0>4X}", name, addr).unwrap();
+        }
+
+        if !self.conflicts().is_empty() {
+            writeln!(summary, "; Conflicts: {}", self.conflicts().iter().map(|addr| format!("${:0>4X}", addr)).collect::<Vec<_>>().join(", ")).unwrap();
+        }
+        summary
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cpu6502::flow_disassembler::{ByteKind, FlowDisassembler, JumpTable};
+    use crate::cpu6502::memory::FlatMemory;
+    use crate::cpu6502::Cpu6502;
+    use crate::cpu6502::assembler::assemble;
+
+    /// Creates a cpu with the given program at 0x8000 and the reset vector pointing to it
+    fn setup(program: &str) -> Cpu6502<FlatMemory> {
+        let mut cpu = assemble(&format!("*= $8000\n{}", program)).expect("Error assembling").cpu();
+        let memory = cpu.bus_mut();
+        memory.load(0xFFFA, &[0x00, 0x90, 0x00, 0x80, 0x00, 0x90]);
+        // NMI and IRQ just return
+        memory.load(0x9000, &[0x40]);
+        cpu
+    }
+
+    #[test]
+    fn data_between_code_test() {
+        let cpu = setup("
+                    jmp start
+                    .byte $FF, $02, $A9
+            start   lda #$01
+                    jsr sub
+                    brk
+                    .byte $00
+            sub     rts
+        ");
+        let analysis = FlowDisassembler::new(0x8000..=0xFFFF, |addr| cpu.peek(addr)).with_vectors().run();
+
+        assert_eq!(analysis.kind(0x8000), ByteKind::Opcode);
+        assert_eq!(analysis.kind(0x8001), ByteKind::Operand);
+        // A linear disassembly would decode these as instructions
+        assert_eq!(analysis.kind(0x8003), ByteKind::Data);
+        assert_eq!(analysis.kind(0x8004), ByteKind::Data);
+        assert_eq!(analysis.kind(0x8005), ByteKind::Data);
+        assert_eq!(analysis.kind(0x8006), ByteKind::Opcode);
+        assert_eq!(analysis.kind(0x800D), ByteKind::Opcode);
+        assert_eq!(analysis.kind(0xFFFC), ByteKind::Pointer);
+
+        assert_eq!(analysis.label(0x8000), Some("reset"));
+        assert_eq!(analysis.label(0x8006), Some("L8006"));
+        assert_eq!(analysis.label(0x800D), Some("sub_800D"));
+        assert_eq!(analysis.label(0x9000), Some("nmi"), "The first name given by hand should stay");
+        assert!(analysis.conflicts().is_empty());
+    }
+
+    #[test]
+    fn branches_test() {
+        let cpu = setup("
+            loop    dex
+                    bne loop
+                    beq skip
+                    .byte $02
+            skip    rts
+        ");
+        let analysis = FlowDisassembler::new(0x8000..=0xFFFF, |addr| cpu.peek(addr)).with_vectors().run();
+
+        assert_eq!(analysis.kind(0x8005), ByteKind::Data, "Byte skipped by the branch is data");
+        assert_eq!(analysis.kind(0x8006), ByteKind::Opcode, "Branch target is code");
+        assert_eq!(analysis.label(0x8006), Some("L8006"));
+    }
+
+    #[test]
+    fn jump_table_test() {
+        let cpu = setup("
+                    lda table,x
+                    sta $00
+                    lda table+1,x
+                    sta $01
+                    jmp ($0000)
+                    .byte $FF, $FF, $FF
+            table   .word first, second
+            first   rts
+            second  rts
+        ");
+        let mut disassembler = FlowDisassembler::new(0x8000..=0xFFFF, |addr| cpu.peek(addr)).with_vectors();
+
+        // Without annotation, the targets of the indirect jump are unknown
+        let analysis = disassembler.run();
+        assert_eq!(analysis.kind(0x8014), ByteKind::Data);
+
+        disassembler.add_jump_table(JumpTable::Words { address: 0x8010, count: 2 });
+        let analysis = disassembler.run();
+        assert_eq!(analysis.kind(0x8010), ByteKind::Pointer);
+        assert_eq!(analysis.kind(0x8014), ByteKind::Opcode);
+        assert_eq!(analysis.kind(0x8015), ByteKind::Opcode);
+        assert_eq!(analysis.label(0x8010), Some("tbl_8010"));
+
+        let listing = analysis.listing(|addr| cpu.peek(addr));
+        assert!(listing.contains("LDA tbl_8010,X"), "{}", listing);
+        assert!(listing.contains(".word L8014, L8015"), "{}", listing);
+    }
+
+    #[test]
+    fn split_jump_table_test() {
+        let cpu = setup("
+                    lda hi,x
+                    pha
+                    lda lo,x
+                    pha
+                    rts
+            lo      .byte <(first-1), <(second-1)
+            hi      .byte >(first-1), >(second-1)
+            first   rts
+            second  rts
+        ");
+        let mut disassembler = FlowDisassembler::new(0x8000..=0xFFFF, |addr| cpu.peek(addr)).with_vectors();
+        disassembler.add_jump_table(JumpTable::Split { lo: 0x8009, hi: 0x800B, count: 2 });
+        // The table is used by pushing the entries and returning to them, so they point one byte before the targets
+        disassembler.set_jump_table_offset(1);
+        let analysis = disassembler.run();
+
+        assert_eq!(analysis.kind(0x8009), ByteKind::Pointer);
+        assert_eq!(analysis.kind(0x800C), ByteKind::Pointer);
+        assert!(analysis.is_code(0x800D));
+        assert!(analysis.is_code(0x800E));
+        assert_eq!(
+            analysis.labels().filter(|(addr, _)| (0x8000..0x9000).contains(addr)).collect::<Vec<_>>(),
+            vec![(0x8000, "reset"), (0x8009, "tbl_8009"), (0x800D, "L800D"), (0x800E, "L800E")]
+        );
+
+        let summary = analysis.summary();
+        assert!(summary.contains(";   tbl_8009         $8009"), "{}", summary);
+        assert!(!summary.contains("Conflicts"), "{}", summary);
+    }
+
+    #[test]
+    fn listing_test() {
+        let cpu = setup("
+                    jmp end
+                    .byte $FF, $02
+            end     rts
+        ");
+        let analysis = FlowDisassembler::new(0x8000..=0x8005, |addr| cpu.peek(addr)).with_vectors().run();
+        let listing = analysis.listing(|addr| cpu.peek(addr));
+
+        assert_eq!(listing, [
+            "reset:",
+            "    $8000  4C 05 80  JMP L8005",
+            "    $8003            .byte $FF, $02",
+            "L8005:",
+            "    $8005  60        RTS",
+            "",
+        ].join("\n"));
+    }
+}
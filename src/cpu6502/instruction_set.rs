@@ -0,0 +1,238 @@
+/// The mnemonics of the 6502 instruction set.
+/// `XXX` stands in for all illegal opcodes that are not emulated
+macro_rules! mnemonics {
+    ($($x:ident), *) => {
+        #[allow(clippy::upper_case_acronyms)]
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        pub enum Mnemonic {
+            $($x,)*
+        }
+
+        impl Mnemonic {
+            /// All mnemonics in alphabetical order
+            pub const ALL: &'static [Mnemonic] = &[$(Mnemonic::$x,)*];
+
+            pub fn name(&self) -> &'static str {
+                match self {
+                    $(Mnemonic::$x => stringify!($x),)*
+                }
+            }
+
+            /// Looks up a mnemonic by its name. The name is case insensitive
+            pub fn from_name(name: &str) -> Option<Mnemonic> {
+                let name = name.to_ascii_uppercase();
+                Self::ALL.iter().copied().find(|mnemonic| mnemonic.name() == name)
+            }
+        }
+    }
+}
+
+mnemonics! {
+    ADC, AND, ASL, BCC, BCS, BEQ, BIT, BMI, BNE, BPL, BRK, BVC, BVS, CLC,
+    CLD, CLI, CLV, CMP, CPX, CPY, DEC, DEX, DEY, EOR, INC, INX, INY, JMP,
+    JSR, LDA, LDX, LDY, LSR, NOP, ORA, PHA, PHP, PLA, PLP, ROL, ROR, RTI,
+    RTS, SBC, SEC, SED, SEI, STA, STX, STY, TAX, TAY, TSX, TXA, TXS, TYA,
+    XXX
+}
+
+impl Mnemonic {
+    /// Whether the instruction writes to the memory its operand points to. These are the store and read-modify-write instructions.
+    /// With indexed addressing, these always take the extra cycle, that other instructions only take when crossing a page
+    pub fn writes_memory(&self) -> bool {
+        use self::Mnemonic::*;
+        matches!(self, STA | STX | STY | ASL | LSR | ROL | ROR | INC | DEC)
+    }
+}
+
+/// The addressing modes of the 6502. The names are the same as the ones of the functions implementing them in `Cpu6502`
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AddressingMode {
+    IMP, // Implied
+    IMM, // Immediate
+    ZP0, // Zero Page
+    ZPX, // Zero Page with X offset
+    ZPY, // Zero Page with Y offset
+    ABS, // Absolute
+    ABX, // Absolute with X offset
+    ABY, // Absolute with Y offset
+    IND, // Indirect
+    IZX, // Indirect Zero Page with X offset
+    IZY, // Indirect Zero Page with Y offset
+    REL, // Relative
+}
+
+impl AddressingMode {
+    /// The amount of bytes following the opcode byte
+    pub const fn operand_bytes(&self) -> u8 {
+        match self {
+            AddressingMode::IMP => 0,
+            AddressingMode::IMM
+            | AddressingMode::ZP0
+            | AddressingMode::ZPX
+            | AddressingMode::ZPY
+            | AddressingMode::IZX
+            | AddressingMode::IZY
+            | AddressingMode::REL => 1,
+            AddressingMode::ABS
+            | AddressingMode::ABX
+            | AddressingMode::ABY
+            | AddressingMode::IND => 2,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            AddressingMode::IMP => "IMP",
+            AddressingMode::IMM => "IMM",
+            AddressingMode::ZP0 => "ZP0",
+            AddressingMode::ZPX => "ZPX",
+            AddressingMode::ZPY => "ZPY",
+            AddressingMode::ABS => "ABS",
+            AddressingMode::ABX => "ABX",
+            AddressingMode::ABY => "ABY",
+            AddressingMode::IND => "IND",
+            AddressingMode::IZX => "IZX",
+            AddressingMode::IZY => "IZY",
+            AddressingMode::REL => "REL",
+        }
+    }
+}
+
+/// An entry of the decode table. Describes what the cpu does when it encounters a given opcode byte
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Opcode {
+    pub mnemonic: Mnemonic,
+    pub mode: AddressingMode,
+    /// The length of the whole instruction in bytes, including the opcode byte itself
+    pub length: u8,
+    /// The amount of cycles the instruction takes at least. Page crossings and branches can add to this
+    pub cycles: u8,
+    /// Whether this opcode is part of the documented instruction set
+    pub official: bool,
+}
+
+impl Opcode {
+    const fn new(mnemonic: Mnemonic, mode: AddressingMode, cycles: u8) -> Self {
+        Opcode {
+            mnemonic,
+            mode,
+            length: Self::length_of(mnemonic, mode),
+            cycles,
+            official: true,
+        }
+    }
+
+    const fn illegal(mnemonic: Mnemonic, mode: AddressingMode, cycles: u8) -> Self {
+        Opcode {
+            mnemonic,
+            mode,
+            length: Self::length_of(mnemonic, mode),
+            cycles,
+            official: false,
+        }
+    }
+
+    const fn length_of(mnemonic: Mnemonic, mode: AddressingMode) -> u8 {
+        // BRK is a special case, as it's an implied address mode instruction
+        // but unlike the other such instructions, it's a 2-byte instruction instead of 1.
+        // The second byte is just padding and is skipped by the cpu
+        if let Mnemonic::BRK = mnemonic {
+            2
+        } else {
+            1 + mode.operand_bytes()
+        }
+    }
+
+    /// The name to show for this opcode in a disassembly. Illegal opcodes show up as "???"
+    pub fn name(&self) -> &'static str {
+        if self.official {
+            self.mnemonic.name()
+        } else {
+            "???"
+        }
+    }
+}
+
+/// The decode table, indexed by opcode byte
+pub const OPCODES: [Opcode; 16 * 16] = {
+    use self::AddressingMode::*;
+    use self::Mnemonic::*;
+    [
+        Opcode::new(BRK, IMP, 7), Opcode::new(ORA, IZX, 6), Opcode::illegal(XXX, IMP, 2), Opcode::illegal(XXX, IMP, 8), Opcode::illegal(NOP, IMP, 3), Opcode::new(ORA, ZP0, 3), Opcode::new(ASL, ZP0, 5), Opcode::illegal(XXX, IMP, 5), Opcode::new(PHP, IMP, 3), Opcode::new(ORA, IMM, 2), Opcode::new(ASL, IMP, 2), Opcode::illegal(XXX, IMP, 2), Opcode::illegal(NOP, IMP, 4), Opcode::new(ORA, ABS, 4), Opcode::new(ASL, ABS, 6), Opcode::illegal(XXX, IMP, 6),
+        Opcode::new(BPL, REL, 2), Opcode::new(ORA, IZY, 5), Opcode::illegal(XXX, IMP, 2), Opcode::illegal(XXX, IMP, 8), Opcode::illegal(NOP, IMP, 4), Opcode::new(ORA, ZPX, 4), Opcode::new(ASL, ZPX, 6), Opcode::illegal(XXX, IMP, 6), Opcode::new(CLC, IMP, 2), Opcode::new(ORA, ABY, 4), Opcode::illegal(NOP, IMP, 2), Opcode::illegal(XXX, IMP, 7), Opcode::illegal(NOP, IMP, 4), Opcode::new(ORA, ABX, 4), Opcode::new(ASL, ABX, 7), Opcode::illegal(XXX, IMP, 7),
+        Opcode::new(JSR, ABS, 6), Opcode::new(AND, IZX, 6), Opcode::illegal(XXX, IMP, 2), Opcode::illegal(XXX, IMP, 8), Opcode::new(BIT, ZP0, 3), Opcode::new(AND, ZP0, 3), Opcode::new(ROL, ZP0, 5), Opcode::illegal(XXX, IMP, 5), Opcode::new(PLP, IMP, 4), Opcode::new(AND, IMM, 2), Opcode::new(ROL, IMP, 2), Opcode::illegal(XXX, IMP, 2), Opcode::new(BIT, ABS, 4), Opcode::new(AND, ABS, 4), Opcode::new(ROL, ABS, 6), Opcode::illegal(XXX, IMP, 6),
+        Opcode::new(BMI, REL, 2), Opcode::new(AND, IZY, 5), Opcode::illegal(XXX, IMP, 2), Opcode::illegal(XXX, IMP, 8), Opcode::illegal(NOP, IMP, 4), Opcode::new(AND, ZPX, 4), Opcode::new(ROL, ZPX, 6), Opcode::illegal(XXX, IMP, 6), Opcode::new(SEC, IMP, 2), Opcode::new(AND, ABY, 4), Opcode::illegal(NOP, IMP, 2), Opcode::illegal(XXX, IMP, 7), Opcode::illegal(NOP, IMP, 4), Opcode::new(AND, ABX, 4), Opcode::new(ROL, ABX, 7), Opcode::illegal(XXX, IMP, 7),
+        Opcode::new(RTI, IMP, 6), Opcode::new(EOR, IZX, 6), Opcode::illegal(XXX, IMP, 2), Opcode::illegal(XXX, IMP, 8), Opcode::illegal(NOP, IMP, 3), Opcode::new(EOR, ZP0, 3), Opcode::new(LSR, ZP0, 5), Opcode::illegal(XXX, IMP, 5), Opcode::new(PHA, IMP, 3), Opcode::new(EOR, IMM, 2), Opcode::new(LSR, IMP, 2), Opcode::illegal(XXX, IMP, 2), Opcode::new(JMP, ABS, 3), Opcode::new(EOR, ABS, 4), Opcode::new(LSR, ABS, 6), Opcode::illegal(XXX, IMP, 6),
+        Opcode::new(BVC, REL, 2), Opcode::new(EOR, IZY, 5), Opcode::illegal(XXX, IMP, 2), Opcode::illegal(XXX, IMP, 8), Opcode::illegal(NOP, IMP, 4), Opcode::new(EOR, ZPX, 4), Opcode::new(LSR, ZPX, 6), Opcode::illegal(XXX, IMP, 6), Opcode::new(CLI, IMP, 2), Opcode::new(EOR, ABY, 4), Opcode::illegal(NOP, IMP, 2), Opcode::illegal(XXX, IMP, 7), Opcode::illegal(NOP, IMP, 4), Opcode::new(EOR, ABX, 4), Opcode::new(LSR, ABX, 7), Opcode::illegal(XXX, IMP, 7),
+        Opcode::new(RTS, IMP, 6), Opcode::new(ADC, IZX, 6), Opcode::illegal(XXX, IMP, 2), Opcode::illegal(XXX, IMP, 8), Opcode::illegal(NOP, IMP, 3), Opcode::new(ADC, ZP0, 3), Opcode::new(ROR, ZP0, 5), Opcode::illegal(XXX, IMP, 5), Opcode::new(PLA, IMP, 4), Opcode::new(ADC, IMM, 2), Opcode::new(ROR, IMP, 2), Opcode::illegal(XXX, IMP, 2), Opcode::new(JMP, IND, 5), Opcode::new(ADC, ABS, 4), Opcode::new(ROR, ABS, 6), Opcode::illegal(XXX, IMP, 6),
+        Opcode::new(BVS, REL, 2), Opcode::new(ADC, IZY, 5), Opcode::illegal(XXX, IMP, 2), Opcode::illegal(XXX, IMP, 8), Opcode::illegal(NOP, IMP, 4), Opcode::new(ADC, ZPX, 4), Opcode::new(ROR, ZPX, 6), Opcode::illegal(XXX, IMP, 6), Opcode::new(SEI, IMP, 2), Opcode::new(ADC, ABY, 4), Opcode::illegal(NOP, IMP, 2), Opcode::illegal(XXX, IMP, 7), Opcode::illegal(NOP, IMP, 4), Opcode::new(ADC, ABX, 4), Opcode::new(ROR, ABX, 7), Opcode::illegal(XXX, IMP, 7),
+        Opcode::illegal(NOP, IMP, 2), Opcode::new(STA, IZX, 6), Opcode::illegal(NOP, IMP, 2), Opcode::illegal(XXX, IMP, 6), Opcode::new(STY, ZP0, 3), Opcode::new(STA, ZP0, 3), Opcode::new(STX, ZP0, 3), Opcode::illegal(XXX, IMP, 3), Opcode::new(DEY, IMP, 2), Opcode::illegal(NOP, IMP, 2), Opcode::new(TXA, IMP, 2), Opcode::illegal(XXX, IMP, 2), Opcode::new(STY, ABS, 4), Opcode::new(STA, ABS, 4), Opcode::new(STX, ABS, 4), Opcode::illegal(XXX, IMP, 4),
+        Opcode::new(BCC, REL, 2), Opcode::new(STA, IZY, 6), Opcode::illegal(XXX, IMP, 2), Opcode::illegal(XXX, IMP, 6), Opcode::new(STY, ZPX, 4), Opcode::new(STA, ZPX, 4), Opcode::new(STX, ZPY, 4), Opcode::illegal(XXX, IMP, 4), Opcode::new(TYA, IMP, 2), Opcode::new(STA, ABY, 5), Opcode::new(TXS, IMP, 2), Opcode::illegal(XXX, IMP, 5), Opcode::illegal(NOP, IMP, 5), Opcode::new(STA, ABX, 5), Opcode::illegal(XXX, IMP, 5), Opcode::illegal(XXX, IMP, 5),
+        Opcode::new(LDY, IMM, 2), Opcode::new(LDA, IZX, 6), Opcode::new(LDX, IMM, 2), Opcode::illegal(XXX, IMP, 6), Opcode::new(LDY, ZP0, 3), Opcode::new(LDA, ZP0, 3), Opcode::new(LDX, ZP0, 3), Opcode::illegal(XXX, IMP, 3), Opcode::new(TAY, IMP, 2), Opcode::new(LDA, IMM, 2), Opcode::new(TAX, IMP, 2), Opcode::illegal(XXX, IMP, 2), Opcode::new(LDY, ABS, 4), Opcode::new(LDA, ABS, 4), Opcode::new(LDX, ABS, 4), Opcode::illegal(XXX, IMP, 4),
+        Opcode::new(BCS, REL, 2), Opcode::new(LDA, IZY, 5), Opcode::illegal(XXX, IMP, 2), Opcode::illegal(XXX, IMP, 5), Opcode::new(LDY, ZPX, 4), Opcode::new(LDA, ZPX, 4), Opcode::new(LDX, ZPY, 4), Opcode::illegal(XXX, IMP, 4), Opcode::new(CLV, IMP, 2), Opcode::new(LDA, ABY, 4), Opcode::new(TSX, IMP, 2), Opcode::illegal(XXX, IMP, 4), Opcode::new(LDY, ABX, 4), Opcode::new(LDA, ABX, 4), Opcode::new(LDX, ABY, 4), Opcode::illegal(XXX, IMP, 4),
+        Opcode::new(CPY, IMM, 2), Opcode::new(CMP, IZX, 6), Opcode::illegal(NOP, IMP, 2), Opcode::illegal(XXX, IMP, 8), Opcode::new(CPY, ZP0, 3), Opcode::new(CMP, ZP0, 3), Opcode::new(DEC, ZP0, 5), Opcode::illegal(XXX, IMP, 5), Opcode::new(INY, IMP, 2), Opcode::new(CMP, IMM, 2), Opcode::new(DEX, IMP, 2), Opcode::illegal(XXX, IMP, 2), Opcode::new(CPY, ABS, 4), Opcode::new(CMP, ABS, 4), Opcode::new(DEC, ABS, 6), Opcode::illegal(XXX, IMP, 6),
+        Opcode::new(BNE, REL, 2), Opcode::new(CMP, IZY, 5), Opcode::illegal(XXX, IMP, 2), Opcode::illegal(XXX, IMP, 8), Opcode::illegal(NOP, IMP, 4), Opcode::new(CMP, ZPX, 4), Opcode::new(DEC, ZPX, 6), Opcode::illegal(XXX, IMP, 6), Opcode::new(CLD, IMP, 2), Opcode::new(CMP, ABY, 4), Opcode::illegal(NOP, IMP, 2), Opcode::illegal(XXX, IMP, 7), Opcode::illegal(NOP, IMP, 4), Opcode::new(CMP, ABX, 4), Opcode::new(DEC, ABX, 7), Opcode::illegal(XXX, IMP, 7),
+        Opcode::new(CPX, IMM, 2), Opcode::new(SBC, IZX, 6), Opcode::illegal(NOP, IMP, 2), Opcode::illegal(XXX, IMP, 8), Opcode::new(CPX, ZP0, 3), Opcode::new(SBC, ZP0, 3), Opcode::new(INC, ZP0, 5), Opcode::illegal(XXX, IMP, 5), Opcode::new(INX, IMP, 2), Opcode::new(SBC, IMM, 2), Opcode::new(NOP, IMP, 2), Opcode::illegal(SBC, IMP, 2), Opcode::new(CPX, ABS, 4), Opcode::new(SBC, ABS, 4), Opcode::new(INC, ABS, 6), Opcode::illegal(XXX, IMP, 6),
+        Opcode::new(BEQ, REL, 2), Opcode::new(SBC, IZY, 5), Opcode::illegal(XXX, IMP, 2), Opcode::illegal(XXX, IMP, 8), Opcode::illegal(NOP, IMP, 4), Opcode::new(SBC, ZPX, 4), Opcode::new(INC, ZPX, 6), Opcode::illegal(XXX, IMP, 6), Opcode::new(SED, IMP, 2), Opcode::new(SBC, ABY, 4), Opcode::illegal(NOP, IMP, 2), Opcode::illegal(XXX, IMP, 7), Opcode::illegal(NOP, IMP, 4), Opcode::new(SBC, ABX, 4), Opcode::new(INC, ABX, 7), Opcode::illegal(XXX, IMP, 7),
+    ]
+};
+
+/// Gets the decode table entry for the given opcode byte
+pub fn decode(opcode: u8) -> &'static Opcode {
+    &OPCODES[opcode as usize]
+}
+
+/// Finds the opcode byte of the official instruction with the given mnemonic and addressing mode.
+/// Returns `None` if there is no such instruction
+pub fn encode(mnemonic: Mnemonic, mode: AddressingMode) -> Option<u8> {
+    OPCODES.iter()
+        .position(|op| op.official && op.mnemonic == mnemonic && op.mode == mode)
+        .map(|i| i as u8)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cpu6502::instruction_set::{decode, encode, AddressingMode, Mnemonic};
+
+    #[test]
+    fn decode_test() {
+        let op = decode(0xA9);
+        assert_eq!(op.mnemonic, Mnemonic::LDA);
+        assert_eq!(op.mode, AddressingMode::IMM);
+        assert_eq!(op.length, 2);
+        assert_eq!(op.cycles, 2);
+
+        let op = decode(0x6C);
+        assert_eq!(op.mnemonic, Mnemonic::JMP);
+        assert_eq!(op.mode, AddressingMode::IND);
+        assert_eq!(op.length, 3);
+
+        assert_eq!(decode(0x00).length, 2, "BRK should skip its padding byte");
+        assert!(!decode(0x02).official);
+    }
+
+    #[test]
+    fn encode_test() {
+        assert_eq!(encode(Mnemonic::LDA, AddressingMode::IMM), Some(0xA9));
+        assert_eq!(encode(Mnemonic::STA, AddressingMode::ABY), Some(0x99));
+        assert_eq!(encode(Mnemonic::STA, AddressingMode::IMM), None);
+        assert_eq!(encode(Mnemonic::NOP, AddressingMode::IMP), Some(0xEA));
+
+        // Every official opcode must be found again from its mnemonic and addressing mode
+        for byte in 0..=0xFFu8 {
+            let op = decode(byte);
+            if op.official {
+                assert_eq!(encode(op.mnemonic, op.mode), Some(byte), "{} {:?} does not round trip", op.name(), op.mode);
+            }
+        }
+    }
+
+    #[test]
+    fn mnemonic_name_test() {
+        assert_eq!(Mnemonic::from_name("lda"), Some(Mnemonic::LDA));
+        assert_eq!(Mnemonic::from_name("Tya"), Some(Mnemonic::TYA));
+        assert_eq!(Mnemonic::from_name("foo"), None);
+        assert_eq!(Mnemonic::SBC.name(), "SBC");
+    }
+}
@@ -0,0 +1,184 @@
+//! Runs Wolfgang Lorenz' 6502 test suite, which lives in `asm_tests/testsuite-2.15`.
+//!
+//! The tests are C64 programs. They are loaded to $0801 like they would be by the C64's LOAD command and
+//! use a few routines of the C64 KERNAL for printing, waiting for keys and loading the next test.
+//! There is no KERNAL here, so those entry points are trapped and emulated instead.
+
+use crate::cpu6502::assembler::{assemble_file, AssembledProgram};
+use crate::cpu6502::memory::{CpuBus, FlatMemory};
+use crate::cpu6502::{Cpu6502, Flags6502};
+use std::path::Path;
+
+/// Where the sources of the test suite are
+const SOURCE_DIR: &str = "asm_tests/testsuite-2.15/ascii-src";
+
+/// The address every test is loaded to. This is the start of BASIC memory on the C64
+const LOAD_ADDRESS: u16 = 0x0801;
+
+/// The tests start with a BASIC line `POKE 2,0:SYS 2070`. This is the address the SYS jumps to
+const START_ADDRESS: u16 = 0x0816;
+
+/// KERNAL CHROUT: prints the character in the accumulator
+const CHROUT: u16 = 0xFFD2;
+/// KERNAL GETIN: gets a pressed key in the accumulator. The tests use this to wait for a key after an error
+const GETIN: u16 = 0xFFE4;
+/// The LOAD routine of the KERNAL, which the tests jump into to load and run the next test
+const LOAD: u16 = 0xE16F;
+/// The tests jump here to exit, if they were started from a cartridge
+const CARTRIDGE_EXIT: u16 = 0x8000;
+/// Where the BASIC warm start vector at $A002 points to. The tests jump there to exit to BASIC
+const BASIC_EXIT: u16 = 0xA474;
+
+/// The PETSCII code of the RUN/STOP key. Pressing it after an error stops the test
+const RUN_STOP: u8 = 3;
+
+/// The tests loop a lot, but every test of the suite is done long before this many instructions
+const MAX_INSTRUCTIONS: usize = 200_000_000;
+
+/// How a test ended
+#[derive(Debug, PartialEq, Eq)]
+enum Outcome {
+    /// The test succeeded and tried to load the next test of the suite
+    Passed { next: String },
+    /// The test found an error and exited after printing the registers
+    Failed,
+    /// The test neither passed nor failed in time
+    TimedOut { pc: u16 },
+}
+
+/// A test of the suite, loaded into a flat memory cpu
+struct LorenzTest {
+    cpu: Cpu6502<FlatMemory>,
+    program: AssembledProgram,
+    /// Everything the test printed through CHROUT
+    output: String,
+}
+
+impl LorenzTest {
+    /// Assembles the test with the given name and prepares the cpu to run it
+    fn load(name: &str) -> Self {
+        let path = Path::new(SOURCE_DIR).join(format!("{}.asm", name));
+        let program = assemble_file(&path).unwrap_or_else(|e| panic!("Error assembling {}: {}", name, e));
+        // Like a C64 program file, the first chunk decides the load address
+        let first = program.chunks().first().map(|(start, _)| *start);
+        assert_eq!(first, Some(LOAD_ADDRESS), "Tests have to be loaded to ${:0>4X}", LOAD_ADDRESS);
+
+        let mut memory = FlatMemory::new();
+        for (start, chunk) in program.chunks() {
+            memory.load(*start, chunk);
+        }
+        // BASIC warm start vector
+        memory.load(0xA002, &[BASIC_EXIT as u8, (BASIC_EXIT >> 8) as u8]);
+        // The POKE 2,0 of the BASIC line. This makes the test load the next one instead of waiting for a key
+        memory.load(0x0002, &[0x00]);
+
+        // The tests are written for the 6502 of the C64, which has decimal mode
+        let mut cpu = Cpu6502::with_bus(memory);
+        cpu.set_decimal_mode(true);
+        cpu.set_program_counter(START_ADDRESS);
+        cpu.set_stack_pointer(0xFD);
+        cpu.status = Flags6502::U | Flags6502::I;
+
+        LorenzTest {
+            cpu,
+            program,
+            output: String::new(),
+        }
+    }
+
+    /// Returns from a trapped KERNAL routine, as if it had been called with JSR
+    fn return_from_subroutine(&mut self) {
+        let lo = self.cpu.pop_stack() as u16;
+        let hi = self.cpu.pop_stack() as u16;
+        self.cpu.set_program_counter(((hi << 8) | lo).wrapping_add(1));
+    }
+
+    /// Runs the test until it passes, fails or takes too long
+    fn run(&mut self) -> Outcome {
+        for _ in 0..MAX_INSTRUCTIONS {
+            match self.cpu.get_program_counter() {
+                CHROUT => {
+                    self.output.push(match self.cpu.get_acc() {
+                        13 => '\n',
+                        c => c as char,
+                    });
+                    self.return_from_subroutine();
+                }
+                GETIN => {
+                    // Always press RUN/STOP, so failing tests stop right away
+                    self.cpu.a = RUN_STOP;
+                    self.cpu.set_flag(Flags6502::Z, false);
+                    self.cpu.set_flag(Flags6502::N, false);
+                    self.return_from_subroutine();
+                }
+                LOAD => {
+                    // The file name is at ($BB) and its length at $B7
+                    let memory = self.cpu.bus();
+                    let name_addr = memory.peek(0xBB) as u16 | ((memory.peek(0xBC) as u16) << 8);
+                    let next = (0..memory.peek(0xB7) as u16)
+                        .map(|i| memory.peek(name_addr + i) as char)
+                        .collect();
+                    return Outcome::Passed { next };
+                }
+                CARTRIDGE_EXIT | BASIC_EXIT => return Outcome::Failed,
+                _ => self.cpu.step(),
+            }
+        }
+
+        Outcome::TimedOut { pc: self.cpu.get_program_counter() }
+    }
+}
+
+/// Runs a test and panics with its output, if it does not pass
+fn run_test(name: &str) {
+    let mut test = LorenzTest::load(name);
+    let outcome = test.run();
+    match outcome {
+        Outcome::Passed { .. } => {}
+        _ => panic!("Test {} did not pass: {:?}\n{}", name, outcome, test.output),
+    }
+}
+
+/// Generates a cargo test for each test of the suite
+macro_rules! lorenz_tests {
+    ($($name:ident), *) => {
+        $(
+            #[test]
+            fn $name() {
+                run_test(stringify!($name));
+            }
+        )*
+    }
+}
+
+#[allow(non_snake_case)]
+#[cfg(test)]
+mod test {
+    use crate::cpu6502::lorenz::{run_test, LorenzTest, Outcome};
+    use crate::cpu6502::memory::CpuBus;
+
+    lorenz_tests! {
+        adca
+    }
+
+    #[test]
+    fn load_next_test() {
+        let mut test = LorenzTest::load("adca");
+        // Skip the test itself and go straight to loading the next one
+        test.cpu.set_program_counter(test.program.symbol("load").unwrap());
+
+        assert_eq!(test.run(), Outcome::Passed { next: "adcax".to_string() });
+        assert_eq!(test.output, "adcax");
+    }
+
+    #[test]
+    fn failure_test() {
+        let mut test = LorenzTest::load("adca");
+        // Replace the tested ADC with an SBC, so the results don't match the expected ones
+        let cmd = test.program.symbol("cmd").unwrap();
+        test.cpu.bus_mut().write(cmd, 0xED);
+
+        assert_eq!(test.run(), Outcome::Failed);
+        assert!(test.output.contains("before"), "The registers should be printed on failure:\n{}", test.output);
+    }
+}
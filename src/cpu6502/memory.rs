@@ -0,0 +1,76 @@
+#[cfg(test)]
+use std::fmt::Debug;
+
+/// The interface the CPU uses to talk to the memory it is connected to.
+/// The CPU itself does not care what sits behind an address. On the NES this is the `Bus`,
+/// which maps addresses to RAM, the PPU and the cartridge, but it might as well just be plain RAM
+pub trait CpuBus {
+    /// Reads a byte from the given address. This may have side effects on the device behind the address
+    fn read(&mut self, addr: u16) -> u8;
+
+    /// Writes a byte to the given address
+    fn write(&mut self, addr: u16, data: u8);
+
+    /// Reads a byte from the given address without changing the state of anything connected to the bus.
+    /// This is used by the disassembler and other debugging tools
+    fn peek(&self, addr: u16) -> u8;
+
+    /// Called once for every clock cycle the CPU works
+    fn tick(&mut self) {}
+
+    /// The scanline and dot of the ppu connected to the bus. This is only used for trace logs.
+    /// Buses without a ppu return `None`
+    fn ppu_position(&self) -> Option<(i16, i16)> {
+        None
+    }
+}
+
+/// The size of the entire address space of the 6502
+#[cfg(test)]
+pub const ADDRESS_SPACE_SIZE: usize = 0x10000;
+
+/// 64kb of RAM covering the whole address space without any devices mapped into it.
+/// This allows running (and testing) the CPU on its own, without a PPU or a cartridge
+#[cfg(test)]
+pub struct FlatMemory {
+    ram: Vec<u8>,
+}
+
+#[cfg(test)]
+impl FlatMemory {
+    pub fn new() -> Self {
+        FlatMemory {
+            ram: vec![0; ADDRESS_SPACE_SIZE],
+        }
+    }
+
+    /// Copies the given bytes into memory, starting at the given address.
+    /// Bytes that don't fit into the address space anymore wrap around to 0x0000
+    pub fn load(&mut self, start: u16, bytes: &[u8]) {
+        for (i, &b) in bytes.iter().enumerate() {
+            self.ram[(start as usize + i) % ADDRESS_SPACE_SIZE] = b;
+        }
+    }
+}
+
+#[cfg(test)]
+impl CpuBus for FlatMemory {
+    fn read(&mut self, addr: u16) -> u8 {
+        self.ram[addr as usize]
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        self.ram[addr as usize] = data;
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        self.ram[addr as usize]
+    }
+}
+
+#[cfg(test)]
+impl Debug for FlatMemory {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(fmt, "flat memory")
+    }
+}
@@ -1,10 +1,29 @@
-use crate::bus::Bus;
-use std::cell::RefCell;
-use std::rc::Rc;
-use std::collections::HashMap;
+use crate::cpu6502::memory::CpuBus;
+use crate::cpu6502::instruction_set::{decode, AddressingMode, Mnemonic};
+use crate::cpu6502::disassembler::{DisassembledInstruction, Disassembly};
+use crate::cpu6502::flow_disassembler::{FlowAnalysis, FlowDisassembler};
+use crate::cpu6502::trace::TraceSink;
+use crate::cpu6502::stack::{Interrupt, StackOrigin};
+use std::ops::RangeInclusive;
 
 mod addressing_modes;
 mod opcodes;
+pub mod memory;
+pub mod instruction_set;
+pub mod disassembler;
+pub mod flow_disassembler;
+pub mod source_export;
+#[cfg(test)]
+pub mod assembler;
+pub mod trace;
+pub mod trace_diff;
+pub mod stack;
+#[cfg(test)]
+mod lorenz;
+#[cfg(test)]
+mod dormann;
+#[cfg(test)]
+mod single_step;
 
 
 bitflags! {
@@ -12,7 +31,7 @@ bitflags! {
         const C = 0x01; // Carry Bit
         const Z = 0x02; // Zero
         const I = 0x04; // Disable Interrupts
-        const D = 0x08; // Decimal Mode (unused in this implementation)
+        const D = 0x08; // Decimal Mode (only used if decimal mode is enabled, the NES doesn't have it)
         const B = 0x10; // Break
         const U = 0x20; // Unused
         const V = 0x40; // Overflow
@@ -20,28 +39,6 @@ bitflags! {
     }
 }
 
-
-lazy_static! {
-    static ref LOOKUP: [Instruction; 16 * 16] = [
-        Instruction::new("BRK", Cpu6502::BRK, Cpu6502::IMP, 7), Instruction::new("ORA", Cpu6502::ORA, Cpu6502::IZX, 6), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 2), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 8), Instruction::new("???", Cpu6502::NOP, Cpu6502::IMP, 3), Instruction::new("ORA", Cpu6502::ORA, Cpu6502::ZP0, 3), Instruction::new("ASL", Cpu6502::ASL, Cpu6502::ZP0, 5), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 5), Instruction::new("PHP", Cpu6502::PHP, Cpu6502::IMP, 3), Instruction::new("ORA", Cpu6502::ORA, Cpu6502::IMM, 2), Instruction::new("ASL", Cpu6502::ASL, Cpu6502::IMP, 2), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 2), Instruction::new("???", Cpu6502::NOP, Cpu6502::IMP, 4), Instruction::new("ORA", Cpu6502::ORA, Cpu6502::ABS, 4), Instruction::new("ASL", Cpu6502::ASL, Cpu6502::ABS, 6), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 6),
-        Instruction::new("BPL", Cpu6502::BPL, Cpu6502::REL, 2), Instruction::new("ORA", Cpu6502::ORA, Cpu6502::IZY, 5), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 2), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 8), Instruction::new("???", Cpu6502::NOP, Cpu6502::IMP, 4), Instruction::new("ORA", Cpu6502::ORA, Cpu6502::ZPX, 4), Instruction::new("ASL", Cpu6502::ASL, Cpu6502::ZPX, 6), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 6), Instruction::new("CLC", Cpu6502::CLC, Cpu6502::IMP, 2), Instruction::new("ORA", Cpu6502::ORA, Cpu6502::ABY, 4), Instruction::new("???", Cpu6502::NOP, Cpu6502::IMP, 2), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 7), Instruction::new("???", Cpu6502::NOP, Cpu6502::IMP, 4), Instruction::new("ORA", Cpu6502::ORA, Cpu6502::ABX, 4), Instruction::new("ASL", Cpu6502::ASL, Cpu6502::ABX, 7), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 7),
-        Instruction::new("JSR", Cpu6502::JSR, Cpu6502::ABS, 6), Instruction::new("AND", Cpu6502::AND, Cpu6502::IZX, 6), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 2), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 8), Instruction::new("BIT", Cpu6502::BIT, Cpu6502::ZP0, 3), Instruction::new("AND", Cpu6502::AND, Cpu6502::ZP0, 3), Instruction::new("ROL", Cpu6502::ROL, Cpu6502::ZP0, 5), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 5), Instruction::new("PLP", Cpu6502::PLP, Cpu6502::IMP, 4), Instruction::new("AND", Cpu6502::AND, Cpu6502::IMM, 2), Instruction::new("ROL", Cpu6502::ROL, Cpu6502::IMP, 2), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 2), Instruction::new("BIT", Cpu6502::BIT, Cpu6502::ABS, 4), Instruction::new("AND", Cpu6502::AND, Cpu6502::ABS, 4), Instruction::new("ROL", Cpu6502::ROL, Cpu6502::ABS, 6), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 6),
-        Instruction::new("BMI", Cpu6502::BMI, Cpu6502::REL, 2), Instruction::new("AND", Cpu6502::AND, Cpu6502::IZY, 5), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 2), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 8), Instruction::new("???", Cpu6502::NOP, Cpu6502::IMP, 4), Instruction::new("AND", Cpu6502::AND, Cpu6502::ZPX, 4), Instruction::new("ROL", Cpu6502::ROL, Cpu6502::ZPX, 6), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 6), Instruction::new("SEC", Cpu6502::SEC, Cpu6502::IMP, 2), Instruction::new("AND", Cpu6502::AND, Cpu6502::ABY, 4), Instruction::new("???", Cpu6502::NOP, Cpu6502::IMP, 2), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 7), Instruction::new("???", Cpu6502::NOP, Cpu6502::IMP, 4), Instruction::new("AND", Cpu6502::AND, Cpu6502::ABX, 4), Instruction::new("ROL", Cpu6502::ROL, Cpu6502::ABX, 7), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 7),
-        Instruction::new("RTI", Cpu6502::RTI, Cpu6502::IMP, 6), Instruction::new("EOR", Cpu6502::EOR, Cpu6502::IZX, 6), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 2), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 8), Instruction::new("???", Cpu6502::NOP, Cpu6502::IMP, 3), Instruction::new("EOR", Cpu6502::EOR, Cpu6502::ZP0, 3), Instruction::new("LSR", Cpu6502::LSR, Cpu6502::ZP0, 5), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 5), Instruction::new("PHA", Cpu6502::PHA, Cpu6502::IMP, 3), Instruction::new("EOR", Cpu6502::EOR, Cpu6502::IMM, 2), Instruction::new("LSR", Cpu6502::LSR, Cpu6502::IMP, 2), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 2), Instruction::new("JMP", Cpu6502::JMP, Cpu6502::ABS, 3), Instruction::new("EOR", Cpu6502::EOR, Cpu6502::ABS, 4), Instruction::new("LSR", Cpu6502::LSR, Cpu6502::ABS, 6), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 6),
-        Instruction::new("BVC", Cpu6502::BVC, Cpu6502::REL, 2), Instruction::new("EOR", Cpu6502::EOR, Cpu6502::IZY, 5), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 2), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 8), Instruction::new("???", Cpu6502::NOP, Cpu6502::IMP, 4), Instruction::new("EOR", Cpu6502::EOR, Cpu6502::ZPX, 4), Instruction::new("LSR", Cpu6502::LSR, Cpu6502::ZPX, 6), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 6), Instruction::new("CLI", Cpu6502::CLI, Cpu6502::IMP, 2), Instruction::new("EOR", Cpu6502::EOR, Cpu6502::ABY, 4), Instruction::new("???", Cpu6502::NOP, Cpu6502::IMP, 2), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 7), Instruction::new("???", Cpu6502::NOP, Cpu6502::IMP, 4), Instruction::new("EOR", Cpu6502::EOR, Cpu6502::ABX, 4), Instruction::new("LSR", Cpu6502::LSR, Cpu6502::ABX, 7), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 7),
-        Instruction::new("RTS", Cpu6502::RTS, Cpu6502::IMP, 6), Instruction::new("ADC", Cpu6502::ADC, Cpu6502::IZX, 6), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 2), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 8), Instruction::new("???", Cpu6502::NOP, Cpu6502::IMP, 3), Instruction::new("ADC", Cpu6502::ADC, Cpu6502::ZP0, 3), Instruction::new("ROR", Cpu6502::ROR, Cpu6502::ZP0, 5), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 5), Instruction::new("PLA", Cpu6502::PLA, Cpu6502::IMP, 4), Instruction::new("ADC", Cpu6502::ADC, Cpu6502::IMM, 2), Instruction::new("ROR", Cpu6502::ROR, Cpu6502::IMP, 2), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 2), Instruction::new("JMP", Cpu6502::JMP, Cpu6502::IND, 5), Instruction::new("ADC", Cpu6502::ADC, Cpu6502::ABS, 4), Instruction::new("ROR", Cpu6502::ROR, Cpu6502::ABS, 6), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 6),
-        Instruction::new("BVS", Cpu6502::BVS, Cpu6502::REL, 2), Instruction::new("ADC", Cpu6502::ADC, Cpu6502::IZY, 5), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 2), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 8), Instruction::new("???", Cpu6502::NOP, Cpu6502::IMP, 4), Instruction::new("ADC", Cpu6502::ADC, Cpu6502::ZPX, 4), Instruction::new("ROR", Cpu6502::ROR, Cpu6502::ZPX, 6), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 6), Instruction::new("SEI", Cpu6502::SEI, Cpu6502::IMP, 2), Instruction::new("ADC", Cpu6502::ADC, Cpu6502::ABY, 4), Instruction::new("???", Cpu6502::NOP, Cpu6502::IMP, 2), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 7), Instruction::new("???", Cpu6502::NOP, Cpu6502::IMP, 4), Instruction::new("ADC", Cpu6502::ADC, Cpu6502::ABX, 4), Instruction::new("ROR", Cpu6502::ROR, Cpu6502::ABX, 7), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 7),
-        Instruction::new("???", Cpu6502::NOP, Cpu6502::IMP, 2), Instruction::new("STA", Cpu6502::STA, Cpu6502::IZX, 6), Instruction::new("???", Cpu6502::NOP, Cpu6502::IMP, 2), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 6), Instruction::new("STY", Cpu6502::STY, Cpu6502::ZP0, 3), Instruction::new("STA", Cpu6502::STA, Cpu6502::ZP0, 3), Instruction::new("STX", Cpu6502::STX, Cpu6502::ZP0, 3), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 3), Instruction::new("DEY", Cpu6502::DEY, Cpu6502::IMP, 2), Instruction::new("???", Cpu6502::NOP, Cpu6502::IMP, 2), Instruction::new("TXA", Cpu6502::TXA, Cpu6502::IMP, 2), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 2), Instruction::new("STY", Cpu6502::STY, Cpu6502::ABS, 4), Instruction::new("STA", Cpu6502::STA, Cpu6502::ABS, 4), Instruction::new("STX", Cpu6502::STX, Cpu6502::ABS, 4), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 4),
-        Instruction::new("BCC", Cpu6502::BCC, Cpu6502::REL, 2), Instruction::new("STA", Cpu6502::STA, Cpu6502::IZY, 6), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 2), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 6), Instruction::new("STY", Cpu6502::STY, Cpu6502::ZPX, 4), Instruction::new("STA", Cpu6502::STA, Cpu6502::ZPX, 4), Instruction::new("STX", Cpu6502::STX, Cpu6502::ZPY, 4), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 4), Instruction::new("TYA", Cpu6502::TYA, Cpu6502::IMP, 2), Instruction::new("STA", Cpu6502::STA, Cpu6502::ABY, 5), Instruction::new("TXS", Cpu6502::TXS, Cpu6502::IMP, 2), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 5), Instruction::new("???", Cpu6502::NOP, Cpu6502::IMP, 5), Instruction::new("STA", Cpu6502::STA, Cpu6502::ABX, 5), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 5), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 5),
-        Instruction::new("LDY", Cpu6502::LDY, Cpu6502::IMM, 2), Instruction::new("LDA", Cpu6502::LDA, Cpu6502::IZX, 6), Instruction::new("LDX", Cpu6502::LDX, Cpu6502::IMM, 2), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 6), Instruction::new("LDY", Cpu6502::LDY, Cpu6502::ZP0, 3), Instruction::new("LDA", Cpu6502::LDA, Cpu6502::ZP0, 3), Instruction::new("LDX", Cpu6502::LDX, Cpu6502::ZP0, 3), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 3), Instruction::new("TAY", Cpu6502::TAY, Cpu6502::IMP, 2), Instruction::new("LDA", Cpu6502::LDA, Cpu6502::IMM, 2), Instruction::new("TAX", Cpu6502::TAX, Cpu6502::IMP, 2), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 2), Instruction::new("LDY", Cpu6502::LDY, Cpu6502::ABS, 4), Instruction::new("LDA", Cpu6502::LDA, Cpu6502::ABS, 4), Instruction::new("LDX", Cpu6502::LDX, Cpu6502::ABS, 4), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 4),
-        Instruction::new("BCS", Cpu6502::BCS, Cpu6502::REL, 2), Instruction::new("LDA", Cpu6502::LDA, Cpu6502::IZY, 5), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 2), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 5), Instruction::new("LDY", Cpu6502::LDY, Cpu6502::ZPX, 4), Instruction::new("LDA", Cpu6502::LDA, Cpu6502::ZPX, 4), Instruction::new("LDX", Cpu6502::LDX, Cpu6502::ZPY, 4), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 4), Instruction::new("CLV", Cpu6502::CLV, Cpu6502::IMP, 2), Instruction::new("LDA", Cpu6502::LDA, Cpu6502::ABY, 4), Instruction::new("TSX", Cpu6502::TSX, Cpu6502::IMP, 2), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 4), Instruction::new("LDY", Cpu6502::LDY, Cpu6502::ABX, 4), Instruction::new("LDA", Cpu6502::LDA, Cpu6502::ABX, 4), Instruction::new("LDX", Cpu6502::LDX, Cpu6502::ABY, 4), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 4),
-        Instruction::new("CPY", Cpu6502::CPY, Cpu6502::IMM, 2), Instruction::new("CMP", Cpu6502::CMP, Cpu6502::IZX, 6), Instruction::new("???", Cpu6502::NOP, Cpu6502::IMP, 2), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 8), Instruction::new("CPY", Cpu6502::CPY, Cpu6502::ZP0, 3), Instruction::new("CMP", Cpu6502::CMP, Cpu6502::ZP0, 3), Instruction::new("DEC", Cpu6502::DEC, Cpu6502::ZP0, 5), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 5), Instruction::new("INY", Cpu6502::INY, Cpu6502::IMP, 2), Instruction::new("CMP", Cpu6502::CMP, Cpu6502::IMM, 2), Instruction::new("DEX", Cpu6502::DEX, Cpu6502::IMP, 2), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 2), Instruction::new("CPY", Cpu6502::CPY, Cpu6502::ABS, 4), Instruction::new("CMP", Cpu6502::CMP, Cpu6502::ABS, 4), Instruction::new("DEC", Cpu6502::DEC, Cpu6502::ABS, 6), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 6),
-        Instruction::new("BNE", Cpu6502::BNE, Cpu6502::REL, 2), Instruction::new("CMP", Cpu6502::CMP, Cpu6502::IZY, 5), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 2), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 8), Instruction::new("???", Cpu6502::NOP, Cpu6502::IMP, 4), Instruction::new("CMP", Cpu6502::CMP, Cpu6502::ZPX, 4), Instruction::new("DEC", Cpu6502::DEC, Cpu6502::ZPX, 6), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 6), Instruction::new("CLD", Cpu6502::CLD, Cpu6502::IMP, 2), Instruction::new("CMP", Cpu6502::CMP, Cpu6502::ABY, 4), Instruction::new("NOP", Cpu6502::NOP, Cpu6502::IMP, 2), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 7), Instruction::new("???", Cpu6502::NOP, Cpu6502::IMP, 4), Instruction::new("CMP", Cpu6502::CMP, Cpu6502::ABX, 4), Instruction::new("DEC", Cpu6502::DEC, Cpu6502::ABX, 7), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 7),
-        Instruction::new("CPX", Cpu6502::CPX, Cpu6502::IMM, 2), Instruction::new("SBC", Cpu6502::SBC, Cpu6502::IZX, 6), Instruction::new("???", Cpu6502::NOP, Cpu6502::IMP, 2), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 8), Instruction::new("CPX", Cpu6502::CPX, Cpu6502::ZP0, 3), Instruction::new("SBC", Cpu6502::SBC, Cpu6502::ZP0, 3), Instruction::new("INC", Cpu6502::INC, Cpu6502::ZP0, 5), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 5), Instruction::new("INX", Cpu6502::INX, Cpu6502::IMP, 2), Instruction::new("SBC", Cpu6502::SBC, Cpu6502::IMM, 2), Instruction::new("NOP", Cpu6502::NOP, Cpu6502::IMP, 2), Instruction::new("???", Cpu6502::SBC, Cpu6502::IMP, 2), Instruction::new("CPX", Cpu6502::CPX, Cpu6502::ABS, 4), Instruction::new("SBC", Cpu6502::SBC, Cpu6502::ABS, 4), Instruction::new("INC", Cpu6502::INC, Cpu6502::ABS, 6), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 6),
-        Instruction::new("BEQ", Cpu6502::BEQ, Cpu6502::REL, 2), Instruction::new("SBC", Cpu6502::SBC, Cpu6502::IZY, 5), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 2), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 8), Instruction::new("???", Cpu6502::NOP, Cpu6502::IMP, 4), Instruction::new("SBC", Cpu6502::SBC, Cpu6502::ZPX, 4), Instruction::new("INC", Cpu6502::INC, Cpu6502::ZPX, 6), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 6), Instruction::new("SED", Cpu6502::SED, Cpu6502::IMP, 2), Instruction::new("SBC", Cpu6502::SBC, Cpu6502::ABY, 4), Instruction::new("NOP", Cpu6502::NOP, Cpu6502::IMP, 2), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 7), Instruction::new("???", Cpu6502::NOP, Cpu6502::IMP, 4), Instruction::new("SBC", Cpu6502::SBC, Cpu6502::ABX, 4), Instruction::new("INC", Cpu6502::INC, Cpu6502::ABX, 7), Instruction::new("???", Cpu6502::XXX, Cpu6502::IMP, 7),
-    ];
-}
-
 /// The 6502's hardcoded stack pointer base location
 const STACK_POINTER_BASE: u16 = 0x0100;
 
@@ -55,12 +52,12 @@ const IRQ_PROGRAM_COUNTER: u16 = 0xFFFE;
 const NMI_PROGRAM_COUNTER: u16 = 0xFFFA;
 
 #[derive(Debug)]
-pub struct Cpu6502 {
-    bus: Option<Rc<RefCell<Bus>>>,
+pub struct Cpu6502<B: CpuBus> {
+    bus: Option<B>,
     a: u8,             // Accumulator Register
     x: u8,             // X Register
     y: u8,             // Y Register
-    stkp: u16,         // Stack Pointer
+    stkp: u8,          // Stack Pointer, an offset into page $01
     pc: u16,           // Program Counter
     status: Flags6502, // Status Register
     fetched: u8,       // Fetched data for executing instruction
@@ -68,11 +65,15 @@ pub struct Cpu6502 {
     addr_rel: u16,     // Relative memory address
     opcode: u8,        // Opcode of current instruction
     cycles: u8,        // Number or clock cycles left for current instruction
-    cycle_count: usize // The amount of cycles worked by the CPU
+    cycle_count: usize, // The amount of cycles worked by the CPU
+    decimal_mode: bool, // Whether ADC and SBC respect the decimal flag
+    trace_sink: Option<Box<dyn TraceSink>>, // Where executed instructions are logged to
+    tracing: bool,      // Whether executed instructions are logged to the trace sink
+    stack_origins: [StackOrigin; 256] // What pushed each byte of the stack page, for the stack view
 }
 
 #[allow(non_snake_case, unused)]
-impl Cpu6502 {
+impl<B: CpuBus> Cpu6502<B> {
     pub fn new() -> Self {
         Cpu6502 {
             bus: None,
@@ -87,10 +88,21 @@ impl Cpu6502 {
             addr_rel: 0,
             opcode: 0,
             cycles: 0,
-            cycle_count: 7
+            cycle_count: 7,
+            decimal_mode: false,
+            trace_sink: None,
+            tracing: false,
+            stack_origins: [StackOrigin::Unknown; 256]
         }
     }
 
+    /// Creates a new cpu that is already connected to the given bus
+    pub fn with_bus(bus: B) -> Self {
+        let mut cpu = Self::new();
+        cpu.connect_bus(bus);
+        cpu
+    }
+
     pub fn get_acc(&self) -> u8 {
         self.a
     }
@@ -103,11 +115,11 @@ impl Cpu6502 {
         self.y
     }
 
-    pub fn get_stack_pointer(&self) -> u16 {
+    pub fn get_stack_pointer(&self) -> u8 {
         self.stkp
     }
 
-    pub fn set_stack_pointer(&mut self, value: u16) {
+    pub fn set_stack_pointer(&mut self, value: u8) {
         self.stkp = value;
     }
 
@@ -123,24 +135,62 @@ impl Cpu6502 {
         self.status
     }
 
-    pub fn connect_bus(&mut self, bus: Rc<RefCell<Bus>>) {
+    /// Enables or disables binary coded decimal arithmetic for ADC and SBC when the decimal flag is set.
+    /// The 2A03 of the NES lacks decimal mode, so it is disabled by default.
+    /// Programs written for a regular 6502, like CPU test suites, need it though
+    pub fn set_decimal_mode(&mut self, enabled: bool) {
+        self.decimal_mode = enabled;
+    }
+
+    /// Sets where executed instructions are logged to and enables tracing
+    pub fn set_trace_sink(&mut self, sink: Box<dyn TraceSink>) {
+        self.trace_sink = Some(sink);
+        self.tracing = true;
+    }
+
+    /// Removes the trace sink, which also disables tracing
+    pub fn remove_trace_sink(&mut self) -> Option<Box<dyn TraceSink>> {
+        self.tracing = false;
+        self.trace_sink.take()
+    }
+
+    /// Enables or disables tracing without removing the trace sink.
+    /// Without a trace sink, nothing is traced either way
+    pub fn set_tracing(&mut self, enabled: bool) {
+        self.tracing = enabled;
+    }
+
+    pub fn is_tracing(&self) -> bool {
+        self.tracing && self.trace_sink.is_some()
+    }
+
+    pub fn connect_bus(&mut self, bus: B) {
         self.bus = Some(bus);
     }
 
-    fn read(&self, addr: u16) -> u8 {
-        self.bus
-            .as_ref()
-            .expect("cpu not connected to Bus")
-            .borrow()
-            .cpu_read(addr, false)
+    pub fn bus(&self) -> &B {
+        self.bus.as_ref().expect("cpu not connected to Bus")
+    }
+
+    pub fn bus_mut(&mut self) -> &mut B {
+        self.bus.as_mut().expect("cpu not connected to Bus")
     }
 
-    fn write(&self, addr: u16, data: u8) {
-        self.bus
-            .as_ref()
-            .expect("cpu not connected to Bus")
-            .borrow()
-            .cpu_write(addr, data)
+    fn read(&mut self, addr: u16) -> u8 {
+        self.bus_mut().read(addr)
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        // Whatever was on the stack there is gone now
+        if addr & 0xFF00 == STACK_POINTER_BASE {
+            self.stack_origins[(addr & 0x00FF) as usize] = StackOrigin::Unknown;
+        }
+        self.bus_mut().write(addr, data)
+    }
+
+    /// Reads from the bus without any side effects. See `CpuBus::peek`
+    fn peek(&self, addr: u16) -> u8 {
+        self.bus().peek(addr)
     }
 
     pub fn get_flag(&self, flag: Flags6502) -> bool {
@@ -157,25 +207,35 @@ impl Cpu6502 {
 
     pub fn clock(&mut self) {
         if self.cycles == 0 {
+            if self.tracing {
+                // Building the entry is not free, so it is only done if there is a sink for it
+                if let Some(mut sink) = self.trace_sink.take() {
+                    sink.trace(&self.trace_entry());
+                    self.trace_sink = Some(sink);
+                }
+            }
+
             // Read the next opcode from the memory at the program counter
             self.opcode = self.read(self.pc);
 
-            let (instr, _) = self.disassemble_instr_at(self.pc);
-            println!("${:0>4X}: {:<20} A:{:0>2X}, X:{:0>2X}, Y:{:0>2X}, P:{:0>2X}, SP:{:0>4X}, Cycle: {}", self.pc, instr, self.a, self.x, self.y, self.status.bits(),self.stkp, self.cycle_count);
-
             self.pc += 1;
 
             // Get the instruction specified by the next opcode
-            let instruction = &LOOKUP[self.opcode as usize];
+            let instruction = decode(self.opcode);
 
             // Get starting number of cycles
             self.cycles = instruction.cycles;
 
-            // Set the addressing mode specified by the instruction
-            let additional_cycle_addrmode = (instruction.addrmode)(self);
+            // Set the addressing mode specified by the instruction.
+            // JSR is the only instruction reading its operand itself, see `Cpu6502::JSR`
+            let additional_cycle_addrmode = if instruction.mnemonic == Mnemonic::JSR {
+                false
+            } else {
+                self.run_addressing_mode(instruction.mode)
+            };
 
             // Call the actual functionality of the Instruction
-            let additional_cycle_operate = (instruction.operate)(self);
+            let additional_cycle_operate = self.run_operation(instruction.mnemonic);
 
             // If both addrmode and operate need another clock cycle, increase the required cycles by 1
             if additional_cycle_addrmode && additional_cycle_operate {
@@ -183,34 +243,138 @@ impl Cpu6502 {
             };
         }
 
+        self.bus_mut().tick();
         self.cycles -= 1;
         self.cycle_count += 1;
     }
 
+    /// Calls the function implementing the given addressing mode.
+    /// Returns true if the addressing mode potentially needs another clock cycle
+    fn run_addressing_mode(&mut self, mode: AddressingMode) -> bool {
+        macro_rules! dispatch {
+            ($($x:ident), *) => {
+                match mode {
+                    $(AddressingMode::$x => self.$x(),)*
+                }
+            }
+        }
+
+        dispatch! {
+            IMP, IMM, ZP0, ZPX, ZPY, ABS, ABX, ABY, IND, IZX, IZY, REL
+        }
+    }
+
+    /// Calls the function implementing the given instruction.
+    /// Returns true if the instruction potentially needs another clock cycle
+    fn run_operation(&mut self, mnemonic: Mnemonic) -> bool {
+        macro_rules! dispatch {
+            ($($x:ident), *) => {
+                match mnemonic {
+                    $(Mnemonic::$x => self.$x(),)*
+                }
+            }
+        }
+
+        dispatch! {
+            ADC, AND, ASL, BCC, BCS, BEQ, BIT, BMI, BNE, BPL, BRK, BVC, BVS, CLC,
+            CLD, CLI, CLV, CMP, CPX, CPY, DEC, DEX, DEY, EOR, INC, INX, INY, JMP,
+            JSR, LDA, LDX, LDY, LSR, NOP, ORA, PHA, PHP, PLA, PLP, ROL, ROR, RTI,
+            RTS, SBC, SEC, SED, SEI, STA, STX, STY, TAX, TAY, TSX, TXA, TXS, TYA,
+            XXX
+        }
+    }
+
+    /// Lets a cycle pass without executing anything, while DMA has halted the cpu.
+    /// The cycle still counts, so halted cycles show up in trace logs like in other emulators
+    pub fn stall(&mut self) {
+        self.cycle_count += 1;
+    }
+
     /// Returns true if the cpu is not currently in the middle of executing an instruction
     pub fn complete(&self) -> bool {
         self.cycles == 0
     }
 
+    /// The amount of cycles left until the current instruction is complete
+    pub fn remaining_cycles(&self) -> u8 {
+        self.cycles
+    }
+
+    /// Clocks through the given amount of the cycles left of the current instruction at once.
+    /// The cpu did all the work of the instruction in its first cycle, so it only has to count them
+    pub fn skip_cycles(&mut self, cycles: u8) {
+        assert!(cycles <= self.cycles, "Only {} cycles are left of the current instruction", self.cycles);
+        for _ in 0..cycles {
+            self.bus_mut().tick();
+        }
+        self.cycles -= cycles;
+        self.cycle_count += cycles as usize;
+    }
+
+    /// Clocks the cpu until the current instruction is complete.
+    /// If the cpu is between instructions, the next instruction is executed as a whole
+    pub fn step(&mut self) {
+        self.clock();
+        while !self.complete() {
+            self.clock();
+        }
+    }
+
+    /// The address the stack pointer points at. The stack always lives in page $01
+    fn stack_address(&self) -> u16 {
+        STACK_POINTER_BASE | self.stkp as u16
+    }
+
     /// Pushes a given byte to the stack
     pub fn push_stack(&mut self, data: u8) {
-        self.write(STACK_POINTER_BASE + self.stkp, data);
-        self.stkp -= 1;
+        self.push_stack_from(data, StackOrigin::Data);
     }
 
-    /// Pops a byte off the stack
+    /// Pushes a byte to the stack and remembers what pushed it, see `Cpu6502::stack_view`.
+    /// Like on the real chip, the stack pointer wraps around within page $01
+    fn push_stack_from(&mut self, data: u8, origin: StackOrigin) {
+        self.write(self.stack_address(), data);
+        self.stack_origins[self.stkp as usize] = origin;
+        self.stkp = self.stkp.wrapping_sub(1);
+    }
+
+    /// Pops a byte off the stack. The stack pointer wraps around within page $01
     pub fn pop_stack(&mut self) -> u8 {
-        self.stkp += 1;
-        self.read(STACK_POINTER_BASE + self.stkp)
+        self.stkp = self.stkp.wrapping_add(1);
+        self.read(self.stack_address())
     }
 
-    // Configure the CPU into a known state
-    pub(crate) fn reset(&mut self) {
+    /// Starts executing at the given address, as if the cpu had just been powered on with that address in the reset vector.
+    /// Test ROMs like nestest are run like this, if there is no screen to show their results on ("automation mode")
+    pub fn start_at(&mut self, pc: u16) {
         self.a = 0;
         self.x = 0;
         self.y = 0;
         self.stkp = 0xFD;
+        self.status = Flags6502::U | Flags6502::I;
+        self.pc = pc;
+
+        // The reset sequence already took its 7 cycles
+        self.cycles = 0;
+        self.cycle_count = 7;
+    }
+
+    /// Switches the cpu on. The registers start out cleared, then the cpu runs the reset sequence
+    pub(crate) fn power_on(&mut self) {
+        self.a = 0;
+        self.x = 0;
+        self.y = 0;
+        self.stkp = 0x00;
         self.status = Flags6502::U;
+        self.cycle_count = 0;
+        self.reset();
+    }
+
+    /// The reset sequence, which runs when the reset button is pressed. It works like an interrupt, except that
+    /// the writes to the stack are suppressed, so only the stack pointer moves. The other registers keep their values
+    pub(crate) fn reset(&mut self) {
+        self.stkp = self.stkp.wrapping_sub(3);
+        self.set_flag(Flags6502::I, true);
 
         // Hardcoded address that contains the address the program counter should be set to, in case of a reset
         self.addr_abs = RESET_PROGRAM_COUNTER;
@@ -223,16 +387,17 @@ impl Cpu6502 {
         self.addr_rel = 0x0000;
         self.fetched = 0x00;
 
-        // A reset takes time
-        self.cycles = 8;
+        // The reset sequence takes 7 cycles. Like with `start_at`, they are already over when the cpu fetches its first instruction
+        self.cycles = 0;
+        self.cycle_count += 7;
     }
 
     /// Interrupt request signal
     fn irq(&mut self) {
         if !self.get_flag(Flags6502::I) {
             // Save the Program counter to the stack
-            self.push_stack(((self.pc >> 8) & 0x00FF) as u8);
-            self.push_stack((self.pc & 0x00FF) as u8);
+            self.push_stack_from((self.pc >> 8) as u8, StackOrigin::InterruptReturn);
+            self.push_stack_from((self.pc & 0x00FF) as u8, StackOrigin::InterruptReturn);
 
             // Set flags accordingly
             self.set_flag(Flags6502::B, false);
@@ -240,7 +405,7 @@ impl Cpu6502 {
             self.set_flag(Flags6502::I, true);
 
             // Save the status register to stack
-            self.push_stack(self.status.bits());
+            self.push_stack_from(self.status.bits(), StackOrigin::InterruptStatus(Interrupt::Irq));
 
             // The value of the new program counter sits at this hardcoded address
             self.addr_abs = IRQ_PROGRAM_COUNTER;
@@ -256,8 +421,8 @@ impl Cpu6502 {
     /// Non-maskable interrupt request signal
     fn nmi(&mut self) {
         // Save the Program counter to the stack
-        self.push_stack((self.pc >> 8) as u8);
-        self.push_stack((self.pc & 0x00FF) as u8);
+        self.push_stack_from((self.pc >> 8) as u8, StackOrigin::InterruptReturn);
+        self.push_stack_from((self.pc & 0x00FF) as u8, StackOrigin::InterruptReturn);
 
         // Set flags accordingly
         self.set_flag(Flags6502::B, false);
@@ -265,7 +430,7 @@ impl Cpu6502 {
         self.set_flag(Flags6502::I, true);
 
         // Save the status register to stack
-        self.push_stack(self.status.bits());
+        self.push_stack_from(self.status.bits(), StackOrigin::InterruptStatus(Interrupt::Nmi));
 
         // The value of the new program counter sits at this hardcoded address
         self.addr_abs = NMI_PROGRAM_COUNTER;
@@ -279,17 +444,14 @@ impl Cpu6502 {
 
     /// Return from an interrupt
     fn rti(&mut self) -> bool {
-        self.stkp += 1;
         // Read status from the stack
-        self.status = Flags6502::from_bits(self.read(STACK_POINTER_BASE + self.stkp)).unwrap();
+        self.status = Flags6502::from_bits(self.pop_stack()).unwrap();
         self.status &= !Flags6502::B;
         self.status &= !Flags6502::U;
 
         // Read the program counter from stack
-        self.stkp += 1;
-        self.pc = self.read(STACK_POINTER_BASE + self.stkp) as u16;
-        self.stkp += 1;
-        self.pc |= (self.read(STACK_POINTER_BASE + self.stkp) as u16) << 8;
+        self.pc = self.pop_stack() as u16;
+        self.pc |= (self.pop_stack() as u16) << 8;
         false
     }
 
@@ -297,7 +459,7 @@ impl Cpu6502 {
     fn fetch(&mut self) -> u8 {
         // If the addressing mode is 'implied', then there is no data to fetch
         // In this case, the fetched data is the data in the accumulator (see the IMP addressing mode)
-        if LOOKUP[self.opcode as usize].addrmode as usize != Self::IMP as usize {
+        if decode(self.opcode).mode != AddressingMode::IMP {
             self.fetched = self.read(self.addr_abs);
         } else {
             self.fetched = self.a;
@@ -305,90 +467,16 @@ impl Cpu6502 {
         self.fetched
     }
 
+    /// Decodes the instruction starting at the given address, without side effects on the bus
+    pub fn decode_instr_at(&self, addr: u16) -> DisassembledInstruction {
+        DisassembledInstruction::decode(addr, |addr| self.peek(addr))
+    }
+
     /// Assembles the instruction starting at the given address
     /// Returns a tuple of the disassembled instruction, plus the address of the next instruction
     pub fn disassemble_instr_at(&self, addr: u16) -> (String, u16) {
-        /// Determines if 2 Functions are the same. Used for instructions
-        fn cmp_fn(f1: fn(&mut Cpu6502) -> bool, f2: fn(&mut Cpu6502) -> bool) -> bool {
-            f1 as usize == f2 as usize
-        }
-
-        /// Used to get an addressing mode's name from its function pointer
-        fn addressing_mode_name(f: fn(&mut Cpu6502) -> bool) -> &'static str {
-            let cmp = |addr_mode: fn(&mut Cpu6502) -> bool| cmp_fn(f, addr_mode);
-
-            macro_rules! gen_if {
-            ( $($x:ident), *) => {
-                    $(
-                        if cmp(Cpu6502::$x) { return stringify!($x)}
-                    )*
-                }
-            }
-
-            gen_if! {
-                IMP, IMM, ZP0, ZPX, ZPY, ABS, ABX, ABY, IND, IZX, IZY, REL
-            }
-
-            "XXX"
-        }
-
-        let mut offset = 0;
-        // The buffer to hold the tokens that make up an instruction string
-        let mut string_instr_tokens: Vec<String> = Vec::new();
-
-        // Get the instruction from the lookup table that is identified by the current byte read
-        let instruction : &Instruction = &LOOKUP[self.read(addr + offset) as usize];
-
-        // A function that determines if the given addressing mode is equal to the addressing mode of the current instruction
-        let mode = |addr_mode: fn(&mut Cpu6502) -> bool| cmp_fn(instruction.addrmode, addr_mode);
-        // Adds the name of the current instruction to the tokens
-        string_instr_tokens.push(instruction.name.clone());
-
-
-        if mode(Cpu6502::IMP) {
-            // BRK is a special case, as it's an implied address mode instruction
-            // but unlike the other such instructions, it's a 2-byte instructions instead of 1
-            if instruction.operate as usize == Self::BRK as usize {
-                offset += 1;
-            }
-
-            // If the addressing mode is implied there is nothing else to do. This if is just here,
-            // so it's clear from reading the code that all addressing modes are handled
-        } else if mode(Cpu6502::IMM) {
-            // For immediate addressing, the additional data is 1 additional byte of data, so
-            // add the data formatted as a hexadecimal number to the tokens
-            offset += 1;
-            string_instr_tokens.push(format!("#${:0>2X}", self.read(addr + offset)));
-        } else if mode(Cpu6502::ZP0)
-            || mode(Cpu6502::ZPX)
-            || mode(Cpu6502::ZPY)
-            || mode(Cpu6502::REL)
-        {
-            // The same as with immediate addressing, but the formatting is a little different
-            offset += 1;
-            string_instr_tokens.push(format!("${:0>4X}", self.read(addr + offset)));
-        } else {
-            // For all other address modes, the supplied data consists of 2 bytes.
-            // Gather them in a vector and convert them to a hexadecimal number
-            let mut address = Vec::new();
-            offset += 1;
-            address.push(self.read(addr + offset));
-            offset += 1;
-            address.push(self.read(addr + offset));
-            address.reverse();
-            string_instr_tokens.push(format!("${:0>4}", hex::encode_upper(address)));
-        }
-
-        offset += 1;
-
-        if addr >= 0xB000 && addr < 0xD000 {
-            //println!("opcode: {:0>2X}, address mode: {}, start addr: {:0>4X}, next addr: {:0>4X}", self.read(addr), addressing_mode_name(instruction.addrmode), addr, addr + offset);
-        }
-
-        // Add the address mode to the tokens
-        string_instr_tokens.push(format!("({})", addressing_mode_name(instruction.addrmode)));
-
-        (string_instr_tokens.join(" "), addr + offset)
+        let instruction = self.decode_instr_at(addr);
+        (instruction.to_string(), instruction.next_address())
     }
 
     pub fn disassemble_next(&self, n: usize) -> Vec<(u16, String)>{
@@ -408,116 +496,28 @@ impl Cpu6502 {
         self.disassemble_next(n).into_iter().map(|(addr, instr)| format!("${:0>4X}: {}", addr, instr)).collect::<Vec<String>>().join("\n")
     }
 
-    pub fn disassemble_range(&self, from: u16, to: u16) -> HashMap<u16, String> {
-        let mut current_addr = from;
-        let mut disassembly = HashMap::new();
-        while current_addr < to {
-            let (instr, next_addr) = self.disassemble_instr_at(current_addr);
-            disassembly.insert(current_addr, instr);
-            current_addr = next_addr;
-        }
-        disassembly
+    /// Decodes all instructions from `from` up to `to`, ordered by address
+    pub fn disassemble_range(&self, from: u16, to: u16) -> Disassembly {
+        disassembler::disassemble_range(from, to, |addr| self.peek(addr))
     }
-}
-
-struct Instruction {
-    pub name: String,
-    pub operate: fn(&mut Cpu6502) -> bool,
-    pub addrmode: fn(&mut Cpu6502) -> bool,
-    pub cycles: u8,
-}
 
-impl Instruction {
-    pub fn new(
-        name: &str,
-        operate: fn(&mut Cpu6502) -> bool,
-        addrmode: fn(&mut Cpu6502) -> bool,
-        cycles: u8,
-    ) -> Self {
-        Instruction {
-            name: String::from(name),
-            operate,
-            addrmode,
-            cycles,
+    /// Follows the code in the given range, starting at the interrupt vectors and the additional entry points.
+    /// See `FlowDisassembler`
+    pub fn analyse_flow(&self, range: RangeInclusive<u16>, entry_points: &[u16]) -> FlowAnalysis {
+        let mut disassembler = FlowDisassembler::new(range, |addr| self.peek(addr)).with_vectors();
+        for &addr in entry_points {
+            disassembler.add_entry_point(addr, None);
         }
+        disassembler.run()
     }
 }
 
+/// Disassembles a program given as raw bytes, one string per instruction
 pub fn disassemble_program(program_bytes: Vec<u8>) -> Vec<String> {
-
-    /// Determines if 2 Functions are the same. Used for instructions
-    fn cmp_fn(f1: fn(&mut Cpu6502) -> bool, f2: fn(&mut Cpu6502) -> bool) -> bool {
-        f1 as usize == f2 as usize
-    }
-
-    fn addressing_mode_name(f: fn(&mut Cpu6502) -> bool) -> &'static str {
-        let cmp = |addr_mode: fn(&mut Cpu6502) -> bool| cmp_fn(f, addr_mode);
-
-        macro_rules! gen_if {
-            ( $($x:ident), *) => {
-                $(
-                    if cmp(Cpu6502::$x) { return stringify!($x)}
-                )*
-            }
-        }
-
-        gen_if! {
-            IMP, IMM, ZP0, ZPX, ZPY, ABS, ABX, ABY, IND, IZX, IZY, REL
-        }
-
-        "XXX"
-    }
-
-    let mut program: Vec<String> = Vec::new();
-
-    let mut i = 0;
-    while i < program_bytes.len() {
-        // The buffer to hold the tokens that make up an instruction string
-        let mut string_instr_tokens: Vec<String> = Vec::new();
-
-        // Get the instruction from the lookup table that is identified by the current byte read
-        let instruction : &Instruction = &LOOKUP[program_bytes[i] as usize];
-        // A function that determines if the given addressing mode is equal to the addressing mode of the current instruction
-        let mode = |addr_mode: fn(&mut Cpu6502) -> bool| cmp_fn(instruction.addrmode, addr_mode);
-        // Adds the name of the current instruction to the tokens
-        string_instr_tokens.push(instruction.name.clone());
-
-
-        if mode(Cpu6502::IMP) {
-            // If the addressing mode is implied there is nothing else to do. This if is just here,
-            // so it's clear from reading the code that all addressing modes are handled
-        } else if mode(Cpu6502::IMM) {
-            // For immediate addressing, the additional data is 1 additional byte of data, so increase i by 1
-            // And add the data formatted as a hexadecimal number to the tokens
-            i += 1;
-            string_instr_tokens.push(format!("#${:0>4}", hex::encode(vec![program_bytes[i]])))
-        } else if mode(Cpu6502::ZP0)
-            || mode(Cpu6502::ZPX)
-            || mode(Cpu6502::ZPY)
-            || mode(Cpu6502::REL)
-        {
-            // The same as with immediate addressing, but the formatting is a little different
-            i += 1;
-            string_instr_tokens.push(format!("${:0>4}", hex::encode(vec![program_bytes[i]])))
-        } else {
-            // For all other address modes, the supplied data consists of 2 bytes.
-            // Gather them in a vector and convert them to a hexadecimal number
-            let mut address = Vec::new();
-            i += 1;
-            address.push(program_bytes[i]);
-            i += 1;
-            address.push(program_bytes[i]);
-            string_instr_tokens.push(format!("${:0>4}", hex::encode(address)));
-        }
-
-        // Add the address mode to the tokens
-        string_instr_tokens.push(format!("({})", addressing_mode_name(instruction.addrmode)));
-
-        program.push(string_instr_tokens.join(" "));
-        i += 1;
-    }
-
-    program
+    disassembler::disassemble_bytes(&program_bytes, 0x0000)
+        .into_iter()
+        .map(|instruction| instruction.to_string())
+        .collect()
 }
 
 #[allow(non_snake_case)]
@@ -525,47 +525,62 @@ pub fn disassemble_program(program_bytes: Vec<u8>) -> Vec<String> {
 mod test {
     use crate::cpu6502::Flags6502;
     use crate::cpu6502::{Cpu6502};
-    use crate::ppu2C02::Ppu2C02;
-    use crate::bus::Bus;
+    use crate::cpu6502::memory::{CpuBus, FlatMemory};
+    use crate::cpu6502::assembler::assemble;
 
     #[test]
     fn init_test() {
-        let cpu = Cpu6502::new();
+        let cpu = Cpu6502::with_bus(FlatMemory::new());
 
         assert_eq!(cpu.status, Flags6502::I , "Initial config incorrect. Only I should be set");
         assert_eq!(cpu.a, 0, "Accumulator != 0");
         assert_eq!(cpu.x, 0, "X Register != 0");
         assert_eq!(cpu.y, 0, "Y Register != 0");
         assert_eq!(cpu.stkp, 0xFD, "Stack Pointer != 0xFD");
+        assert_eq!(cpu.cycle_count, 7, "The reset sequence should have taken 7 cycles");
     }
 
     #[test]
-    fn reset_test() {
-        let mut bus = {
-            let cpu = Cpu6502::new();
-            let ppu = Ppu2C02::new();
-            Bus::new(cpu, ppu)
-        };
-        let bus_borrow = bus.borrow();
-        let mut cpu = bus_borrow.cpu_mut();
+    fn power_on_test() {
+        let mut cpu = Cpu6502::with_bus(FlatMemory::new());
 
         cpu.status = Flags6502::C | Flags6502::V;
         cpu.a = 123;
         cpu.x = 012;
         cpu.y = 111;
 
-        cpu.reset();
+        cpu.power_on();
 
-        assert_eq!(cpu.status, Flags6502::U , "Initial config incorrect. Only U should be set");
+        assert_eq!(cpu.status, Flags6502::U | Flags6502::I, "Initial config incorrect. Only U and I should be set");
         assert_eq!(cpu.a, 0, "Accumulator != 0");
         assert_eq!(cpu.x, 0, "X Register != 0");
         assert_eq!(cpu.y, 0, "Y Register != 0");
         assert_eq!(cpu.stkp, 0xFD, "Stack Pointer != 0xFD");
     }
 
+    #[test]
+    fn reset_test() {
+        let mut memory = FlatMemory::new();
+        memory.load(0xFFFC, &[0x34, 0x12]);
+        let mut cpu = Cpu6502::with_bus(memory);
+
+        cpu.status = Flags6502::C | Flags6502::V | Flags6502::U;
+        cpu.a = 123;
+        cpu.x = 12;
+        cpu.y = 111;
+        cpu.stkp = 0xF0;
+
+        cpu.reset();
+
+        assert_eq!(cpu.status, Flags6502::C | Flags6502::V | Flags6502::U | Flags6502::I, "Only I should be set by a reset");
+        assert_eq!((cpu.a, cpu.x, cpu.y), (123, 12, 111), "A reset should keep the registers");
+        assert_eq!(cpu.stkp, 0xED, "A reset should move the stack pointer by 3");
+        assert_eq!(cpu.pc, 0x1234, "A reset should jump to the reset vector");
+    }
+
     #[test]
     fn flags_test() {
-        let mut cpu = Cpu6502::new();
+        let mut cpu = Cpu6502::with_bus(FlatMemory::new());
         cpu.status = Flags6502::from_bits(0x00).unwrap();
         cpu.set_flag(Flags6502::C, true);
         assert_eq!(cpu.status, Flags6502::C);
@@ -573,4 +588,54 @@ mod test {
         cpu.set_flag(Flags6502::I, true);
         assert_eq!(cpu.status, Flags6502::C | Flags6502::I);
     }
+
+    #[test]
+    fn flat_memory_program_test() {
+        let mut cpu = assemble("
+            *= $8000
+            lda #$05
+            tax
+            lda #$06
+            stx $1111
+            adc $1111
+        ").unwrap().cpu();
+        cpu.bus_mut().load(0xFFFC, &[0x00, 0x80]);
+        cpu.reset();
+        // Use up the cycles of the reset
+        while !cpu.complete() {
+            cpu.clock();
+        }
+
+        for _ in 0..5 {
+            cpu.clock();
+            while !cpu.complete() {
+                cpu.clock();
+            }
+        }
+
+        assert_eq!(cpu.bus_mut().read(0x1111), 0x05, "X register not stored correctly");
+        assert_eq!(cpu.a, 0x0B, "Accumulator value incorrect after add");
+        assert_eq!(cpu.pc, 0x800B, "Program counter incorrect after running the program");
+    }
+
+    #[test]
+    fn disassemble_program_test() {
+        let program = assemble("
+            lda #$05
+            tax
+            stx $C011
+            lda ($20),y
+            brk
+            .byte $01
+        ").unwrap();
+        let program = crate::cpu6502::disassemble_program(program.bytes());
+
+        assert_eq!(program, vec![
+            "LDA #$05 (IMM)",
+            "TAX (IMP)",
+            "STX $C011 (ABS)",
+            "LDA $0020 (IZY)",
+            "BRK (IMP)",
+        ]);
+    }
 }
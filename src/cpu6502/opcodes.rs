@@ -1,13 +1,15 @@
-use crate::cpu6502::{Cpu6502, STACK_POINTER_BASE, IRQ_PROGRAM_COUNTER};
+use crate::cpu6502::{Cpu6502, IRQ_PROGRAM_COUNTER};
+use crate::cpu6502::stack::{Interrupt, StackOrigin};
 use crate::cpu6502::Flags6502;
-use crate::cpu6502::LOOKUP;
+use crate::cpu6502::memory::CpuBus;
+use crate::cpu6502::instruction_set::{decode, AddressingMode};
 use std::num::Wrapping;
 use std::ops::{Add, Sub};
 
 // Opcodes. These return true if they *potentially* need another clock cycle. false otherwise
 // They also set the flags accordingly
 #[allow(non_snake_case, unused)]
-impl Cpu6502 {
+impl<B: CpuBus> Cpu6502<B> {
     /// Addition of the fetched value to the accumulator with carry bit
     /// This instruction can overflow the accumulator register if working with signed numbers and the value overflows.
     /// In that case the following truth table determines whether an overflow happened:
@@ -28,6 +30,12 @@ impl Cpu6502 {
     /// As a result, the formula that fulfills this truth table is V = (A ^ R) & (M ^ R)
     pub fn ADC(&mut self) -> bool {
         self.fetch();
+
+        if self.decimal_mode && self.get_flag(Flags6502::D) {
+            self.adc_decimal();
+            return true;
+        }
+
         // Add the accumulator, the fetched data, and the carry bit (Use Wrapping, to allow overflow)
         let temp: u16 = (Wrapping(self.a as u16)
             + Wrapping(self.fetched as u16)
@@ -74,7 +82,7 @@ impl Cpu6502 {
         if self.is_implied() {
             self.a = (temp & 0x00FF) as u8;
         } else {
-            self.write(self.addr_abs, (temp & 0x00FF) as u8);
+            self.write_modified((temp & 0x00FF) as u8);
         }
 
         false
@@ -160,17 +168,13 @@ impl Cpu6502 {
     pub fn BRK(&mut self) -> bool {
         self.pc += 1;
 
-        self.set_flag(Flags6502::I, true);
         // Store the high and low bytes of the program counter to the stack
-        self.push_stack((self.pc >> 8) as u8);
-        self.push_stack((self.pc & 0x00FF) as u8);
+        self.push_stack_from((self.pc >> 8) as u8, StackOrigin::InterruptReturn);
+        self.push_stack_from((self.pc & 0x00FF) as u8, StackOrigin::InterruptReturn);
 
-        self.set_flag(Flags6502::B, true);
-        self.set_flag(Flags6502::U, true);
-        // Push status register to the stack with the B flag set
-        self.push_stack(self.status.bits());
-        self.set_flag(Flags6502::B, false);
-        self.set_flag(Flags6502::U, false);
+        // Push status register to the stack with the B flag set. Interrupts are only disabled afterwards
+        self.push_stack_from((self.status | Flags6502::B | Flags6502::U).bits(), StackOrigin::InterruptStatus(Interrupt::Brk));
+        self.set_flag(Flags6502::I, true);
 
         self.pc = self.read(IRQ_PROGRAM_COUNTER) as u16
             | ((self.read(IRQ_PROGRAM_COUNTER + 1) as u16) << 8);
@@ -249,7 +253,7 @@ impl Cpu6502 {
         self.fetch();
 
         let value = wrap_sub(self.fetched, 1);
-        self.write(self.addr_abs, value);
+        self.write_modified(value);
 
         self.set_flag(Flags6502::Z, value == 0);
         self.set_flag(Flags6502::N, (value & 0x80) > 0);
@@ -287,7 +291,7 @@ impl Cpu6502 {
         self.fetch();
 
         let value = wrap_add(self.fetched, 1);
-        self.write(self.addr_abs, value);
+        self.write_modified(value);
 
         self.set_flag(Flags6502::Z, value == 0);
         self.set_flag(Flags6502::N, (value & 0x80) > 0);
@@ -317,14 +321,22 @@ impl Cpu6502 {
         false
     }
 
-    /// Jump to memory location *with* saving return address
+    /// Jump to memory location *with* saving return address.
+    /// Unlike other instructions, JSR reads its operand itself (see `Cpu6502::clock`),
+    /// as the cpu pushes the return address in between reading the low and the high byte of the new address
     pub fn JSR(&mut self) -> bool {
-        // Write current program counter to stack
-        self.pc -= 1;
-        self.push_stack((self.pc >> 8) as u8);
-        self.push_stack((self.pc & 0x00FF) as u8);
+        let lo = self.read(self.pc) as u16;
+        self.pc += 1;
+        // The cpu reads from the stack while it is busy storing the low byte internally
+        self.read_stack();
+
+        // Write current program counter to stack. It points at the high byte of the new address
+        self.push_stack_from((self.pc >> 8) as u8, StackOrigin::SubroutineReturn);
+        self.push_stack_from((self.pc & 0x00FF) as u8, StackOrigin::SubroutineReturn);
 
         // Jump to new address
+        let hi = self.read(self.pc) as u16;
+        self.addr_abs = (hi << 8) | lo;
         self.pc = self.addr_abs;
         false
     }
@@ -368,7 +380,7 @@ impl Cpu6502 {
         if self.is_implied() {
             self.a = value;
         } else {
-            self.write(self.addr_abs, value);
+            self.write_modified(value);
         }
 
         false
@@ -398,12 +410,13 @@ impl Cpu6502 {
     /// Push processor status on stack
     pub fn PHP(&mut self) -> bool {
         // For PHP the status register is pushed to the stack along with the B and U flags
-        self.push_stack((self.status | Flags6502::B | Flags6502::U).bits());
+        self.push_stack_from((self.status | Flags6502::B | Flags6502::U).bits(), StackOrigin::Status);
         false
     }
 
     // Pop off the stack into the accumulator
     pub fn PLA(&mut self) -> bool {
+        self.read_stack();
         self.a = self.pop_stack();
         self.set_flag(Flags6502::Z, self.a == 0);
         self.set_flag(Flags6502::N, (self.a & 0x80) > 0);
@@ -412,20 +425,21 @@ impl Cpu6502 {
 
     /// Pull processor status from stack
     pub fn PLP(&mut self) -> bool {
+        self.read_stack();
         self.status = Flags6502::from_bits(self.pop_stack()).unwrap();
         self.set_flag(Flags6502::U, true);
         false
     }
 
-    /// Rotate 1 bit left (Memory or accumulator)
-    /// E.g. 100101 -> 001011
+    /// Rotate 1 bit left through the carry flag (Memory or accumulator)
+    /// E.g. 10010101 with carry set -> 00101011 with carry set
     pub fn ROL(&mut self) -> bool {
         self.fetch();
 
         // Shift the fetched value to the left by 1
         let mut value = ((self.fetched as u16) << 1);
-        // Add a 1 as the least significant bit, if a 1 was "shifted out of the 8-bit bounds"
-        value |= ((value & 0x100) > 0) as u16;
+        // The carry flag is shifted in as the least significant bit
+        value |= self.get_flag(Flags6502::C) as u16;
 
         self.set_flag(Flags6502::C, (value & 0xFF00) > 0);
 
@@ -438,21 +452,21 @@ impl Cpu6502 {
         if self.is_implied() {
             self.a = value;
         } else {
-            self.write(self.addr_abs, value);
+            self.write_modified(value);
         }
 
         false
     }
 
-    /// Rotate 1 bit right (Memory or accumulator)
-    /// E.g. 100101 -> 110010
+    /// Rotate 1 bit right through the carry flag (Memory or accumulator)
+    /// E.g. 10010101 with carry clear -> 01001010 with carry set
     pub fn ROR(&mut self) -> bool {
         self.fetch();
 
         // Shift the fetched value to the right by 1
         let mut value = (self.fetched >> 1);
-        // Add a 1 as the most significant bit, if a 1 was "shifted out"
-        value |= (self.fetched & 1) << 7;
+        // The carry flag is shifted in as the most significant bit
+        value |= (self.get_flag(Flags6502::C) as u8) << 7;
 
         self.set_flag(Flags6502::C, (self.fetched & 1) > 0);
 
@@ -462,7 +476,7 @@ impl Cpu6502 {
         if self.is_implied() {
             self.a = value;
         } else {
-            self.write(self.addr_abs, value);
+            self.write_modified(value);
         }
 
         false
@@ -471,6 +485,7 @@ impl Cpu6502 {
     /// Return from interrupt.
     /// Get the status register and the program counter from stack
     pub fn RTI(&mut self) -> bool {
+        self.read_stack();
         self.status = Flags6502::from_bits(self.pop_stack()).unwrap();
         self.status &= !Flags6502::B;
         self.status &= !Flags6502::U;
@@ -485,9 +500,14 @@ impl Cpu6502 {
     /// Return from Subroutine
     /// Returns to a saved program counter after jumping there (see JSR)
     pub fn RTS(&mut self) -> bool {
+        self.read_stack();
         let lo = self.pop_stack() as u16;
         let hi = self.pop_stack() as u16;
-        self.pc = ((hi << 8) | lo) + 1;
+        self.pc = (hi << 8) | lo;
+
+        // The cpu reads from the saved address, while it increments it to point at the next instruction
+        self.read(self.pc);
+        self.pc = self.pc.wrapping_add(1);
         false
     }
 
@@ -501,6 +521,7 @@ impl Cpu6502 {
 
         // Invert M
         let value = Wrapping((self.fetched as u16) ^ 0x00FF);
+        let (a, carry) = (self.a, self.get_flag(Flags6502::C));
 
         // Add just like in ADC
         let temp: u16 =
@@ -510,10 +531,15 @@ impl Cpu6502 {
         self.set_flag(Flags6502::N, (temp & 0x80) > 0);
         self.set_flag(
             Flags6502::V,
-            ((self.a as u16 ^ temp) & (self.fetched as u16 ^ temp) & 0x0080) > 0,
+            ((self.a as u16 ^ temp) & (value.0 ^ temp) & 0x0080) > 0,
         );
 
         self.a = (temp & 0x00FF) as u8;
+
+        // In decimal mode, the flags are the same as in binary mode. Only the result is different
+        if self.decimal_mode && self.get_flag(Flags6502::D) {
+            self.a = sbc_decimal(a, self.fetched, carry);
+        }
         true
     }
 
@@ -573,7 +599,7 @@ impl Cpu6502 {
 
     /// Transfer Stack Pointer to X register
     pub fn TSX(&mut self) -> bool {
-        self.x = (self.stkp & 0xFF) as u8;
+        self.x = self.stkp;
         self.set_flag(Flags6502::Z, self.x == 0);
         self.set_flag(Flags6502::N, (self.x & 0x80) > 0);
 
@@ -591,7 +617,7 @@ impl Cpu6502 {
 
     /// Transfer the X register to the Stack Pointer register
     pub fn TXS(&mut self) -> bool {
-        self.stkp = self.x as u16;
+        self.stkp = self.x;
         false
     }
 
@@ -609,26 +635,81 @@ impl Cpu6502 {
         false
     }
 
+    /// Adds the fetched data to the accumulator as binary coded decimal numbers, the way the NMOS 6502 does it.
+    /// The zero flag is set from the binary sum, the negative and overflow flags from the sum after only the low digit was adjusted
+    fn adc_decimal(&mut self) {
+        let a = self.a as u16;
+        let m = self.fetched as u16;
+        let carry = self.get_flag(Flags6502::C) as u16;
+
+        let mut lo = (a & 0x0F) + (m & 0x0F) + carry;
+        if lo >= 0x0A {
+            lo = ((lo + 0x06) & 0x0F) + 0x10;
+        }
+        let mut sum = (a & 0xF0) + (m & 0xF0) + lo;
+
+        self.set_flag(Flags6502::Z, (a + m + carry) & 0xFF == 0);
+        self.set_flag(Flags6502::N, sum & 0x80 > 0);
+        self.set_flag(Flags6502::V, (!(a ^ m) & (a ^ sum) & 0x80) > 0);
+
+        if sum >= 0xA0 {
+            sum += 0x60;
+        }
+        self.set_flag(Flags6502::C, sum > 0xFF);
+        self.a = (sum & 0xFF) as u8;
+    }
+
     /// Branch method, because all branches *basically* work the same, just with different branch conditions
     pub fn branch(&mut self) {
         // Uses 1 more cycle for branching
         self.cycles += 1;
 
-        // Calculate jump address
-        let new_addr = self.pc + self.addr_rel;
+        // The cpu reads the next opcode while calculating the jump address
+        self.read(self.pc);
+        let new_addr = self.pc.wrapping_add(self.addr_rel);
 
-        // If the branch requires crossing a page boundary, it requires 1 more cycle
+        // If the branch requires crossing a page boundary, it requires 1 more cycle.
+        // At first, only the low byte of the jump address is used and the cpu reads from that address in the wrong page
         if (new_addr & 0xFF00) != (self.pc & 0xFF00) {
+            self.read((self.pc & 0xFF00) | (new_addr & 0x00FF));
             self.cycles += 1;
         }
 
         self.pc = new_addr;
     }
 
+    /// Writes the result of a read-modify-write instruction back to memory.
+    /// While the cpu is modifying the value, it writes the unmodified value back, so the address is written twice
+    fn write_modified(&mut self, value: u8) {
+        self.write(self.addr_abs, self.fetched);
+        self.write(self.addr_abs, value);
+    }
+
+    /// Reads from the current top of the stack without popping anything.
+    /// Instructions pulling from the stack do this while incrementing the stack pointer
+    fn read_stack(&mut self) {
+        self.read(self.stack_address());
+    }
+
     /// Returns true if the current addressing mode is implied (see Cpu6502::IMP())
     pub fn is_implied(&self) -> bool {
-        LOOKUP[self.opcode as usize].addrmode as usize == Self::IMP as usize
+        decode(self.opcode).mode == AddressingMode::IMP
+    }
+}
+
+/// Subtracts two binary coded decimal numbers the way the NMOS 6502 does it
+fn sbc_decimal(a: u8, m: u8, carry: bool) -> u8 {
+    let (a, m) = (a as i16, m as i16);
+
+    let mut lo = (a & 0x0F) - (m & 0x0F) + carry as i16 - 1;
+    if lo < 0 {
+        lo = ((lo - 0x06) & 0x0F) - 0x10;
+    }
+    let mut result = (a & 0xF0) - (m & 0xF0) + lo;
+    if result < 0 {
+        result -= 0x60;
     }
+    (result & 0xFF) as u8
 }
 
 #[inline(always)]
@@ -644,11 +725,8 @@ fn wrap_sub(a: u8, b: u8) -> u8 {
 #[allow(non_snake_case)]
 #[cfg(test)]
 mod test {
-    use std::cell::RefCell;
-    use std::rc::Rc;
-    use crate::bus::Bus;
     use crate::cpu6502::{Cpu6502, Flags6502, IRQ_PROGRAM_COUNTER};
-    use crate::ppu2C02::Ppu2C02;
+    use crate::cpu6502::memory::FlatMemory;
 
     const START_PC: u16 = 0x0110;
     const START_ADDR_ABS: u16 = 0x0080;
@@ -680,115 +758,148 @@ mod test {
     /// if it's false, the instruction should branch when the flag is clear
     macro_rules! branch_test {
         ($flag:ident, $instr:ident, true) => {
-            let bus = setup();
-            let bus_ref = bus.borrow();
+            let mut cpu = setup();
 
-            bus_ref.cpu_mut().addr_rel = 0x0010;
+            cpu.addr_rel = 0x0010;
 
-            bus_ref.cpu_mut().status = Flags6502::empty();
-            bus_ref.cpu_mut().$instr();
-            assert_eq!(bus_ref.cpu().pc, START_PC, "Branched, despite {} flag being clear", stringify!($flag));
+            cpu.status = Flags6502::empty();
+            cpu.$instr();
+            assert_eq!(cpu.pc, START_PC, "Branched, despite {} flag being clear", stringify!($flag));
 
-            bus_ref.cpu_mut().status = Flags6502::$flag;
-            bus_ref.cpu_mut().$instr();
-            assert_eq!(bus_ref.cpu().pc, START_PC + 0x0010, "Did not branch, despite {} flag being set", stringify!($flag));
+            cpu.status = Flags6502::$flag;
+            cpu.$instr();
+            assert_eq!(cpu.pc, START_PC + 0x0010, "Did not branch, despite {} flag being set", stringify!($flag));
         };
         ($flag:ident, $instr:ident, false) => {
-            let bus = setup();
-            let bus_ref = bus.borrow();
+            let mut cpu = setup();
 
-            bus_ref.cpu_mut().addr_rel = 0x0010;
+            cpu.addr_rel = 0x0010;
 
-            bus_ref.cpu_mut().status = Flags6502::$flag;
-            bus_ref.cpu_mut().$instr();
-            assert_eq!(bus_ref.cpu().pc, START_PC, "Branched, despite {} flag being set", stringify!($flag));
+            cpu.status = Flags6502::$flag;
+            cpu.$instr();
+            assert_eq!(cpu.pc, START_PC, "Branched, despite {} flag being set", stringify!($flag));
 
-            bus_ref.cpu_mut().status = Flags6502::empty();
-            bus_ref.cpu_mut().$instr();
-            assert_eq!(bus_ref.cpu().pc, START_PC + 0x0010, "Did not branch, despite {} flag being clear", stringify!($flag));
+            cpu.status = Flags6502::empty();
+            cpu.$instr();
+            assert_eq!(cpu.pc, START_PC + 0x0010, "Did not branch, despite {} flag being clear", stringify!($flag));
         };
     }
 
     /// Tests whether the instructions that set and clear instructions work
     macro_rules! flag_set_test {
         ($flag:ident, $instr:ident, true) => {
-            let bus = setup();
-            let bus_ref = bus.borrow();
-            bus_ref.cpu_mut().set_flag(Flags6502::$flag, false);
-            bus_ref.cpu_mut().$instr();
-            assert!(bus_ref.cpu().get_flag(Flags6502::$flag), "{} flag clear, but should be set");
+            let mut cpu = setup();
+            cpu.set_flag(Flags6502::$flag, false);
+            cpu.$instr();
+            assert!(cpu.get_flag(Flags6502::$flag), "{} flag clear, but should be set");
         };
         ($flag:ident, $instr:ident, false) => {
-            let bus = setup();
-            let bus_ref = bus.borrow();
-            bus_ref.cpu_mut().set_flag(Flags6502::$flag, true);
-            bus_ref.cpu_mut().$instr();
-            assert!(!bus_ref.cpu().get_flag(Flags6502::$flag), "{} flag set, but should be cleared");
+            let mut cpu = setup();
+            cpu.set_flag(Flags6502::$flag, true);
+            cpu.$instr();
+            assert!(!cpu.get_flag(Flags6502::$flag), "{} flag set, but should be cleared");
         };
     }
 
-    /// Creates a bus for usage in tests
+    /// Creates a cpu connected to flat memory for usage in tests
     /// The PC is initialized to 0x0100 and the addressing mode is immediate
     /// The absolute address to be read by fetch is initialized to 0x0080
-    fn setup() -> Rc<RefCell<Bus>> {
-        let mut bus = {
-            let cpu = Cpu6502::new();
-            let ppu = Ppu2C02::new();
-            Bus::new(cpu, ppu)
-        };
-        {
-            let mut bus_ref = bus.borrow_mut();
-            // So that the addressing mode of the current instruction is "immediate"
-            bus_ref.cpu_mut().opcode = 0x09;
-            bus_ref.cpu_mut().pc = START_PC;
-            bus_ref.cpu_mut().addr_abs = START_ADDR_ABS;
-        }
-        bus
+    fn setup() -> Cpu6502<FlatMemory> {
+        let mut cpu = Cpu6502::with_bus(FlatMemory::new());
+        // So that the addressing mode of the current instruction is "immediate"
+        cpu.opcode = 0x09;
+        cpu.pc = START_PC;
+        cpu.addr_abs = START_ADDR_ABS;
+        cpu
     }
 
     #[test]
     fn branch_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
-        bus_ref.cpu_mut().addr_rel = 0x0010;
+        let mut cpu = setup();
+        cpu.addr_rel = 0x0010;
 
-        bus_ref.cpu_mut().branch();
+        cpu.branch();
 
-        assert_eq!(bus_ref.cpu().pc, START_PC + 0x0010, "Branched to wrong address");
+        assert_eq!(cpu.pc, START_PC + 0x0010, "Branched to wrong address");
     }
 
     #[test]
     fn ADC_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
+        let mut cpu = setup();
 
-        bus_ref.cpu_write(START_ADDR_ABS, 10);
-        bus_ref.cpu_mut().a = 30;
+        cpu.write(START_ADDR_ABS, 10);
+        cpu.a = 30;
 
-        bus_ref.cpu_mut().ADC();
+        cpu.ADC();
 
-        let status = bus_ref.cpu().status;
+        let status = cpu.status;
 
-        assert_eq!(bus_ref.cpu().a, 40, "Accumulator value incorrect after add");
+        assert_eq!(cpu.a, 40, "Accumulator value incorrect after add");
         check_flag!(status, Z, false);
         check_flag!(status, C, false);
         check_flag!(status, V, false);
         check_flag!(status, N, false);
     }
 
+    #[test]
+    fn ADC_decimal_test() {
+        let mut cpu = setup();
+        cpu.set_flag(Flags6502::D, true);
+        cpu.write(START_ADDR_ABS, 0x27);
+        cpu.a = 0x15;
+
+        // The NES has no decimal mode, so this is a binary add
+        cpu.ADC();
+        assert_eq!(cpu.a, 0x3C, "Decimal flag should be ignored by default");
+
+        cpu.set_decimal_mode(true);
+        cpu.set_flag(Flags6502::C, false);
+        cpu.a = 0x15;
+        cpu.ADC();
+        assert_eq!(cpu.a, 0x42, "Decimal add incorrect");
+        let status = cpu.status;
+        check_flag!(status, C, false);
+
+        cpu.write(START_ADDR_ABS, 0x60);
+        cpu.ADC();
+        assert_eq!(cpu.a, 0x02, "Decimal add with carry out incorrect");
+        let status = cpu.status;
+        check_flag!(status, C, true);
+    }
+
+    #[test]
+    fn SBC_decimal_test() {
+        let mut cpu = setup();
+        cpu.set_decimal_mode(true);
+        cpu.set_flag(Flags6502::D, true);
+        cpu.set_flag(Flags6502::C, true);
+        cpu.write(START_ADDR_ABS, 0x15);
+        cpu.a = 0x42;
+
+        cpu.SBC();
+        assert_eq!(cpu.a, 0x27, "Decimal subtract incorrect");
+        let status = cpu.status;
+        check_flag!(status, C, true);
+
+        cpu.write(START_ADDR_ABS, 0x30);
+        cpu.SBC();
+        assert_eq!(cpu.a, 0x97, "Decimal subtract with borrow incorrect");
+        let status = cpu.status;
+        check_flag!(status, C, false);
+    }
+
     #[test]
     fn ADC_carry_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
+        let mut cpu = setup();
 
-        bus_ref.cpu_write(START_ADDR_ABS, 1);
-        bus_ref.cpu_mut().a = 0xFF;
+        cpu.write(START_ADDR_ABS, 1);
+        cpu.a = 0xFF;
 
-        bus_ref.cpu_mut().ADC();
+        cpu.ADC();
 
-        let status = bus_ref.cpu().status;
+        let status = cpu.status;
 
-        assert_eq!(bus_ref.cpu().a, 0, "Accumulator value incorrect after add");
+        assert_eq!(cpu.a, 0, "Accumulator value incorrect after add");
 
         check_flag!(status, Z, true);
         check_flag!(status, C, true);
@@ -798,17 +909,16 @@ mod test {
 
     #[test]
     fn ADC_negative_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
+        let mut cpu = setup();
 
-        bus_ref.cpu_write(START_ADDR_ABS, 1);
-        bus_ref.cpu_mut().a = 0x7F;
+        cpu.write(START_ADDR_ABS, 1);
+        cpu.a = 0x7F;
 
-        bus_ref.cpu_mut().ADC();
+        cpu.ADC();
 
-        let status = bus_ref.cpu().status;
+        let status = cpu.status;
 
-        assert_eq!(bus_ref.cpu().a, 0x80, "Accumulator value incorrect after add");
+        assert_eq!(cpu.a, 0x80, "Accumulator value incorrect after add");
 
         check_flag!(status, Z, false);
         check_flag!(status, C, false);
@@ -818,69 +928,65 @@ mod test {
 
     #[test]
     fn AND_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
+        let mut cpu = setup();
 
-        bus_ref.cpu_write(START_ADDR_ABS, 1);
-        bus_ref.cpu_mut().a = 0x7F;
+        cpu.write(START_ADDR_ABS, 1);
+        cpu.a = 0x7F;
 
-        bus_ref.cpu_mut().AND();
+        cpu.AND();
 
-        let status = bus_ref.cpu().status;
+        let status = cpu.status;
 
-        assert_eq!(bus_ref.cpu().a, 0x01, "Accumulator value incorrect after and");
+        assert_eq!(cpu.a, 0x01, "Accumulator value incorrect after and");
         check_flag!(status, Z, false);
         check_flag!(status, N, false);
     }
 
     #[test]
     fn AND_zero_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
+        let mut cpu = setup();
 
-        bus_ref.cpu_write(START_ADDR_ABS, 0x80);
-        bus_ref.cpu_mut().a = 0x7F;
+        cpu.write(START_ADDR_ABS, 0x80);
+        cpu.a = 0x7F;
 
-        bus_ref.cpu_mut().AND();
+        cpu.AND();
 
-        let status = bus_ref.cpu().status;
+        let status = cpu.status;
 
-        assert_eq!(bus_ref.cpu().a, 0x00, "Accumulator value incorrect after and");
+        assert_eq!(cpu.a, 0x00, "Accumulator value incorrect after and");
         check_flag!(status, Z, true);
         check_flag!(status, N, false);
     }
 
     #[test]
     fn AND_negative_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
+        let mut cpu = setup();
 
-        bus_ref.cpu_write(START_ADDR_ABS, 0x81);
-        bus_ref.cpu_mut().a = 0xF0;
+        cpu.write(START_ADDR_ABS, 0x81);
+        cpu.a = 0xF0;
 
-        bus_ref.cpu_mut().AND();
+        cpu.AND();
 
-        let status = bus_ref.cpu().status;
+        let status = cpu.status;
 
-        assert_eq!(bus_ref.cpu().a, 0x80, "Accumulator value incorrect after and");
+        assert_eq!(cpu.a, 0x80, "Accumulator value incorrect after and");
         check_flag!(status, Z, false);
         check_flag!(status, N, true);
     }
 
     #[test]
     fn ASL_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
+        let mut cpu = setup();
 
-        bus_ref.cpu_write(START_ADDR_ABS, 0x3F);
-        bus_ref.cpu_mut().a = 0x3F;
+        cpu.write(START_ADDR_ABS, 0x3F);
+        cpu.a = 0x3F;
 
-        bus_ref.cpu_mut().ASL();
+        cpu.ASL();
 
-        let status = bus_ref.cpu().status;
+        let status = cpu.status;
 
-        assert_eq!(bus_ref.cpu().a, 0x3F, "Accumulator modified, despite addressing mode not implied");
-        assert_eq!(bus_ref.cpu_read(START_ADDR_ABS, false), 0x7E, "Read value incorrect after left shift");
+        assert_eq!(cpu.a, 0x3F, "Accumulator modified, despite addressing mode not implied");
+        assert_eq!(cpu.read(START_ADDR_ABS), 0x7E, "Read value incorrect after left shift");
         check_flag!(status, C, false);
         check_flag!(status, Z, false);
         check_flag!(status, N, false);
@@ -888,19 +994,18 @@ mod test {
 
     #[test]
     fn ASL_immediate_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
+        let mut cpu = setup();
 
-        bus_ref.cpu_mut().a = 0xFF;
+        cpu.a = 0xFF;
 
         // instruction with implied addressing mode
-        bus_ref.cpu_mut().opcode = 0x00;
+        cpu.opcode = 0x00;
 
-        bus_ref.cpu_mut().ASL();
+        cpu.ASL();
 
-        let status = bus_ref.cpu().status;
+        let status = cpu.status;
 
-        assert_eq!(bus_ref.cpu().a, 0xFE, "Accumulator value incorrect after left shift");
+        assert_eq!(cpu.a, 0xFE, "Accumulator value incorrect after left shift");
         check_flag!(status, C, true);
         check_flag!(status, Z, false);
         check_flag!(status, N, true);
@@ -908,19 +1013,18 @@ mod test {
 
     #[test]
     fn ASL_zero_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
+        let mut cpu = setup();
 
-        bus_ref.cpu_mut().a = 0x80;
+        cpu.a = 0x80;
 
         // instruction with implied addressing mode
-        bus_ref.cpu_mut().opcode = 0x00;
+        cpu.opcode = 0x00;
 
-        bus_ref.cpu_mut().ASL();
+        cpu.ASL();
 
-        let status = bus_ref.cpu().status;
+        let status = cpu.status;
 
-        assert_eq!(bus_ref.cpu().a, 0x00, "Accumulator value incorrect after left shift");
+        assert_eq!(cpu.a, 0x00, "Accumulator value incorrect after left shift");
         check_flag!(status, C, true);
         check_flag!(status, Z, true);
         check_flag!(status, N, false);
@@ -968,44 +1072,42 @@ mod test {
 
     #[test]
     fn BRK_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
-        let mut cpu_ref = bus_ref.cpu_mut();
-        cpu_ref.status = Flags6502::empty();
+        let mut cpu = setup();
+        cpu.status = Flags6502::empty();
 
-        cpu_ref.BRK();
+        cpu.BRK();
 
-        assert_eq!(cpu_ref.pc, 0x0000, "New program counter incorrectly read");
+        assert_eq!(cpu.pc, 0x0000, "New program counter incorrectly read");
 
-        let stored_status = Flags6502::from_bits(cpu_ref.pop_stack()).unwrap();
-        check_flag!(stored_status, I, true);
+        // Interrupts are only disabled after the status was pushed
+        let stored_status = Flags6502::from_bits(cpu.pop_stack()).unwrap();
+        check_flag!(stored_status, I, false);
         check_flag!(stored_status, B, true);
         check_flag!(stored_status, U, true);
 
         let old_pc = {
-            let lo = cpu_ref.pop_stack();
-            let hi = cpu_ref.pop_stack();
+            let lo = cpu.pop_stack();
+            let hi = cpu.pop_stack();
             (lo as u16) | ((hi as u16) << 8)
         };
 
         assert_eq!(old_pc, START_PC + 1, "Old program counter not read/saved correctly");
 
-        let status = cpu_ref.status;
+        let status = cpu.status;
         check_flag!(status, I, true);
         check_flag!(status, B, false);
     }
 
     #[test]
     fn BIT_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
+        let mut cpu = setup();
 
-        bus_ref.cpu_write(START_ADDR_ABS, 0xF0);
-        bus_ref.cpu_mut().a = 0x0F;
+        cpu.write(START_ADDR_ABS, 0xF0);
+        cpu.a = 0x0F;
 
-        bus_ref.cpu_mut().BIT();
+        cpu.BIT();
 
-        let status = bus_ref.cpu().status;
+        let status = cpu.status;
 
         check_flag!(status, Z, true);
         check_flag!(status, V, true);
@@ -1034,15 +1136,14 @@ mod test {
 
     #[test]
     fn CMP_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
+        let mut cpu = setup();
 
-        bus_ref.cpu_write(START_ADDR_ABS, 10);
-        bus_ref.cpu_mut().a = 10;
+        cpu.write(START_ADDR_ABS, 10);
+        cpu.a = 10;
 
-        bus_ref.cpu_mut().CMP();
+        cpu.CMP();
 
-        let status = bus_ref.cpu().status;
+        let status = cpu.status;
 
         check_flag!(status, Z, true);
         check_flag!(status, C, true);
@@ -1051,15 +1152,14 @@ mod test {
 
     #[test]
     fn CMP_negative_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
+        let mut cpu = setup();
 
-        bus_ref.cpu_write(START_ADDR_ABS, 30);
-        bus_ref.cpu_mut().a = 10;
+        cpu.write(START_ADDR_ABS, 30);
+        cpu.a = 10;
 
-        bus_ref.cpu_mut().CMP();
+        cpu.CMP();
 
-        let status = bus_ref.cpu().status;
+        let status = cpu.status;
 
         check_flag!(status, Z, false);
         check_flag!(status, C, false);
@@ -1068,15 +1168,14 @@ mod test {
 
     #[test]
     fn CPX_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
+        let mut cpu = setup();
 
-        bus_ref.cpu_write(START_ADDR_ABS, 10);
-        bus_ref.cpu_mut().x = 10;
+        cpu.write(START_ADDR_ABS, 10);
+        cpu.x = 10;
 
-        bus_ref.cpu_mut().CPX();
+        cpu.CPX();
 
-        let status = bus_ref.cpu().status;
+        let status = cpu.status;
 
         check_flag!(status, Z, true);
         check_flag!(status, C, true);
@@ -1085,15 +1184,14 @@ mod test {
 
     #[test]
     fn CPX_negative_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
+        let mut cpu = setup();
 
-        bus_ref.cpu_write(START_ADDR_ABS, 30);
-        bus_ref.cpu_mut().x = 10;
+        cpu.write(START_ADDR_ABS, 30);
+        cpu.x = 10;
 
-        bus_ref.cpu_mut().CPX();
+        cpu.CPX();
 
-        let status = bus_ref.cpu().status;
+        let status = cpu.status;
 
         check_flag!(status, Z, false);
         check_flag!(status, C, false);
@@ -1102,15 +1200,14 @@ mod test {
 
     #[test]
     fn CPY_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
+        let mut cpu = setup();
 
-        bus_ref.cpu_write(START_ADDR_ABS, 10);
-        bus_ref.cpu_mut().y = 10;
+        cpu.write(START_ADDR_ABS, 10);
+        cpu.y = 10;
 
-        bus_ref.cpu_mut().CPY();
+        cpu.CPY();
 
-        let status = bus_ref.cpu().status;
+        let status = cpu.status;
 
         check_flag!(status, Z, true);
         check_flag!(status, C, true);
@@ -1119,15 +1216,14 @@ mod test {
 
     #[test]
     fn CPY_negative_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
+        let mut cpu = setup();
 
-        bus_ref.cpu_write(START_ADDR_ABS, 30);
-        bus_ref.cpu_mut().y = 10;
+        cpu.write(START_ADDR_ABS, 30);
+        cpu.y = 10;
 
-        bus_ref.cpu_mut().CPY();
+        cpu.CPY();
 
-        let status = bus_ref.cpu().status;
+        let status = cpu.status;
 
         check_flag!(status, Z, false);
         check_flag!(status, C, false);
@@ -1136,362 +1232,341 @@ mod test {
 
     #[test]
     fn DEC_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
+        let mut cpu = setup();
 
-        bus_ref.cpu_write(START_ADDR_ABS, 1);
+        cpu.write(START_ADDR_ABS, 1);
 
-        bus_ref.cpu_mut().DEC();
+        cpu.DEC();
 
-        let status = bus_ref.cpu().status;
+        let status = cpu.status;
 
-        assert_eq!(bus_ref.cpu_read(START_ADDR_ABS, false), 0, "Memory value not decremented correctly");
+        assert_eq!(cpu.read(START_ADDR_ABS), 0, "Memory value not decremented correctly");
         check_flag!(status, Z, true);
         check_flag!(status, N, false);
     }
 
     #[test]
     fn DEC_negative_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
+        let mut cpu = setup();
 
-        bus_ref.cpu_write(START_ADDR_ABS, 0);
+        cpu.write(START_ADDR_ABS, 0);
 
-        bus_ref.cpu_mut().DEC();
+        cpu.DEC();
 
-        let status = bus_ref.cpu().status;
+        let status = cpu.status;
 
-        assert_eq!(bus_ref.cpu_read(START_ADDR_ABS, false), 0xFF, "Memory value not decremented correctly");
+        assert_eq!(cpu.read(START_ADDR_ABS), 0xFF, "Memory value not decremented correctly");
         check_flag!(status, Z, false);
         check_flag!(status, N, true);
     }
 
     #[test]
     fn DEX_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
+        let mut cpu = setup();
 
-        bus_ref.cpu_mut().x = 1;
+        cpu.x = 1;
 
-        bus_ref.cpu_mut().DEX();
+        cpu.DEX();
 
-        let status = bus_ref.cpu().status;
+        let status = cpu.status;
 
-        assert_eq!(bus_ref.cpu().x, 0, "x register not decremented correctly");
+        assert_eq!(cpu.x, 0, "x register not decremented correctly");
         check_flag!(status, Z, true);
         check_flag!(status, N, false);
     }
 
     #[test]
     fn DEX_negative_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
+        let mut cpu = setup();
 
-        bus_ref.cpu_mut().x = 0;
+        cpu.x = 0;
 
-        bus_ref.cpu_mut().DEX();
+        cpu.DEX();
 
-        let status = bus_ref.cpu().status;
+        let status = cpu.status;
 
-        assert_eq!(bus_ref.cpu().x, 255, "x register not decremented correctly");
+        assert_eq!(cpu.x, 255, "x register not decremented correctly");
         check_flag!(status, Z, false);
         check_flag!(status, N, true);
     }
 
     #[test]
     fn DEY_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
+        let mut cpu = setup();
 
-        bus_ref.cpu_mut().y = 1;
+        cpu.y = 1;
 
-        bus_ref.cpu_mut().DEY();
+        cpu.DEY();
 
-        let status = bus_ref.cpu().status;
+        let status = cpu.status;
 
-        assert_eq!(bus_ref.cpu().y, 0, "y register not decremented correctly");
+        assert_eq!(cpu.y, 0, "y register not decremented correctly");
         check_flag!(status, Z, true);
         check_flag!(status, N, false);
     }
 
     #[test]
     fn DEY_negative_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
+        let mut cpu = setup();
 
-        bus_ref.cpu_mut().y = 0;
+        cpu.y = 0;
 
-        bus_ref.cpu_mut().DEY();
+        cpu.DEY();
 
-        let status = bus_ref.cpu().status;
+        let status = cpu.status;
 
-        assert_eq!(bus_ref.cpu().y, 255, "y register not decremented correctly");
+        assert_eq!(cpu.y, 255, "y register not decremented correctly");
         check_flag!(status, Z, false);
         check_flag!(status, N, true);
     }
 
     #[test]
     fn EOR_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
-        let mut cpu_ref = bus_ref.cpu_mut();
+        let mut cpu = setup();
 
-        bus_ref.cpu_write(START_ADDR_ABS, 0xF0);
-        cpu_ref.a = 0x0F;
+        cpu.write(START_ADDR_ABS, 0xF0);
+        cpu.a = 0x0F;
 
-        cpu_ref.EOR();
+        cpu.EOR();
 
-        let status = cpu_ref.status;
+        let status = cpu.status;
 
-        assert_eq!(cpu_ref.a, 0xFF, "Accumulator value incorrect after XOR");
+        assert_eq!(cpu.a, 0xFF, "Accumulator value incorrect after XOR");
         check_flag!(status, Z, false);
-        check_flag!(status, N, true)
+        check_flag!(status, N, true);
     }
 
     #[test]
     fn EOR_zero_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
-        let mut cpu_ref = bus_ref.cpu_mut();
+        let mut cpu = setup();
 
-        bus_ref.cpu_write(START_ADDR_ABS, 0x0F);
-        cpu_ref.a = 0x0F;
+        cpu.write(START_ADDR_ABS, 0x0F);
+        cpu.a = 0x0F;
 
-        cpu_ref.EOR();
+        cpu.EOR();
 
-        let status = cpu_ref.status;
+        let status = cpu.status;
 
-        assert_eq!(cpu_ref.a, 0x00, "Accumulator value incorrect after XOR");
+        assert_eq!(cpu.a, 0x00, "Accumulator value incorrect after XOR");
         check_flag!(status, Z, true);
-        check_flag!(status, N, false)
+        check_flag!(status, N, false);
     }
 
     #[test]
     fn INC_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
+        let mut cpu = setup();
 
-        bus_ref.cpu_write(START_ADDR_ABS, 255);
+        cpu.write(START_ADDR_ABS, 255);
 
-        bus_ref.cpu_mut().INC();
+        cpu.INC();
 
-        let status = bus_ref.cpu().status;
+        let status = cpu.status;
 
-        assert_eq!(bus_ref.cpu_read(START_ADDR_ABS, false), 0, "Memory value not incremented correctly");
+        assert_eq!(cpu.read(START_ADDR_ABS), 0, "Memory value not incremented correctly");
         check_flag!(status, Z, true);
         check_flag!(status, N, false);
     }
 
     #[test]
     fn INC_negative_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
+        let mut cpu = setup();
 
-        bus_ref.cpu_write(START_ADDR_ABS, 0x7F);
+        cpu.write(START_ADDR_ABS, 0x7F);
 
-        bus_ref.cpu_mut().INC();
+        cpu.INC();
 
-        let status = bus_ref.cpu().status;
+        let status = cpu.status;
 
-        assert_eq!(bus_ref.cpu_read(START_ADDR_ABS, false), 0x80, "Memory value not incremented correctly");
+        assert_eq!(cpu.read(START_ADDR_ABS), 0x80, "Memory value not incremented correctly");
         check_flag!(status, Z, false);
         check_flag!(status, N, true);
     }
 
     #[test]
     fn INX_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
+        let mut cpu = setup();
 
-        bus_ref.cpu_mut().x = 255;
+        cpu.x = 255;
 
-        bus_ref.cpu_mut().INX();
+        cpu.INX();
 
-        let status = bus_ref.cpu().status;
+        let status = cpu.status;
 
-        assert_eq!(bus_ref.cpu().x, 0, "x register not incremented correctly");
+        assert_eq!(cpu.x, 0, "x register not incremented correctly");
         check_flag!(status, Z, true);
         check_flag!(status, N, false);
     }
 
     #[test]
     fn INX_negative_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
+        let mut cpu = setup();
 
-        bus_ref.cpu_mut().x = 0x7F;
+        cpu.x = 0x7F;
 
-        bus_ref.cpu_mut().INX();
+        cpu.INX();
 
-        let status = bus_ref.cpu().status;
+        let status = cpu.status;
 
-        assert_eq!(bus_ref.cpu().x, 0x80, "x register not incremented correctly");
+        assert_eq!(cpu.x, 0x80, "x register not incremented correctly");
         check_flag!(status, Z, false);
         check_flag!(status, N, true);
     }
 
     #[test]
     fn INY_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
+        let mut cpu = setup();
 
-        bus_ref.cpu_mut().y = 255;
+        cpu.y = 255;
 
-        bus_ref.cpu_mut().INY();
+        cpu.INY();
 
-        let status = bus_ref.cpu().status;
+        let status = cpu.status;
 
-        assert_eq!(bus_ref.cpu().y, 0, "y register not incremented correctly");
+        assert_eq!(cpu.y, 0, "y register not incremented correctly");
         check_flag!(status, Z, true);
         check_flag!(status, N, false);
     }
 
     #[test]
     fn INY_negative_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
+        let mut cpu = setup();
 
-        bus_ref.cpu_mut().y = 0x7F;
+        cpu.y = 0x7F;
 
-        bus_ref.cpu_mut().INY();
+        cpu.INY();
 
-        let status = bus_ref.cpu().status;
+        let status = cpu.status;
 
-        assert_eq!(bus_ref.cpu().y, 0x80, "y register not incremented correctly");
+        assert_eq!(cpu.y, 0x80, "y register not incremented correctly");
         check_flag!(status, Z, false);
         check_flag!(status, N, true);
     }
 
     #[test]
     fn JMP_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
+        let mut cpu = setup();
 
-        bus_ref.cpu_mut().JMP();
-        assert_eq!(bus_ref.cpu().pc, START_ADDR_ABS, "Jump to incorrect address");
+        cpu.JMP();
+        assert_eq!(cpu.pc, START_ADDR_ABS, "Jump to incorrect address");
     }
 
     #[test]
     fn JSR_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
+        let mut cpu = setup();
+        // JSR reads the address itself
+        cpu.write(START_PC, (START_ADDR_ABS & 0xFF) as u8);
+        cpu.write(START_PC + 1, (START_ADDR_ABS >> 8) as u8);
 
-        bus_ref.cpu_mut().JSR();
-        assert_eq!(bus_ref.cpu().pc, START_ADDR_ABS, "Jump to incorrect address");
+        cpu.JSR();
+        assert_eq!(cpu.pc, START_ADDR_ABS, "Jump to incorrect address");
 
         let old_addr = {
-            let lo = bus_ref.cpu_mut().pop_stack();
-            let hi = bus_ref.cpu_mut().pop_stack();
+            let lo = cpu.pop_stack();
+            let hi = cpu.pop_stack();
             (lo as u16) | ((hi as u16) << 8)
         };
 
-        assert_eq!(old_addr, START_PC - 1, "Old address saved/read incorrectly");
+        // The saved address points at the last byte of the JSR instruction
+        assert_eq!(old_addr, START_PC + 1, "Old address saved/read incorrectly");
     }
 
     #[test]
     fn LDA_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
+        let mut cpu = setup();
 
-        bus_ref.cpu_write(START_ADDR_ABS, 0x80);
-        bus_ref.cpu_mut().LDA();
+        cpu.write(START_ADDR_ABS, 0x80);
+        cpu.LDA();
 
-        let status = bus_ref.cpu().status;
+        let status = cpu.status;
 
-        assert_eq!(bus_ref.cpu().a, 0x80, "data not loaded correctly");
+        assert_eq!(cpu.a, 0x80, "data not loaded correctly");
         check_flag!(status, Z, false);
         check_flag!(status, N, true);
     }
 
     #[test]
     fn LDA_zero_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
+        let mut cpu = setup();
 
-        bus_ref.cpu_write(START_ADDR_ABS, 0x00);
-        bus_ref.cpu_mut().LDA();
+        cpu.write(START_ADDR_ABS, 0x00);
+        cpu.LDA();
 
-        let status = bus_ref.cpu().status;
+        let status = cpu.status;
 
-        assert_eq!(bus_ref.cpu().a, 0x00, "data not loaded correctly");
+        assert_eq!(cpu.a, 0x00, "data not loaded correctly");
         check_flag!(status, Z, true);
         check_flag!(status, N, false);
     }
 
     #[test]
     fn LDX_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
+        let mut cpu = setup();
 
-        bus_ref.cpu_write(START_ADDR_ABS, 0x80);
-        bus_ref.cpu_mut().LDX();
+        cpu.write(START_ADDR_ABS, 0x80);
+        cpu.LDX();
 
-        let status = bus_ref.cpu().status;
+        let status = cpu.status;
 
-        assert_eq!(bus_ref.cpu().x, 0x80, "data not loaded correctly");
+        assert_eq!(cpu.x, 0x80, "data not loaded correctly");
         check_flag!(status, Z, false);
         check_flag!(status, N, true);
     }
 
     #[test]
     fn LDX_zero_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
+        let mut cpu = setup();
 
-        bus_ref.cpu_write(START_ADDR_ABS, 0x00);
-        bus_ref.cpu_mut().LDX();
+        cpu.write(START_ADDR_ABS, 0x00);
+        cpu.LDX();
 
-        let status = bus_ref.cpu().status;
+        let status = cpu.status;
 
-        assert_eq!(bus_ref.cpu().x, 0x00, "data not loaded correctly");
+        assert_eq!(cpu.x, 0x00, "data not loaded correctly");
         check_flag!(status, Z, true);
         check_flag!(status, N, false);
     }
 
     #[test]
     fn LDY_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
+        let mut cpu = setup();
 
-        bus_ref.cpu_write(START_ADDR_ABS, 0x80);
-        bus_ref.cpu_mut().LDY();
+        cpu.write(START_ADDR_ABS, 0x80);
+        cpu.LDY();
 
-        let status = bus_ref.cpu().status;
+        let status = cpu.status;
 
-        assert_eq!(bus_ref.cpu().y, 0x80, "data not loaded correctly");
+        assert_eq!(cpu.y, 0x80, "data not loaded correctly");
         check_flag!(status, Z, false);
         check_flag!(status, N, true);
     }
 
     #[test]
     fn LDY_zero_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
+        let mut cpu = setup();
 
-        bus_ref.cpu_write(START_ADDR_ABS, 0x00);
-        bus_ref.cpu_mut().LDY();
+        cpu.write(START_ADDR_ABS, 0x00);
+        cpu.LDY();
 
-        let status = bus_ref.cpu().status;
+        let status = cpu.status;
 
-        assert_eq!(bus_ref.cpu().y, 0x00, "data not loaded correctly");
+        assert_eq!(cpu.y, 0x00, "data not loaded correctly");
         check_flag!(status, Z, true);
         check_flag!(status, N, false);
     }
 
     #[test]
     fn LSR_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
+        let mut cpu = setup();
 
-        bus_ref.cpu_write(START_ADDR_ABS, 0x01);
-        bus_ref.cpu_mut().a = 0x01;
+        cpu.write(START_ADDR_ABS, 0x01);
+        cpu.a = 0x01;
 
-        bus_ref.cpu_mut().LSR();
+        cpu.LSR();
 
-        let status = bus_ref.cpu().status;
+        let status = cpu.status;
 
-        assert_eq!(bus_ref.cpu().a, 0x01, "Accumulator modified, despite addressing mode not implied");
-        assert_eq!(bus_ref.cpu_read(START_ADDR_ABS, false), 0x00, "Read value incorrect after right shift");
+        assert_eq!(cpu.a, 0x01, "Accumulator modified, despite addressing mode not implied");
+        assert_eq!(cpu.read(START_ADDR_ABS), 0x00, "Read value incorrect after right shift");
         check_flag!(status, C, true);
         check_flag!(status, Z, true);
         check_flag!(status, N, false);
@@ -1499,19 +1574,18 @@ mod test {
 
     #[test]
     fn LSR_immediate_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
+        let mut cpu = setup();
 
-        bus_ref.cpu_mut().a = 0xFE;
+        cpu.a = 0xFE;
 
         // instruction with implied addressing mode
-        bus_ref.cpu_mut().opcode = 0x00;
+        cpu.opcode = 0x00;
 
-        bus_ref.cpu_mut().LSR();
+        cpu.LSR();
 
-        let status = bus_ref.cpu().status;
+        let status = cpu.status;
 
-        assert_eq!(bus_ref.cpu().a, 0x7F, "Accumulator value incorrect after right shift");
+        assert_eq!(cpu.a, 0x7F, "Accumulator value incorrect after right shift");
         check_flag!(status, C, false);
         check_flag!(status, Z, false);
         check_flag!(status, N, false);
@@ -1519,127 +1593,126 @@ mod test {
 
     #[test]
     fn NOP_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
+        let mut cpu = setup();
 
-        bus_ref.cpu_mut().NOP();
+        cpu.NOP();
         // huh
     }
 
     #[test]
     fn ORA_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
-        let mut cpu_ref = bus_ref.cpu_mut();
+        let mut cpu = setup();
 
-        bus_ref.cpu_write(START_ADDR_ABS, 0xF0);
-        cpu_ref.a = 0x0F;
+        cpu.write(START_ADDR_ABS, 0xF0);
+        cpu.a = 0x0F;
 
-        cpu_ref.ORA();
+        cpu.ORA();
 
-        let status = cpu_ref.status;
+        let status = cpu.status;
 
-        assert_eq!(cpu_ref.a, 0xFF, "Accumulator value incorrect after OR");
+        assert_eq!(cpu.a, 0xFF, "Accumulator value incorrect after OR");
         check_flag!(status, Z, false);
-        check_flag!(status, N, true)
+        check_flag!(status, N, true);
     }
 
     #[test]
     fn ORA_zero_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
-        let mut cpu_ref = bus_ref.cpu_mut();
+        let mut cpu = setup();
 
-        bus_ref.cpu_write(START_ADDR_ABS, 0x00);
-        cpu_ref.a = 0x00;
+        cpu.write(START_ADDR_ABS, 0x00);
+        cpu.a = 0x00;
 
-        cpu_ref.ORA();
+        cpu.ORA();
 
-        let status = cpu_ref.status;
+        let status = cpu.status;
 
-        assert_eq!(cpu_ref.a, 0x00, "Accumulator value incorrect after OR");
+        assert_eq!(cpu.a, 0x00, "Accumulator value incorrect after OR");
         check_flag!(status, Z, true);
-        check_flag!(status, N, false)
+        check_flag!(status, N, false);
     }
 
     #[test]
     fn PHA_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
-        let mut cpu_ref = bus_ref.cpu_mut();
-        cpu_ref.a = 10;
-        cpu_ref.PHA();
+        let mut cpu = setup();
+        cpu.a = 10;
+        cpu.PHA();
+
+        assert_eq!(cpu.pop_stack(), 10, "Accumulator not pushed to stack");
+    }
 
-        assert_eq!(cpu_ref.pop_stack(), 10, "Accumulator not pushed to stack");
+    #[test]
+    fn PHA_wrap_test() {
+        let mut cpu = setup();
+        cpu.stkp = 0x00;
+        cpu.a = 0x12;
+        cpu.PHA();
+        cpu.a = 0x34;
+        cpu.PHA();
+
+        assert_eq!(cpu.stkp, 0xFE, "Stack pointer did not wrap around");
+        assert_eq!(cpu.peek(0x0100), 0x12, "Pushed outside of page $01");
+        assert_eq!(cpu.peek(0x01FF), 0x34, "Pushed outside of page $01");
     }
 
     #[test]
     fn PHP_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
-        let mut cpu_ref = bus_ref.cpu_mut();
-        cpu_ref.status = Flags6502::from_bits(0x0F).unwrap();
-        cpu_ref.PHP();
-        let status = cpu_ref.pop_stack();
+        let mut cpu = setup();
+        cpu.status = Flags6502::from_bits(0x0F).unwrap();
+        cpu.PHP();
+        let status = cpu.pop_stack();
         assert_ne!(status, 0x0F, "B and U flag not pushed to stack");
         assert_eq!(status, 0x3F, "Status not pushed to stack");
     }
 
     #[test]
     fn PLA_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
-        let mut cpu_ref = bus_ref.cpu_mut();
-        cpu_ref.push_stack(0xF0);
-        cpu_ref.PLA();
-
-        let status = cpu_ref.status;
-        assert_eq!(cpu_ref.a, 0xF0, "Accumulator not popped from stack");
+        let mut cpu = setup();
+        cpu.push_stack(0xF0);
+        cpu.PLA();
+
+        let status = cpu.status;
+        assert_eq!(cpu.a, 0xF0, "Accumulator not popped from stack");
         check_flag!(status, Z, false);
         check_flag!(status, N, true);
     }
 
     #[test]
     fn PLA_zero_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
-        let mut cpu_ref = bus_ref.cpu_mut();
-        cpu_ref.push_stack(0x00);
-        cpu_ref.PLA();
-
-        let status = cpu_ref.status;
-        assert_eq!(cpu_ref.a, 0x00, "Accumulator not popped from stack");
+        let mut cpu = setup();
+        cpu.push_stack(0x00);
+        cpu.PLA();
+
+        let status = cpu.status;
+        assert_eq!(cpu.a, 0x00, "Accumulator not popped from stack");
         check_flag!(status, Z, true);
-        check_flag!(status, N, false)
+        check_flag!(status, N, false);
     }
 
     #[test]
     fn PLP_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
-        let mut cpu_ref = bus_ref.cpu_mut();
-        cpu_ref.push_stack(0x0F);
-        cpu_ref.PLP();
+        let mut cpu = setup();
+        cpu.push_stack(0x0F);
+        cpu.PLP();
 
-        let status = cpu_ref.status.bits();
+        let status = cpu.status.bits();
         assert_ne!(status, 0x0F, "Unused flag not pulled from stack");
         assert_eq!(status, 0x2F, "Status not pulled from stack");
     }
 
     #[test]
     fn ROL_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
+        let mut cpu = setup();
 
-        bus_ref.cpu_write(START_ADDR_ABS, 0x80);
-        bus_ref.cpu_mut().a = 0x80;
+        cpu.write(START_ADDR_ABS, 0x80);
+        cpu.a = 0x80;
+        cpu.set_flag(Flags6502::C, true);
 
-        bus_ref.cpu_mut().ROL();
+        cpu.ROL();
 
-        let status = bus_ref.cpu().status;
+        let status = cpu.status;
 
-        assert_eq!(bus_ref.cpu().a, 0x80, "Accumulator modified, despite addressing mode not implied");
-        assert_eq!(bus_ref.cpu_read(START_ADDR_ABS, false), 0x01, "Read value incorrect after left bit rotate");
+        assert_eq!(cpu.a, 0x80, "Accumulator modified, despite addressing mode not implied");
+        assert_eq!(cpu.read(START_ADDR_ABS), 0x01, "Read value incorrect after left bit rotate");
         check_flag!(status, C, true);
         check_flag!(status, Z, false);
         check_flag!(status, N, false);
@@ -1647,19 +1720,18 @@ mod test {
 
     #[test]
     fn ROL_immediate_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
+        let mut cpu = setup();
 
-        bus_ref.cpu_mut().a = 0x40;
+        cpu.a = 0x40;
 
         // instruction with implied addressing mode
-        bus_ref.cpu_mut().opcode = 0x00;
+        cpu.opcode = 0x00;
 
-        bus_ref.cpu_mut().ROL();
+        cpu.ROL();
 
-        let status = bus_ref.cpu().status;
+        let status = cpu.status;
 
-        assert_eq!(bus_ref.cpu().a, 0x80, "Accumulator value incorrect after left bit rotate");
+        assert_eq!(cpu.a, 0x80, "Accumulator value incorrect after left bit rotate");
         check_flag!(status, C, false);
         check_flag!(status, Z, false);
         check_flag!(status, N, true);
@@ -1667,19 +1739,18 @@ mod test {
 
     #[test]
     fn ROL_zero_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
+        let mut cpu = setup();
 
-        bus_ref.cpu_mut().a = 0x00;
+        cpu.a = 0x00;
 
         // instruction with implied addressing mode
-        bus_ref.cpu_mut().opcode = 0x00;
+        cpu.opcode = 0x00;
 
-        bus_ref.cpu_mut().ROL();
+        cpu.ROL();
 
-        let status = bus_ref.cpu().status;
+        let status = cpu.status;
 
-        assert_eq!(bus_ref.cpu().a, 0x00, "Accumulator value incorrect after left bit rotate");
+        assert_eq!(cpu.a, 0x00, "Accumulator value incorrect after left bit rotate");
         check_flag!(status, C, false);
         check_flag!(status, Z, true);
         check_flag!(status, N, false);
@@ -1687,18 +1758,18 @@ mod test {
 
     #[test]
     fn ROR_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
+        let mut cpu = setup();
 
-        bus_ref.cpu_write(START_ADDR_ABS, 0x01);
-        bus_ref.cpu_mut().a = 0x01;
+        cpu.write(START_ADDR_ABS, 0x01);
+        cpu.a = 0x01;
+        cpu.set_flag(Flags6502::C, true);
 
-        bus_ref.cpu_mut().ROR();
+        cpu.ROR();
 
-        let status = bus_ref.cpu().status;
+        let status = cpu.status;
 
-        assert_eq!(bus_ref.cpu().a, 0x01, "Accumulator modified, despite addressing mode not implied");
-        assert_eq!(bus_ref.cpu_read(START_ADDR_ABS, false), 0x80, "Read value incorrect after right bit rotate");
+        assert_eq!(cpu.a, 0x01, "Accumulator modified, despite addressing mode not implied");
+        assert_eq!(cpu.read(START_ADDR_ABS), 0x80, "Read value incorrect after right bit rotate");
         check_flag!(status, C, true);
         check_flag!(status, Z, false);
         check_flag!(status, N, true);
@@ -1706,19 +1777,18 @@ mod test {
 
     #[test]
     fn ROR_immediate_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
+        let mut cpu = setup();
 
-        bus_ref.cpu_mut().a = 0x40;
+        cpu.a = 0x40;
 
         // instruction with implied addressing mode
-        bus_ref.cpu_mut().opcode = 0x00;
+        cpu.opcode = 0x00;
 
-        bus_ref.cpu_mut().ROR();
+        cpu.ROR();
 
-        let status = bus_ref.cpu().status;
+        let status = cpu.status;
 
-        assert_eq!(bus_ref.cpu().a, 0x20, "Accumulator value incorrect after right bit rotate");
+        assert_eq!(cpu.a, 0x20, "Accumulator value incorrect after right bit rotate");
         check_flag!(status, C, false);
         check_flag!(status, Z, false);
         check_flag!(status, N, false);
@@ -1726,19 +1796,18 @@ mod test {
 
     #[test]
     fn ROR_zero_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
+        let mut cpu = setup();
 
-        bus_ref.cpu_mut().a = 0x00;
+        cpu.a = 0x00;
 
         // instruction with implied addressing mode
-        bus_ref.cpu_mut().opcode = 0x00;
+        cpu.opcode = 0x00;
 
-        bus_ref.cpu_mut().ROR();
+        cpu.ROR();
 
-        let status = bus_ref.cpu().status;
+        let status = cpu.status;
 
-        assert_eq!(bus_ref.cpu().a, 0x00, "Accumulator value incorrect after right bit rotate");
+        assert_eq!(cpu.a, 0x00, "Accumulator value incorrect after right bit rotate");
         check_flag!(status, C, false);
         check_flag!(status, Z, true);
         check_flag!(status, N, false);
@@ -1746,54 +1815,63 @@ mod test {
 
     #[test]
     fn RTI_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
-        let mut cpu_ref = bus_ref.cpu_mut();
-        cpu_ref.status = Flags6502::empty();
+        let mut cpu = setup();
+        cpu.status = Flags6502::empty();
 
-        assert_eq!(cpu_ref.pc, START_PC);
+        assert_eq!(cpu.pc, START_PC);
 
-        cpu_ref.BRK();
+        cpu.BRK();
 
-        assert_eq!(cpu_ref.pc, 0x0000);
+        assert_eq!(cpu.pc, 0x0000);
 
-        cpu_ref.RTI();
+        cpu.RTI();
 
-        assert_eq!(cpu_ref.pc, START_PC + 1, "RTI did not return to connect address");
+        assert_eq!(cpu.pc, START_PC + 1, "RTI did not return to connect address");
 
-        let status = cpu_ref.status;
+        let status = cpu.status;
         check_flag!(status, B, false);
         check_flag!(status, U, false);
     }
 
     #[test]
     fn RTS_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
-        let mut cpu_ref = bus_ref.cpu_mut();
+        let mut cpu = setup();
 
-        cpu_ref.push_stack(0x12);
-        cpu_ref.push_stack(0x33);
+        cpu.push_stack(0x12);
+        cpu.push_stack(0x33);
 
-        cpu_ref.RTS();
+        cpu.RTS();
 
-        assert_eq!(cpu_ref.pc, 0x1234, "Returned to wrong address");
+        assert_eq!(cpu.pc, 0x1234, "Returned to wrong address");
+    }
+
+    #[test]
+    fn RTS_wrap_test() {
+        let mut cpu = setup();
+        // The return address is split between the end and the start of the stack page
+        cpu.stkp = 0xFE;
+        cpu.write(0x01FF, 0x33);
+        cpu.write(0x0100, 0x12);
+
+        cpu.RTS();
+
+        assert_eq!(cpu.pc, 0x1234, "Returned to wrong address");
+        assert_eq!(cpu.stkp, 0x00, "Stack pointer did not wrap around");
     }
 
     #[test]
     fn SBC_no_carry_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
+        let mut cpu = setup();
 
-        bus_ref.cpu_write(START_ADDR_ABS, 10);
-        bus_ref.cpu_mut().a = 30;
+        cpu.write(START_ADDR_ABS, 10);
+        cpu.a = 30;
 
-        bus_ref.cpu_mut().SBC();
+        cpu.SBC();
 
 
-        let status = bus_ref.cpu().status;
+        let status = cpu.status;
 
-        assert_eq!(bus_ref.cpu().a, 19, "Accumulator value incorrect after subtraction");
+        assert_eq!(cpu.a, 19, "Accumulator value incorrect after subtraction");
         check_flag!(status, Z, false);
         check_flag!(status, C, true);
         check_flag!(status, V, false);
@@ -1802,19 +1880,18 @@ mod test {
 
     #[test]
     fn SBC_carry_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
+        let mut cpu = setup();
 
-        bus_ref.cpu_write(START_ADDR_ABS, 10);
-        bus_ref.cpu_mut().a = 30;
-        bus_ref.cpu_mut().set_flag(Flags6502::C, true);
+        cpu.write(START_ADDR_ABS, 10);
+        cpu.a = 30;
+        cpu.set_flag(Flags6502::C, true);
 
-        bus_ref.cpu_mut().SBC();
+        cpu.SBC();
 
 
-        let status = bus_ref.cpu().status;
+        let status = cpu.status;
 
-        assert_eq!(bus_ref.cpu().a, 20, "Accumulator value incorrect after subtraction");
+        assert_eq!(cpu.a, 20, "Accumulator value incorrect after subtraction");
         check_flag!(status, Z, false);
         check_flag!(status, C, true);
         check_flag!(status, V, false);
@@ -1823,19 +1900,18 @@ mod test {
 
     #[test]
     fn SBC_zero_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
+        let mut cpu = setup();
 
-        bus_ref.cpu_write(START_ADDR_ABS, 30);
-        bus_ref.cpu_mut().a = 30;
-        bus_ref.cpu_mut().set_flag(Flags6502::C, true);
+        cpu.write(START_ADDR_ABS, 30);
+        cpu.a = 30;
+        cpu.set_flag(Flags6502::C, true);
 
-        bus_ref.cpu_mut().SBC();
+        cpu.SBC();
 
 
-        let status = bus_ref.cpu().status;
+        let status = cpu.status;
 
-        assert_eq!(bus_ref.cpu().a, 0, "Accumulator value incorrect after subtraction");
+        assert_eq!(cpu.a, 0, "Accumulator value incorrect after subtraction");
         check_flag!(status, Z, true);
         check_flag!(status, C, true);
         check_flag!(status, V, false);
@@ -1844,19 +1920,39 @@ mod test {
 
     #[test]
     fn SBC_borrow_required_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
+        let mut cpu = setup();
 
-        bus_ref.cpu_write(START_ADDR_ABS, 20);
-        bus_ref.cpu_mut().a = 10;
-        bus_ref.cpu_mut().set_flag(Flags6502::C, true);
+        cpu.write(START_ADDR_ABS, 20);
+        cpu.a = 10;
+        cpu.set_flag(Flags6502::C, true);
 
-        bus_ref.cpu_mut().SBC();
+        cpu.SBC();
 
 
-        let status = bus_ref.cpu().status;
+        let status = cpu.status;
 
-        assert_eq!(bus_ref.cpu().a, 246, "Accumulator value incorrect after subtraction");
+        assert_eq!(cpu.a, 246, "Accumulator value incorrect after subtraction");
+        check_flag!(status, Z, false);
+        check_flag!(status, C, false);
+        check_flag!(status, V, false);
+        check_flag!(status, N, true);
+    }
+
+    #[test]
+    fn SBC_overflow_test() {
+        let mut cpu = setup();
+
+        // 80 - (-80) = 160, which does not fit into a signed byte
+        cpu.write(START_ADDR_ABS, 0xB0);
+        cpu.a = 0x50;
+        cpu.set_flag(Flags6502::C, true);
+
+        cpu.SBC();
+
+
+        let status = cpu.status;
+
+        assert_eq!(cpu.a, 0xA0, "Accumulator value incorrect after subtraction");
         check_flag!(status, Z, false);
         check_flag!(status, C, false);
         check_flag!(status, V, true);
@@ -1880,232 +1976,194 @@ mod test {
 
     #[test]
     fn STA_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
-        let mut cpu_ref = bus_ref.cpu_mut();
-        cpu_ref.a = 20;
-        cpu_ref.STA();
+        let mut cpu = setup();
+        cpu.a = 20;
+        cpu.STA();
 
-        assert_eq!(cpu_ref.read(START_ADDR_ABS), 20, "Accumulator not stored correctly");
+        assert_eq!(cpu.read(START_ADDR_ABS), 20, "Accumulator not stored correctly");
     }
 
     #[test]
     fn STX_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
-        let mut cpu_ref = bus_ref.cpu_mut();
-        cpu_ref.x = 20;
-        cpu_ref.STX();
+        let mut cpu = setup();
+        cpu.x = 20;
+        cpu.STX();
 
-        assert_eq!(cpu_ref.read(START_ADDR_ABS), 20, "X register not stored correctly");
+        assert_eq!(cpu.read(START_ADDR_ABS), 20, "X register not stored correctly");
     }
 
 
     #[test]
     fn STY_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
-        let mut cpu_ref = bus_ref.cpu_mut();
-        cpu_ref.y = 20;
-        cpu_ref.STY();
+        let mut cpu = setup();
+        cpu.y = 20;
+        cpu.STY();
 
-        assert_eq!(cpu_ref.read(START_ADDR_ABS), 20, "Y register not stored correctly");
+        assert_eq!(cpu.read(START_ADDR_ABS), 20, "Y register not stored correctly");
     }
 
     #[test]
     fn TAX_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
-        let mut cpu_ref = bus_ref.cpu_mut();
-        cpu_ref.a = 0xF0;
-        cpu_ref.TAX();
+        let mut cpu = setup();
+        cpu.a = 0xF0;
+        cpu.TAX();
 
-        let status = cpu_ref.status;
+        let status = cpu.status;
 
-        assert_eq!(cpu_ref.x, 0xF0, "Accumulator not moved to X register");
+        assert_eq!(cpu.x, 0xF0, "Accumulator not moved to X register");
         check_flag!(status, Z, false);
         check_flag!(status, N, true);
     }
 
     #[test]
     fn TAX_zero_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
-        let mut cpu_ref = bus_ref.cpu_mut();
-        cpu_ref.a = 0x00;
-        cpu_ref.TAX();
+        let mut cpu = setup();
+        cpu.a = 0x00;
+        cpu.TAX();
 
-        let status = cpu_ref.status;
+        let status = cpu.status;
 
-        assert_eq!(cpu_ref.x, 0x00, "Accumulator not moved to X register");
+        assert_eq!(cpu.x, 0x00, "Accumulator not moved to X register");
         check_flag!(status, Z, true);
         check_flag!(status, N, false);
     }
 
     #[test]
     fn TAY_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
-        let mut cpu_ref = bus_ref.cpu_mut();
-        cpu_ref.a = 0xF0;
-        cpu_ref.TAY();
+        let mut cpu = setup();
+        cpu.a = 0xF0;
+        cpu.TAY();
 
-        let status = cpu_ref.status;
+        let status = cpu.status;
 
-        assert_eq!(cpu_ref.y, 0xF0, "Accumulator not moved to Y register");
+        assert_eq!(cpu.y, 0xF0, "Accumulator not moved to Y register");
         check_flag!(status, Z, false);
         check_flag!(status, N, true);
     }
 
     #[test]
     fn TAY_zero_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
-        let mut cpu_ref = bus_ref.cpu_mut();
-        cpu_ref.a = 0x00;
-        cpu_ref.TAY();
+        let mut cpu = setup();
+        cpu.a = 0x00;
+        cpu.TAY();
 
-        let status = cpu_ref.status;
+        let status = cpu.status;
 
-        assert_eq!(cpu_ref.y, 0x00, "Accumulator not moved to Y register");
+        assert_eq!(cpu.y, 0x00, "Accumulator not moved to Y register");
         check_flag!(status, Z, true);
         check_flag!(status, N, false);
     }
 
     #[test]
     fn TSX_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
-        let mut cpu_ref = bus_ref.cpu_mut();
-        cpu_ref.stkp = 0xF0;
-        cpu_ref.TSX();
+        let mut cpu = setup();
+        cpu.stkp = 0xF0;
+        cpu.TSX();
 
-        let status = cpu_ref.status;
+        let status = cpu.status;
 
-        assert_eq!(cpu_ref.x, 0xF0, "Stack pointer not moved to X register");
+        assert_eq!(cpu.x, 0xF0, "Stack pointer not moved to X register");
         check_flag!(status, Z, false);
         check_flag!(status, N, true);
     }
 
     #[test]
     fn TSX_zero_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
-        let mut cpu_ref = bus_ref.cpu_mut();
-        cpu_ref.stkp = 0x00;
-        cpu_ref.TSX();
+        let mut cpu = setup();
+        cpu.stkp = 0x00;
+        cpu.TSX();
 
-        let status = cpu_ref.status;
+        let status = cpu.status;
 
-        assert_eq!(cpu_ref.x, 0x00, "Stack pointer not moved to X register");
+        assert_eq!(cpu.x, 0x00, "Stack pointer not moved to X register");
         check_flag!(status, Z, true);
         check_flag!(status, N, false);
     }
 
     #[test]
     fn TXA_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
-        let mut cpu_ref = bus_ref.cpu_mut();
-        cpu_ref.x = 0xF0;
-        cpu_ref.TXA();
+        let mut cpu = setup();
+        cpu.x = 0xF0;
+        cpu.TXA();
 
-        let status = cpu_ref.status;
+        let status = cpu.status;
 
-        assert_eq!(cpu_ref.a, 0xF0, "X register not moved to Accumulator");
+        assert_eq!(cpu.a, 0xF0, "X register not moved to Accumulator");
         check_flag!(status, Z, false);
         check_flag!(status, N, true);
     }
 
     #[test]
     fn TXA_zero_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
-        let mut cpu_ref = bus_ref.cpu_mut();
-        cpu_ref.x = 0x00;
-        cpu_ref.TXA();
+        let mut cpu = setup();
+        cpu.x = 0x00;
+        cpu.TXA();
 
-        let status = cpu_ref.status;
+        let status = cpu.status;
 
-        assert_eq!(cpu_ref.a, 0x00, "X register not moved to Accumulator");
+        assert_eq!(cpu.a, 0x00, "X register not moved to Accumulator");
         check_flag!(status, Z, true);
         check_flag!(status, N, false);
     }
 
     #[test]
     fn TXS_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
-        let mut cpu_ref = bus_ref.cpu_mut();
-        cpu_ref.x = 0xF0;
-        cpu_ref.TXS();
+        let mut cpu = setup();
+        cpu.x = 0xF0;
+        cpu.TXS();
 
-        let status = cpu_ref.status;
-
-        assert_eq!(cpu_ref.stkp, 0xF0, "X register not moved to Stack Pointer");
+        assert_eq!(cpu.stkp, 0xF0, "X register not moved to Stack Pointer");
     }
 
     #[test]
     fn TXS_zero_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
-        let mut cpu_ref = bus_ref.cpu_mut();
-        cpu_ref.x = 0x00;
-        cpu_ref.TXS();
-
-        let status = cpu_ref.status;
+        let mut cpu = setup();
+        cpu.x = 0x00;
+        cpu.TXS();
 
-        assert_eq!(cpu_ref.stkp, 0x00, "X register not moved to Stack Pointer");
+        assert_eq!(cpu.stkp, 0x00, "X register not moved to Stack Pointer");
     }
 
     #[test]
     fn TYA_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
-        let mut cpu_ref = bus_ref.cpu_mut();
-        cpu_ref.y = 0xF0;
-        cpu_ref.TYA();
+        let mut cpu = setup();
+        cpu.y = 0xF0;
+        cpu.TYA();
 
-        let status = cpu_ref.status;
+        let status = cpu.status;
 
-        assert_eq!(cpu_ref.a, 0xF0, "Y register not moved to Accumulator");
+        assert_eq!(cpu.a, 0xF0, "Y register not moved to Accumulator");
         check_flag!(status, Z, false);
         check_flag!(status, N, true);
     }
 
     #[test]
     fn TYA_zero_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
-        let mut cpu_ref = bus_ref.cpu_mut();
-        cpu_ref.y = 0x00;
-        cpu_ref.TYA();
+        let mut cpu = setup();
+        cpu.y = 0x00;
+        cpu.TYA();
 
-        let status = cpu_ref.status;
+        let status = cpu.status;
 
-        assert_eq!(cpu_ref.a, 0x00, "Y register not moved to Accumulator");
+        assert_eq!(cpu.a, 0x00, "Y register not moved to Accumulator");
         check_flag!(status, Z, true);
         check_flag!(status, N, false);
     }
 
     #[test]
     fn XXX_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
-        let mut cpu_ref = bus_ref.cpu_mut();
-        cpu_ref.XXX();
+        let mut cpu = setup();
+        cpu.XXX();
         // ok
     }
 
     #[test]
     fn is_implied_test() {
-        let bus = setup();
-        let bus_ref = bus.borrow();
-        let mut cpu_ref = bus_ref.cpu_mut();
+        let mut cpu = setup();
 
-        assert!(!cpu_ref.is_implied());
-        cpu_ref.opcode = 0x00;
-        assert!(cpu_ref.is_implied());
+        assert!(!cpu.is_implied());
+        cpu.opcode = 0x00;
+        assert!(cpu.is_implied());
     }
 }
 
@@ -0,0 +1,249 @@
+//! Runs single step tests in the format of https://github.com/SingleStepTests/65x02.
+//!
+//! Every test file covers one opcode and contains a list of tests. Each test consists of the state of the cpu and the memory
+//! before and after executing one instruction, and every bus access the cpu does during that instruction.
+//! As every cycle of the 6502 is exactly one bus access, this also checks the amount of cycles the instruction takes.
+//!
+//! The test files live in `asm_tests/single_step/6502`, one file per opcode named after it in lowercase hex (`a9.json`).
+//! The upstream files contain 10000 tests per opcode, which is too much to keep in the repository. The vendored files are
+//! not taken from upstream. They are a small set written in the upstream format instead, with ten tests for each official
+//! opcode that follow the cycle tables of 64doc. Those all start with the B and U bits set in P, so PHP ($08) and BRK ($00)
+//! have five more tests each that start with both bits clear, to check that they are pushed as 1 anyway.
+//! See `asm_tests/single_step/README.md` for how the files were made.
+//!
+//! To run the full upstream suite, point `SINGLE_STEP_DIR` at a checkout of its `6502/v1` directory.
+//! `scripts/vendor_single_step.py` copies the first tests of every upstream file into the repository.
+//!
+//! All 256 opcodes are run. The unofficial opcodes are not emulated, so they are expected to fail. They are listed in
+//! `UNOFFICIAL_OPCODES`, and one of them passing fails the run as well, so the list has to be updated when they are added.
+
+use crate::cpu6502::instruction_set::decode;
+use crate::cpu6502::memory::CpuBus;
+use crate::cpu6502::{Cpu6502, Flags6502};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+
+/// Where the vendored test files are
+const TEST_DIR: &str = "asm_tests/single_step/6502";
+/// The environment variable that points to another directory of test files, e.g. the upstream ones
+const TEST_DIR_VARIABLE: &str = "SINGLE_STEP_DIR";
+
+/// The opcodes that are not part of the documented instruction set. The cpu does not emulate them yet
+/// (they run as placeholders with the wrong length and timing), so their tests are expected to fail
+const UNOFFICIAL_OPCODES: [u8; 105] = [
+    // JAM, which halts the cpu
+    0x02, 0x12, 0x22, 0x32, 0x42, 0x52, 0x62, 0x72, 0x92, 0xB2, 0xD2, 0xF2,
+    // Read-modify-write combined with an ALU operation: SLO, RLA, SRE, RRA, DCP and ISC
+    0x03, 0x07, 0x0F, 0x13, 0x17, 0x1B, 0x1F,
+    0x23, 0x27, 0x2F, 0x33, 0x37, 0x3B, 0x3F,
+    0x43, 0x47, 0x4F, 0x53, 0x57, 0x5B, 0x5F,
+    0x63, 0x67, 0x6F, 0x73, 0x77, 0x7B, 0x7F,
+    0xC3, 0xC7, 0xCF, 0xD3, 0xD7, 0xDB, 0xDF,
+    0xE3, 0xE7, 0xEF, 0xF3, 0xF7, 0xFB, 0xFF,
+    // SAX and LAX
+    0x83, 0x87, 0x8F, 0x97,
+    0xA3, 0xA7, 0xAF, 0xB3, 0xB7, 0xBF,
+    // NOPs with an operand or without one
+    0x04, 0x44, 0x64, 0x14, 0x34, 0x54, 0x74, 0xD4, 0xF4, 0x0C, 0x1C, 0x3C, 0x5C, 0x7C, 0xDC, 0xFC,
+    0x1A, 0x3A, 0x5A, 0x7A, 0xDA, 0xFA, 0x80, 0x82, 0x89, 0xC2, 0xE2,
+    // Immediate operations: ANC, ALR, ARR, ANE, LXA, AXS and the second SBC
+    0x0B, 0x2B, 0x4B, 0x6B, 0x8B, 0xAB, 0xCB, 0xEB,
+    // SHA, TAS, SHY and SHX, which store AND-ed registers and are unstable on real hardware, and LAS
+    0x93, 0x9F, 0x9B, 0x9C, 0x9E, 0xBB,
+];
+
+/// Whether a bus access reads or writes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Access {
+    Read,
+    Write,
+}
+
+/// One cycle of an instruction: the address on the bus, the data on the bus and whether it was read or written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+struct BusCycle(u16, u8, Access);
+
+impl Display for BusCycle {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let BusCycle(addr, data, access) = self;
+        write!(f, "{:?} ${:0>4X} = ${:0>2X}", access, addr, data)
+    }
+}
+
+/// The state of the cpu and all memory locations relevant to a test
+#[derive(Debug, Deserialize)]
+struct State {
+    pc: u16,
+    s: u8,
+    a: u8,
+    x: u8,
+    y: u8,
+    p: u8,
+    ram: Vec<(u16, u8)>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SingleStepTest {
+    name: String,
+    initial: State,
+    #[serde(rename = "final")]
+    final_state: State,
+    cycles: Vec<BusCycle>,
+}
+
+/// Memory that records every access made to it. Memory locations that are not part of a test read as 0
+#[derive(Debug, Default)]
+struct RecordingBus {
+    memory: HashMap<u16, u8>,
+    cycles: Vec<BusCycle>,
+    ticks: usize,
+}
+
+impl CpuBus for RecordingBus {
+    fn read(&mut self, addr: u16) -> u8 {
+        let data = self.peek(addr);
+        self.cycles.push(BusCycle(addr, data, Access::Read));
+        data
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        self.memory.insert(addr, data);
+        self.cycles.push(BusCycle(addr, data, Access::Write));
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        self.memory.get(&addr).copied().unwrap_or(0)
+    }
+
+    fn tick(&mut self) {
+        self.ticks += 1;
+    }
+}
+
+/// Bits 4 and 5 of the status register don't exist in the cpu. They only show up when the status is pushed to the stack
+const STATUS_MASK: u8 = !(Flags6502::B.bits() | Flags6502::U.bits());
+
+impl SingleStepTest {
+    /// Executes the instruction of this test and returns a description of every difference to the expected outcome
+    fn run(&self) -> Result<(), String> {
+        let initial = &self.initial;
+        let mut bus = RecordingBus::default();
+        bus.memory.extend(initial.ram.iter().copied());
+
+        // The tests are generated on a regular 6502, which has decimal mode
+        let mut cpu = Cpu6502::with_bus(bus);
+        cpu.set_decimal_mode(true);
+        cpu.pc = initial.pc;
+        cpu.stkp = initial.s;
+        cpu.a = initial.a;
+        cpu.x = initial.x;
+        cpu.y = initial.y;
+        cpu.status = Flags6502::from_bits_truncate(initial.p);
+
+        cpu.step();
+
+        let expected = &self.final_state;
+        let mut errors = Vec::new();
+        let mut compare = |name: &str, actual: u16, expected: u16| {
+            if actual != expected {
+                errors.push(format!("{} is ${:0>2X}, expected ${:0>2X}", name, actual, expected));
+            }
+        };
+        compare("PC", cpu.pc, expected.pc);
+        compare("S", cpu.stkp as u16, expected.s as u16);
+        compare("A", cpu.a as u16, expected.a as u16);
+        compare("X", cpu.x as u16, expected.x as u16);
+        compare("Y", cpu.y as u16, expected.y as u16);
+        compare("P", (cpu.status.bits() & STATUS_MASK) as u16, (expected.p & STATUS_MASK) as u16);
+        for &(addr, data) in &expected.ram {
+            compare(&format!("${:0>4X}", addr), cpu.bus().peek(addr) as u16, data as u16);
+        }
+
+        let bus = cpu.bus();
+        if bus.cycles != self.cycles {
+            errors.push(format!(
+                "Bus accesses differ:\n      actual: {}\n    expected: {}",
+                bus.cycles.iter().map(BusCycle::to_string).collect::<Vec<_>>().join(", "),
+                self.cycles.iter().map(BusCycle::to_string).collect::<Vec<_>>().join(", "),
+            ));
+        }
+        if bus.ticks != self.cycles.len() {
+            errors.push(format!("Took {} cycles, expected {}", bus.ticks, self.cycles.len()));
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join("\n  "))
+        }
+    }
+}
+
+/// Loads the tests of the given opcode from `dir`. Returns `None`, if there is no test file for it
+fn load_tests(dir: &Path, opcode: u8) -> Option<Vec<SingleStepTest>> {
+    let path = dir.join(format!("{:0>2x}.json", opcode));
+    let file = std::fs::read_to_string(&path).ok()?;
+    Some(serde_json::from_str(&file).unwrap_or_else(|e| panic!("Error parsing {}: {}", path.display(), e)))
+}
+
+/// Runs all tests of an opcode and reports the first failing one
+fn run_opcode(opcode: u8, tests: &[SingleStepTest]) -> Result<(), String> {
+    let failures = tests.iter()
+        .filter_map(|test| test.run().err().map(|error| (test, error)))
+        .collect::<Vec<_>>();
+
+    match failures.first() {
+        None => Ok(()),
+        Some((test, error)) => Err(format!(
+            "${:0>2X} {}: {} of {} tests failed. First failure \"{}\":\n  {}",
+            opcode, decode(opcode).name(), failures.len(), tests.len(), test.name, error
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cpu6502::instruction_set::decode;
+    use crate::cpu6502::single_step::{load_tests, run_opcode, TEST_DIR, TEST_DIR_VARIABLE, UNOFFICIAL_OPCODES};
+    use std::path::PathBuf;
+
+    #[test]
+    fn single_step_test() {
+        let dir = std::env::var_os(TEST_DIR_VARIABLE).map_or_else(|| PathBuf::from(TEST_DIR), PathBuf::from);
+        let mut failures = Vec::new();
+        let mut unexpected_passes = Vec::new();
+        let mut expected_failures = Vec::new();
+        let mut missing = Vec::new();
+        let mut untested = Vec::new();
+
+        for opcode in 0..=0xFFu8 {
+            let expected_to_fail = UNOFFICIAL_OPCODES.contains(&opcode);
+            match (load_tests(&dir, opcode), expected_to_fail) {
+                (Some(tests), false) => failures.extend(run_opcode(opcode, &tests).err()),
+                (Some(tests), true) => match run_opcode(opcode, &tests) {
+                    Ok(()) => unexpected_passes.push(format!("${:0>2X}", opcode)),
+                    Err(_) => expected_failures.push(format!("${:0>2X}", opcode)),
+                },
+                (None, false) => missing.push(format!("${:0>2X}", opcode)),
+                (None, true) => untested.push(format!("${:0>2X}", opcode)),
+            }
+        }
+
+        println!("{} opcodes failed as expected: {}", expected_failures.len(), expected_failures.join(", "));
+        println!("{} unofficial opcodes have no tests in {}: {}", untested.len(), dir.display(), untested.join(", "));
+        assert!(missing.is_empty(), "No tests found in {} for opcodes {}", dir.display(), missing.join(", "));
+        assert!(failures.is_empty(), "{} opcodes failed:\n{}", failures.len(), failures.join("\n"));
+        assert!(unexpected_passes.is_empty(), "Opcodes {} pass now. Remove them from UNOFFICIAL_OPCODES", unexpected_passes.join(", "));
+    }
+
+    #[test]
+    fn unofficial_opcodes_test() {
+        // Every opcode that is expected to fail has to be unofficial, so no official opcode is allowed to fail
+        for opcode in 0..=0xFFu8 {
+            assert_eq!(UNOFFICIAL_OPCODES.contains(&opcode), !decode(opcode).official, "${:0>2X}", opcode);
+        }
+    }
+}
@@ -0,0 +1,223 @@
+use crate::cpu6502::disassembler::DisassembledInstruction;
+use crate::cpu6502::flow_disassembler::{ByteKind, Data, FlowAnalysis, FlowDisassembler};
+use crate::cpu6502::instruction_set::{encode, AddressingMode, Mnemonic};
+use crate::cpu6502::NMI_PROGRAM_COUNTER;
+use std::fmt::Write;
+
+/// The assembler the generated source is meant for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SourceDialect {
+    /// ca65 from the cc65 suite. Every bank is put into its own segment named `BANK_XX`
+    Ca65,
+    /// asm6 (and asm6f). Banks are separated with `.base` directives
+    Asm6,
+}
+
+/// Writes a bank of program memory as assembler source, which assembles back into exactly the same bytes.
+/// `origin` is the address the bank is mapped to in the CPU address space, `bank_number` is only used for naming.
+/// The code is found by following it from the entry points, and from the interrupt vectors, if they are part of the bank.
+/// Everything else is written as data
+pub fn export_bank(bank: &[u8], origin: u16, bank_number: usize, entry_points: &[u16], dialect: SourceDialect) -> String {
+    assert!(!bank.is_empty(), "Can't export an empty bank");
+    assert!(origin as usize + bank.len() <= 0x10000, "Bank does not fit into the address space at ${:0>4X}", origin);
+
+    let end = (origin as usize + bank.len() - 1) as u16;
+    let read = |addr: u16| bank.get(addr.wrapping_sub(origin) as usize).copied().unwrap_or(0);
+
+    let mut disassembler = FlowDisassembler::new(origin..=end, read);
+    if origin <= NMI_PROGRAM_COUNTER && end == 0xFFFF {
+        disassembler = disassembler.with_vectors();
+    }
+    for &addr in entry_points {
+        disassembler.add_entry_point(addr, None);
+    }
+    let analysis = disassembler.run();
+
+    let mut source = String::new();
+    match dialect {
+        SourceDialect::Ca65 => {
+            writeln!(source, ".setcpu \"6502\"").unwrap();
+            writeln!(source, ".segment \"BANK_{:0>2}\"", bank_number).unwrap();
+            writeln!(source, ".org ${:0>4X}", origin).unwrap();
+        }
+        SourceDialect::Asm6 => {
+            writeln!(source, "; bank {}", bank_number).unwrap();
+            writeln!(source, ".base ${:0>4X}", origin).unwrap();
+        }
+    }
+    writeln!(source).unwrap();
+
+    // Only labels that end up in the source may be used. Labels outside of the bank
+    // or in the middle of an instruction are written as numbers instead
+    let label = |addr: u16| if is_label_emitted(&analysis, addr) {
+        analysis.label(addr).map(String::from)
+    } else {
+        None
+    };
+
+    let mut addr = origin as u32;
+    while addr <= end as u32 {
+        let a = addr as u16;
+        if let Some(name) = label(a) {
+            writeln!(source, "{}:", name).unwrap();
+        }
+
+        if let Some(instruction) = analysis.instructions().get(&a) {
+            if encodes_identically(instruction) {
+                writeln!(source, "    {}", instruction.assembly_text(label)).unwrap();
+            } else {
+                // The assembler would pick a different encoding, so the instruction has to be written as bytes
+                writeln!(source, "    .byte {}  ; {}", byte_list(&instruction.bytes), instruction.assembly_text(label)).unwrap();
+            }
+            addr += instruction.length() as u32;
+            continue;
+        }
+
+        let data = analysis.data_at(a, 16, read);
+        match &data {
+            Data::Words(words) => {
+                let words = words.iter()
+                    .map(|&word| label(word).unwrap_or_else(|| format!("${:0>4X}", word)))
+                    .collect::<Vec<_>>();
+                writeln!(source, "    .word {}", words.join(", ")).unwrap();
+            }
+            Data::Bytes(bytes) => writeln!(source, "    .byte {}", byte_list(bytes)).unwrap(),
+        }
+        addr += data.len() as u32;
+    }
+
+    source
+}
+
+/// Whether the label at the given address gets written to the source.
+/// This is the case, if the address is inside the bank and the start of an instruction or data
+fn is_label_emitted(analysis: &FlowAnalysis, addr: u16) -> bool {
+    analysis.range().contains(&addr) && analysis.kind(addr) != ByteKind::Operand
+}
+
+/// Whether an assembler would produce exactly the bytes of the instruction from its assembly text.
+/// This is not the case for absolute addressing of the zero page, as assemblers use the shorter
+/// zero page instruction instead, and for BRK, which assemblers emit without its padding byte
+fn encodes_identically(instruction: &DisassembledInstruction) -> bool {
+    let zero_page_mode = match instruction.mode() {
+        AddressingMode::ABS => AddressingMode::ZP0,
+        AddressingMode::ABX => AddressingMode::ZPX,
+        AddressingMode::ABY => AddressingMode::ZPY,
+        _ => return instruction.mnemonic() != Mnemonic::BRK,
+    };
+
+    let zero_page_operand = instruction.operand().map(|operand| operand < 0x100).unwrap_or(false);
+    !(zero_page_operand && encode(instruction.mnemonic(), zero_page_mode).is_some())
+}
+
+fn byte_list(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("${:0>2X}", b)).collect::<Vec<_>>().join(", ")
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cpu6502::source_export::{export_bank, SourceDialect};
+    use crate::cpu6502::assembler::assemble;
+    use std::path::PathBuf;
+    use std::process::Command;
+
+    /// A small bank at $C000 with code, data between the code, non-canonical encodings and interrupt vectors
+    fn test_bank() -> Vec<u8> {
+        let program = assemble("
+                    *= $C000
+            reset   sei
+                    cld
+                    ldx #$FF
+                    txs
+            loop    .byte $AD, $10, $00   ; LDA $0010 with absolute addressing of the zero page
+                    jsr sub
+                    jmp loop
+            data    .text \"Hello\"
+            sub     lda data,x
+                    bne sub
+                    brk
+                    .byte $EA
+                    rts
+            nmi     rti
+        ").expect("Error assembling");
+
+        let mut bank = program.bytes();
+        bank.resize(0x4000 - 6, 0xFF);
+        // NMI and IRQ point to the RTI, reset to the start of the bank
+        for vector in &["nmi", "reset", "nmi"] {
+            let addr = program.symbol(vector).unwrap();
+            bank.extend_from_slice(&[addr as u8, (addr >> 8) as u8]);
+        }
+        bank
+    }
+
+    #[test]
+    fn export_test() {
+        let source = export_bank(&test_bank(), 0xC000, 1, &[], SourceDialect::Ca65);
+        let lines = source.lines().collect::<Vec<_>>();
+
+        assert_eq!(&lines[..3], &[".setcpu \"6502\"", ".segment \"BANK_01\"", ".org $C000"]);
+        assert!(lines.contains(&"reset:"), "{}", source);
+        assert!(lines.contains(&"    JSR sub_C013"), "{}", source);
+        assert!(lines.contains(&"    .byte $AD, $10, $00  ; LDA $0010"), "Absolute zero page access should be kept as bytes\n{}", source);
+        assert!(lines.contains(&"    .byte $00, $EA  ; BRK"), "{}", source);
+        assert!(lines.contains(&"    .word nmi, reset, nmi"), "{}", source);
+    }
+
+    /// Assembles the exported source of both dialects with our own assembler, which understands the directives of both
+    #[test]
+    fn built_in_round_trip_test() {
+        let bank = test_bank();
+        for &dialect in &[SourceDialect::Asm6, SourceDialect::Ca65] {
+            let source = export_bank(&bank, 0xC000, 0, &[], dialect);
+            let program = assemble(&source).unwrap_or_else(|e| panic!("Error assembling the exported {:?} source: {}\n{}", dialect, e, source));
+
+            assert_eq!(program.origin(), 0xC000);
+            assert!(program.bytes() == bank, "Assembled {:?} source differs from the original bank", dialect);
+        }
+    }
+
+    /// Runs an external assembler. Returns false, if it is not installed
+    fn run(command: &mut Command) -> bool {
+        match command.status() {
+            Ok(status) => {
+                assert!(status.success(), "{:?} failed", command);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Assembles the exported source with ca65 and asm6 and compares the result with the original bank.
+    /// Run with `cargo test -- --ignored`, if the assemblers are installed
+    #[test]
+    #[ignore]
+    fn round_trip_test() {
+        let bank = test_bank();
+        let dir = std::env::temp_dir().join(format!("nest_round_trip_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| -> PathBuf { dir.join(name) };
+
+        let mut assembled = false;
+
+        std::fs::write(path("bank.asm"), export_bank(&bank, 0xC000, 0, &[], SourceDialect::Asm6)).unwrap();
+        if run(Command::new("asm6").arg(path("bank.asm")).arg(path("asm6.bin"))) {
+            assert!(std::fs::read(path("asm6.bin")).unwrap() == bank, "asm6 output differs from the original bank");
+            assembled = true;
+        }
+
+        std::fs::write(path("bank.s"), export_bank(&bank, 0xC000, 0, &[], SourceDialect::Ca65)).unwrap();
+        std::fs::write(path("bank.cfg"), concat!(
+            "MEMORY { PRG: start = $C000, size = $4000, fill = yes, file = %O; }\n",
+            "SEGMENTS { BANK_00: load = PRG, type = ro; }\n",
+        )).unwrap();
+        if run(Command::new("ca65").arg(path("bank.s")).arg("-o").arg(path("bank.o"))) {
+            assert!(run(Command::new("ld65").arg("-C").arg(path("bank.cfg")).arg("-o").arg(path("ca65.bin")).arg(path("bank.o"))));
+            assert!(std::fs::read(path("ca65.bin")).unwrap() == bank, "ca65 output differs from the original bank");
+            assembled = true;
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(assembled, "Neither asm6 nor ca65 is installed");
+    }
+}
@@ -0,0 +1,220 @@
+//! A view of the stack for debuggers.
+//!
+//! The stack is plain memory in page $01, so on its own it can't tell a return address from a pushed accumulator.
+//! The cpu remembers what pushed each byte of the stack page, which lets `Cpu6502::stack_view` decode
+//! return addresses of subroutines, interrupt frames and pushed status bytes.
+
+use crate::cpu6502::memory::CpuBus;
+use crate::cpu6502::trace::flags_text;
+use crate::cpu6502::{Cpu6502, STACK_POINTER_BASE};
+use std::fmt::{Display, Formatter};
+
+/// What pushed a byte on the stack
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackOrigin {
+    /// Nothing the cpu knows of. E.g. the byte was written with a regular store instruction
+    Unknown,
+    /// PHA
+    Data,
+    /// PHP
+    Status,
+    /// One byte of the return address JSR pushes
+    SubroutineReturn,
+    /// One byte of the return address BRK, IRQ and NMI push
+    InterruptReturn,
+    /// The status register BRK, IRQ and NMI push, and which of them pushed it
+    InterruptStatus(Interrupt),
+}
+
+/// What pushed an interrupt frame. The pushed status only tells BRK apart from the others, so the cpu remembers it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interrupt {
+    Brk,
+    Irq,
+    Nmi,
+}
+
+impl Interrupt {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Interrupt::Brk => "BRK",
+            Interrupt::Irq => "IRQ",
+            Interrupt::Nmi => "NMI",
+        }
+    }
+}
+
+/// One or more bytes on the stack, that belong together
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackEntry {
+    /// A return address pushed by JSR. The pushed address points at the last byte of the JSR instruction
+    Subroutine {
+        address: u16,
+        /// Where RTS continues
+        return_to: u16,
+        /// The address of the JSR instruction
+        caller: u16,
+    },
+    /// The status register and return address pushed by an interrupt or BRK
+    Interrupt {
+        address: u16,
+        status: u8,
+        /// Where RTI continues
+        return_to: u16,
+        source: Interrupt,
+    },
+    /// The status register pushed by PHP
+    Status {
+        address: u16,
+        status: u8,
+    },
+    /// A byte pushed by PHA, or a byte the cpu doesn't know the meaning of
+    Data {
+        address: u16,
+        value: u8,
+        /// Whether the byte was pushed by PHA
+        pushed: bool,
+    },
+}
+
+impl Display for StackEntry {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            StackEntry::Subroutine { address, return_to, caller } =>
+                write!(f, "${:0>4X} RTS ${:0>4X} (JSR ${:0>4X})", address, return_to, caller),
+            StackEntry::Interrupt { address, status, return_to, source } =>
+                write!(f, "${:0>4X} RTI ${:0>4X} {} P:{}", address, return_to, source.name(), flags_text(status)),
+            StackEntry::Status { address, status } => write!(f, "${:0>4X} PHP P:{}", address, flags_text(status)),
+            StackEntry::Data { address, value, pushed: true } => write!(f, "${:0>4X} PHA ${:0>2X}", address, value),
+            StackEntry::Data { address, value, pushed: false } => write!(f, "${:0>4X} ??? ${:0>2X}", address, value),
+        }
+    }
+}
+
+impl<B: CpuBus> Cpu6502<B> {
+    /// Decodes the stack from the top of the stack to the end of the stack page at $01FF.
+    /// The most recently pushed entry comes first. This only peeks at memory, so it does not affect the emulation.
+    ///
+    /// Like on the cpu, the stack wraps around within its page. An entry at the end of the page, that was pushed while
+    /// the stack pointer wrapped from $00 to $FF, continues at $0100. Once the stack pointer itself has wrapped around,
+    /// the entries pushed before can't be told from bytes that were already pulled, so they are not shown
+    pub fn stack_view(&self) -> Vec<StackEntry> {
+        let mut entries = Vec::new();
+        // Offsets into the stack page. Bytes above the stack pointer are the ones on the stack
+        let mut offset = self.stkp as usize + 1;
+
+        let origin = |offset: usize| self.stack_origins[offset & 0xFF];
+        let value = |offset: usize| self.peek(STACK_POINTER_BASE | (offset & 0xFF) as u16);
+        let word = |offset: usize| value(offset) as u16 | (value(offset + 1) as u16) << 8;
+
+        while offset <= 0xFF {
+            let address = STACK_POINTER_BASE | offset as u16;
+            let (entry, length) = match origin(offset) {
+                StackOrigin::SubroutineReturn if origin(offset + 1) == StackOrigin::SubroutineReturn => {
+                    let pushed = word(offset);
+                    (StackEntry::Subroutine { address, return_to: pushed.wrapping_add(1), caller: pushed.wrapping_sub(2) }, 2)
+                }
+                StackOrigin::InterruptStatus(source)
+                    if origin(offset + 1) == StackOrigin::InterruptReturn && origin(offset + 2) == StackOrigin::InterruptReturn =>
+                    (StackEntry::Interrupt { address, status: value(offset), return_to: word(offset + 1), source }, 3),
+                StackOrigin::Status => (StackEntry::Status { address, status: value(offset) }, 1),
+                other => (StackEntry::Data { address, value: value(offset), pushed: other == StackOrigin::Data }, 1),
+            };
+            entries.push(entry);
+            offset += length;
+        }
+
+        entries
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cpu6502::assembler::assemble;
+    use crate::cpu6502::memory::FlatMemory;
+    use crate::cpu6502::stack::{Interrupt, StackEntry};
+    use crate::cpu6502::{Cpu6502, Flags6502};
+
+    fn run(source: &str, instructions: usize) -> Cpu6502<FlatMemory> {
+        let mut cpu = assemble(source).expect("Error assembling").cpu();
+        for _ in 0..instructions {
+            cpu.step();
+        }
+        cpu
+    }
+
+    #[test]
+    fn stack_view_test() {
+        let cpu = run("
+                *= $8000
+                jsr sub
+          end   jmp end
+          sub   lda #$42
+                pha
+                php
+                brk
+                nop
+          irq   rti
+                *= $FFFE
+                .word irq
+        ", 5);
+
+        assert_eq!(cpu.stack_view(), vec![
+            StackEntry::Interrupt { address: 0x01F7, status: 0x34, return_to: 0x800C, source: Interrupt::Brk },
+            StackEntry::Status { address: 0x01FA, status: 0x34 },
+            StackEntry::Data { address: 0x01FB, value: 0x42, pushed: true },
+            StackEntry::Subroutine { address: 0x01FC, return_to: 0x8003, caller: 0x8000 },
+            StackEntry::Data { address: 0x01FE, value: 0x00, pushed: false },
+            StackEntry::Data { address: 0x01FF, value: 0x00, pushed: false },
+        ]);
+        assert_eq!(cpu.stack_view()[0].to_string(), "$01F7 RTI $800C BRK P:nvUBdIzc");
+        assert_eq!(cpu.stack_view()[3].to_string(), "$01FC RTS $8003 (JSR $8000)");
+    }
+
+    #[test]
+    fn overwritten_stack_test() {
+        // Storing over a return address makes it unknown
+        let cpu = run("
+                *= $8000
+                jsr sub
+          end   jmp end
+          sub   lda #$12
+                sta $01FC
+        ", 3);
+
+        assert_eq!(cpu.stack_view()[..2], [
+            StackEntry::Data { address: 0x01FC, value: 0x12, pushed: false },
+            StackEntry::Data { address: 0x01FD, value: 0x80, pushed: false },
+        ]);
+    }
+
+    #[test]
+    fn nmi_test() {
+        let mut cpu = run("
+                *= $8000
+          loop  jmp loop
+        ", 1);
+        cpu.nmi();
+
+        // Without the B flag, the status alone would look like an IRQ
+        assert_eq!(cpu.stack_view()[0].to_string(), "$01FB RTI $8000 NMI P:nvUbdIzc");
+        // The NMI vector points to $0000
+        cpu.set_flag(Flags6502::I, false);
+        cpu.irq();
+        assert_eq!(cpu.stack_view()[0], StackEntry::Interrupt { address: 0x01F8, status: 0x24, return_to: 0x0000, source: Interrupt::Irq });
+    }
+
+    #[test]
+    fn page_wrap_test() {
+        let mut cpu = run("
+                *= $8000
+                jsr sub
+          sub   nop
+        ", 0);
+        // JSR pushes the high byte to $0100 and the low byte to $01FF
+        cpu.set_stack_pointer(0x00);
+        cpu.step();
+
+        assert_eq!(cpu.stack_view(), vec![StackEntry::Subroutine { address: 0x01FF, return_to: 0x8003, caller: 0x8000 }]);
+    }
+}
@@ -0,0 +1,511 @@
+//! Trace logging of executed instructions.
+//!
+//! While tracing is enabled, the cpu builds a `TraceEntry` before each instruction and hands it to its `TraceSink`.
+//! Sinks decide what happens with the entries: print them, write them to a file, keep the last few in memory or filter them first.
+//! `TraceFormat` turns entries into lines in the formats of other emulators, so their logs can be compared with ours.
+
+use crate::cpu6502::disassembler::DisassembledInstruction;
+use crate::cpu6502::instruction_set::{AddressingMode, Mnemonic};
+use crate::cpu6502::memory::CpuBus;
+use crate::cpu6502::{Cpu6502, Flags6502};
+use std::collections::VecDeque;
+use std::fmt::Debug;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// The state of the cpu right before it executes an instruction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceEntry {
+    /// The instruction about to be executed. Its address is the program counter
+    pub instruction: DisassembledInstruction,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    /// The status register. Like in other emulators' traces, bit 5 is always set and bit 4 is always clear,
+    /// as these are not actual flags and only show up when the status is pushed to the stack
+    pub status: u8,
+    pub stack_pointer: u8,
+    /// The amount of cycles the cpu worked before this instruction
+    pub cycle: usize,
+    /// The scanline and dot the ppu is at, if the cpu is connected to one
+    pub ppu: Option<(i16, i16)>,
+    /// The address the instruction reads, writes or jumps to. For indexed and indirect addressing this already has the
+    /// registers applied and the pointers followed. `None` for implied and immediate addressing
+    pub address: Option<u16>,
+    /// The address in between for indirect addressing: the pointer in the zero page after adding X for `($nn,X)`,
+    /// and the address read from the zero page before adding Y for `($nn),Y`
+    pub pointer: Option<u16>,
+    /// The value at `address` before the instruction executes. `None` for jumps and branches
+    pub value: Option<u8>,
+}
+
+impl<B: CpuBus> Cpu6502<B> {
+    /// Builds the trace entry for the instruction at the program counter.
+    /// This only peeks at memory, so it does not affect the emulation
+    pub fn trace_entry(&self) -> TraceEntry {
+        let instruction = self.decode_instr_at(self.pc);
+        let operand = instruction.operand().unwrap_or(0);
+        // Zero page pointers wrap around within the zero page
+        let peek_word = |addr: u16, next: u16| self.peek(addr) as u16 | (self.peek(next) as u16) << 8;
+        let peek_zp_word = |addr: u16| peek_word(addr & 0x00FF, (addr + 1) & 0x00FF);
+
+        let (address, pointer) = match instruction.mode() {
+            AddressingMode::IMP | AddressingMode::IMM => (None, None),
+            AddressingMode::ZP0 | AddressingMode::ABS => (Some(operand), None),
+            AddressingMode::ZPX => (Some((operand + self.x as u16) & 0x00FF), None),
+            AddressingMode::ZPY => (Some((operand + self.y as u16) & 0x00FF), None),
+            AddressingMode::ABX => (Some(operand.wrapping_add(self.x as u16)), None),
+            AddressingMode::ABY => (Some(operand.wrapping_add(self.y as u16)), None),
+            // The high byte of the pointer does not cross pages, see `Cpu6502::IND`
+            AddressingMode::IND => (Some(peek_word(operand, (operand & 0xFF00) | (operand.wrapping_add(1) & 0x00FF))), None),
+            AddressingMode::IZX => {
+                let pointer = (operand + self.x as u16) & 0x00FF;
+                (Some(peek_zp_word(pointer)), Some(pointer))
+            }
+            AddressingMode::IZY => {
+                let base = peek_zp_word(operand);
+                (Some(base.wrapping_add(self.y as u16)), Some(base))
+            }
+            AddressingMode::REL => (instruction.target(), None),
+        };
+
+        let jumps = matches!(instruction.mnemonic(), Mnemonic::JMP | Mnemonic::JSR) || instruction.mode() == AddressingMode::REL;
+        let value = address.filter(|_| !jumps).map(|addr| self.peek(addr));
+
+        TraceEntry {
+            instruction,
+            a: self.a,
+            x: self.x,
+            y: self.y,
+            status: ((self.status | Flags6502::U) & !Flags6502::B).bits(),
+            stack_pointer: self.stkp,
+            cycle: self.cycle_count,
+            ppu: self.bus().ppu_position(),
+            address,
+            pointer,
+            value,
+        }
+    }
+}
+
+/// Receives a trace entry for every instruction the cpu executes, while tracing is enabled.
+/// See `Cpu6502::set_trace_sink`
+pub trait TraceSink: Send {
+    fn trace(&mut self, entry: &TraceEntry);
+}
+
+/// Lets a sink chosen at runtime be wrapped by another one, e.g. a `FilteredSink`
+impl<S: TraceSink + ?Sized> TraceSink for Box<S> {
+    fn trace(&mut self, entry: &TraceEntry) {
+        (**self).trace(entry);
+    }
+}
+
+impl Debug for dyn TraceSink {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(fmt, "trace sink")
+    }
+}
+
+/// The trace log formats of other emulators
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    /// The format of Nintendulator, which is also the format of the well known `nestest.log`. E.g.
+    /// `C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7`
+    Nintendulator,
+    /// The format of FCEUX's trace logger. E.g.
+    /// `$C000:4C F5 C5  JMP $C5F5                         A:00 X:00 Y:00 S:FD P:nvUbdIzc`
+    Fceux,
+    /// The default format of Mesen's trace logger. E.g.
+    /// `C000  JMP $C5F5                                  A:00 X:00 Y:00 S:FD P:nvUbdIzc V:0   H:21  Cycle:7`
+    Mesen,
+}
+
+impl TraceFormat {
+    pub const ALL: &'static [TraceFormat] = &[TraceFormat::Nintendulator, TraceFormat::Fceux, TraceFormat::Mesen];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            TraceFormat::Nintendulator => "nintendulator",
+            TraceFormat::Fceux => "fceux",
+            TraceFormat::Mesen => "mesen",
+        }
+    }
+
+    /// Looks up a format by its name. `nestest` is accepted for `Nintendulator` as well. The name is case insensitive
+    pub fn from_name(name: &str) -> Option<TraceFormat> {
+        let name = name.to_ascii_lowercase();
+        if name == "nestest" {
+            return Some(TraceFormat::Nintendulator);
+        }
+        Self::ALL.iter().copied().find(|format| format.name() == name)
+    }
+
+    /// Formats an entry as a single line, without a line break
+    pub fn format(&self, entry: &TraceEntry) -> String {
+        match self {
+            TraceFormat::Nintendulator => format_nintendulator(entry),
+            TraceFormat::Fceux => format_fceux(entry),
+            TraceFormat::Mesen => format_mesen(entry),
+        }
+    }
+}
+
+/// The raw bytes of the instruction in hex, separated by spaces
+fn bytes_text(instruction: &DisassembledInstruction) -> String {
+    instruction.bytes.iter().map(|b| format!("{:0>2X}", b)).collect::<Vec<_>>().join(" ")
+}
+
+/// The status register as letters. Set flags are upper case, clear flags lower case. E.g. `nvUbdIzc`
+pub(crate) fn flags_text(status: u8) -> String {
+    "NVUBDIZC".chars().enumerate()
+        .map(|(i, flag)| if status & (0x80 >> i) != 0 { flag } else { flag.to_ascii_lowercase() })
+        .collect()
+}
+
+/// Shift and rotate instructions in implied mode work on the accumulator, which is written as `A`
+fn is_accumulator(instruction: &DisassembledInstruction) -> bool {
+    instruction.mode() == AddressingMode::IMP
+        && matches!(instruction.mnemonic(), Mnemonic::ASL | Mnemonic::LSR | Mnemonic::ROL | Mnemonic::ROR)
+}
+
+/// Formats the instruction with the operand annotations of Nintendulator, e.g. `LDA ($80,X) @ 80 = 0200 = 5A`
+fn nintendulator_instruction(entry: &TraceEntry) -> String {
+    let instruction = &entry.instruction;
+    let text = instruction.assembly_text(|_| None);
+    let address = entry.address.unwrap_or(0);
+    let value = entry.value.unwrap_or(0);
+
+    match instruction.mode() {
+        _ if is_accumulator(instruction) => format!("{} A", text),
+        AddressingMode::ZP0 => format!("{} = {:0>2X}", text, value),
+        AddressingMode::ABS if entry.value.is_some() => format!("{} = {:0>2X}", text, value),
+        AddressingMode::ZPX | AddressingMode::ZPY => format!("{} @ {:0>2X} = {:0>2X}", text, address, value),
+        AddressingMode::ABX | AddressingMode::ABY => format!("{} @ {:0>4X} = {:0>2X}", text, address, value),
+        AddressingMode::IND => format!("{} = {:0>4X}", text, address),
+        AddressingMode::IZX => format!("{} @ {:0>2X} = {:0>4X} = {:0>2X}", text, entry.pointer.unwrap_or(0), address, value),
+        AddressingMode::IZY => format!("{} = {:0>4X} @ {:0>4X} = {:0>2X}", text, entry.pointer.unwrap_or(0), address, value),
+        _ => text,
+    }
+}
+
+fn format_nintendulator(entry: &TraceEntry) -> String {
+    let instruction = &entry.instruction;
+    // Illegal opcodes are marked with a * in front of the mnemonic
+    let marker = if instruction.opcode.official { ' ' } else { '*' };
+    let mut line = format!(
+        "{:0>4X}  {:<9}{}{:<32}A:{:0>2X} X:{:0>2X} Y:{:0>2X} P:{:0>2X} SP:{:0>2X}",
+        instruction.address, bytes_text(instruction), marker, nintendulator_instruction(entry),
+        entry.a, entry.x, entry.y, entry.status, entry.stack_pointer
+    );
+    if let Some((scanline, dot)) = entry.ppu {
+        line += &format!(" PPU:{:>3},{:>3}", scanline, dot);
+    }
+    line + &format!(" CYC:{}", entry.cycle)
+}
+
+/// Formats the instruction with the operand annotations of FCEUX and Mesen.
+/// Both write the accessed address in `separator`s and the value as hex numbers with a $
+fn annotated_instruction(entry: &TraceEntry, separator: (&str, &str)) -> String {
+    let instruction = &entry.instruction;
+    let text = instruction.assembly_text(|_| None);
+    let (open, close) = separator;
+    let address = entry.address.unwrap_or(0);
+    let value = entry.value.unwrap_or(0);
+
+    match instruction.mode() {
+        _ if is_accumulator(instruction) => format!("{} A", text),
+        AddressingMode::ZP0 | AddressingMode::ABS if entry.value.is_some() => format!("{} = #${:0>2X}", text, value),
+        AddressingMode::ZPX | AddressingMode::ZPY | AddressingMode::ABX | AddressingMode::ABY | AddressingMode::IZX | AddressingMode::IZY =>
+            format!("{} {}${:0>4X}{} = #${:0>2X}", text, open, address, close, value),
+        AddressingMode::IND => format!("{} {}${:0>4X}{}", text, open, address, close),
+        _ => text,
+    }
+}
+
+fn format_fceux(entry: &TraceEntry) -> String {
+    let instruction = &entry.instruction;
+    format!(
+        "${:0>4X}:{:<9} {:<33} A:{:0>2X} X:{:0>2X} Y:{:0>2X} S:{:0>2X} P:{}",
+        instruction.address, bytes_text(instruction), annotated_instruction(entry, ("@ ", "")),
+        entry.a, entry.x, entry.y, entry.stack_pointer, flags_text(entry.status)
+    )
+}
+
+fn format_mesen(entry: &TraceEntry) -> String {
+    let mut line = format!(
+        "{:0>4X}  {:<42} A:{:0>2X} X:{:0>2X} Y:{:0>2X} S:{:0>2X} P:{}",
+        entry.instruction.address, annotated_instruction(entry, ("[", "]")),
+        entry.a, entry.x, entry.y, entry.stack_pointer, flags_text(entry.status)
+    );
+    if let Some((scanline, dot)) = entry.ppu {
+        line += &format!(" V:{:<3} H:{:<3}", scanline, dot);
+    }
+    line + &format!(" Cycle:{}", entry.cycle)
+}
+
+/// Prints every entry to stdout
+#[derive(Debug)]
+pub struct StdoutSink {
+    format: TraceFormat,
+}
+
+impl StdoutSink {
+    pub fn new(format: TraceFormat) -> Self {
+        StdoutSink { format }
+    }
+}
+
+impl TraceSink for StdoutSink {
+    fn trace(&mut self, entry: &TraceEntry) {
+        println!("{}", self.format.format(entry));
+    }
+}
+
+/// Writes every entry to a file, one line each
+#[derive(Debug)]
+pub struct FileSink {
+    writer: BufWriter<File>,
+    format: TraceFormat,
+}
+
+impl FileSink {
+    /// Creates the file, replacing it if it already exists
+    pub fn create<P: AsRef<Path>>(path: P, format: TraceFormat) -> std::io::Result<Self> {
+        Ok(FileSink {
+            writer: BufWriter::new(File::create(path)?),
+            format,
+        })
+    }
+}
+
+impl TraceSink for FileSink {
+    fn trace(&mut self, entry: &TraceEntry) {
+        writeln!(self.writer, "{}", self.format.format(entry)).expect("Error writing trace log");
+    }
+}
+
+/// Keeps the last entries in memory. This is useful to see what led up to a crash, without logging everything.
+/// Clones share the same buffer, so one clone can be given to the cpu while the other one is used to look at the entries
+#[derive(Debug, Clone)]
+pub struct RingBufferSink {
+    capacity: usize,
+    entries: Arc<Mutex<VecDeque<TraceEntry>>>,
+}
+
+impl RingBufferSink {
+    /// Creates a buffer keeping the last `capacity` entries
+    pub fn new(capacity: usize) -> Self {
+        RingBufferSink {
+            capacity,
+            entries: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+        }
+    }
+
+    /// The entries in the buffer, oldest first
+    pub fn entries(&self) -> Vec<TraceEntry> {
+        self.entries.lock().expect("Trace buffer poisoned").iter().cloned().collect()
+    }
+
+    /// The entries in the buffer formatted as lines, oldest first
+    pub fn lines(&self, format: TraceFormat) -> Vec<String> {
+        self.entries().iter().map(|entry| format.format(entry)).collect()
+    }
+
+    pub fn clear(&self) {
+        self.entries.lock().expect("Trace buffer poisoned").clear();
+    }
+}
+
+impl TraceSink for RingBufferSink {
+    fn trace(&mut self, entry: &TraceEntry) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().expect("Trace buffer poisoned");
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(entry.clone());
+    }
+}
+
+/// Only passes entries on to another sink, if they match a filter
+pub struct FilteredSink<S: TraceSink> {
+    sink: S,
+    filter: Box<dyn FnMut(&TraceEntry) -> bool + Send>,
+}
+
+impl<S: TraceSink> FilteredSink<S> {
+    /// Passes on the entries, for which the condition holds. The condition may keep state,
+    /// e.g. to start tracing once a certain address is reached
+    pub fn new<F: FnMut(&TraceEntry) -> bool + Send + 'static>(sink: S, condition: F) -> Self {
+        FilteredSink {
+            sink,
+            filter: Box::new(condition),
+        }
+    }
+
+    /// Only passes on instructions within the given address range
+    pub fn pc_range(sink: S, range: RangeInclusive<u16>) -> Self {
+        Self::new(sink, move |entry| range.contains(&entry.instruction.address))
+    }
+}
+
+impl<S: TraceSink> TraceSink for FilteredSink<S> {
+    fn trace(&mut self, entry: &TraceEntry) {
+        if (self.filter)(entry) {
+            self.sink.trace(entry);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cpu6502::assembler::assemble;
+    use crate::cpu6502::memory::FlatMemory;
+    use crate::cpu6502::trace::{FileSink, FilteredSink, RingBufferSink, TraceFormat};
+    use crate::cpu6502::Cpu6502;
+
+    fn setup(source: &str) -> Cpu6502<FlatMemory> {
+        assemble(source).expect("Error assembling").cpu()
+    }
+
+    #[test]
+    fn format_test() {
+        let mut cpu = setup("
+                *= $C000
+                lda ($80,x)
+                lda ($89),y
+                lsr a
+                jmp ($0200)
+                *= $0080
+                .word $0300
+                *= $0089
+                .word $0300
+                *= $0200
+                .word $DB7E
+                *= $0300
+                .byte $5A
+        ");
+        let buffer = RingBufferSink::new(8);
+        cpu.set_trace_sink(Box::new(buffer.clone()));
+        cpu.set_program_counter(0xC000);
+        for _ in 0..4 {
+            cpu.step();
+        }
+
+        assert_eq!(buffer.lines(TraceFormat::Nintendulator), vec![
+            "C000  A1 80     LDA ($80,X) @ 80 = 0300 = 5A    A:00 X:00 Y:00 P:24 SP:FD CYC:7",
+            "C002  B1 89     LDA ($89),Y = 0300 @ 0300 = 5A  A:5A X:00 Y:00 P:24 SP:FD CYC:13",
+            "C004  4A        LSR A                           A:5A X:00 Y:00 P:24 SP:FD CYC:18",
+            "C005  6C 00 02  JMP ($0200) = DB7E              A:2D X:00 Y:00 P:24 SP:FD CYC:20",
+        ]);
+        assert_eq!(buffer.lines(TraceFormat::Fceux)[1], "$C002:B1 89     LDA ($89),Y @ $0300 = #$5A        A:5A X:00 Y:00 S:FD P:nvUbdIzc");
+        assert_eq!(buffer.lines(TraceFormat::Mesen)[3], "C005  JMP ($0200) [$DB7E]                        A:2D X:00 Y:00 S:FD P:nvUbdIzc Cycle:20");
+    }
+
+    #[test]
+    fn file_test() {
+        let mut cpu = setup("
+                *= $8000
+                ldx #2
+          loop  dex
+                bne loop
+        ");
+        let path = std::env::temp_dir().join(format!("nest_trace_{}.log", std::process::id()));
+        cpu.set_trace_sink(Box::new(FileSink::create(&path, TraceFormat::Mesen).expect("Error creating trace log")));
+        for _ in 0..5 {
+            cpu.step();
+        }
+        // The log is flushed, when the sink is dropped
+        drop(cpu);
+
+        let log = std::fs::read_to_string(&path).expect("Error reading trace log");
+        std::fs::remove_file(&path).expect("Error removing trace log");
+        let lines = log.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 5, "Every instruction should be written to the file");
+        assert!(lines[0].starts_with("8000  LDX #$02"), "Wrong first line: {}", lines[0]);
+        assert!(lines[4].starts_with("8003  BNE $8002"), "Wrong last line: {}", lines[4]);
+    }
+
+    #[test]
+    fn ring_buffer_test() {
+        let mut cpu = setup("
+                *= $8000
+                ldx #10
+          loop  dex
+                bne loop
+        ");
+        let buffer = RingBufferSink::new(3);
+        cpu.set_trace_sink(Box::new(buffer.clone()));
+        for _ in 0..21 {
+            cpu.step();
+        }
+
+        let entries = buffer.entries();
+        assert_eq!(entries.len(), 3, "Only the last entries should be kept");
+        assert_eq!(entries.iter().map(|entry| entry.x).collect::<Vec<_>>(), vec![1, 1, 0]);
+        assert_eq!(entries[2].instruction.address, 0x8003);
+    }
+
+    #[test]
+    fn toggle_test() {
+        let mut cpu = setup("
+                *= $8000
+                nop
+                nop
+                nop
+        ");
+        let buffer = RingBufferSink::new(8);
+        cpu.set_trace_sink(Box::new(buffer.clone()));
+        assert!(cpu.is_tracing(), "Setting a sink should enable tracing");
+
+        cpu.step();
+        cpu.set_tracing(false);
+        cpu.step();
+        cpu.set_tracing(true);
+        cpu.step();
+
+        let addresses = buffer.entries().iter().map(|entry| entry.instruction.address).collect::<Vec<_>>();
+        assert_eq!(addresses, vec![0x8000, 0x8002]);
+    }
+
+    #[test]
+    fn filter_test() {
+        let mut cpu = setup("
+                *= $8000
+                ldx #3
+                jsr sub
+                jsr sub
+                jsr sub
+          end   jmp end
+          sub   dex
+                rts
+        ");
+        let buffer = RingBufferSink::new(16);
+        cpu.set_trace_sink(Box::new(FilteredSink::pc_range(buffer.clone(), 0x800E..=0x800F)));
+        for _ in 0..10 {
+            cpu.step();
+        }
+        assert_eq!(buffer.entries().len(), 6, "Only the subroutine should be traced");
+
+        buffer.clear();
+        let mut cpu = setup("
+                *= $8000
+                ldx #3
+          loop  dex
+                bne loop
+                nop
+        ");
+        cpu.set_trace_sink(Box::new(FilteredSink::new(buffer.clone(), |entry| entry.x == 1)));
+        for _ in 0..8 {
+            cpu.step();
+        }
+        let lines = buffer.lines(TraceFormat::Nintendulator);
+        assert_eq!(lines.len(), 2, "Only instructions with X = 1 should be traced:\n{}", lines.join("\n"));
+    }
+}
@@ -0,0 +1,391 @@
+//! Finds the first difference between two trace logs.
+//!
+//! The logs may come from different emulators. Every line is parsed into the values all `TraceFormat`s have in common,
+//! so only those are compared, not the disassembly. Lines that are no instructions, like headers or messages in between,
+//! are left out. The logs don't have to start at the same instruction either: they are lined up at the first instruction
+//! of one log, that also shows up in the other one.
+
+use crate::cpu6502::trace::TraceFormat;
+use crate::cpu6502::Flags6502;
+use std::fmt::{Display, Formatter};
+
+/// A value in a trace line, that can be left out of the comparison
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceField {
+    Pc,
+    /// The bytes of the instruction
+    Bytes,
+    A,
+    X,
+    Y,
+    P,
+    Sp,
+    /// The scanline and dot of the ppu
+    Ppu,
+    Cycle,
+}
+
+impl TraceField {
+    pub const ALL: &'static [TraceField] = &[
+        TraceField::Pc, TraceField::Bytes, TraceField::A, TraceField::X, TraceField::Y,
+        TraceField::P, TraceField::Sp, TraceField::Ppu, TraceField::Cycle,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            TraceField::Pc => "pc",
+            TraceField::Bytes => "bytes",
+            TraceField::A => "a",
+            TraceField::X => "x",
+            TraceField::Y => "y",
+            TraceField::P => "p",
+            TraceField::Sp => "sp",
+            TraceField::Ppu => "ppu",
+            TraceField::Cycle => "cycle",
+        }
+    }
+
+    /// Looks up a field by its name. The name is case insensitive
+    pub fn from_name(name: &str) -> Option<TraceField> {
+        let name = name.to_ascii_lowercase();
+        Self::ALL.iter().copied().find(|field| field.name() == name)
+    }
+}
+
+/// The values of one instruction in a trace log. Values a format doesn't log are `None`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceLine {
+    /// The line in the log, starting at 1
+    pub line_number: usize,
+    pub text: String,
+    pub pc: u16,
+    pub bytes: Option<Vec<u8>>,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub status: u8,
+    pub stack_pointer: u8,
+    pub ppu: Option<(i16, i16)>,
+    pub cycle: Option<usize>,
+}
+
+/// Bits 4 and 5 of the status register are no actual flags, and emulators don't agree on how to show them
+const STATUS_MASK: u8 = !(Flags6502::B.bits() | Flags6502::U.bits());
+
+impl TraceLine {
+    /// The value of a field as text, the way it is shown in a diff. `None`, if the line doesn't have that value
+    fn value(&self, field: TraceField) -> Option<String> {
+        match field {
+            TraceField::Pc => Some(format!("{:0>4X}", self.pc)),
+            TraceField::Bytes => self.bytes.as_ref().map(|bytes| bytes.iter().map(|b| format!("{:0>2X}", b)).collect::<Vec<_>>().join(" ")),
+            TraceField::A => Some(format!("{:0>2X}", self.a)),
+            TraceField::X => Some(format!("{:0>2X}", self.x)),
+            TraceField::Y => Some(format!("{:0>2X}", self.y)),
+            TraceField::P => Some(format!("{:0>2X}", self.status & STATUS_MASK)),
+            TraceField::Sp => Some(format!("{:0>2X}", self.stack_pointer)),
+            TraceField::Ppu => self.ppu.map(|(scanline, dot)| format!("{},{}", scanline, dot)),
+            TraceField::Cycle => self.cycle.map(|cycle| cycle.to_string()),
+        }
+    }
+
+    /// The fields that differ between two lines, with the value of this line and the one of the other line.
+    /// Values only one of the lines has are not compared
+    pub fn differences(&self, other: &TraceLine, skip: &[TraceField]) -> Vec<(TraceField, String, String)> {
+        TraceField::ALL.iter().copied()
+            .filter(|field| !skip.contains(field))
+            .filter_map(|field| match (self.value(field), other.value(field)) {
+                (Some(left), Some(right)) if left != right => Some((field, left, right)),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// The text after the label up to the next space
+fn labeled<'a>(line: &'a str, label: &str) -> Option<&'a str> {
+    let start = line.find(label)? + label.len();
+    line[start..].split_whitespace().next()
+}
+
+fn labeled_hex(line: &str, label: &str) -> Option<u8> {
+    u8::from_str_radix(labeled(line, label)?, 16).ok()
+}
+
+/// Parses the status register, either as hex number or as letters like `nvUbdIzc`, where set flags are upper case
+fn parse_status(text: &str) -> Option<u8> {
+    if text.len() == 8 && text.chars().all(|c| "nvubdizc".contains(c.to_ascii_lowercase())) {
+        Some(text.chars().enumerate().filter(|(_, c)| c.is_ascii_uppercase()).fold(0, |status, (i, _)| status | 0x80 >> i))
+    } else {
+        u8::from_str_radix(text, 16).ok()
+    }
+}
+
+/// Parses hex bytes separated by spaces, like `4C F5 C5`
+fn parse_bytes(text: &str) -> Option<Vec<u8>> {
+    text.split_whitespace().map(|byte| u8::from_str_radix(byte, 16).ok()).collect()
+}
+
+impl TraceFormat {
+    /// Parses a line of a trace log in this format. Returns `None` for lines that are no instructions
+    pub fn parse(&self, line_number: usize, line: &str) -> Option<TraceLine> {
+        let (pc, bytes, stack_pointer, ppu, cycle) = match self {
+            TraceFormat::Nintendulator => {
+                // The ppu position is left out of logs of a cpu without a ppu
+                let ppu = match line.find(" PPU:") {
+                    Some(start) => {
+                        let mut ppu = line.get(start + 5..)?.split(',')
+                            .map(|part| part.split_whitespace().next().and_then(|n| n.parse().ok()));
+                        Some((ppu.next()??, ppu.next()??))
+                    }
+                    None => None,
+                };
+                (
+                    line.get(0..4)?,
+                    Some(parse_bytes(line.get(6..15)?)?),
+                    labeled_hex(line, " SP:")?,
+                    ppu,
+                    labeled(line, " CYC:").and_then(|cycle| cycle.parse().ok()),
+                )
+            }
+            TraceFormat::Fceux => (
+                line.strip_prefix('$')?.get(0..4)?,
+                Some(parse_bytes(line.get(6..15)?)?),
+                labeled_hex(line, " S:")?,
+                None,
+                None,
+            ),
+            TraceFormat::Mesen => {
+                let scanline = labeled(line, " V:").and_then(|scanline| scanline.parse().ok());
+                let dot = labeled(line, " H:").and_then(|dot| dot.parse().ok());
+                (
+                    line.get(0..4)?,
+                    None,
+                    labeled_hex(line, " S:")?,
+                    scanline.zip(dot),
+                    labeled(line, " Cycle:").and_then(|cycle| cycle.parse().ok()),
+                )
+            }
+        };
+
+        Some(TraceLine {
+            line_number,
+            text: line.to_owned(),
+            pc: u16::from_str_radix(pc, 16).ok()?,
+            bytes,
+            a: labeled_hex(line, " A:")?,
+            x: labeled_hex(line, " X:")?,
+            y: labeled_hex(line, " Y:")?,
+            status: parse_status(labeled(line, " P:")?)?,
+            stack_pointer,
+            ppu,
+            cycle,
+        })
+    }
+
+    /// Guesses the format of a log from its first line that any of the formats can parse
+    pub fn detect(log: &str) -> Option<TraceFormat> {
+        log.lines()
+            .find_map(|line| Self::ALL.iter().copied().find(|format| format.parse(0, line).is_some()))
+    }
+
+    /// Parses all instructions of a log, leaving out any other lines
+    pub fn parse_log(&self, log: &str) -> Vec<TraceLine> {
+        log.lines().enumerate().filter_map(|(i, line)| self.parse(i + 1, line)).collect()
+    }
+}
+
+/// Where two logs first differ
+#[derive(Debug)]
+pub struct Divergence {
+    /// The number of instructions that matched, counted from where the logs were lined up
+    pub matching: usize,
+    /// The matching instructions right before the difference, from the left log
+    pub context: Vec<TraceLine>,
+    pub left: TraceLine,
+    pub right: TraceLine,
+    /// The fields that differ, with the values of the left and the right log
+    pub differences: Vec<(TraceField, String, String)>,
+}
+
+impl Display for Divergence {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f, "The logs differ after {} matching instructions, at line {} of the left log and line {} of the right log:",
+            self.matching, self.left.line_number, self.right.line_number
+        )?;
+        for (field, left, right) in &self.differences {
+            writeln!(f, "  {} is {} on the left and {} on the right", field.name(), left, right)?;
+        }
+        writeln!(f)?;
+        for line in &self.context {
+            writeln!(f, "  {:>7} {}", line.line_number, line.text)?;
+        }
+        writeln!(f, "< {:>7} {}", self.left.line_number, self.left.text)?;
+        writeln!(f, "> {:>7} {}", self.right.line_number, self.right.text)
+    }
+}
+
+/// The outcome of comparing two logs
+#[derive(Debug)]
+pub enum TraceComparison {
+    /// Every instruction matched, until one of the logs ended
+    Matching {
+        instructions: usize,
+        /// The instructions that are left in the longer log
+        left_over: usize,
+    },
+    Diverged(Box<Divergence>),
+    /// The first instruction of neither log shows up in the other one
+    Unaligned,
+}
+
+impl Display for TraceComparison {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TraceComparison::Matching { instructions, left_over: 0 } =>
+                writeln!(f, "The logs match. Both contain {} instructions", instructions),
+            TraceComparison::Matching { instructions, left_over } =>
+                writeln!(f, "The first {} instructions match. The longer log contains {} more", instructions, left_over),
+            TraceComparison::Diverged(divergence) => divergence.fmt(f),
+            TraceComparison::Unaligned => writeln!(f, "The logs could not be lined up. The first instruction of neither log is in the other one"),
+        }
+    }
+}
+
+/// Finds the first instruction of `log` that matches the first instruction of `start`
+fn find_start(start: &[TraceLine], log: &[TraceLine], skip: &[TraceField]) -> Option<usize> {
+    let first = start.first()?;
+    log.iter().position(|line| first.differences(line, skip).is_empty())
+}
+
+/// Lines up two logs and compares them instruction by instruction, ignoring the fields in `skip`.
+/// If they differ, the `context` instructions before the difference are kept
+pub fn compare(left: &[TraceLine], right: &[TraceLine], skip: &[TraceField], context: usize) -> TraceComparison {
+    let (left, right) = match (find_start(left, right, skip), find_start(right, left, skip)) {
+        (Some(start), _) => (left, &right[start..]),
+        (None, Some(start)) => (&left[start..], right),
+        (None, None) if left.is_empty() || right.is_empty() => (left, right),
+        (None, None) => return TraceComparison::Unaligned,
+    };
+
+    for (i, (left_line, right_line)) in left.iter().zip(right).enumerate() {
+        let differences = left_line.differences(right_line, skip);
+        if !differences.is_empty() {
+            return TraceComparison::Diverged(Box::new(Divergence {
+                matching: i,
+                context: left[i.saturating_sub(context)..i].to_vec(),
+                left: left_line.clone(),
+                right: right_line.clone(),
+                differences,
+            }));
+        }
+    }
+
+    TraceComparison::Matching {
+        instructions: left.len().min(right.len()),
+        left_over: left.len().max(right.len()) - left.len().min(right.len()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cpu6502::assembler::assemble;
+    use crate::cpu6502::trace::{RingBufferSink, TraceFormat};
+    use crate::cpu6502::trace_diff::{compare, TraceComparison, TraceField};
+
+    const NINTENDULATOR: &str = "\
+C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7
+C5F5  A2 00     LDX #$00                        A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 30 CYC:10
+C5F7  86 00     STX $00 = 00                    A:00 X:00 Y:00 P:26 SP:FD PPU:  0, 36 CYC:12
+C5F9  E8        INX                             A:00 X:00 Y:00 P:26 SP:FD PPU:  0, 45 CYC:15
+C5FA  4C F9 C5  JMP $C5F9                       A:00 X:01 Y:00 P:24 SP:FD PPU:  0, 51 CYC:17";
+
+    #[test]
+    fn parse_test() {
+        let line = TraceFormat::Nintendulator.parse(3, "C5F7  86 00     STX $00 = 00                    A:01 X:02 Y:03 P:A6 SP:FB PPU:241, 36 CYC:12").unwrap();
+        assert_eq!((line.line_number, line.pc, line.bytes.clone()), (3, 0xC5F7, Some(vec![0x86, 0x00])));
+        assert_eq!((line.a, line.x, line.y, line.status, line.stack_pointer), (1, 2, 3, 0xA6, 0xFB));
+        assert_eq!((line.ppu, line.cycle), (Some((241, 36)), Some(12)));
+
+        let line = TraceFormat::Fceux.parse(1, "$C002:B1 89     LDA ($89),Y @ $0300 = #$5A        A:5A X:00 Y:00 S:FD P:nvUbdIzc").unwrap();
+        assert_eq!((line.pc, line.bytes, line.a, line.status, line.stack_pointer), (0xC002, Some(vec![0xB1, 0x89]), 0x5A, 0x24, 0xFD));
+        assert_eq!((line.ppu, line.cycle), (None, None));
+
+        let line = TraceFormat::Mesen.parse(1, "C005  JMP ($0200) [$DB7E]                        A:2D X:00 Y:00 S:FD P:NvUbdIZC V:-1  H:340 Cycle:20").unwrap();
+        assert_eq!((line.pc, line.bytes, line.a, line.status), (0xC005, None, 0x2D, 0xA7));
+        assert_eq!((line.ppu, line.cycle), (Some((-1, 340)), Some(20)));
+
+        assert!(TraceFormat::Nintendulator.parse(1, "Reset").is_none(), "Other lines should be left out");
+        assert_eq!(TraceFormat::detect(&format!("Log start\n{}", NINTENDULATOR)), Some(TraceFormat::Nintendulator));
+        assert_eq!(TraceFormat::detect("$C002:B1 89     LDA ($89),Y  A:5A X:00 Y:00 S:FD P:nvUbdIzc"), Some(TraceFormat::Fceux));
+    }
+
+    #[test]
+    fn without_ppu_test() {
+        let mut cpu = assemble("
+                *= $8000
+                ldx #$03
+        loop    dex
+                bne loop
+        ").expect("Error assembling").cpu();
+        let buffer = RingBufferSink::new(16);
+        cpu.set_trace_sink(Box::new(buffer.clone()));
+        for _ in 0..7 {
+            cpu.step();
+        }
+
+        // Our own logs of a cpu on flat memory have no ppu position
+        let log = buffer.lines(TraceFormat::Nintendulator).join("\n");
+        assert_eq!(TraceFormat::detect(&log), Some(TraceFormat::Nintendulator));
+        let lines = TraceFormat::Nintendulator.parse_log(&log);
+        assert_eq!(lines.len(), 7, "Every instruction should be parsed:\n{}", log);
+        assert_eq!((lines[1].pc, lines[1].x, lines[1].ppu, lines[1].cycle), (0x8002, 3, None, Some(9)));
+
+        match compare(&lines, &lines, &[], 2) {
+            TraceComparison::Matching { instructions, left_over } => assert_eq!((instructions, left_over), (7, 0)),
+            other => panic!("A log should match itself:\n{}", other),
+        }
+    }
+
+    #[test]
+    fn matching_test() {
+        let left = TraceFormat::Nintendulator.parse_log(NINTENDULATOR);
+        let right = TraceFormat::Mesen.parse_log("\
+Reset
+C5F5  LDX #$00                                   A:00 X:00 Y:00 S:FD P:nvUbdIzc V:0   H:30  Cycle:10
+C5F7  STX $00 = #$00                             A:00 X:00 Y:00 S:FD P:nvUbdIZc V:0   H:36  Cycle:12
+C5F9  INX                                        A:00 X:00 Y:00 S:FD P:nvUbdIZc V:0   H:45  Cycle:15");
+
+        match compare(&left, &right, &[], 2) {
+            TraceComparison::Matching { instructions, left_over } => assert_eq!((instructions, left_over), (3, 1)),
+            other => panic!("The logs should match after lining them up:\n{}", other),
+        }
+    }
+
+    #[test]
+    fn divergence_test() {
+        let left = TraceFormat::Nintendulator.parse_log(NINTENDULATOR);
+        let right = TraceFormat::Nintendulator.parse_log(&NINTENDULATOR
+            .replace("P:26 SP:FD PPU:  0, 45 CYC:15", "P:A4 SP:FD PPU:  0, 48 CYC:16"));
+
+        let divergence = match compare(&left, &right, &[], 2) {
+            TraceComparison::Diverged(divergence) => divergence,
+            other => panic!("The logs should differ:\n{}", other),
+        };
+        assert_eq!((divergence.matching, divergence.left.line_number), (3, 4));
+        assert_eq!(divergence.context.iter().map(|line| line.pc).collect::<Vec<_>>(), vec![0xC5F5, 0xC5F7]);
+        assert_eq!(divergence.differences, vec![
+            (TraceField::P, "06".to_owned(), "84".to_owned()),
+            (TraceField::Ppu, "0,45".to_owned(), "0,48".to_owned()),
+            (TraceField::Cycle, "15".to_owned(), "16".to_owned()),
+        ]);
+        assert!(divergence.to_string().contains("p is 06 on the left and 84 on the right"), "{}", divergence);
+
+        let skipped = compare(&left, &right, &[TraceField::Ppu, TraceField::Cycle], 2);
+        match skipped {
+            TraceComparison::Diverged(divergence) => assert_eq!(divergence.differences.len(), 1),
+            other => panic!("The status should still differ:\n{}", other),
+        }
+        assert!(matches!(compare(&left, &right, &[TraceField::P, TraceField::Ppu, TraceField::Cycle], 2), TraceComparison::Matching { .. }));
+    }
+}
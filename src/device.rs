@@ -0,0 +1,122 @@
+//! Devices on the cpu bus, and how addresses are decoded to them.
+//!
+//! Every device registers for a range of addresses. Devices that only decode some of the address lines, like the 2kb of RAM
+//! that show up 4 times in the first 8kb, give a mirroring mask, and only see the address ANDed with it.
+//! Ranges can overlap. The device with the highest priority is asked first, and if it doesn't answer (e.g. a cartridge
+//! whose mapper doesn't map the address), the next one gets its chance. Devices with the same priority are asked
+//! in the order they were registered. If no device answers a read, the value left on the data bus is read ("open bus").
+//!
+//! The built in devices of the console are registered by `Bus` itself. Other devices, like expansion hardware,
+//! test devices or debugger overlays, implement `BusDevice` and are registered with `Bus::register_device`.
+
+use std::ops::RangeInclusive;
+
+/// The priority of the cartridge. It has "priority access" to the whole address space, so it is asked before the console
+pub const CARTRIDGE_PRIORITY: i8 = 16;
+/// The priority of registers inside the range of another device, like the controller ports and $4014 in the APU's range
+pub const REGISTER_PRIORITY: i8 = 1;
+/// The priority of the RAM, the ppu and the APU
+pub const DEVICE_PRIORITY: i8 = 0;
+
+/// A device that can be connected to the cpu bus. The address it is given has already been mirrored
+pub trait BusDevice: Send {
+    /// Reads from the device. `open_bus` is the value left on the data bus, for the bits the device doesn't drive.
+    /// Returns None if the device doesn't answer, so the next device gets a chance
+    fn read(&mut self, addr: u16, open_bus: u8) -> Option<u8> {
+        self.peek(addr, open_bus)
+    }
+
+    /// What reading from the device would return, without changing its state
+    fn peek(&self, addr: u16, open_bus: u8) -> Option<u8>;
+
+    /// Writes to the device. Returns false if the device ignores the write, so the next device gets a chance
+    fn write(&mut self, addr: u16, data: u8) -> bool;
+}
+
+/// The addresses a device is registered for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    start: u16,
+    end: u16,
+    /// The address lines the device decodes. The address is ANDed with it before it is handed to the device
+    mask: u16,
+    priority: i8,
+}
+
+impl Mapping {
+    pub fn new(range: RangeInclusive<u16>, mask: u16, priority: i8) -> Self {
+        Mapping {
+            start: *range.start(),
+            end: *range.end(),
+            mask,
+            priority,
+        }
+    }
+
+    /// The address as the device sees it, if the device is mapped there
+    pub fn decode(&self, addr: u16) -> Option<u16> {
+        if (self.start..=self.end).contains(&addr) {
+            Some(addr & self.mask)
+        } else {
+            None
+        }
+    }
+}
+
+/// The mappings of all devices on a bus, ordered by priority. `D` identifies the devices
+#[derive(Debug, Clone)]
+pub struct AddressMap<D> {
+    mappings: Vec<(Mapping, D)>,
+}
+
+impl<D> Default for AddressMap<D> {
+    fn default() -> Self {
+        AddressMap { mappings: Vec::new() }
+    }
+}
+
+impl<D: Copy> AddressMap<D> {
+    pub fn new() -> Self {
+        AddressMap::default()
+    }
+
+    /// Maps the device into the address space. It is asked after the devices registered before it with the same priority
+    pub fn register(&mut self, mapping: Mapping, device: D) {
+        let index = self.mappings.iter().position(|(other, _)| other.priority < mapping.priority).unwrap_or(self.mappings.len());
+        self.mappings.insert(index, (mapping, device));
+    }
+
+    /// The amount of mappings
+    pub fn mapping_count(&self) -> usize {
+        self.mappings.len()
+    }
+
+    /// The device of the n-th mapping in the order the devices are asked, and the address as it sees it, if it is mapped there.
+    /// Looking mappings up by index leaves the bus free to access its devices while it goes through them
+    pub fn decode(&self, n: usize, addr: u16) -> Option<(D, u16)> {
+        let (mapping, device) = self.mappings[n];
+        mapping.decode(addr).map(|addr| (device, addr))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::device::{AddressMap, Mapping};
+
+    /// The devices asked for the address, in order
+    fn devices(map: &AddressMap<char>, addr: u16) -> Vec<(char, u16)> {
+        (0..map.mapping_count()).filter_map(|n| map.decode(n, addr)).collect()
+    }
+
+    #[test]
+    fn decode_test() {
+        let mut map = AddressMap::new();
+        map.register(Mapping::new(0x0000..=0x1FFF, 0x07FF, 0), 'a');
+        map.register(Mapping::new(0x1000..=0x1FFF, 0xFFFF, 1), 'b');
+        map.register(Mapping::new(0x0000..=0xFFFF, 0xFFFF, 0), 'c');
+
+        assert_eq!(devices(&map, 0x0812), vec![('a', 0x0012), ('c', 0x0812)], "The address should be mirrored with the mask");
+        assert_eq!(devices(&map, 0x1812), vec![('b', 0x1812), ('a', 0x0012), ('c', 0x1812)], "Higher priorities should be asked first");
+        assert_eq!(devices(&map, 0x2000), vec![('c', 0x2000)]);
+    }
+}
@@ -0,0 +1,137 @@
+//! The DMA unit of the 2A03, which copies memory while the cpu is halted.
+//!
+//! Writing a page number to $4014 starts an OAM DMA, which copies that page of cpu memory to the ppu's object attribute memory.
+//! The DMA unit first halts the cpu for a cycle. It only reads on even ("get") cycles and writes on odd ("put") cycles,
+//! so it may have to wait another cycle to get in step, before it alternates between reading a byte and writing it to $2004.
+//! In total, the cpu is halted for 513 or 514 cycles.
+//!
+//! Each cycle, `Dma::cycle` decides what the DMA unit uses the bus for. DMC sample fetches will be another kind of request
+//! that is served there, taking get cycles away from the OAM transfer.
+
+/// What the DMA unit does during one cpu cycle
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaCycle {
+    /// The cycle the cpu is halted in
+    Halt,
+    /// Waiting for a get cycle, to get in step
+    Align,
+    /// Reading a byte from cpu memory. The byte has to be handed back with `Dma::read_complete`
+    Read(u16),
+    /// Writing the byte read before to the ppu's OAM data register
+    Write(u8),
+}
+
+/// A running OAM DMA
+#[derive(Debug, Clone, Copy)]
+struct OamTransfer {
+    page: u8,
+    /// The amount of bytes read so far
+    read: u16,
+    /// The byte read, that still has to be written
+    data: Option<u8>,
+    halted: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Dma {
+    oam: Option<OamTransfer>,
+}
+
+impl Dma {
+    pub fn new() -> Self {
+        Dma::default()
+    }
+
+    /// Cancels a running transfer. The reset button halts the DMA unit along with the rest of the 2A03
+    pub fn reset(&mut self) {
+        self.oam = None;
+    }
+
+    /// Starts copying the given page of cpu memory to OAM. This is what a write to $4014 does
+    pub fn start_oam(&mut self, page: u8) {
+        self.oam = Some(OamTransfer {
+            page,
+            read: 0,
+            data: None,
+            halted: false,
+        });
+    }
+
+    /// Whether the DMA unit needs the bus. While it does, the cpu is halted
+    pub fn is_active(&self) -> bool {
+        self.oam.is_some()
+    }
+
+    /// Decides what to do in the current cpu cycle. `get_cycle` is true on the cycles the DMA unit can read on
+    pub fn cycle(&mut self, get_cycle: bool) -> Option<DmaCycle> {
+        let oam = self.oam.as_mut()?;
+
+        if !oam.halted {
+            oam.halted = true;
+            return Some(DmaCycle::Halt);
+        }
+
+        match oam.data.take() {
+            Some(data) if !get_cycle => {
+                if oam.read == 256 {
+                    self.oam = None;
+                }
+                Some(DmaCycle::Write(data))
+            }
+            Some(data) => {
+                // Reads and writes always alternate once in step, so this doesn't happen
+                oam.data = Some(data);
+                Some(DmaCycle::Align)
+            }
+            None if get_cycle => {
+                let addr = (oam.page as u16) << 8 | oam.read;
+                oam.read += 1;
+                Some(DmaCycle::Read(addr))
+            }
+            None => Some(DmaCycle::Align),
+        }
+    }
+
+    /// Hands the byte read in a `DmaCycle::Read` to the DMA unit
+    pub fn read_complete(&mut self, data: u8) {
+        if let Some(oam) = self.oam.as_mut() {
+            oam.data = Some(data);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::dma::{Dma, DmaCycle};
+
+    /// Runs a whole OAM DMA starting on the given cycle and returns the cycles it took and the addresses it read
+    fn run(first_cycle: usize) -> (usize, Vec<u16>) {
+        let mut dma = Dma::new();
+        dma.start_oam(0x02);
+
+        let mut cycles = 0;
+        let mut reads = Vec::new();
+        while let Some(cycle) = dma.cycle((first_cycle + cycles) & 1 == 0) {
+            cycles += 1;
+            match cycle {
+                DmaCycle::Read(addr) => {
+                    reads.push(addr);
+                    dma.read_complete(addr as u8);
+                }
+                DmaCycle::Write(data) => assert_eq!(Some(&(data as u16 | 0x0200)), reads.last(), "Wrong byte written"),
+                DmaCycle::Halt | DmaCycle::Align => {}
+            }
+        }
+        (cycles, reads)
+    }
+
+    #[test]
+    fn oam_dma_timing_test() {
+        let (even, reads) = run(0);
+        let (odd, _) = run(1);
+
+        assert_eq!(reads, (0x0200..=0x02FF).collect::<Vec<_>>(), "The whole page should be read in order");
+        assert_eq!(even, 514, "Halting on a get cycle requires an alignment cycle");
+        assert_eq!(odd, 513);
+    }
+}
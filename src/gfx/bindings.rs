@@ -0,0 +1,327 @@
+//! Which keys do what in the app.
+//!
+//! Every key can be bound to an action of the debugger, or to a button of one of the two controllers.
+//! Turbo buttons press A or B over and over again while their key is held, a configurable amount of times per second.
+//!
+//! The bindings are stored in a plain text file with one binding per line, so everyone can use their own layout:
+//! ```text
+//! # Lines starting with # are comments
+//! turbo_rate = 15
+//! Space = run
+//! X = 1 a
+//! D = 1 turbo_a
+//! ```
+//! Keys are named like the variants of minifb's `Key`, controllers are numbered 1 and 2.
+
+use crate::controller::Buttons;
+use minifb::{Key, KeyRepeat};
+use std::fmt::Display;
+use std::time::Duration;
+
+/// Everything a key can do in the debugger
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Run the next instruction
+    Step,
+    /// Run until the current frame is complete
+    Frame,
+    /// Start or stop running the emulation
+    Run,
+    /// Switch between the code and the stack
+    Stack,
+    /// Switch the picture between the screen, the pattern tables and the name tables
+    View,
+    /// Press the reset button
+    Reset,
+    /// Switch the machine off and on again
+    PowerCycle,
+    /// Toggle the trace log on stdout
+    Trace,
+}
+
+impl Action {
+    pub const ALL: &'static [Action] = &[Action::Step, Action::Frame, Action::Run, Action::Stack, Action::View, Action::Reset, Action::PowerCycle, Action::Trace];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Action::Step => "step",
+            Action::Frame => "frame",
+            Action::Run => "run",
+            Action::Stack => "stack",
+            Action::View => "view",
+            Action::Reset => "reset",
+            Action::PowerCycle => "power_cycle",
+            Action::Trace => "trace",
+        }
+    }
+
+    /// Looks up an action by its name. The name is case insensitive
+    pub fn from_name(name: &str) -> Option<Action> {
+        let name = name.to_ascii_lowercase();
+        Self::ALL.iter().copied().find(|action| action.name() == name)
+    }
+
+    /// Whether the action is repeated while its key is held. Stepping is, so the code can be run through slowly
+    fn key_repeat(&self) -> KeyRepeat {
+        match self {
+            Action::Step => KeyRepeat::Yes,
+            _ => KeyRepeat::No,
+        }
+    }
+}
+
+/// What a key is bound to. Controller ports are 0 and 1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Binding {
+    Action(Action),
+    /// A button of a controller, held down as long as the key is
+    Button(usize, Buttons),
+    /// A or B of a controller, pressed and released at the turbo rate as long as the key is held
+    Turbo(usize, Buttons),
+}
+
+/// The names of the controller buttons in the bindings file
+const BUTTON_NAMES: [(&str, Buttons); 8] = [
+    ("a", Buttons::A),
+    ("b", Buttons::B),
+    ("select", Buttons::SELECT),
+    ("start", Buttons::START),
+    ("up", Buttons::UP),
+    ("down", Buttons::DOWN),
+    ("left", Buttons::LEFT),
+    ("right", Buttons::RIGHT),
+];
+
+/// Every key that can be bound. minifb can't list its keys, and they are named by their `Debug` output in the bindings file
+const KEYS: &[Key] = &[
+    Key::Key0, Key::Key1, Key::Key2, Key::Key3, Key::Key4, Key::Key5, Key::Key6, Key::Key7, Key::Key8, Key::Key9,
+    Key::A, Key::B, Key::C, Key::D, Key::E, Key::F, Key::G, Key::H, Key::I, Key::J, Key::K, Key::L, Key::M,
+    Key::N, Key::O, Key::P, Key::Q, Key::R, Key::S, Key::T, Key::U, Key::V, Key::W, Key::X, Key::Y, Key::Z,
+    Key::F1, Key::F2, Key::F3, Key::F4, Key::F5, Key::F6, Key::F7, Key::F8, Key::F9, Key::F10, Key::F11, Key::F12,
+    Key::F13, Key::F14, Key::F15,
+    Key::Down, Key::Left, Key::Right, Key::Up,
+    Key::Apostrophe, Key::Backquote, Key::Backslash, Key::Comma, Key::Equal, Key::LeftBracket, Key::Minus, Key::Period,
+    Key::RightBracket, Key::Semicolon, Key::Slash, Key::Backspace, Key::Delete, Key::End, Key::Enter, Key::Escape,
+    Key::Home, Key::Insert, Key::Menu, Key::PageDown, Key::PageUp, Key::Pause, Key::Space, Key::Tab,
+    Key::NumLock, Key::CapsLock, Key::ScrollLock, Key::LeftShift, Key::RightShift, Key::LeftCtrl, Key::RightCtrl,
+    Key::NumPad0, Key::NumPad1, Key::NumPad2, Key::NumPad3, Key::NumPad4, Key::NumPad5, Key::NumPad6, Key::NumPad7,
+    Key::NumPad8, Key::NumPad9, Key::NumPadDot, Key::NumPadSlash, Key::NumPadAsterisk, Key::NumPadMinus, Key::NumPadPlus,
+    Key::NumPadEnter, Key::LeftAlt, Key::RightAlt, Key::LeftSuper, Key::RightSuper,
+];
+
+/// Looks up a key by its name. The name is case insensitive
+fn key_from_name(name: &str) -> Option<Key> {
+    KEYS.iter().copied().find(|key| format!("{:?}", key).eq_ignore_ascii_case(name))
+}
+
+/// The keys and what they are bound to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bindings {
+    keys: Vec<(Key, Binding)>,
+    /// How many times per second turbo buttons are pressed
+    turbo_rate: u32,
+}
+
+/// The layout of the debugger so far, with the first controller on Z, X, the arrow keys, Enter and the right Shift key
+impl Default for Bindings {
+    fn default() -> Self {
+        let mut bindings = Bindings { keys: Vec::new(), turbo_rate: 15 };
+        bindings.bind(Key::C, Binding::Action(Action::Step));
+        bindings.bind(Key::F, Binding::Action(Action::Frame));
+        bindings.bind(Key::Space, Binding::Action(Action::Run));
+        bindings.bind(Key::S, Binding::Action(Action::Stack));
+        bindings.bind(Key::V, Binding::Action(Action::View));
+        bindings.bind(Key::R, Binding::Action(Action::Reset));
+        bindings.bind(Key::P, Binding::Action(Action::PowerCycle));
+        bindings.bind(Key::T, Binding::Action(Action::Trace));
+        for &(key, button) in &[
+            (Key::X, Buttons::A), (Key::Z, Buttons::B), (Key::RightShift, Buttons::SELECT), (Key::Enter, Buttons::START),
+            (Key::Up, Buttons::UP), (Key::Down, Buttons::DOWN), (Key::Left, Buttons::LEFT), (Key::Right, Buttons::RIGHT),
+        ] {
+            bindings.bind(key, Binding::Button(0, button));
+        }
+        bindings.bind(Key::D, Binding::Turbo(0, Buttons::A));
+        bindings.bind(Key::A, Binding::Turbo(0, Buttons::B));
+        bindings
+    }
+}
+
+impl Bindings {
+    /// Binds the key, replacing what it was bound to before
+    pub fn bind(&mut self, key: Key, binding: Binding) {
+        self.keys.retain(|&(bound, _)| bound != key);
+        self.keys.push((key, binding));
+    }
+
+    /// Parses bindings in the format of the bindings file. Keys that aren't mentioned are not bound
+    pub fn parse(text: &str) -> Result<Bindings, String> {
+        let mut bindings = Bindings { keys: Vec::new(), turbo_rate: Bindings::default().turbo_rate };
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = |message: String| format!("Line {}: {}", number + 1, message);
+
+            let (name, value) = line.split_once('=').ok_or_else(|| error(format!("Expected <key> = <binding>, found {}", line)))?;
+            let (name, value) = (name.trim(), value.trim());
+            if name == "turbo_rate" {
+                bindings.turbo_rate = value.parse().ok().filter(|&rate| rate > 0)
+                    .ok_or_else(|| error(format!("The turbo rate has to be a positive number, not {}", value)))?;
+                continue;
+            }
+
+            let key = key_from_name(name).ok_or_else(|| error(format!("Unknown key {}", name)))?;
+            let binding = Self::parse_binding(value).map_err(error)?;
+            bindings.bind(key, binding);
+        }
+        Ok(bindings)
+    }
+
+    /// Parses an action, or a controller button like `1 a` or `2 turbo_b`
+    fn parse_binding(value: &str) -> Result<Binding, String> {
+        if let Some(action) = Action::from_name(value) {
+            return Ok(Binding::Action(action));
+        }
+
+        let (port, button) = value.split_once(' ').ok_or_else(|| format!("Unknown action {}", value))?;
+        let port = match port {
+            "1" => 0,
+            "2" => 1,
+            _ => return Err(format!("There is no controller {}, only 1 and 2", port)),
+        };
+        let button = button.trim().to_ascii_lowercase();
+        match button.strip_prefix("turbo_") {
+            Some("a") => Ok(Binding::Turbo(port, Buttons::A)),
+            Some("b") => Ok(Binding::Turbo(port, Buttons::B)),
+            Some(_) => Err(format!("Only A and B can be turbo buttons, not {}", button)),
+            None => BUTTON_NAMES.iter()
+                .find(|(name, _)| *name == button)
+                .map(|&(_, button)| Binding::Button(port, button))
+                .ok_or_else(|| format!("Unknown button {}", button)),
+        }
+    }
+
+    /// Loads the bindings from a file
+    pub fn load(path: &str) -> Result<Bindings, String> {
+        let text = std::fs::read_to_string(path).map_err(|error| error.to_string())?;
+        Self::parse(&text)
+    }
+
+    /// Saves the bindings to a file, which `load` can read again
+    pub fn save(&self, path: &str) -> std::io::Result<()> {
+        std::fs::write(path, self.to_string())
+    }
+
+    /// The actions whose keys were pressed. `is_key_pressed` is usually `Window::is_key_pressed`
+    pub fn actions(&self, is_key_pressed: impl Fn(Key, KeyRepeat) -> bool) -> Vec<Action> {
+        self.keys.iter()
+            .filter_map(|&(key, binding)| match binding {
+                Binding::Action(action) if is_key_pressed(key, action.key_repeat()) => Some(action),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The buttons of the controller in the port whose keys are held. `is_key_down` is usually `Window::is_key_down`.
+    /// Turbo buttons are only pressed if `turbo` is true, see `turbo_pressed`
+    pub fn buttons(&self, port: usize, turbo: bool, is_key_down: impl Fn(Key) -> bool) -> Buttons {
+        self.keys.iter()
+            .filter(|&&(key, _)| is_key_down(key))
+            .fold(Buttons::empty(), |buttons, &(_, binding)| match binding {
+                Binding::Button(bound, button) if bound == port => buttons | button,
+                Binding::Turbo(bound, button) if bound == port && turbo => buttons | button,
+                _ => buttons,
+            })
+    }
+
+    /// Whether turbo buttons are pressed in the given frame. They are pressed for the first half of every turbo period
+    pub fn turbo_pressed(&self, frame: u64, frame_duration: Duration) -> bool {
+        let presses = frame as f64 * frame_duration.as_secs_f64() * self.turbo_rate as f64;
+        presses.fract() < 0.5
+    }
+}
+
+/// The bindings in the format of the bindings file
+impl Display for Bindings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "# Key bindings of nest. Keys are named like minifb's Key")?;
+        writeln!(f, "# Actions: {}", Action::ALL.iter().map(Action::name).collect::<Vec<_>>().join(", "))?;
+        writeln!(f, "# Controller buttons: <controller 1 or 2> <{}|turbo_a|turbo_b>", BUTTON_NAMES.iter().map(|(name, _)| *name).collect::<Vec<_>>().join("|"))?;
+        writeln!(f, "# Turbo buttons are pressed turbo_rate times per second")?;
+        writeln!(f, "turbo_rate = {}", self.turbo_rate)?;
+        for &(key, binding) in &self.keys {
+            match binding {
+                Binding::Action(action) => writeln!(f, "{:?} = {}", key, action.name())?,
+                Binding::Button(port, button) => {
+                    let name = BUTTON_NAMES.iter().find(|(_, bound)| *bound == button).map_or("?", |(name, _)| name);
+                    writeln!(f, "{:?} = {} {}", key, port + 1, name)?
+                }
+                Binding::Turbo(port, button) => writeln!(f, "{:?} = {} turbo_{}", key, port + 1, if button == Buttons::A { "a" } else { "b" })?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::controller::Buttons;
+    use crate::gfx::bindings::{Action, Binding, Bindings};
+    use minifb::{Key, KeyRepeat};
+    use std::time::Duration;
+
+    #[test]
+    fn parse_test() {
+        let bindings = Bindings::parse("
+            # Comment
+            turbo_rate = 10
+            space = run
+            NumPad8 = 2 up
+            X = 1 a
+            X = 1 turbo_b
+        ").expect("Error parsing bindings");
+
+        assert_eq!(bindings.turbo_rate, 10);
+        assert_eq!(bindings.keys, vec![
+            (Key::Space, Binding::Action(Action::Run)),
+            (Key::NumPad8, Binding::Button(1, Buttons::UP)),
+            (Key::X, Binding::Turbo(0, Buttons::B)),
+        ], "Binding a key again should replace its binding");
+
+        assert_eq!(Bindings::parse("Foo = run"), Err("Line 1: Unknown key Foo".to_string()));
+        assert_eq!(Bindings::parse("\nX = 3 a"), Err("Line 2: There is no controller 3, only 1 and 2".to_string()));
+        assert_eq!(Bindings::parse("X = 1 turbo_up"), Err("Line 1: Only A and B can be turbo buttons, not turbo_up".to_string()));
+        assert_eq!(Bindings::parse("X = jump"), Err("Line 1: Unknown action jump".to_string()));
+    }
+
+    #[test]
+    fn save_test() {
+        let bindings = Bindings::default();
+        assert_eq!(Bindings::parse(&bindings.to_string()), Ok(bindings), "Saved bindings should load the same");
+    }
+
+    #[test]
+    fn input_test() {
+        let bindings = Bindings::default();
+        let held = [Key::X, Key::Up, Key::A, Key::C, Key::R];
+        let is_down = |key| held.contains(&key);
+
+        assert_eq!(bindings.buttons(0, false, is_down), Buttons::A | Buttons::UP);
+        assert_eq!(bindings.buttons(0, true, is_down), Buttons::A | Buttons::B | Buttons::UP, "The turbo B should be pressed");
+        assert_eq!(bindings.buttons(1, true, is_down), Buttons::empty(), "The second controller isn't bound");
+
+        // Stepping repeats while the key is held, resetting doesn't
+        let repeated = bindings.actions(|key, repeat| is_down(key) && matches!(repeat, KeyRepeat::Yes));
+        assert_eq!(repeated, vec![Action::Step]);
+        assert_eq!(bindings.actions(|key, _| is_down(key)), vec![Action::Step, Action::Reset]);
+    }
+
+    #[test]
+    fn turbo_test() {
+        let bindings = Bindings::parse("turbo_rate = 10").expect("Error parsing bindings");
+        // 50 frames per second, so turbo buttons are pressed every 5 frames
+        let pressed = (0..10).map(|frame| bindings.turbo_pressed(frame, Duration::from_millis(20))).collect::<Vec<_>>();
+        assert_eq!(pressed, vec![true, true, true, false, false, true, true, true, false, false]);
+    }
+}
@@ -1,3 +1,4 @@
 
 pub mod nest_app;
+pub mod bindings;
 pub mod utils;
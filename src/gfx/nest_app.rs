@@ -1,13 +1,18 @@
-use minifb::{Window, WindowOptions, Scale, Key, KeyRepeat};
+use minifb::{Window, WindowOptions, Scale, Key};
 use image::{ImageBuffer, GenericImage};
 use crate::gfx::utils::*;
 use crate::gfx::utils::{create_char_sprites, image_to_vec};
-use crate::{cpu6502, parse_program, bus};
-use std::rc::Rc;
-use std::cell::RefCell;
-use crate::ppu2C02::Ppu2C02;
-use crate::bus::Bus;
+use crate::cpu6502;
+use crate::nes::Nes;
 use crate::cartridge::Cartridge;
+use crate::cpu6502::trace::{RingBufferSink, StdoutSink, TraceFormat, TraceSink};
+use crate::gfx::bindings::{Action, Bindings};
+
+/// Where the key bindings are kept. It is created with the default bindings, if it doesn't exist yet
+const BINDINGS_FILE: &str = "bindings.cfg";
+
+/// How many of the last instructions are printed, if the emulator crashes
+const CRASH_LOG_LENGTH: usize = 64;
 
 pub fn run(game: &str) {
 
@@ -30,8 +35,9 @@ pub fn test_run() {
 
     let sprites = create_char_sprites("res/font_scaled.png", 7, 9);
 
-    let program = "A9 05 AA A9 06 8E 11 11 6D 11 11";
-    let program = cpu6502::disassemble_program(parse_program(program)).join("\n");
+    // lda #$05, tax, lda #$06, stx $1111, adc $1111
+    let program = vec![0xA9, 0x05, 0xAA, 0xA9, 0x06, 0x8E, 0x11, 0x11, 0x6D, 0x11, 0x11];
+    let program = cpu6502::disassemble_program(program).join("\n");
 
     while window.is_open() && !window.is_key_down(Key::Escape) {
         let mut canvas = ImageBuffer::new(WIDTH as u32, HEIGHT as u32);
@@ -47,29 +53,58 @@ pub fn test_run() {
 pub fn test_run2() {
 
     const EDGE_OFFSET: u32 = 5;
-    const WIDTH : usize = 450;
+    const WIDTH : usize = 490;
     const HEIGHT : usize = 250;
 
-    let (mut window, bus, sprites) = setup(WIDTH, HEIGHT);
+    let (mut window, mut nes, sprites) = setup(WIDTH, HEIGHT);
 
-    bus.borrow_mut().insert_cartridge(Cartridge::new("nestest.nes"));
-    bus.borrow_mut().cpu_mut().set_program_counter(0xC000);
+    nes.insert_cartridge(Cartridge::new("nestest.nes"));
+    nes.cpu_mut().set_program_counter(0xC000);
+    // Run at the frame rate of the region the game was made for, 60 or 50 frames per second
+    window.limit_update_rate(Some(nes.region().frame_duration()));
 
-    let mut emulation_run = false;
+    // The keys are bound in the bindings file. Both controllers are played from the keyboard
+    let bindings = load_bindings();
+    let mut frame = 0;
 
-    let disassembly = bus.borrow().cpu().disassemble_range(0x0000, 0xFFFF);
+    // The last instructions are kept, so they can be printed if the emulator crashes.
+    // The trace action switches between this and a full trace log on stdout
+    let crash_log = RingBufferSink::new(CRASH_LOG_LENGTH);
+    print_on_panic(crash_log.clone());
+    let mut trace_stdout = false;
+    nes.cpu_mut().set_trace_sink(Box::new(crash_log.clone()));
+
+    let mut emulation_run = false;
+    // The stack action switches between the code and the stack
+    let mut show_stack = false;
+    // The view action switches the picture between the screen (0), the pattern tables (1) and the name tables (2 and 3)
+    let mut view = 0;
+
+    // Only follow the code of the cartridge, so data in between doesn't mess up the disassembly
+    let analysis = nes.cpu().analyse_flow(0x8000..=0xFFFF, &[0xC000]);
+    let disassembly = analysis.instructions();
     /*let mut temp = disassembly.iter().collect::<Vec<_>>();
     temp.sort_by(|(&a, _), (&b, _)| if (a as i32 - b as i32) > 0 { std::cmp::Ordering::Greater } else if (a as i32 - b as i32) < 0 { std::cmp::Ordering::Less } else { std::cmp::Ordering::Equal });
 
     temp.into_iter().for_each(|(addr, s)| println!("{:0>4X}: {}", addr, s));*/
 
-    //println!("{}", bus.borrow().cpu().disassemble_instr_at(0xC000).0);
+    //println!("{}", nes.cpu().disassemble_instr_at(0xC000).0);
 
     while window.is_open() && !window.is_key_down(Key::Escape) {
         let mut canvas = ImageBuffer::new(WIDTH as u32, HEIGHT as u32);
-        let cpu_state_img = draw_cpu_state(bus.borrow().cpu(), &sprites);
-        let cpu_ops_img = draw_cpu_ops(bus.borrow().cpu(), &disassembly, 15, &sprites);
-        canvas.copy_from(bus.borrow().ppu().get_screen(), EDGE_OFFSET, EDGE_OFFSET).expect("Error copying to image buffer");
+        let cpu_state_img = draw_cpu_state(nes.cpu(), &sprites);
+        let cpu_ops_img = if show_stack {
+            draw_stack(nes.cpu(), 15, &sprites)
+        } else {
+            draw_cpu_ops(nes.cpu(), disassembly, 15, &sprites)
+        };
+        match view {
+            0 => canvas.copy_from(nes.ppu().get_screen(), EDGE_OFFSET, EDGE_OFFSET),
+            // The pattern tables next to each other, in the first background palette
+            1 => canvas.copy_from(nes.bus_mut().draw_pattern_table(0, 0), EDGE_OFFSET, EDGE_OFFSET)
+                .and_then(|_| canvas.copy_from(nes.bus_mut().draw_pattern_table(1, 0), 128 + EDGE_OFFSET, EDGE_OFFSET)),
+            _ => canvas.copy_from(nes.bus_mut().draw_name_table(view - 2), EDGE_OFFSET, EDGE_OFFSET),
+        }.expect("Error copying to image buffer");
         canvas.copy_from(&cpu_state_img, 256 + 2 * EDGE_OFFSET, EDGE_OFFSET).expect("Error copying to image buffer");
 
         // Add each line of ops
@@ -81,67 +116,108 @@ pub fn test_run2() {
             ).expect("Error copying to image buffer");
         });
 
-        if emulation_run {
-            bus.borrow().clock();
-            while !bus.borrow().ppu().is_frame_complete() {
-                bus.borrow().clock();
+        let turbo = bindings.turbo_pressed(frame, nes.region().frame_duration());
+        for port in 0..2 {
+            nes.set_buttons(port, bindings.buttons(port, turbo, |key| window.is_key_down(key)));
+        }
+        for action in bindings.actions(|key, repeat| window.is_key_pressed(key, repeat)) {
+            match action {
+                Action::Run => emulation_run = !emulation_run,
+                Action::Stack => show_stack = !show_stack,
+                Action::View => view = (view + 1) % 4,
+                Action::Trace => {
+                    trace_stdout = !trace_stdout;
+                    let sink: Box<dyn TraceSink> = if trace_stdout {
+                        Box::new(StdoutSink::new(TraceFormat::Nintendulator))
+                    } else {
+                        Box::new(crash_log.clone())
+                    };
+                    nes.cpu_mut().set_trace_sink(sink);
+                }
+                // The other actions are for stepping through the code, while the emulation isn't running
+                _ if !emulation_run => handle_action(&mut nes, &crash_log, action),
+                _ => {}
             }
-            bus.borrow_mut().ppu_mut().set_frame_complete(false);
-        } else {
-            handle_input(&window, bus.clone());
         }
-        if window.is_key_pressed(Key::Space, KeyRepeat::No) { emulation_run = !emulation_run; }
+        if emulation_run {
+            nes.run_frame();
+            nes.ppu_mut().set_frame_complete(false);
+        }
+        frame += 1;
 
         let converted: Vec<u32> = image_to_vec(&canvas);
         window.update_with_buffer(&converted, WIDTH, HEIGHT).unwrap();
     }
 }
 
-fn handle_input(window: &Window, bus: Rc<RefCell<Bus>>) {
+/// Loads the key bindings from the bindings file, or saves the default bindings there, so they can be changed
+fn load_bindings() -> Bindings {
+    if std::path::Path::new(BINDINGS_FILE).exists() {
+        Bindings::load(BINDINGS_FILE).unwrap_or_else(|error| panic!("Error loading {}: {}", BINDINGS_FILE, error))
+    } else {
+        let bindings = Bindings::default();
+        bindings.save(BINDINGS_FILE).expect("Error saving the key bindings");
+        bindings
+    }
+}
 
-    // Code step by step
-    if window.is_key_pressed(Key::C, KeyRepeat::Yes) {
-        let bus = bus.borrow();
-        bus.clock();
-        while !bus.cpu().complete() {
-            bus.clock();
+/// Prints the instructions in the crash log before the panic message, if the emulator panics
+fn print_on_panic(crash_log: RingBufferSink) {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        eprintln!("The last {} instructions before the crash:", CRASH_LOG_LENGTH);
+        for line in crash_log.lines(TraceFormat::Nintendulator) {
+            eprintln!("{}", line);
         }
+        default_hook(info);
+    }));
+}
 
-        // As the CPU runs slower than the system clock, "use up" the rest of the clocks for which the
-        // current instruction is "completed"
-        bus.clock();
-        while bus.cpu().complete() {
-            bus.clock();
-        }
-    }
+/// Runs an action of the debugger. Running, switching to the stack, switching the view and the trace log
+/// are handled in the main loop
+fn handle_action(nes: &mut Nes, crash_log: &RingBufferSink, action: Action) {
+    match action {
+        // Code step by step
+        Action::Step => {
+            nes.clock();
+            while !nes.cpu().complete() {
+                nes.clock();
+            }
 
-    // Emulate entire frame
-    if window.is_key_pressed(Key::F, KeyRepeat::No) {
-        {
-            let bus = bus.borrow();
-            bus.clock();
-            while !bus.ppu().is_frame_complete() {
-                bus.clock();
+            // As the CPU runs slower than the system clock, "use up" the rest of the clocks for which the
+            // current instruction is "completed"
+            nes.clock();
+            while nes.cpu().complete() {
+                nes.clock();
             }
+        }
+
+        // Emulate entire frame
+        Action::Frame => {
+            nes.run_frame();
 
             // As the CPU runs slower than the system clock, "use up" the rest of the clocks for which the
             // current instruction is "completed"
-            bus.clock();
-            while !bus.cpu().complete() {
-                bus.clock();
+            nes.clock();
+            while !nes.cpu().complete() {
+                nes.clock();
             }
+            nes.ppu_mut().set_frame_complete(false);
+        }
+
+        Action::Reset => nes.reset(),
+        // What ran before the power cycle didn't lead up to anything that happens afterwards
+        Action::PowerCycle => {
+            crash_log.clear();
+            nes.power_cycle();
         }
-        let mut bus = bus.borrow_mut();
-        bus.ppu_mut().set_frame_complete(false);
-    }
 
-    if window.is_key_pressed(Key::R, KeyRepeat::No) {
-        bus.borrow_mut().reset();
+        Action::Run | Action::Stack | Action::View | Action::Trace => {}
     }
 }
 
-/// Builds a test window with a bus and font sprite sheet included
-fn setup(width: usize, height: usize) -> (Window, Rc<RefCell<Bus>>, CharacterSheet) {
+/// Builds a test window with a machine and font sprite sheet included
+fn setup(width: usize, height: usize) -> (Window, Nes, CharacterSheet) {
     // Create the window options which are responsible for scale etc
     let mut options = WindowOptions::default();
     options.scale = Scale::X4;
@@ -157,13 +233,9 @@ fn setup(width: usize, height: usize) -> (Window, Rc<RefCell<Bus>>, CharacterShe
     // Limit to max ~60 fps update rate
     window.limit_update_rate(Some(std::time::Duration::from_micros(16600)));
 
-    // Build the Sprite Sheet for the font and create the bus with cpu and ppu
+    // Build the Sprite Sheet for the font and create the machine
     let sprites = create_char_sprites("res/font_scaled.png", 7, 9);
-    let bus = {
-        let cpu = cpu6502::Cpu6502::new();
-        let ppu = Ppu2C02::new();
-        bus::Bus::new(cpu, ppu)
-    };
+    let nes = Nes::new();
 
-    (window, bus, sprites)
+    (window, nes, sprites)
 }
@@ -2,6 +2,8 @@ use image::{SubImage, RgbaImage, ImageBuffer, GenericImage, Rgba, Pixel};
 use std::rc::Rc;
 use std::collections::HashMap;
 use crate::cpu6502::Cpu6502;
+use crate::cpu6502::memory::CpuBus;
+use crate::cpu6502::disassembler::{Disassembly, DisassembledInstruction};
 use crate::cpu6502::Flags6502;
 use std::time::Instant;
 
@@ -107,13 +109,13 @@ pub fn compose_text_with_tint<T: Pixel<Subpixel=u8>>(text: &str, character_sheet
     buffer
 }
 
-pub fn draw_cpu_state<T: std::ops::Deref<Target=Cpu6502>>(cpu: T, character_sheet: &CharacterSheet) -> RgbaImage {
+pub fn draw_cpu_state<B: CpuBus, T: std::ops::Deref<Target=Cpu6502<B>>>(cpu: T, character_sheet: &CharacterSheet) -> RgbaImage {
 
     let (char_w, char_h) = character_sheet[&'a'].dimensions();
     let mut registers: RgbaImage = RgbaImage::new(16 * char_w, 6 * char_h);
     registers.copy_from(
         &compose_text(format!
-             ("STATUS:\nPC: ${:0>4X}\nA: ${:0>2X}\nX: ${:0>2X}\nY: ${:0>2X}\nSP: ${:0>4X}",
+             ("STATUS:\nPC: ${:0>4X}\nA: ${:0>2X}\nX: ${:0>2X}\nY: ${:0>2X}\nSP: ${:0>2X}",
               cpu.get_program_counter(),
               cpu.get_acc(),
               cpu.get_x(),
@@ -156,43 +158,37 @@ pub fn draw_cpu_state<T: std::ops::Deref<Target=Cpu6502>>(cpu: T, character_shee
     registers
 }
 
-pub fn draw_cpu_ops<T: std::ops::Deref<Target=Cpu6502>>(cpu: T, disassembly: &HashMap<u16, String>, n: usize, character_sheet: &CharacterSheet) -> Vec<RgbaImage> {
-    let mut lines = Vec::new();
+pub fn draw_cpu_ops<B: CpuBus, T: std::ops::Deref<Target=Cpu6502<B>>>(cpu: T, disassembly: &Disassembly, n: usize, character_sheet: &CharacterSheet) -> Vec<RgbaImage> {
+    let pc = cpu.get_program_counter();
+    let format_instr = |instr: &DisassembledInstruction| format!("${:0>4X}: {}", instr.address, instr);
+
+    // Draw instructions before the pc. Not every memory address contains the start of an instruction,
+    // but the disassembly only contains the addresses that do
+    let mut lines = disassembly.range(..pc)
+        .rev()
+        .take(n / 2)
+        .map(|(_, instr)| compose_text(format_instr(instr).as_str(), character_sheet))
+        .collect::<Vec<_>>();
+    lines.reverse();
 
     // Draw the instruction at the program counter
-    if disassembly.contains_key(&cpu.get_program_counter()) {
-        lines.push(
-            compose_text_with_tint( format!("${:0>4X}: {}", cpu.get_program_counter(), disassembly[&cpu.get_program_counter()]).as_str(), &character_sheet, Rgba([0, 255, 255, 255]))
-        );
+    if let Some(instr) = disassembly.get(&pc) {
+        lines.push(compose_text_with_tint(format_instr(instr).as_str(), character_sheet, Rgba([0, 255, 255, 255])));
     }
 
-    // Draw instructions before the pc
-    let mut count = 0;
-    let mut current_addr = cpu.get_program_counter();
-    while count < n as u16 / 2 {
-        if current_addr == 0 { break; }
-        current_addr -= 1;
-        // Not every memory address contains the start of an instruction
-        if disassembly.contains_key(&current_addr) {
-            let instr = disassembly[&current_addr].as_str();
-            lines.insert(0, compose_text(format!("${:0>4X}: {}", current_addr, disassembly[&current_addr]).as_str(), &character_sheet));
-            count += 1;
-        }
-    }
     // Draw instructions after the pc
-    let mut count = 0;
-    let mut current_addr = cpu.get_program_counter();
-    while count < n as u16 / 2 {
-        if current_addr == 0xFFFF { break; }
-        current_addr += 1;
-
-        // Not every memory address contains the start of an instruction
-        if disassembly.contains_key(&current_addr) {
-            let instr = disassembly[&current_addr].as_str();
-            lines.push( compose_text(format!("${:0>4X}: {}", current_addr, disassembly[&current_addr]).as_str(), &character_sheet));
-            count += 1;
-        }
-    }
+    lines.extend(disassembly.range(pc.saturating_add(1)..)
+        .take(n / 2)
+        .map(|(_, instr)| compose_text(format_instr(instr).as_str(), character_sheet)));
 
     lines
-}
\ No newline at end of file
+}
+
+/// Draws the top `n` entries of the stack, with return addresses and pushed status bytes decoded. See `Cpu6502::stack_view`
+pub fn draw_stack<B: CpuBus, T: std::ops::Deref<Target=Cpu6502<B>>>(cpu: T, n: usize, character_sheet: &CharacterSheet) -> Vec<RgbaImage> {
+    let mut lines = vec![compose_text(&format!("STACK: SP ${:0>2X}", cpu.get_stack_pointer()), character_sheet)];
+    lines.extend(cpu.stack_view().iter()
+        .take(n)
+        .map(|entry| compose_text(&entry.to_string(), character_sheet)));
+    lines
+}
@@ -1,12 +1,15 @@
 #[macro_use]
 extern crate bitflags;
 
-#[macro_use]
-extern crate lazy_static;
-
-use crate::ppu2C02::Ppu2C02;
 use crate::gfx::nest_app;
-use crate::cpu6502::Cpu6502;
+use crate::cpu6502::flow_disassembler::{FlowDisassembler, JumpTable};
+use crate::cpu6502::source_export::SourceDialect;
+use crate::cpu6502::trace::{FileSink, FilteredSink, StdoutSink, TraceFormat, TraceSink};
+use crate::cpu6502::trace_diff::{TraceComparison, TraceField};
+use crate::cartridge::Cartridge;
+use crate::nes::Nes;
+use crate::region::Region;
+use crate::power_on::PowerOnProfile;
 
 
 mod bus;
@@ -15,33 +18,275 @@ mod ppu2C02;
 mod mappers;
 mod cartridge;
 mod gfx;
+mod apu2A03;
+mod controller;
+mod dma;
+mod device;
+mod nes;
+mod region;
+mod power_on;
 
 fn main() {
-    let _bus = bus::Bus::new(Cpu6502::new(), Ppu2C02::new());
-    /*
-    let program = "A9 05 AA A9 06 8E 11 11 6D 11 11";
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    match args.first().map(String::as_str) {
+        Some("export-bank") => return export_bank(&args[1..]),
+        Some("disasm") => return disasm(&args[1..]),
+        Some("trace") => return trace(&args[1..]),
+        Some("trace-diff") => return trace_diff(&args[1..]),
+        Some("bench") => return bench(&args[1..]),
+        _ => {}
+    }
+
+    //Cartridge::new("Super Mario Bros (E).nes");
+    nest_app::test_run2();
+
+
+
+}
+
+/// Writes a program bank of a ROM as assembler source.
+/// Usage: `nest export-bank <rom> <bank> <output file> [ca65|asm6] [origin]`
+fn export_bank(args: &[String]) {
+    const USAGE: &str = "Usage: nest export-bank <rom> <bank> <output file> [ca65|asm6] [origin]";
 
-    for (i, b) in parse_program(program).into_iter().enumerate() {
-        bus.borrow_mut().cpu_write(i as u16, b);
+    let rom = args.first().expect(USAGE);
+    let bank = args.get(1).expect(USAGE).parse::<usize>().expect("The bank has to be a number");
+    let output = args.get(2).expect(USAGE);
+    let dialect = match args.get(3).map(String::as_str) {
+        None | Some("ca65") => SourceDialect::Ca65,
+        Some("asm6") => SourceDialect::Asm6,
+        Some(other) => panic!("Unknown assembler {}. {}", other, USAGE),
+    };
+
+    let cartridge = Cartridge::new(rom);
+
+    // The last bank contains the interrupt vectors, so it is usually mapped to the end of the address space
+    let default_origin = if bank + 1 == cartridge.program_bank_count() { 0xC000 } else { 0x8000 };
+    let origin = args.get(4)
+        .map(|origin| u16::from_str_radix(origin.trim_start_matches('$'), 16).expect("The origin has to be a hex address"))
+        .unwrap_or(default_origin);
+
+    let source = cpu6502::source_export::export_bank(cartridge.program_bank(bank), origin, bank, &[], dialect);
+    std::fs::write(output, source).expect("Error writing source file");
+}
+
+/// Disassembles a program bank of a ROM by following the flow of the code from the interrupt vectors
+/// (if the bank contains them) and the given entry points, and prints the listing with a summary of the labels.
+/// Jump tables are given as `address:count`, or as `lo:hi:count` for tables split into low and high bytes.
+/// `--jump-table-offset 1` is for jump tables that are used by pushing the entry and calling RTS.
+/// Usage: `nest disasm <rom> <bank> [origin] [--entry address[:name]] [--jump-table address:count] [--split-table lo:hi:count] [--jump-table-offset offset] [--output file]`
+fn disasm(args: &[String]) {
+    const USAGE: &str = "Usage: nest disasm <rom> <bank> [origin] [--entry address[:name]] [--jump-table address:count] \
+        [--split-table lo:hi:count] [--jump-table-offset offset] [--output file]";
+
+    let rom = args.first().expect(USAGE);
+    let bank = args.get(1).expect(USAGE).parse::<usize>().expect("The bank has to be a number");
+    let hex = |value: &str| u16::from_str_radix(value.trim_start_matches('$'), 16)
+        .unwrap_or_else(|_| panic!("{} has to be a hex address. {}", value, USAGE));
+
+    let cartridge = Cartridge::new(rom);
+    let data = cartridge.program_bank(bank);
+
+    // The origin is optional, so the options start after it if it is given
+    let (origin, options) = match args.get(2) {
+        Some(origin) if !origin.starts_with("--") => (hex(origin), &args[3..]),
+        // The last bank contains the interrupt vectors, so it is usually mapped to the end of the address space
+        _ if bank + 1 == cartridge.program_bank_count() => (0xC000, &args[2..]),
+        _ => (0x8000, &args[2..]),
+    };
+    let end = origin as usize + data.len() - 1;
+    assert!(end <= 0xFFFF, "The bank does not fit into the address space at ${:0>4X}", origin);
+    let read = |addr: u16| data.get(addr.wrapping_sub(origin) as usize).copied().unwrap_or(0);
+
+    let mut disassembler = FlowDisassembler::new(origin..=end as u16, read);
+    if end == 0xFFFF {
+        disassembler = disassembler.with_vectors();
     }
 
-    for instr in cpu6502::disassemble(parse_program(program)).into_iter() {
-        println!("{}", instr);
+    if options.len() % 2 == 1 {
+        panic!("Missing value for {}. {}", options.last().unwrap(), USAGE);
+    }
+    let mut output = None;
+    for option in options.chunks(2) {
+        let (name, value) = (option[0].as_str(), option[1].as_str());
+        let fields = value.split(':').collect::<Vec<_>>();
+        let count = |count: &str| count.parse::<u16>().unwrap_or_else(|_| panic!("{} has to be a number. {}", count, USAGE));
+        match (name, fields.as_slice()) {
+            ("--entry", [address]) => disassembler.add_entry_point(hex(address), None),
+            ("--entry", [address, label]) => disassembler.add_entry_point(hex(address), Some(label)),
+            ("--jump-table", [address, n]) => disassembler.add_jump_table(JumpTable::Words { address: hex(address), count: count(n) }),
+            ("--split-table", [lo, hi, n]) => disassembler.add_jump_table(JumpTable::Split { lo: hex(lo), hi: hex(hi), count: count(n) }),
+            ("--jump-table-offset", [offset]) => disassembler.set_jump_table_offset(count(offset)),
+            ("--output", _) => output = Some(value),
+            _ => panic!("Invalid option {} {}. {}", name, value, USAGE),
+        }
     }
 
-    for _ in 0..cpu6502::disassemble(parse_program(program)).len() {
-        cpu.borrow_mut().clock()
+    let analysis = disassembler.run();
+    let listing = format!("{}\n{}", analysis.listing(read), analysis.summary());
+    match output {
+        Some(path) => std::fs::write(path, listing).unwrap_or_else(|e| panic!("Error writing {}: {}", path, e)),
+        None => print!("{}", listing),
     }
+}
 
-    println!("{}", bus.borrow().cpu_read(0x1111, false));
-    println!("{:?}", cpu.borrow());*/
-    //Cartridge::new("Super Mario Bros (E).nes");
-    nest_app::test_run2();
+/// Runs a ROM without a window and prints a trace log of the executed instructions to stdout.
+/// If a start address is given, the ROM is started there instead of at the reset vector ("automation mode").
+/// The region is taken from the header of the ROM, unless it is given. Memory is cleared, unless a power on profile is given.
+/// The log is written to a file instead of stdout, if `--output` is given.
+/// With `--range`, only the instructions within the given addresses are logged.
+/// Usage: `nest trace <rom> <instruction count> [start address|reset] [nintendulator|fceux|mesen] [ntsc|pal|dendy] [zeros|ones|pattern|random:<seed>] [--output file] [--range first:last]`
+fn trace(args: &[String]) {
+    const USAGE: &str = "Usage: nest trace <rom> <instruction count> [start address|reset] [nintendulator|fceux|mesen] [ntsc|pal|dendy] \
+        [zeros|ones|pattern|random:<seed>] [--output file] [--range first:last]";
+
+    // The options may be given anywhere, the other arguments are positional
+    let mut args = args.to_vec();
+    let mut option = |name: &str| args.iter().position(|arg| arg == name).map(|i| {
+        let value = args.get(i + 1).unwrap_or_else(|| panic!("Missing value for {}. {}", name, USAGE)).clone();
+        args.drain(i..=i + 1);
+        value
+    });
+    let output = option("--output");
+    let range = option("--range").map(|range| {
+        let address = |addr: &str| u16::from_str_radix(addr.trim_start_matches('$'), 16)
+            .unwrap_or_else(|_| panic!("The range has to be two hex addresses. {}", USAGE));
+        match range.split(':').collect::<Vec<_>>().as_slice() {
+            [first, last] => address(first)..=address(last),
+            _ => panic!("The range has to be two hex addresses. {}", USAGE),
+        }
+    });
 
+    let rom = args.first().expect(USAGE);
+    let count = args.get(1).expect(USAGE).parse::<usize>().expect("The instruction count has to be a number");
+    let start = match args.get(2).map(String::as_str) {
+        None | Some("reset") => None,
+        Some(addr) => Some(u16::from_str_radix(addr.trim_start_matches('$'), 16).expect("The start address has to be a hex address")),
+    };
+    let format = match args.get(3) {
+        None => TraceFormat::Nintendulator,
+        Some(name) => TraceFormat::from_name(name).unwrap_or_else(|| panic!("Unknown trace format {}. {}", name, USAGE)),
+    };
+    let region = args.get(4).map(|name| Region::from_name(name).unwrap_or_else(|| panic!("Unknown region {}. {}", name, USAGE)));
+    let power_on = args.get(5).map_or(PowerOnProfile::default(), |name| {
+        PowerOnProfile::from_name(name).unwrap_or_else(|| panic!("Unknown power on profile {}. {}", name, USAGE))
+    });
 
+    let mut nes = Nes::with_power_on(power_on);
+    nes.insert_cartridge(Cartridge::new(rom));
+    if let Some(region) = region {
+        nes.set_region(region);
+    }
+    match start {
+        Some(pc) => nes.start_at(pc),
+        // The machine was just switched on, so the cpu runs the reset sequence it runs on power on
+        None => nes.power_cycle(),
+    }
 
+    let sink: Box<dyn TraceSink> = match output {
+        Some(path) => Box::new(FileSink::create(&path, format).unwrap_or_else(|e| panic!("Error creating {}: {}", path, e))),
+        None => Box::new(StdoutSink::new(format)),
+    };
+    let sink: Box<dyn TraceSink> = match range {
+        Some(range) => Box::new(FilteredSink::pc_range(sink, range)),
+        None => sink,
+    };
+    nes.cpu_mut().set_trace_sink(sink);
+    for _ in 0..count {
+        nes.clock_instruction();
+    }
 }
 
-fn parse_program(program: &str) -> Vec<u8> {
-    hex::decode(program.split_whitespace().collect::<String>()).expect("error parsing program")
+/// Compares two trace logs and reports the first instruction they differ in. The formats are detected, if not given.
+/// Exits with 1, if the logs differ.
+/// Usage: `nest trace-diff <left log> <right log> [--skip field,...] [--context lines] [--left-format format] [--right-format format]`
+fn trace_diff(args: &[String]) {
+    const USAGE: &str = "Usage: nest trace-diff <left log> <right log> [--skip field,...] [--context lines] \
+        [--left-format nintendulator|fceux|mesen] [--right-format nintendulator|fceux|mesen]";
+
+    let left_path = args.first().expect(USAGE);
+    let right_path = args.get(1).expect(USAGE);
+    let mut skip = Vec::new();
+    let mut context = 10;
+    let mut formats = (None, None);
+
+    if args.len() % 2 == 1 {
+        panic!("Missing value for {}. {}", args.last().unwrap(), USAGE);
+    }
+    let mut options = args[2..].chunks(2);
+    while let Some([option, value]) = options.next() {
+        let format = || TraceFormat::from_name(value).unwrap_or_else(|| panic!("Unknown trace format {}. {}", value, USAGE));
+        match option.as_str() {
+            "--skip" => skip.extend(value.split(',').map(|name| {
+                TraceField::from_name(name).unwrap_or_else(|| panic!(
+                    "Unknown field {}. The fields are {}",
+                    name, TraceField::ALL.iter().map(TraceField::name).collect::<Vec<_>>().join(", ")
+                ))
+            })),
+            "--context" => context = value.parse().expect("The context has to be a number"),
+            "--left-format" => formats.0 = Some(format()),
+            "--right-format" => formats.1 = Some(format()),
+            _ => panic!("Unknown option {}. {}", option, USAGE),
+        }
+    }
+
+    let read = |path: &String, format: Option<TraceFormat>| {
+        let log = std::fs::read_to_string(path).unwrap_or_else(|e| panic!("Error reading {}: {}", path, e));
+        let format = format.or_else(|| TraceFormat::detect(&log))
+            .unwrap_or_else(|| panic!("The format of {} could not be detected. {}", path, USAGE));
+        format.parse_log(&log)
+    };
+    let left = read(left_path, formats.0);
+    let right = read(right_path, formats.1);
+
+    let comparison = cpu6502::trace_diff::compare(&left, &right, &skip, context);
+    print!("{}", comparison);
+    if !matches!(comparison, TraceComparison::Matching { .. }) {
+        std::process::exit(1);
+    }
+}
+
+/// Measures how fast a ROM runs when the cpu and the ppu are clocked in lockstep, dot by dot,
+/// compared to letting the cpu run ahead a frame at a time. Both have to end up in the same state.
+/// The ppu draws every dot the same way in both modes, so the difference is only the cost of scheduling them
+/// Usage: `nest bench <rom> [frames]`
+fn bench(args: &[String]) {
+    const USAGE: &str = "Usage: nest bench <rom> [frames]";
+
+    let rom = args.first().expect(USAGE);
+    let frames = args.get(1).map_or(600, |frames| frames.parse::<usize>().expect("The frame count has to be a number"));
+
+    let run = |batched: bool| {
+        let mut nes = Nes::new();
+        nes.insert_cartridge(Cartridge::new(rom));
+        nes.power_cycle();
+
+        let start = std::time::Instant::now();
+        for _ in 0..frames {
+            if batched {
+                nes.run_frame();
+            } else {
+                while !nes.ppu().is_frame_complete() {
+                    nes.clock();
+                }
+            }
+            nes.ppu_mut().set_frame_complete(false);
+        }
+        let time = start.elapsed();
+
+        let cpu = nes.cpu();
+        let state = (cpu.get_program_counter(), cpu.get_acc(), cpu.trace_entry().cycle, nes.ppu().scanline(), nes.ppu().dot());
+        (time, state, nes.ppu().get_screen().clone())
+    };
+
+    let (lockstep, lockstep_state, lockstep_screen) = run(false);
+    let (batched, batched_state, batched_screen) = run(true);
+    assert_eq!(lockstep_state, batched_state, "Running ahead ended up in a different state than lockstep");
+    assert!(*lockstep_screen == *batched_screen, "Running ahead drew a different picture than lockstep");
+
+    let fps = |time: std::time::Duration| frames as f64 / time.as_secs_f64();
+    println!("{} frames of {}", frames, rom);
+    println!("lockstep: {:>8.2?} {:>8.1} fps", lockstep, fps(lockstep));
+    println!("batched:  {:>8.2?} {:>8.1} fps", batched, fps(batched));
+    println!("speedup:  {:.2}x", lockstep.as_secs_f64() / batched.as_secs_f64());
 }
@@ -15,7 +15,7 @@ impl Mapper000 {
 }
 
 impl Mapper for Mapper000 {
-    fn cpu_map_read(&mut self, addr: u16, mapped_addr: &mut u32) -> bool {
+    fn cpu_map_peek(&self, addr: u16, mapped_addr: &mut u32) -> bool {
         if addr >= 0x8000 {
             // For mapper 0, there can be either 1 or 2 program banks with 16kib each within the total 32kib memory
             // If there is only 1, then the remaining 16kib are mirrored to be the same as the first 16kib
@@ -35,10 +35,20 @@ impl Mapper for Mapper000 {
         false
     }
 
+    // Some mapper 0 boards (Family BASIC) have 8kb of program RAM at $6000-$7FFF. There is no harm in always providing it
+    fn cpu_map_ram(&self, addr: u16, mapped_addr: &mut u32) -> bool {
+        if (0x6000..=0x7FFF).contains(&addr) {
+            *mapped_addr = (addr & 0x1FFF) as u32;
+            return true;
+        }
+
+        false
+    }
+
     // The character memory is always 1 bank of 8kb memory for mapper 0,
     // so there is no mapping required for the PPU
 
-    fn ppu_map_read(&mut self, addr: u16, mapped_addr: &mut u32) -> bool {
+    fn ppu_map_peek(&self, addr: u16, mapped_addr: &mut u32) -> bool {
         if addr <= 0x1FFF {
             *mapped_addr = addr as u32;
             return true;
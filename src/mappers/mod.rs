@@ -2,11 +2,26 @@
 pub mod mapper_000;
 
 
-pub trait Mapper {
+/// Mappers are owned by the cartridge, which is owned by the machine, so they have to be `Send` for it to be `Send`
+pub trait Mapper: Send {
 
     // These return true if the address has been mapped successfully
-    fn cpu_map_read(&mut self, addr: u16, mapped_addr : &mut u32) -> bool;
+    // Peeking maps an address with the banks currently selected, without changing the mapper. Debuggers use it to look at memory
+    fn cpu_map_peek(&self, addr: u16, mapped_addr : &mut u32) -> bool;
+    fn ppu_map_peek(&self, addr: u16, mapped_addr : &mut u32) -> bool;
+    // Reads map the same way. Mappers that react to reads (e.g. MMC2 switching banks when the ppu fetches certain tiles) override them
+    fn cpu_map_read(&mut self, addr: u16, mapped_addr : &mut u32) -> bool {
+        self.cpu_map_peek(addr, mapped_addr)
+    }
+    fn ppu_map_read(&mut self, addr: u16, mapped_addr : &mut u32) -> bool {
+        self.ppu_map_peek(addr, mapped_addr)
+    }
     fn cpu_map_write(&mut self, addr: u16, mapped_addr : &mut u32) -> bool;
-    fn ppu_map_read(&mut self, addr: u16, mapped_addr : &mut u32) -> bool;
+    // Program RAM on the cartridge is mapped separately from the program ROM. The mapped address is an offset into the RAM
+    fn cpu_map_ram(&self, addr: u16, mapped_addr : &mut u32) -> bool;
     fn ppu_map_write(&mut self, addr: u16, mapped_addr : &mut u32) -> bool;
+
+    // The reset button only reaches the mapper on some boards. Mappers that react to it (e.g. by selecting their
+    // first banks again) override this, the others keep their state like on hardware
+    fn reset(&mut self) {}
 }
\ No newline at end of file
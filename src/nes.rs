@@ -0,0 +1,403 @@
+//! The whole console.
+//!
+//! `Nes` is the single owner of the machine. It owns the cpu, which owns the bus, which owns the ppu, the APU,
+//! the controllers, the DMA unit and the cartridge. There are no shared references between the components:
+//! the bus lends the cartridge to the ppu when the cpu accesses the ppu registers, and the machine clocks the ppu next to the cpu.
+//! As nothing is shared, a `Nes` can be moved to another thread, e.g. to run the emulation next to the window.
+//!
+//! The machine keeps time in master clock cycles. The dividers of the `Region` decide how many of them a ppu dot and
+//! a cpu cycle take, so the cpu runs every third dot on NTSC consoles, and 5 times every 16 dots on PAL consoles.
+//! The components don't run in lockstep though. The cpu runs ahead as long as it can, and the ppu catches up when
+//! the cpu accesses it, or when the machine has run for as long as it was asked to. Clocking the ppu on its own,
+//! in one go, is a lot faster than switching between the cpu and the ppu every dot.
+//! Once the APU and mappers with timers (like the MMC3 scanline counter) do work on their own, they are caught up the same way,
+//! and interrupts they raise will have to be scheduled ahead of time.
+
+use crate::bus::Bus;
+use crate::cartridge::Cartridge;
+use crate::controller::Buttons;
+use crate::cpu6502::Cpu6502;
+use crate::ppu2C02::Ppu2C02;
+use crate::region::Region;
+use crate::power_on::PowerOnProfile;
+
+#[derive(Debug)]
+pub struct Nes {
+    cpu: Cpu6502<Bus>,
+    region: Region,
+    /// What the memories contain when the machine is switched on. Cartridges are filled the same way when they are inserted
+    power_on: PowerOnProfile,
+    /// The amount of ppu dots the machine has run for. The ppu itself may lag behind while the cpu runs, see `Bus`
+    dots: u64,
+    /// The amount of cpu cycles that started so far. They are counted from the same point in time as the dots
+    cpu_cycles: u64,
+}
+
+impl Nes {
+    /// Creates a machine with all of its memory cleared
+    pub fn new() -> Self {
+        Self::with_power_on(PowerOnProfile::default())
+    }
+
+    /// Creates a machine with its memory filled the way the profile says
+    pub fn with_power_on(power_on: PowerOnProfile) -> Self {
+        let mut bus = Bus::new();
+        bus.power_on(power_on);
+        Nes {
+            cpu: Cpu6502::with_bus(bus),
+            region: Region::default(),
+            power_on,
+            dots: 0,
+            cpu_cycles: 0,
+        }
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    /// Switches the timing of the whole machine to the region
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+        // Keep the cpu running from where it is, the next cpu cycle starts with the next dot
+        self.dots = region.dots_before_cycle(self.cpu_cycles);
+        let (cpu_cycles, dots) = (self.cpu_cycles, self.dots);
+        let bus = self.bus_mut();
+        bus.set_region(region);
+        bus.set_clock(cpu_cycles, dots);
+    }
+
+    pub fn cpu(&self) -> &Cpu6502<Bus> {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut Cpu6502<Bus> {
+        &mut self.cpu
+    }
+
+    pub fn bus(&self) -> &Bus {
+        self.cpu.bus()
+    }
+
+    pub fn bus_mut(&mut self) -> &mut Bus {
+        self.cpu.bus_mut()
+    }
+
+    pub fn ppu(&self) -> &Ppu2C02 {
+        self.bus().ppu()
+    }
+
+    pub fn ppu_mut(&mut self) -> &mut Ppu2C02 {
+        self.bus_mut().ppu_mut()
+    }
+
+    /// Presses the given buttons on the controller in port 0 or 1, and releases all others
+    pub fn set_buttons(&mut self, port: usize, buttons: Buttons) {
+        self.bus_mut().set_buttons(port, buttons);
+    }
+
+    /// Inserts the cartridge and switches to the region it was made for, if its header says so.
+    /// The region can still be changed afterwards with `set_region`
+    pub fn insert_cartridge(&mut self, mut cartridge: Cartridge) {
+        cartridge.power_on(self.power_on);
+        self.bus_mut().insert_cartridge(cartridge);
+        if let Some(region) = self.bus().cartridge_region() {
+            self.set_region(region);
+        }
+    }
+
+    /// Presses the reset button. The cpu runs its reset sequence, the ppu ignores writes until the next frame
+    /// and the mapper resets the way its board does. All RAM keeps its contents
+    pub fn reset(&mut self) {
+        self.bus_mut().reset();
+        self.cpu.reset();
+        self.finish_reset_sequence();
+    }
+
+    /// Switches the machine off and on again. Every component starts over,
+    /// and the memories are filled again with the power on profile the machine was created with
+    pub fn power_cycle(&mut self) {
+        let power_on = self.power_on;
+        self.bus_mut().power_on(power_on);
+        self.cpu.power_on();
+        self.finish_reset_sequence();
+    }
+
+    /// Starts the cpu at the given address instead of the one in the reset vector. See `Cpu6502::start_at`
+    pub fn start_at(&mut self, pc: u16) {
+        self.cpu.start_at(pc);
+        self.finish_reset_sequence();
+    }
+
+    /// The cpu counts the 7 cycles of its reset sequence as done right away. The ppu kept running during them, so it catches up here
+    fn finish_reset_sequence(&mut self) {
+        let dots = self.region.dots_before_cycle(7);
+        self.restart_clock();
+        self.bus_mut().run_ppu(dots);
+        self.restart_clock();
+    }
+
+    /// Starts counting cpu cycles and dots from 0
+    fn restart_clock(&mut self) {
+        self.dots = 0;
+        self.cpu_cycles = 0;
+        self.bus_mut().set_clock(0, 0);
+    }
+
+    /// Runs the machine for a single ppu dot, and the cpu cycle starting during it, if there is one
+    pub fn clock(&mut self) {
+        self.run(1);
+    }
+
+    /// Runs the machine for the given amount of ppu dots.
+    /// The cpu runs ahead for the whole time, and the ppu catches up afterwards. As the bus catches the ppu up
+    /// whenever the cpu accesses it, this has the same result as clocking them in lockstep, dot by dot
+    pub fn run(&mut self, dots: u64) {
+        let end = self.dots + dots;
+        let cpu_cycles = self.region.cycles_before_dot(end);
+        while self.cpu_cycles < cpu_cycles {
+            if self.cpu.complete() {
+                self.clock_cpu();
+            } else {
+                // Nothing happens in the remaining cycles of an instruction, so they are counted in one go
+                let cycles = (self.cpu.remaining_cycles() as u64).min(cpu_cycles - self.cpu_cycles);
+                self.cpu.skip_cycles(cycles as u8);
+                self.cpu_cycles += cycles;
+            }
+        }
+        self.bus_mut().run_ppu(end);
+        self.dots = end;
+    }
+
+    /// Runs the machine until the ppu has completed the current frame
+    pub fn run_frame(&mut self) {
+        let dots = self.ppu().dots_until_frame_complete();
+        self.run(dots);
+    }
+
+    /// Clocks the system until the cpu has executed the next instruction.
+    /// The cpu does all the work of an instruction in its first cycle, so the remaining cycles are left for later
+    pub fn clock_instruction(&mut self) {
+        loop {
+            let starting = self.cpu.complete();
+            let cycle = self.cpu_cycles;
+            self.clock_cpu();
+            if starting && !self.cpu.complete() {
+                // Stop at the end of the dot the instruction started in, like clocking dot by dot does
+                let end = self.region.dots_before_cycle(cycle) + 1;
+                self.bus_mut().run_ppu(end);
+                self.dots = end;
+                return;
+            }
+        }
+    }
+
+    /// Runs the next cpu cycle, or lets the DMA unit use it
+    fn clock_cpu(&mut self) {
+        let cycle = self.cpu_cycles;
+        // DMA halts the cpu before it fetches its next instruction, and takes over the bus until it is done
+        let complete = self.cpu.complete();
+        let tracing = complete && self.cpu.is_tracing();
+        let bus = self.bus_mut();
+        bus.start_cpu_cycle(cycle);
+        // Trace logs show where the ppu is when an instruction starts
+        if tracing {
+            bus.sync_ppu();
+        }
+
+        if complete && bus.is_dma_active() {
+            bus.clock_dma(cycle & 1 == 0);
+            self.cpu.stall();
+        } else {
+            self.cpu.clock();
+        }
+        self.cpu_cycles += 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cartridge::Cartridge;
+    use crate::cpu6502::trace::{RingBufferSink, TraceFormat};
+    use crate::nes::Nes;
+    use crate::region::Region;
+    use crate::power_on::PowerOnProfile;
+
+    #[test]
+    fn send_test() {
+        fn assert_send<T: Send>() {}
+        assert_send::<Nes>();
+    }
+
+    #[test]
+    fn thread_test() {
+        let mut nes = Nes::new();
+        nes.cpu_mut().set_program_counter(0x0000);
+
+        // Runs nops from RAM, which is all $00 (BRK) otherwise. Just make sure the machine works on another thread
+        let nes = std::thread::spawn(move || {
+            for addr in 0..0x10 {
+                nes.bus_mut().cpu_write(addr, 0xEA);
+            }
+            for _ in 0..4 {
+                nes.clock_instruction();
+            }
+            nes
+        }).join().expect("Emulation thread panicked");
+
+        assert_eq!(nes.cpu().get_program_counter(), 0x0004, "The nops should have run on the other thread");
+    }
+
+    /// Counts the cpu cycles during the given amount of ppu dots
+    fn cpu_cycles(region: Region, dots: usize) -> u64 {
+        let mut nes = Nes::new();
+        nes.set_region(region);
+        for _ in 0..dots {
+            nes.clock();
+        }
+        nes.cpu_cycles
+    }
+
+    /// Polls vblank and starts an OAM DMA every frame, counting the frames in $00
+    fn vblank_program(region: Region) -> (Nes, RingBufferSink) {
+        let program = [
+            0xAD, 0x02, 0x20, // lda $2002
+            0x10, 0xFB,       // bpl $0300
+            0xE6, 0x00,       // inc $00
+            0xA9, 0x02,       // lda #$02
+            0x8D, 0x14, 0x40, // sta $4014
+            0x4C, 0x00, 0x03, // jmp $0300
+        ];
+        let mut nes = Nes::new();
+        nes.set_region(region);
+        for (i, &byte) in program.iter().enumerate() {
+            nes.bus_mut().cpu_write(0x0300 + i as u16, byte);
+        }
+        nes.start_at(0x0300);
+
+        let sink = RingBufferSink::new(100);
+        nes.cpu_mut().set_trace_sink(Box::new(sink.clone()));
+        (nes, sink)
+    }
+
+    /// Everything the cpu and ppu timing shows up in
+    fn state(nes: &Nes, sink: &RingBufferSink) -> String {
+        let ppu = nes.ppu();
+        format!(
+            "PC:{:04X} CYC:{} PPU:{},{} dots:{} frames:{}\n{}",
+            nes.cpu().get_program_counter(), nes.cpu().trace_entry().cycle, ppu.scanline(), ppu.dot(),
+            nes.dots, nes.bus().cpu_peek(0x0000), sink.lines(TraceFormat::Nintendulator).join("\n"),
+        )
+    }
+
+    #[test]
+    fn catch_up_test() {
+        for &region in Region::ALL {
+            let dots = 3 * region.scanlines() as u64 * 341 + 1234;
+
+            let (mut lockstep, lockstep_sink) = vblank_program(region);
+            for _ in 0..dots {
+                lockstep.clock();
+            }
+
+            let (mut batched, batched_sink) = vblank_program(region);
+            batched.run(1000);
+            batched.run_frame();
+            batched.run_frame();
+            let left = dots - batched.dots;
+            batched.run(left);
+
+            assert_eq!(lockstep.bus().cpu_peek(0x0000), 3, "{:?}: There should have been 3 vblanks", region);
+            let expected = state(&lockstep, &lockstep_sink);
+            assert_eq!(state(&batched, &batched_sink), expected, "{:?}: Running ahead should give the same result as lockstep", region);
+        }
+    }
+
+    #[test]
+    fn clock_instruction_test() {
+        let (mut lockstep, lockstep_sink) = vblank_program(Region::Pal);
+        let (mut batched, batched_sink) = vblank_program(Region::Pal);
+        for _ in 0..5000 {
+            batched.clock_instruction();
+            // Clock dot by dot until the next instruction started
+            loop {
+                let starting = lockstep.cpu().complete();
+                lockstep.clock();
+                if starting && !lockstep.cpu().complete() {
+                    break;
+                }
+            }
+        }
+        assert_eq!(state(&batched, &batched_sink), state(&lockstep, &lockstep_sink));
+    }
+
+    #[test]
+    fn reset_test() {
+        let mut nes = Nes::with_power_on(PowerOnProfile::Ones);
+        nes.insert_cartridge(Cartridge::test_rom(&[], &[]));
+        nes.power_cycle();
+        nes.bus_mut().cpu_write(0x0010, 0x42);
+        nes.bus_mut().cpu_write(0x6000, 0x24);
+
+        nes.reset();
+        assert_eq!(nes.cpu().get_program_counter(), 0x8000, "The cpu should start at the reset vector");
+        assert_eq!(nes.bus().cpu_peek(0x0010), 0x42, "A reset should keep the cpu RAM");
+        assert_eq!(nes.bus().cpu_peek(0x6000), 0x24, "A reset should keep the program RAM");
+    }
+
+    #[test]
+    fn power_cycle_test() {
+        let mut nes = Nes::with_power_on(PowerOnProfile::Ones);
+        nes.insert_cartridge(Cartridge::test_rom(&[], &[]));
+        nes.power_cycle();
+        nes.bus_mut().cpu_write(0x0010, 0x42);
+        nes.bus_mut().cpu_write(0x6000, 0x24);
+        nes.cpu_mut().set_program_counter(0x1234);
+
+        nes.power_cycle();
+        assert_eq!(nes.cpu().get_program_counter(), 0x8000, "The cpu should start at the reset vector");
+        assert_eq!(nes.bus().cpu_peek(0x0010), 0xFF, "The cpu RAM should be filled by the power on profile again");
+        assert_eq!(nes.bus().cpu_peek(0x6000), 0xFF, "The program RAM should be filled by the power on profile again");
+
+        // Switch the machine off in the middle of vblank of the next frame
+        nes.run_frame();
+        nes.run(242 * 341 + 100);
+        assert_eq!(nes.bus().cpu_peek(0x2002) & 0x80, 0x80, "Vblank should be set");
+        nes.power_cycle();
+        assert_eq!((nes.ppu().scanline(), nes.ppu().dot()), (0, 21), "The ppu should start over, and run during the reset sequence");
+        assert!(!nes.ppu().is_frame_complete(), "The ppu should start a new frame");
+        assert_eq!(nes.bus().cpu_peek(0x2002) & 0x80, 0x00, "Vblank should be clear");
+    }
+
+    #[test]
+    fn power_cycle_trace_test() {
+        let mut nes = Nes::new();
+        nes.insert_cartridge(Cartridge::test_rom(&[], &[]));
+        nes.run(12345);
+        nes.power_cycle();
+
+        let sink = RingBufferSink::new(1);
+        nes.cpu_mut().set_trace_sink(Box::new(sink.clone()));
+        nes.clock_instruction();
+        let line = sink.lines(TraceFormat::Nintendulator).join("");
+        assert!(line.ends_with("PPU:  0, 21 CYC:7"), "The first instruction should start after the reset sequence, like in nestest.log: {}", line);
+    }
+
+    #[test]
+    fn region_clock_test() {
+        assert_eq!(cpu_cycles(Region::Ntsc, 3 * 100), 100);
+        assert_eq!(cpu_cycles(Region::Pal, 16 * 100), 5 * 100, "PAL consoles draw 3.2 dots per cpu cycle");
+        assert_eq!(cpu_cycles(Region::Dendy, 3 * 100), 100);
+    }
+
+    #[test]
+    fn header_region_test() {
+        // A NES 2.0 header for a PAL game
+        let mut nes = Nes::new();
+        nes.insert_cartridge(Cartridge::test_rom(&[], &[0, 0x08, 0, 0, 0, 0, 0x01]));
+        assert_eq!(nes.region(), Region::Pal, "The region should come from the header");
+
+        nes.set_region(Region::Dendy);
+        assert_eq!(nes.region(), Region::Dendy, "The user should be able to override the region");
+    }
+}
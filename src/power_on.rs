@@ -0,0 +1,102 @@
+//! What the memories of the console contain when it is switched on.
+//!
+//! RAM doesn't start out empty on real hardware. Its contents depend on the console, and sometimes even on the temperature.
+//! Most games clear it first, but some read memory they never wrote, and behave differently (or crash) depending on what they find.
+//! The profiles cover the usual patterns emulators use, so these games can be tested against all of them.
+
+use rand::rngs::StdRng;
+use rand::{RngCore, SeedableRng};
+use std::fmt::Display;
+
+/// How the memories are filled on power on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PowerOnProfile {
+    /// Every byte is $00
+    #[default]
+    Zeros,
+    /// Every byte is $FF
+    Ones,
+    /// 4 bytes of $00 followed by 4 bytes of $FF, over and over again. Many consoles start up with roughly this in their RAM
+    Pattern,
+    /// Random bytes. The same seed always gives the same contents
+    Random(u64),
+}
+
+/// The memories filled on power on. Each of them gets its own random numbers,
+/// so the contents of one don't depend on the size of the others
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Memory {
+    CpuRam,
+    NameTables,
+    Oam,
+    Palette,
+    CartridgeRam,
+}
+
+impl PowerOnProfile {
+    /// Looks up a profile by the name it is displayed with. The name is case insensitive
+    pub fn from_name(name: &str) -> Option<PowerOnProfile> {
+        match name.to_ascii_lowercase().as_str() {
+            "zeros" => Some(PowerOnProfile::Zeros),
+            "ones" => Some(PowerOnProfile::Ones),
+            "pattern" => Some(PowerOnProfile::Pattern),
+            name => name.strip_prefix("random:")?.parse().ok().map(PowerOnProfile::Random),
+        }
+    }
+
+    /// Fills one of the memories of the console
+    pub fn fill(&self, memory: Memory, data: &mut [u8]) {
+        match self {
+            PowerOnProfile::Zeros => data.fill(0x00),
+            PowerOnProfile::Ones => data.fill(0xFF),
+            PowerOnProfile::Pattern => data.iter_mut().enumerate().for_each(|(i, byte)| *byte = if i & 0x04 == 0 { 0x00 } else { 0xFF }),
+            PowerOnProfile::Random(seed) => StdRng::seed_from_u64(seed.wrapping_add(memory as u64)).fill_bytes(data),
+        }
+    }
+}
+
+/// The name of the profile. Random profiles are named `random:<seed>`
+impl Display for PowerOnProfile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PowerOnProfile::Zeros => write!(f, "zeros"),
+            PowerOnProfile::Ones => write!(f, "ones"),
+            PowerOnProfile::Pattern => write!(f, "pattern"),
+            PowerOnProfile::Random(seed) => write!(f, "random:{}", seed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::power_on::{Memory, PowerOnProfile};
+
+    fn filled(profile: PowerOnProfile, memory: Memory) -> Vec<u8> {
+        let mut data = vec![0x12; 16];
+        profile.fill(memory, &mut data);
+        data
+    }
+
+    #[test]
+    fn fill_test() {
+        assert_eq!(filled(PowerOnProfile::Zeros, Memory::CpuRam), vec![0x00; 16]);
+        assert_eq!(filled(PowerOnProfile::Ones, Memory::CpuRam), vec![0xFF; 16]);
+        assert_eq!(filled(PowerOnProfile::Pattern, Memory::CpuRam)[..8], [0x00, 0x00, 0x00, 0x00, 0xFF, 0xFF, 0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn random_test() {
+        let random = |seed, memory| filled(PowerOnProfile::Random(seed), memory);
+        assert_eq!(random(1, Memory::CpuRam), random(1, Memory::CpuRam), "The same seed should give the same contents");
+        assert_ne!(random(1, Memory::CpuRam), random(2, Memory::CpuRam), "Different seeds should give different contents");
+        assert_ne!(random(1, Memory::CpuRam), random(1, Memory::Oam), "Every memory should get different contents");
+    }
+
+    #[test]
+    fn name_test() {
+        for &profile in &[PowerOnProfile::Zeros, PowerOnProfile::Ones, PowerOnProfile::Pattern, PowerOnProfile::Random(1234)] {
+            assert_eq!(PowerOnProfile::from_name(&profile.to_string()), Some(profile));
+        }
+        assert_eq!(PowerOnProfile::from_name("random:x"), None);
+    }
+}
@@ -1,13 +1,34 @@
-use std::rc::Rc;
-use crate::cartridge::Cartridge;
-use std::cell::RefCell;
+use crate::cartridge::{Cartridge, Mirroring};
+use crate::region::Region;
+use crate::power_on::{Memory, PowerOnProfile};
 use image::{Rgba, RgbaImage};
-use rand::Rng;
+use rand::SeedableRng;
+use rand::distributions::{Distribution, Uniform};
+use rand::rngs::StdRng;
 
+/// How long the bits on the data bus between the cpu and the ppu keep their value, in ppu dots. This is about 600ms
+const IO_BUS_DECAY_DOTS: u64 = 3_200_000;
+
+/// The ppu doesn't hold on to the cartridge. The bus owns it and lends it to the ppu whenever the cpu accesses the ppu registers,
+/// as PPUDATA reaches the pattern tables on the cartridge through the ppu bus
 pub struct Ppu2C02 {
-    cartridge: Option<Rc<RefCell<Cartridge>>>,
+    region: Region, // Decides how many scanlines a frame has and when vblank starts
     name_table: [[u8; 1024]; 2],
     palette_table: [u8; 32],
+    oam: [u8; 256], // Object attribute memory: position, tile and attributes of the 64 sprites
+    oam_address: u8,
+    io_bus: u8, // The data bus to the cpu. It keeps the value last read or written, until it decays
+    io_bus_refreshed: [u64; 8], // When each bit of the data bus was last driven, in dots
+    dot_counter: u64, // The amount of dots since power on
+    vblank: bool, // Bit 7 of the status register
+    control: u8, // $2000
+    mask: u8, // $2001
+    reset_latch: bool, // Set by power on and reset. Until the end of the next vblank, writes to $2000, $2001, $2005 and $2006 are ignored
+    vram_address: u16, // "v": The address PPUDATA accesses. While rendering, it holds the scroll position
+    temp_address: u16, // "t": $2000, $2005 and $2006 write here first. The second write to $2006 copies it to v
+    fine_x: u8, // The horizontal scroll within a tile
+    address_latch: bool, // "w": Whether the next write to $2005 or $2006 is the second one
+    data_buffer: u8, // Reading PPUDATA returns what the read before it fetched, except for the palette
     //pattern_table: Option[[u8; 4096]; 2], // Javid Future reminder
     //Debug info:
     palette_screen: [Rgba<u8>; 64],
@@ -16,16 +37,33 @@ pub struct Ppu2C02 {
     sprite_pattern_table: [RgbaImage; 2],
     frame_complete: bool,
     scan_line: i16,
-    cycle: i16
+    cycle: i16,
+    // The fake noise that is drawn until there is rendering. It is created once, as it is drawn on every dot
+    noise: StdRng,
+    noise_colors: Uniform<usize>,
 }
 
 impl Ppu2C02 {
 
     pub fn new() -> Self {
         let mut ppu = Ppu2C02 {
-            cartridge: None,
+            region: Region::default(),
             name_table: [[0; 1024]; 2],
             palette_table: [0; 32],
+            oam: [0; 256],
+            oam_address: 0,
+            io_bus: 0,
+            io_bus_refreshed: [0; 8],
+            dot_counter: 0,
+            vblank: false,
+            control: 0,
+            mask: 0,
+            reset_latch: false,
+            vram_address: 0,
+            temp_address: 0,
+            fine_x: 0,
+            address_latch: false,
+            data_buffer: 0,
             //Debug information:
             palette_screen: [Rgba([0, 0, 0, 0]); 64],
             sprite_screen: RgbaImage::new(256, 240),
@@ -35,6 +73,8 @@ impl Ppu2C02 {
             // Basically which column and row the renderer is working on
             scan_line: 0,
             cycle: 0,
+            noise: StdRng::seed_from_u64(0),
+            noise_colors: Uniform::new(0x00, 0x3F),
         };
         ppu.setup_palette_screen();
         ppu
@@ -111,6 +151,46 @@ impl Ppu2C02 {
         self.palette_screen[0x3F] = Rgba([0, 0, 0, 255]);
     }
 
+    /// Fills the name tables, OAM and the palette the way the profile says
+    pub fn power_on(&mut self, profile: PowerOnProfile) {
+        for name_table in self.name_table.iter_mut() {
+            profile.fill(Memory::NameTables, name_table);
+        }
+        profile.fill(Memory::Oam, &mut self.oam);
+        profile.fill(Memory::Palette, &mut self.palette_table);
+        // The palette only has 6 bits per entry
+        self.palette_table.iter_mut().for_each(|color| *color &= 0x3F);
+        self.oam_address = 0;
+        self.io_bus = 0;
+        self.io_bus_refreshed = [0; 8];
+        self.vblank = false;
+        self.vram_address = 0;
+        self.temp_address = 0;
+        // Start drawing a new frame from the top
+        self.scan_line = 0;
+        self.cycle = 0;
+        self.dot_counter = 0;
+        self.frame_complete = false;
+        self.reset();
+    }
+
+    /// What the reset button does to the ppu. The control and mask registers are cleared, and writes to them are
+    /// ignored until the end of the next vblank. The write latch of $2005/$2006, the fine scroll and the PPUDATA buffer
+    /// are cleared as well. The memories, OAM address, vblank flag and the VRAM address keep their values
+    pub fn reset(&mut self) {
+        self.control = 0;
+        self.mask = 0;
+        self.fine_x = 0;
+        self.address_latch = false;
+        self.data_buffer = 0;
+        self.reset_latch = true;
+    }
+
+    /// Switches the amount of scanlines and the length of vblank to those of the region
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+    }
+
     pub fn is_frame_complete(&self) -> bool {
         self.frame_complete
     }
@@ -119,77 +199,231 @@ impl Ppu2C02 {
         self.frame_complete = b
     }
 
-    /// Read from the main bus
-    pub fn cpu_read(&self, addr: u16, _read_only: bool) -> u8 {
+    /// The amount of dots left to draw, until the current frame is complete
+    pub fn dots_until_frame_complete(&self) -> u64 {
+        // The frame is complete after the last dot of the scanline before the pre-render scanline
+        let last_scanline = self.region.scanlines() - 2;
+        ((last_scanline - self.scan_line) as u64 * 341) + (341 - self.cycle) as u64
+    }
+
+    /// The scanline the ppu is working on. The pre-render scanline is -1
+    pub fn scanline(&self) -> i16 {
+        self.scan_line
+    }
+
+    /// The dot within the current scanline the ppu is working on
+    pub fn dot(&self) -> i16 {
+        self.cycle
+    }
+
+    /// Read from the main bus. Bits the register doesn't drive, and the write only registers as a whole,
+    /// read as what is left on the data bus ("open bus")
+    /// PPUDATA reads from the ppu bus, which reaches the cartridge
+    pub fn cpu_read(&mut self, cartridge: Option<&mut Cartridge>, addr: u16) -> u8 {
+        let (data, driven) = self.register(addr);
+        let data = (data & driven) | (self.io_bus() & !driven);
+        self.drive_io_bus(data, driven);
         match addr {
-            0x0000 => 0, // Control
-            0x0001 => 0, // Mask
-            0x0002 => 0, // Status
-            0x0003 => 0, // OAM Address
-            0x0004 => 0, // OAM Data
-            0x0005 => 0, // Scroll
-            0x0006 => 0, // PPU Address
-            0x0007 => 0, // PPU Data
-            _ => 0
+            // Reading the status acknowledges vblank and resets the write latch of $2005/$2006
+            0x0002 => {
+                self.vblank = false;
+                self.address_latch = false;
+            }
+            // The buffer is refilled for the next read. Palette reads are not buffered,
+            // but still fill the buffer with the name table byte "underneath" the palette
+            0x0007 => {
+                let addr = if self.vram_address & 0x3FFF >= 0x3F00 { self.vram_address & 0x2FFF } else { self.vram_address };
+                self.data_buffer = self.ppu_read(cartridge, addr);
+                self.increment_vram_address();
+            }
+            _ => {}
         }
+        data
     }
 
-    /// Write to the main bus
-    pub fn cpu_write(&mut self, addr: u16,   data: u8) {
+    /// What reading from the main bus would return, without refreshing the data bus or acknowledging vblank.
+    /// Peeking PPUDATA neither advances the VRAM address nor refills the buffer, so debuggers can look at the registers safely
+    pub fn cpu_peek(&self, addr: u16) -> u8 {
+        let (data, driven) = self.register(addr);
+        (data & driven) | (self.io_bus() & !driven)
+    }
+
+    /// The value of a register and the bits of it that are actually driven
+    fn register(&self, addr: u16) -> (u8, u8) {
+        match addr {
+            0x0000 => (0, 0x00), // Control
+            0x0001 => (0, 0x00), // Mask
+            0x0002 => ((self.vblank as u8) << 7, 0xE0), // Status. Only the upper 3 bits are flags
+            0x0003 => (0, 0x00), // OAM Address
+            0x0004 => (self.oam[self.oam_address as usize], 0xFF), // OAM Data
+            0x0005 => (0, 0x00), // Scroll
+            0x0006 => (0, 0x00), // PPU Address
+            // PPU Data. Only the 6 bits of a palette entry are driven
+            0x0007 if self.vram_address & 0x3FFF >= 0x3F00 => (self.palette_table[palette_index(self.vram_address)], 0x3F),
+            0x0007 => (self.data_buffer, 0xFF),
+            _ => (0, 0x00)
+        }
+    }
+
+    /// Write to the main bus. PPUDATA writes to the ppu bus, which reaches the cartridge
+    pub fn cpu_write(&mut self, cartridge: Option<&mut Cartridge>, addr: u16, data: u8) {
+        self.drive_io_bus(data, 0xFF);
+        // Writes ignored after a reset still reach the data bus
+        if self.reset_latch && matches!(addr, 0x0000 | 0x0001 | 0x0005 | 0x0006) {
+            return;
+        }
         match addr {
-            0x0000 => 0, // Control
-            0x0001 => 0, // Mask
-            0x0002 => 0, // Status
-            0x0003 => 0, // OAM Address
-            0x0004 => 0, // OAM Data
-            0x0005 => 0, // Scroll
-            0x0006 => 0, // PPU Address
-            0x0007 => 0, // PPU Data
-            _ => 0
+            0x0000 => { // Control. The lower 2 bits select the name table to start rendering from
+                self.control = data;
+                self.temp_address = (self.temp_address & !0x0C00) | ((data as u16 & 0x03) << 10);
+            }
+            0x0001 => self.mask = data, // Mask
+            0x0002 => {}, // Status
+            0x0003 => self.oam_address = data, // OAM Address
+            0x0004 => { // OAM Data. Writes advance the OAM address, which is how OAM DMA fills the whole OAM
+                self.oam[self.oam_address as usize] = data;
+                self.oam_address = self.oam_address.wrapping_add(1);
+            }
+            0x0005 => { // Scroll. X first, then Y
+                if !self.address_latch {
+                    self.temp_address = (self.temp_address & !0x001F) | (data as u16 >> 3);
+                    self.fine_x = data & 0x07;
+                } else {
+                    self.temp_address = (self.temp_address & !0x73E0) | ((data as u16 & 0x07) << 12) | ((data as u16 & 0xF8) << 2);
+                }
+                self.address_latch = !self.address_latch;
+            }
+            0x0006 => { // PPU Address. High byte first, then the low byte, which makes the address take effect
+                if !self.address_latch {
+                    self.temp_address = (self.temp_address & 0x00FF) | ((data as u16 & 0x3F) << 8);
+                } else {
+                    self.temp_address = (self.temp_address & 0xFF00) | data as u16;
+                    self.vram_address = self.temp_address;
+                }
+                self.address_latch = !self.address_latch;
+            }
+            0x0007 => { // PPU Data
+                self.ppu_write(cartridge, self.vram_address, data);
+                self.increment_vram_address();
+            }
+            _ => {}
         };
     }
 
-    /// Read from the PPU bus
-    pub fn ppu_read(&self, addr: u16, read_only: bool) -> u8 {
-        let mut data = 0x00;
+    /// Moves the VRAM address on after an access to PPUDATA. Bit 2 of the control register chooses between
+    /// going across (1) and down (32) a name table
+    fn increment_vram_address(&mut self) {
+        let step = if self.control & 0x04 != 0 { 32 } else { 1 };
+        self.vram_address = self.vram_address.wrapping_add(step) & 0x7FFF;
+    }
+
+    /// The value on the data bus to the cpu. Bits that weren't driven for a while have decayed to 0
+    fn io_bus(&self) -> u8 {
+        (0..8)
+            .filter(|&bit| self.dot_counter - self.io_bus_refreshed[bit] < IO_BUS_DECAY_DOTS)
+            .fold(0, |value, bit| value | (self.io_bus & (1 << bit)))
+    }
+
+    /// Puts a value on the data bus to the cpu. Only the bits in `mask` are driven, the others keep decaying
+    fn drive_io_bus(&mut self, value: u8, mask: u8) {
+        self.io_bus = (self.io_bus() & !mask) | (value & mask);
+        for bit in 0..8 {
+            if mask & (1 << bit) != 0 {
+                self.io_bus_refreshed[bit] = self.dot_counter;
+            }
+        }
+    }
+
+    /// Read from the PPU bus. The cartridge is asked first, the name tables and the palette are inside the console
+    pub fn ppu_read(&mut self, mut cartridge: Option<&mut Cartridge>, addr: u16) -> u8 {
         let addr = addr & 0x3FFF;
+        let mut data = 0x00;
+        if let Some(cartridge) = cartridge.as_deref_mut() {
+            if cartridge.ppu_read(addr, &mut data) {
+                return data;
+            }
+        }
 
-        if let Some(cartridge) = self.cartridge.as_ref() {
-            if cartridge.borrow_mut().ppu_read(addr, &mut data) {
+        self.internal_read(cartridge.map(|cartridge| cartridge.mirroring()), addr).unwrap_or(data)
+    }
 
+    /// What reading from the PPU bus would return, without changing the state of the mapper
+    pub fn ppu_peek(&self, cartridge: Option<&Cartridge>, addr: u16) -> u8 {
+        let addr = addr & 0x3FFF;
+        let mut data = 0x00;
+        if let Some(cartridge) = cartridge {
+            if cartridge.ppu_peek(addr, &mut data) {
+                return data;
             }
         }
 
-        data
+        self.internal_read(cartridge.map(Cartridge::mirroring), addr).unwrap_or(data)
+    }
+
+    /// Reads the name tables or the palette. The pattern tables are on the cartridge, so they read as None
+    fn internal_read(&self, mirroring: Option<Mirroring>, addr: u16) -> Option<u8> {
+        match addr {
+            0x0000..=0x1FFF => None,
+            0x2000..=0x3EFF => {
+                let (table, offset) = name_table_index(addr, mirroring);
+                Some(self.name_table[table][offset])
+            }
+            _ => Some(self.palette_table[palette_index(addr)]),
+        }
     }
 
-    /// Write to the PPU bus
-    pub fn ppu_write(&mut self, addr: u16, data: u8) {
+    /// Write to the PPU bus. The cartridge is asked first, the name tables and the palette are inside the console
+    pub fn ppu_write(&mut self, mut cartridge: Option<&mut Cartridge>, addr: u16, data: u8) {
         let addr = addr & 0x3FFF;
-        if let Some(cartridge) = self.cartridge.as_ref() {
-            if cartridge.borrow_mut().ppu_write(addr, data) {
+        if let Some(cartridge) = cartridge.as_deref_mut() {
+            if cartridge.ppu_write(addr, data) {
+                return;
+            }
+        }
 
+        match addr {
+            // Pattern tables in CHR ROM can't be written
+            0x0000..=0x1FFF => {}
+            0x2000..=0x3EFF => {
+                let (table, offset) = name_table_index(addr, cartridge.map(|cartridge| cartridge.mirroring()));
+                self.name_table[table][offset] = data;
             }
+            // The palette only has 6 bits per entry
+            _ => self.palette_table[palette_index(addr)] = data & 0x3F,
         }
     }
 
-    pub fn connect_cartridge(&mut self, cartridge: Rc<RefCell<Cartridge>>) {
-        self.cartridge = Some(cartridge);
+    /// Clocks the ppu for the given amount of dots in one go
+    pub fn run(&mut self, dots: u64) {
+        for _ in 0..dots {
+            self.draw_dot();
+        }
     }
 
-    pub fn clock(&mut self) {
+    fn draw_dot(&mut self) {
+
+        // Vblank starts and ends on the second dot of a scanline
+        if self.cycle == 1 {
+            if self.scan_line == self.region.vblank_scanline() {
+                self.vblank = true;
+            } else if self.scan_line == -1 {
+                self.vblank = false;
+                self.reset_latch = false;
+            }
+        }
 
         // Todo temporary fake noise
-        let mut rng = rand::thread_rng();
         if ((self.cycle - 1) as u32) < 256 && (self.scan_line as u32) < 240 {
-            self.sprite_screen.put_pixel((self.cycle - 1) as u32, self.scan_line as u32, self.palette_screen[rng.gen_range(0x00, 0x3F)]);
+            self.sprite_screen.put_pixel((self.cycle - 1) as u32, self.scan_line as u32, self.palette_screen[self.noise_colors.sample(&mut self.noise)]);
         }
         self.cycle += 1;
+        self.dot_counter += 1;
         // Weird numbers are due to how the NES works
         if self.cycle >= 341 {
             self.cycle = 0;
             self.scan_line += 1;
-            if self.scan_line >= 261 {
+            // The last scanline is the pre-render scanline, which is numbered -1
+            if self.scan_line >= self.region.scanlines() - 1 {
                 self.scan_line = -1;
                 self.frame_complete = true;
             }
@@ -209,4 +443,292 @@ impl Ppu2C02 {
     pub fn get_pattern_table(&self, i: usize) -> &RgbaImage {
         &self.sprite_pattern_table[i]
     }
-}
\ No newline at end of file
+
+    /// Draws the 256 tiles of pattern table 0 or 1 in the colors of the given palette (0-7), see `get_pattern_table`.
+    /// The tiles are read with `ppu_peek`, so neither the mapper nor the emulation notice
+    pub fn draw_pattern_table(&mut self, cartridge: Option<&Cartridge>, i: usize, palette: u8) {
+        let mut image = RgbaImage::new(128, 128);
+        for tile_y in 0..16 {
+            for tile_x in 0..16 {
+                let tile = (i as u16 * 0x1000) + tile_y * 256 + tile_x * 16;
+                self.draw_tile(cartridge, &mut image, tile, palette, (tile_x as u32 * 8, tile_y as u32 * 8));
+            }
+        }
+        self.sprite_pattern_table[i] = image;
+    }
+
+    /// Draws the 32x30 tiles of name table 0 or 1 with the background pattern table and the attributes of the tiles,
+    /// see `get_name_table`. Everything is read with `ppu_peek`, so neither the mapper nor the emulation notice
+    pub fn draw_name_table(&mut self, cartridge: Option<&Cartridge>, i: usize) {
+        // The first address of the name table in VRAM. The second one is at $2400 or at $2800, depending on the mirroring
+        let start = match cartridge.map_or(Mirroring::Horizontal, Cartridge::mirroring) {
+            Mirroring::Horizontal => 0x2000 + i as u16 * 0x0800,
+            Mirroring::Vertical => 0x2000 + i as u16 * 0x0400,
+        };
+        let patterns = if self.control & 0x10 != 0 { 0x1000 } else { 0x0000 };
+
+        let mut image = RgbaImage::new(256, 240);
+        for y in 0..30 {
+            for x in 0..32 {
+                let tile = self.ppu_peek(cartridge, start + y * 32 + x) as u16;
+                // Every byte of the attribute table covers 4x4 tiles, with 2 bits of palette for each 2x2 of them
+                let attribute = self.ppu_peek(cartridge, start + 0x03C0 + (y / 4) * 8 + x / 4);
+                let shift = ((y & 0x02) << 1) | (x & 0x02);
+                let palette = (attribute >> shift) & 0x03;
+                self.draw_tile(cartridge, &mut image, patterns + tile * 16, palette, (x as u32 * 8, y as u32 * 8));
+            }
+        }
+        self.sprite_name_table[i] = image;
+    }
+
+    /// Draws the 8x8 pixels of the tile whose pattern starts at the given address into the image, with its top left corner at (x, y)
+    fn draw_tile(&self, cartridge: Option<&Cartridge>, image: &mut RgbaImage, pattern: u16, palette: u8, (x, y): (u32, u32)) {
+        for row in 0..8 {
+            // The low bits of the pixels are in the first 8 bytes of a tile, the high bits in the next 8
+            let low = self.ppu_peek(cartridge, pattern + row);
+            let high = self.ppu_peek(cartridge, pattern + row + 8);
+            for column in 0..8 {
+                let pixel = ((low >> (7 - column)) & 0x01) | (((high >> (7 - column)) & 0x01) << 1);
+                let color = self.palette_screen[(self.ppu_peek(cartridge, 0x3F00 + palette as u16 * 4 + pixel as u16) & 0x3F) as usize];
+                image.put_pixel(x + column, y + row as u32, color);
+            }
+        }
+    }
+}
+
+/// Where an address of the name tables ($2000-$3EFF) ends up in VRAM: which of the 2 name tables, and the offset into it.
+/// Without a cartridge, the name tables are mirrored horizontally
+fn name_table_index(addr: u16, mirroring: Option<Mirroring>) -> (usize, usize) {
+    let table = (addr >> 10) & 0x03;
+    let table = match mirroring.unwrap_or(Mirroring::Horizontal) {
+        Mirroring::Horizontal => table >> 1,
+        Mirroring::Vertical => table & 0x01,
+    };
+    (table as usize, (addr & 0x03FF) as usize)
+}
+
+/// Where an address of the palette ($3F00-$3FFF) ends up in the palette memory.
+/// The background colors of the sprite palettes are the ones of the background palettes
+fn palette_index(addr: u16) -> usize {
+    let index = addr & 0x1F;
+    if index & 0x13 == 0x10 { (index & 0x0F) as usize } else { index as usize }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cartridge::Cartridge;
+    use crate::ppu2C02::{Ppu2C02, IO_BUS_DECAY_DOTS};
+    use crate::region::Region;
+
+    /// A mapper 0 cartridge with the given mirroring, whose pattern tables count up from 0
+    fn cartridge(vertical: bool) -> Cartridge {
+        Cartridge::test_rom(&[], &[vertical as u8])
+    }
+
+    /// Points PPUDATA to the address, high byte first
+    fn set_address(ppu: &mut Ppu2C02, addr: u16) {
+        ppu.cpu_write(None, 0x0006, (addr >> 8) as u8);
+        ppu.cpu_write(None, 0x0006, addr as u8);
+    }
+
+    #[test]
+    fn ppu_data_test() {
+        let mut ppu = Ppu2C02::new();
+        set_address(&mut ppu, 0x2108);
+        ppu.cpu_write(None, 0x0007, 0x42);
+        ppu.cpu_write(None, 0x0007, 0x43);
+
+        set_address(&mut ppu, 0x2108);
+        ppu.cpu_read(None, 0x0007);
+        assert_eq!(ppu.cpu_read(None, 0x0007), 0x42, "Reads should return the byte the read before fetched");
+        assert_eq!(ppu.cpu_read(None, 0x0007), 0x43, "Reads should advance the address");
+
+        // Going down a name table
+        ppu.cpu_write(None, 0x0000, 0x04);
+        set_address(&mut ppu, 0x2000);
+        ppu.cpu_write(None, 0x0007, 0x11);
+        ppu.cpu_write(None, 0x0007, 0x22);
+        assert_eq!(ppu.vram_address, 0x2040);
+        assert_eq!(ppu.name_table[0][0x20], 0x22, "Writes should advance the address by 32");
+    }
+
+    #[test]
+    fn address_latch_test() {
+        let mut ppu = Ppu2C02::new();
+        ppu.cpu_write(None, 0x0006, 0x3F);
+        // Reading the status resets the latch, so this is the high byte again
+        ppu.cpu_read(None, 0x0002);
+        set_address(&mut ppu, 0x2345);
+        assert_eq!(ppu.vram_address, 0x2345);
+
+        // $2005 shares the latch with $2006
+        ppu.cpu_write(None, 0x0005, 0x7D);
+        assert_eq!(ppu.fine_x, 0x05);
+        assert_eq!(ppu.temp_address & 0x001F, 0x0F, "The first write to $2005 should be the coarse X scroll");
+        assert_eq!(ppu.vram_address, 0x2345, "Scrolling should not change the address");
+        ppu.cpu_write(None, 0x0006, 0x21);
+        assert_eq!(ppu.vram_address, 0x2321, "The write after $2005 should be the low byte");
+    }
+
+    #[test]
+    fn palette_test() {
+        let mut ppu = Ppu2C02::new();
+        set_address(&mut ppu, 0x3F10);
+        ppu.cpu_write(None, 0x0007, 0xEA);
+        ppu.name_table[1][0x300] = 0x99;
+
+        // Drive the upper bits of the data bus, which palette reads don't drive
+        set_address(&mut ppu, 0x3F00);
+        ppu.cpu_write(None, 0x0003, 0xC0);
+        assert_eq!(ppu.cpu_read(None, 0x0007), 0xEA, "The sprite background color should mirror the background color");
+        assert_eq!(ppu.data_buffer, 0x99, "The buffer should be filled from the name table underneath the palette");
+        assert_eq!(ppu.palette_table[0x00], 0x2A, "Palette entries only have 6 bits");
+    }
+
+    #[test]
+    fn mirroring_test() {
+        for &(vertical, mirror) in &[(false, 0x2400), (true, 0x2800)] {
+            let mut ppu = Ppu2C02::new();
+            let mut cartridge = cartridge(vertical);
+            ppu.ppu_write(Some(&mut cartridge), 0x2010, 0x42);
+            assert_eq!(ppu.ppu_read(Some(&mut cartridge), mirror + 0x10), 0x42, "Vertical mirroring: {}", vertical);
+            assert_eq!(ppu.ppu_read(Some(&mut cartridge), 0x2C10), 0x00, "Vertical mirroring: {}", vertical);
+            assert_eq!(ppu.ppu_read(Some(&mut cartridge), 0x3010), 0x42, "$3000-$3EFF should mirror the name tables");
+        }
+    }
+
+    #[test]
+    fn pattern_table_test() {
+        let mut ppu = Ppu2C02::new();
+        let mut cartridge = cartridge(false);
+        ppu.cpu_write(Some(&mut cartridge), 0x0006, 0x12);
+        ppu.cpu_write(Some(&mut cartridge), 0x0006, 0x34);
+        ppu.cpu_read(Some(&mut cartridge), 0x0007);
+
+        assert_eq!(ppu.cpu_read(Some(&mut cartridge), 0x0007), 0x34, "PPUDATA should read the pattern tables on the cartridge");
+        ppu.cpu_write(Some(&mut cartridge), 0x0007, 0xFF);
+        assert_eq!(ppu.ppu_read(Some(&mut cartridge), 0x1236), 0x36, "CHR ROM can't be written");
+    }
+
+    #[test]
+    fn ppu_data_peek_test() {
+        let mut ppu = Ppu2C02::new();
+        let mut cartridge = cartridge(false);
+        ppu.cpu_write(Some(&mut cartridge), 0x0006, 0x00);
+        ppu.cpu_write(Some(&mut cartridge), 0x0006, 0x10);
+        ppu.cpu_read(Some(&mut cartridge), 0x0007);
+
+        for _ in 0..3 {
+            assert_eq!(ppu.cpu_peek(0x0007), 0x10, "Peeking should return what the next read returns");
+        }
+        assert_eq!(ppu.vram_address, 0x0011, "Peeking should not advance the address");
+        assert_eq!(ppu.data_buffer, 0x10, "Peeking should not refill the buffer");
+        assert_eq!(ppu.cpu_read(Some(&mut cartridge), 0x0007), 0x10);
+        assert_eq!(ppu.cpu_read(Some(&mut cartridge), 0x0007), 0x11);
+    }
+
+    #[test]
+    fn pattern_table_view_test() {
+        let mut ppu = Ppu2C02::new();
+        let cartridge = cartridge(false);
+        ppu.palette_table[..4].copy_from_slice(&[0x0F, 0x01, 0x02, 0x03]);
+        ppu.vram_address = 0x2345;
+        ppu.data_buffer = 0x67;
+
+        ppu.draw_pattern_table(Some(&cartridge), 1, 0);
+        // The first row of tile $10 of the second pattern table is $00 in the low and $08 in the high bits
+        let image = ppu.get_pattern_table(1);
+        assert_eq!(*image.get_pixel(0, 8), ppu.palette_screen[0x0F]);
+        assert_eq!(*image.get_pixel(4, 8), ppu.palette_screen[0x02]);
+        assert_eq!((ppu.vram_address, ppu.data_buffer), (0x2345, 0x67), "Drawing should not touch PPUDATA");
+    }
+
+    #[test]
+    fn open_bus_test() {
+        let mut ppu = Ppu2C02::new();
+        ppu.cpu_write(None, 0x0000, 0x5F);
+
+        assert_eq!(ppu.cpu_read(None, 0x0006), 0x5F, "Write only registers should read the last value on the bus");
+        assert_eq!(ppu.cpu_read(None, 0x0002), 0x1F, "The lower bits of the status should come from the bus");
+        assert_eq!(ppu.cpu_read(None, 0x0005), 0x1F, "Reading the status should drive its upper bits");
+    }
+
+    #[test]
+    fn open_bus_decay_test() {
+        let mut ppu = Ppu2C02::new();
+        ppu.cpu_write(None, 0x0000, 0xFF);
+
+        ppu.dot_counter += IO_BUS_DECAY_DOTS / 2;
+        ppu.cpu_read(None, 0x0002);
+        assert_eq!(ppu.cpu_peek(0x0000), 0x1F, "The bus should not have decayed yet");
+
+        // Only the bits driven by the status read are still there
+        ppu.dot_counter += IO_BUS_DECAY_DOTS / 2;
+        assert_eq!(ppu.cpu_peek(0x0000), 0x00, "The lower bits should have decayed");
+
+        ppu.cpu_write(None, 0x0000, 0xE0);
+        ppu.dot_counter += IO_BUS_DECAY_DOTS;
+        assert_eq!(ppu.cpu_peek(0x0000), 0x00, "The upper bits should have decayed");
+    }
+
+    /// Counts the scanlines of a frame and the ones of them with the vblank flag set
+    fn frame_timing(region: Region) -> (usize, usize) {
+        let mut ppu = Ppu2C02::new();
+        ppu.set_region(region);
+        // The ppu starts on the first visible scanline, so run up to the pre-render scanline first
+        while !ppu.is_frame_complete() {
+            ppu.run(1);
+        }
+        ppu.set_frame_complete(false);
+
+        let (mut scanlines, mut vblank) = (0, 0);
+        while !ppu.is_frame_complete() {
+            ppu.run(341);
+            scanlines += 1;
+            vblank += ppu.vblank as usize;
+        }
+        (scanlines, vblank)
+    }
+
+    #[test]
+    fn region_timing_test() {
+        assert_eq!(frame_timing(Region::Ntsc), (262, 20));
+        assert_eq!(frame_timing(Region::Pal), (312, 70));
+        assert_eq!(frame_timing(Region::Dendy), (312, 20));
+    }
+
+    #[test]
+    fn reset_latch_test() {
+        let mut ppu = Ppu2C02::new();
+        ppu.cpu_write(None, 0x0000, 0x80);
+        ppu.reset();
+        assert_eq!(ppu.control, 0x00, "A reset should clear the control register");
+
+        ppu.cpu_write(None, 0x0000, 0x80);
+        ppu.cpu_write(None, 0x0001, 0x1E);
+        assert_eq!((ppu.control, ppu.mask), (0x00, 0x00), "Writes should be ignored after a reset");
+        assert_eq!(ppu.cpu_peek(0x0006), 0x1E, "Ignored writes should still reach the data bus");
+
+        // The latch is cleared at the end of vblank, on the pre-render scanline
+        while !ppu.is_frame_complete() {
+            ppu.run(1);
+        }
+        ppu.cpu_write(None, 0x0000, 0x80);
+        assert_eq!(ppu.control, 0x00, "Writes should be ignored until vblank ends");
+        ppu.run(2);
+        ppu.cpu_write(None, 0x0000, 0x80);
+        assert_eq!(ppu.control, 0x80, "Writes should work again after vblank");
+    }
+
+    #[test]
+    fn vblank_read_test() {
+        let mut ppu = Ppu2C02::new();
+        while !ppu.vblank {
+            ppu.run(1);
+        }
+
+        assert_eq!(ppu.cpu_peek(0x0002) & 0x80, 0x80, "Vblank should be set");
+        assert_eq!(ppu.cpu_read(None, 0x0002) & 0x80, 0x80, "Vblank should be set");
+        assert_eq!(ppu.cpu_read(None, 0x0002) & 0x80, 0x00, "Reading the status should clear vblank");
+    }
+}
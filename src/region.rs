@@ -0,0 +1,156 @@
+//! The TV systems the NES was made for, and how the console is timed for each of them.
+//!
+//! All chips are clocked from one master clock. The cpu and the ppu divide it by different amounts, so on an NTSC console
+//! the ppu draws exactly 3 dots per cpu cycle, while a PAL console draws 3.2. PAL consoles also draw 50 frames per second
+//! instead of 60, with 312 scanlines per frame, most of them spent in a long vblank.
+//! The Dendy, a Russian famiclone, is a hybrid: it uses the PAL master clock and frame rate, but keeps the NTSC ratio of
+//! 3 dots per cpu cycle and the NTSC vblank length, by adding 50 idle scanlines after the picture instead.
+//! Its APU is an NTSC one running at the slightly faster cpu clock, so it uses the NTSC rate tables.
+
+use std::time::Duration;
+
+/// The TV system of a console (or the one a game was made for)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Region {
+    #[default]
+    Ntsc,
+    Pal,
+    Dendy,
+}
+
+impl Region {
+    pub const ALL: &'static [Region] = &[Region::Ntsc, Region::Pal, Region::Dendy];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Region::Ntsc => "ntsc",
+            Region::Pal => "pal",
+            Region::Dendy => "dendy",
+        }
+    }
+
+    /// Looks up a region by its name. The name is case insensitive
+    pub fn from_name(name: &str) -> Option<Region> {
+        let name = name.to_ascii_lowercase();
+        Self::ALL.iter().copied().find(|region| region.name() == name)
+    }
+
+    /// The frequency of the master clock in Hz
+    pub fn master_clock_rate(&self) -> u64 {
+        match self {
+            Region::Ntsc => 21_477_272,
+            Region::Pal | Region::Dendy => 26_601_712,
+        }
+    }
+
+    /// How long a frame takes. NTSC consoles skip a dot on odd frames while rendering, which is ignored here
+    pub fn frame_duration(&self) -> Duration {
+        // 341 dots per scanline
+        let master_cycles = self.ppu_divider() * 341 * self.scanlines() as u64;
+        Duration::from_nanos(master_cycles * 1_000_000_000 / self.master_clock_rate())
+    }
+
+    /// The amount of master clock cycles per cpu cycle
+    pub fn cpu_divider(&self) -> u64 {
+        match self {
+            Region::Ntsc => 12,
+            Region::Pal => 16,
+            Region::Dendy => 15,
+        }
+    }
+
+    /// The amount of master clock cycles per ppu dot
+    pub fn ppu_divider(&self) -> u64 {
+        match self {
+            Region::Ntsc => 4,
+            Region::Pal | Region::Dendy => 5,
+        }
+    }
+
+    /// The amount of dots the ppu has drawn when the cpu starts the given cycle, if both started at the same time
+    pub fn dots_before_cycle(&self, cycle: u64) -> u64 {
+        cycle * self.cpu_divider() / self.ppu_divider()
+    }
+
+    /// The amount of cpu cycles that started before the ppu finished the given amount of dots, if both started at the same time
+    pub fn cycles_before_dot(&self, dots: u64) -> u64 {
+        (dots * self.ppu_divider()).div_ceil(self.cpu_divider())
+    }
+
+    /// The amount of scanlines per frame, including the pre-render scanline
+    pub fn scanlines(&self) -> i16 {
+        match self {
+            Region::Ntsc => 262,
+            Region::Pal | Region::Dendy => 312,
+        }
+    }
+
+    /// The scanline vblank starts on. It lasts until the pre-render scanline,
+    /// which is 20 scanlines on NTSC consoles and the Dendy, and 70 on PAL consoles
+    pub fn vblank_scanline(&self) -> i16 {
+        match self {
+            Region::Ntsc | Region::Pal => 241,
+            Region::Dendy => 291,
+        }
+    }
+
+    /// The periods of the noise channel in cpu cycles, selected by the lower 4 bits of $400E
+    pub fn noise_periods(&self) -> &'static [u16; 16] {
+        match self {
+            Region::Ntsc | Region::Dendy => &[4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068],
+            Region::Pal => &[4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778],
+        }
+    }
+
+    /// The periods of the DMC in cpu cycles, selected by the lower 4 bits of $4010
+    pub fn dmc_rates(&self) -> &'static [u16; 16] {
+        match self {
+            Region::Ntsc | Region::Dendy => &[428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54],
+            Region::Pal => &[398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50],
+        }
+    }
+
+    /// The cpu cycles the frame counter steps on, counted from the start of its sequence.
+    /// The 5-step sequence doesn't clock anything on its fourth step
+    pub fn frame_counter_steps(&self, five_step: bool) -> &'static [u32] {
+        match (self, five_step) {
+            (Region::Ntsc, false) | (Region::Dendy, false) => &[7457, 14913, 22371, 29829],
+            (Region::Ntsc, true) | (Region::Dendy, true) => &[7457, 14913, 22371, 29829, 37281],
+            (Region::Pal, false) => &[8313, 16627, 24939, 33253],
+            (Region::Pal, true) => &[8313, 16627, 24939, 33253, 41565],
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::region::Region;
+
+    #[test]
+    fn clock_ratio_test() {
+        let ratio = |region: Region| region.cpu_divider() as f64 / region.ppu_divider() as f64;
+        assert_eq!(ratio(Region::Ntsc), 3.0);
+        assert_eq!(ratio(Region::Pal), 3.2);
+        assert_eq!(ratio(Region::Dendy), 3.0);
+    }
+
+    #[test]
+    fn cycle_dot_conversion_test() {
+        for &region in Region::ALL {
+            for cycle in 0..100 {
+                let dots = region.dots_before_cycle(cycle);
+                assert_eq!(region.cycles_before_dot(dots), cycle, "{:?}: cycle {} starts during dot {}", region, cycle, dots);
+                assert_eq!(region.cycles_before_dot(dots + 1), cycle + 1, "{:?}: cycle {} starts during dot {}", region, cycle, dots);
+            }
+        }
+        assert_eq!(Region::Pal.dots_before_cycle(5), 16);
+    }
+
+    #[test]
+    fn frame_rate_test() {
+        let frame_rate = |region: Region| 1.0 / region.frame_duration().as_secs_f64();
+        assert!((frame_rate(Region::Ntsc) - 60.1).abs() < 0.1, "NTSC runs at 60 frames per second");
+        assert!((frame_rate(Region::Pal) - 50.0).abs() < 0.1, "PAL runs at 50 frames per second");
+        assert!((frame_rate(Region::Dendy) - 50.0).abs() < 0.1, "The Dendy runs at 50 frames per second");
+    }
+}
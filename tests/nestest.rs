@@ -0,0 +1,125 @@
+//! Compares the trace of nestest (https://www.qmtpro.com/~nes/misc/nestest.txt) with the reference log of Nintendulator.
+//!
+//! nestest runs without a screen in "automation mode", when it is started at $C000 instead of the reset vector.
+//! The emulator is run headless with `nest trace`, and every line of its trace is compared with `nestest.log`:
+//! the program counter, the opcode bytes, the registers, the ppu position and the cpu cycle.
+//! The comparison stops at the first line that differs and reports the fields that differ, together with the lines before it.
+//!
+//! The ROM and the log belong in `tests/fixtures`. See `tests/fixtures/README.md` for where to get them.
+
+use std::process::Command;
+
+const ROM: &str = "tests/fixtures/nestest.nes";
+const LOG: &str = "tests/fixtures/nestest.log";
+
+/// Where nestest starts in automation mode
+const START: &str = "C000";
+
+/// How many lines before a difference are shown
+const CONTEXT_LINES: usize = 5;
+
+/// The fields of a Nintendulator log line that are compared
+#[derive(Debug, PartialEq, Eq)]
+struct LogLine<'a> {
+    pc: &'a str,
+    bytes: &'a str,
+    a: &'a str,
+    x: &'a str,
+    y: &'a str,
+    p: &'a str,
+    sp: &'a str,
+    scanline: &'a str,
+    dot: &'a str,
+    cycle: &'a str,
+}
+
+impl<'a> LogLine<'a> {
+    fn parse(line: &'a str) -> Option<Self> {
+        let (ppu_start, ppu_end) = (line.find(" PPU:")? + 5, line.find(" CYC:")?);
+        let mut ppu = line.get(ppu_start..ppu_end)?.split(',').map(str::trim);
+
+        Some(LogLine {
+            pc: line.get(0..4)?,
+            bytes: line.get(6..14)?.trim(),
+            a: field(line, " A:")?,
+            x: field(line, " X:")?,
+            y: field(line, " Y:")?,
+            p: field(line, " P:")?,
+            sp: field(line, " SP:")?,
+            scanline: ppu.next()?,
+            dot: ppu.next()?,
+            cycle: field(line, " CYC:")?,
+        })
+    }
+
+    /// The names of all fields that differ between the two lines, together with both values
+    fn differences(&self, actual: &LogLine) -> Vec<String> {
+        let fields = [
+            ("PC", self.pc, actual.pc),
+            ("opcode bytes", self.bytes, actual.bytes),
+            ("A", self.a, actual.a),
+            ("X", self.x, actual.x),
+            ("Y", self.y, actual.y),
+            ("P", self.p, actual.p),
+            ("SP", self.sp, actual.sp),
+            ("PPU scanline", self.scanline, actual.scanline),
+            ("PPU dot", self.dot, actual.dot),
+            ("CYC", self.cycle, actual.cycle),
+        ];
+
+        fields.iter()
+            .filter(|(_, expected, actual)| expected != actual)
+            .map(|(name, expected, actual)| format!("{} is {}, expected {}", name, actual, expected))
+            .collect()
+    }
+}
+
+/// The value following the given label, up to the next space
+fn field<'a>(line: &'a str, label: &str) -> Option<&'a str> {
+    let start = line.find(label)? + label.len();
+    line[start..].split_whitespace().next()
+}
+
+#[test]
+#[ignore = "nestest.nes and nestest.log are not vendored yet, see tests/fixtures/README.md"]
+fn nestest() {
+    let log = std::fs::read_to_string(LOG)
+        .unwrap_or_else(|e| panic!("Error reading {}: {}. See the documentation of this test", LOG, e));
+
+    // Unofficial opcodes are marked with a '*' in front of the mnemonic. They are not emulated, so the comparison
+    // only covers the official opcodes, which nestest tests first
+    let expected = log.lines()
+        .take_while(|line| line.as_bytes().get(15) != Some(&b'*'))
+        .collect::<Vec<_>>();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_nest"))
+        .args(["trace", ROM, &expected.len().to_string(), START, "nintendulator"])
+        .output()
+        .expect("Error running nest");
+    assert!(output.status.success(), "nest trace failed:\n{}", String::from_utf8_lossy(&output.stderr));
+
+    let stdout = String::from_utf8(output.stdout).expect("The trace is not valid UTF-8");
+    let actual = stdout.lines().collect::<Vec<_>>();
+
+    for (i, (&expected_line, &actual_line)) in expected.iter().zip(&actual).enumerate() {
+        let expected_fields = LogLine::parse(expected_line)
+            .unwrap_or_else(|| panic!("Error parsing line {} of {}: {}", i + 1, LOG, expected_line));
+        let differences = match LogLine::parse(actual_line) {
+            Some(actual_fields) => expected_fields.differences(&actual_fields),
+            None => vec![String::from("The line could not be parsed")],
+        };
+
+        if !differences.is_empty() {
+            let context = expected[i.saturating_sub(CONTEXT_LINES)..i].iter()
+                .map(|line| format!("      {}", line))
+                .collect::<Vec<_>>()
+                .join("\n");
+            panic!(
+                "The trace differs at line {}:\n  {}\n\n{}\n    - {}\n    + {}\n",
+                i + 1, differences.join("\n  "), context, expected_line, actual_line
+            );
+        }
+    }
+
+    assert_eq!(actual.len(), expected.len(), "The trace ended early");
+}